@@ -1 +1 @@
-cargo run --bin "enoki_wrapped_token" > "$(dirname "$0")"/enoki_wrapped_token.did
+cargo run --bin "enoki_wrapped_token_did" > "$(dirname "$0")"/enoki_wrapped_token.did
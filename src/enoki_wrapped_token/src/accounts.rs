@@ -2,11 +2,14 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 
 use candid::{candid_method, CandidType, Nat, Principal};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 use serde::{Deserialize, Serialize};
 
+use enoki_wrapped_token_shared::{env, log};
 use enoki_wrapped_token_shared::types::*;
 
+use crate::metrics::{self, Counter};
+use crate::routing::route;
 use crate::shards::{get_lowest_utilization_shard, update_shard_accounts};
 
 #[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
@@ -28,62 +31,138 @@ pub fn import_stable_storage(user_accounts: UserAccounts) {
     USER_ACCOUNTS.with(|a| a.replace(user_accounts));
 }
 
+pub fn get_account_count() -> usize {
+    USER_ACCOUNTS.with(|a| a.borrow().len())
+}
+
 pub fn get_user_account(user: &Principal) -> Option<UserAccount> {
     USER_ACCOUNTS.with(|a| a.borrow().get(user).cloned())
 }
 
+/// Every registered user with its assigned shard.
+pub fn get_assigned_shards() -> Vec<(Principal, Principal)> {
+    USER_ACCOUNTS.with(|a| {
+        a.borrow()
+            .iter()
+            .map(|(user, account)| (*user, account.assigned_shard))
+            .collect()
+    })
+}
+
+/// The shard holding the accounts of `user`: its assigned shard, or in the hashed routing mode
+/// the shard the ring places it on. `None` if it has to be registered first.
+pub fn locate_shard(user: &Principal) -> Option<Principal> {
+    get_user_account(user)
+        .map(|account| account.assigned_shard)
+        .or_else(|| route(user))
+}
+
+/// Records that the accounts of `user` moved to `shard`, registering `user` if it was not.
+pub fn move_account(user: Principal, shard: Principal) {
+    let previous = USER_ACCOUNTS.with(|a| {
+        a.borrow_mut()
+            .insert(user, UserAccount { assigned_shard: shard })
+            .map(|account| account.assigned_shard)
+    });
+    if previous != Some(shard) {
+        if let Some(previous) = previous {
+            update_shard_accounts(previous, |count| *count = count.saturating_sub(1));
+        }
+        update_shard_accounts(shard, |count| *count += 1);
+    }
+}
+
+/// Assigns a shard to `address`, which also holds all of its subaccounts. In the hashed routing
+/// mode, that is the shard the ring places it on, which needs no call.
 #[update(name = "register")]
 #[candid_method(update)]
-async fn register(address: Principal) -> Principal {
+pub async fn register(address: Principal) -> Principal {
+    try_register(address).await.unwrap()
+}
+
+/// Like `register`, but returns an error instead of trapping when the shard refuses the
+/// account, which is then left unassigned.
+pub async fn try_register(address: Principal) -> Result<Principal> {
     if let Some(existing) =
     USER_ACCOUNTS.with(|a| a.borrow().get(&address).map(|a| a.assigned_shard))
     {
-        return existing;
+        return Ok(existing);
+    }
+    if let Some(routed) = route(&address) {
+        // the shard already takes the accounts the ring places on it
+        move_account(address, routed);
+        return Ok(routed);
     }
     let assigned_shard = get_lowest_utilization_shard();
     let new_user = UserAccount { assigned_shard };
     USER_ACCOUNTS.with(|a| a.borrow_mut().insert(address, new_user));
     update_shard_accounts(assigned_shard, |count| *count += 1);
 
-    let response: Result<()> = ic_cdk::call(assigned_shard, "createAccount", (address, ))
+    let digest = log::digest((address, ));
+    log::started("createAccount", digest.clone());
+    let response: Result<()> = env::call(assigned_shard, "createAccount", (address, ))
         .await
         .map_err(|err| err.into());
+    let result = match response {
+        Ok(_) | Err(TxError::AccountAlreadyExists) => Ok(assigned_shard),
+        Err(err) => {
+            unassign(address, assigned_shard);
+            Err(err)
+        }
+    };
+    log::finished("createAccount", digest, &result);
+    result
+}
 
-    match response {
-        Ok(_) | Err(TxError::AccountAlreadyExists) => assigned_shard,
-        Err(err) => panic!("{:?}", err),
+/// Drops the assignment of `address` to `shard`, unless it moved in the meantime.
+fn unassign(address: Principal, shard: Principal) {
+    let removed = USER_ACCOUNTS.with(|a| {
+        let mut accounts = a.borrow_mut();
+        match accounts.get(&address) {
+            Some(account) if account.assigned_shard == shard => accounts.remove(&address).is_some(),
+            _ => false,
+        }
+    });
+    if removed {
+        update_shard_accounts(shard, |count| *count = count.saturating_sub(1));
     }
 }
 
 #[query(name = "getAssignedShardId")]
 #[candid_method(query, rename = "getAssignedShardId")]
-fn get_assigned_shard_id(address: Principal) -> Principal {
-    USER_ACCOUNTS
-        .with(|a| {
-            if let Some(UserAccount { assigned_shard, .. }) = a.borrow().get(&address) {
-                Ok(*assigned_shard)
-            } else {
-                Err(TxError::AccountDoesNotExist {
-                    shard: format!("main contract {}", ic_cdk::id()),
-                    user: address.to_string(),
-                })
-            }
+pub fn get_assigned_shard_id(address: Principal) -> Principal {
+    locate_shard(&address)
+        .ok_or_else(|| TxError::AccountDoesNotExist {
+            shard: format!("main contract {}", env::id()),
+            user: address.to_string(),
         })
         .unwrap()
 }
 
 #[update(name = "transfer")]
 #[candid_method(update)]
-async fn transfer(to: Principal, amount: Nat) {
-    let from = ic_cdk::caller();
-    let from_shard = register(from).await;
-    let to_shard = register(to).await;
-    let response: Result<()> = ic_cdk::call(
+pub async fn transfer(to: Principal, amount: Nat) {
+    let from = env::caller();
+    // in the hashed routing mode, shards take the accounts the ring places on them
+    let from_shard = match locate_shard(&from) {
+        Some(shard) => shard,
+        None => register(from).await,
+    };
+    let to_shard = match locate_shard(&to) {
+        Some(shard) => shard,
+        None => register(to).await,
+    };
+    // the shard logs the same digest for `transferFromManager`
+    let digest = log::digest((from, to_shard, to, amount.clone()));
+    log::started("transfer", digest.clone());
+    let response: Result<(Result<()>, )> = env::call(
         from_shard,
         "transferFromManager",
         (from, to_shard, to, amount),
     )
         .await
         .map_err(|err| err.into());
-    response.unwrap()
+    response.unwrap().0.unwrap();
+    metrics::record(Counter::Transfer);
+    log::succeeded("transfer", digest);
 }
@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, types::number::Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::backup::{Backup, BackupManifest, Restore};
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::accounts::get_account_count;
+use crate::governance::assert_governance_disabled;
+use crate::roles::assert_has_role;
+use crate::shards::get_shard_ids;
+use crate::snapshots::get_snapshots;
+use crate::upgrade::{export_state, import_state, UpgradePayload};
+
+thread_local! {
+    static BACKUP: RefCell<Option<Backup>> = const { RefCell::new(None) };
+    static RESTORE: RefCell<Option<Restore>> = const { RefCell::new(None) };
+}
+
+/// Checked again when the restore finishes, in case shards or accounts were added since it
+/// began.
+fn assert_fresh() -> Result<()> {
+    if !get_shard_ids().is_empty() || get_account_count() > 0 {
+        return Err(TxError::Other(
+            "Backups are only restored onto a fresh main contract".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// What a restored main contract must hold for its backup to be accepted.
+fn get_totals() -> Vec<(String, Nat)> {
+    vec![
+        ("accounts".to_string(), Nat::from(get_account_count())),
+        ("shards".to_string(), Nat::from(get_shard_ids().len())),
+        ("snapshots".to_string(), Nat::from(get_snapshots().len())),
+    ]
+}
+
+/// Encodes the whole state of the main contract, to be read with `getBackupChunk`, and returns
+/// its manifest. Replaces the previous backup. Shards are backed up separately.
+#[update(name = "beginBackup")]
+#[candid_method(update, rename = "beginBackup")]
+pub fn begin_backup() -> Result<BackupManifest> {
+    assert_has_role(Role::Admin)?;
+    let payload = export_state();
+    import_state(payload.clone());
+    let bytes = candid::encode_one(payload).map_err(|err| TxError::Other(err.to_string()))?;
+    let backup = Backup::new(bytes, env::time(), get_totals());
+    let manifest = backup.manifest.clone();
+    BACKUP.with(|b| b.replace(Some(backup)));
+    log::succeeded("beginBackup", log::digest((manifest.size, )));
+    Ok(manifest)
+}
+
+#[query(name = "getBackupChunk")]
+#[candid_method(query, rename = "getBackupChunk")]
+pub fn get_backup_chunk(index: u64) -> Result<ByteBuf> {
+    assert_has_role(Role::Admin)?;
+    BACKUP.with(|b| match b.borrow().as_ref() {
+        Some(backup) => backup.chunk(index),
+        None => Err(TxError::Other("No backup was taken".to_string())),
+    })
+}
+
+/// Starts restoring a backup onto a main contract that was just installed, before any shard is
+/// added. Refused under governance, whose proposals the restore would replace.
+#[update(name = "beginRestore")]
+#[candid_method(update, rename = "beginRestore")]
+pub fn begin_restore(manifest: BackupManifest) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::Admin)?;
+    assert_fresh()?;
+    let restore = Restore::new(manifest)?;
+    RESTORE.with(|r| r.replace(Some(restore)));
+    Ok(())
+}
+
+#[update(name = "putRestoreChunk")]
+#[candid_method(update, rename = "putRestoreChunk")]
+pub fn put_restore_chunk(index: u64, chunk: ByteBuf) -> Result<()> {
+    assert_has_role(Role::Admin)?;
+    RESTORE.with(|r| match r.borrow_mut().as_mut() {
+        Some(restore) => restore.put(index, chunk.into_vec()),
+        None => Err(TxError::Other("No restore was started".to_string())),
+    })
+}
+
+/// Replaces the state of the main contract with the uploaded backup, once it matches its
+/// checksum, and keeps it only if its totals match those of the manifest.
+#[update(name = "finishRestore")]
+#[candid_method(update, rename = "finishRestore")]
+pub fn finish_restore() -> Result<()> {
+    let result = finish_restore_internal();
+    log::finished("finishRestore", log::digest(()), &result);
+    result
+}
+
+/// Has shard `id` restore the backup uploaded to it, which a backup of a governed shard
+/// requires.
+pub async fn finish_shard_restore_internal(id: Principal) -> Result<()> {
+    let response: Result<(Result<()>, )> = env::call(id, "finishRestore", ())
+        .await
+        .map_err(|err| err.into());
+    response?.0
+}
+
+fn finish_restore_internal() -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::Admin)?;
+    assert_fresh()?;
+    let restore = RESTORE
+        .with(|r| r.take())
+        .ok_or_else(|| TxError::Other("No restore was started".to_string()))?;
+    let totals = restore.manifest.totals.clone();
+    let bytes = restore.finish()?;
+    let payload: UpgradePayload =
+        candid::decode_one(&bytes).map_err(|err| TxError::Other(err.to_string()))?;
+
+    let previous = export_state();
+    import_state(payload);
+    let restored = get_totals();
+    if restored != totals {
+        import_state(previous);
+        return Err(TxError::Other(format!(
+            "Restored totals {:?} do not match the backup's {:?}",
+            restored, totals
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use enoki_wrapped_token_macros::*;
+use ic_cdk::api::call::CallResult;
+use serde::Serialize;
+
+use enoki_wrapped_token_shared::types::*;
+use enoki_wrapped_token_shared::{env, log};
+
+use crate::governance::assert_governance_disabled;
+use crate::roles::assert_has_role;
+use crate::shards::{get_shard_ids, record_shard_cycles};
+
+/// Polls kept in the history of each shard, a day's worth at the default interval.
+pub const MAX_CYCLES_SAMPLES: usize = 24;
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct CyclesConfig {
+    /// Shards polled below this balance are topped up.
+    pub threshold: u64,
+    /// Cycles sent with each top-up.
+    pub top_up_amount: u64,
+    /// Cycles the main contract keeps for itself: only its balance above this, and the pool,
+    /// fund top-ups.
+    pub min_reserve: u64,
+    /// Nanoseconds between two polls of the shards.
+    pub poll_interval: u64,
+}
+
+impl Default for CyclesConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1_000_000_000_000,
+            top_up_amount: 2_000_000_000_000,
+            min_reserve: 2_000_000_000_000,
+            poll_interval: 3_600_000_000_000,
+        }
+    }
+}
+
+/// The cycle balance of a shard when it was polled, and the cycles sent to it then.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct CyclesSample {
+    pub time: u64,
+    pub balance: u64,
+    pub top_up: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct CyclesState {
+    config: CyclesConfig,
+    /// Cycles received through `depositCycles`, spent on top-ups before the reserve.
+    pool: u64,
+    last_poll: u64,
+}
+
+thread_local! {
+    static CYCLES: RefCell<CyclesState> = RefCell::new(CyclesState::default());
+}
+
+pub fn export_stable_storage() -> (CyclesState, ) {
+    (CYCLES.with(|c| c.take()), )
+}
+
+pub fn import_stable_storage(state: CyclesState) {
+    CYCLES.with(|c| c.replace(state));
+}
+
+#[query(name = "getCyclesConfig")]
+#[candid_method(query, rename = "getCyclesConfig")]
+pub fn get_cycles_config() -> CyclesConfig {
+    CYCLES.with(|c| c.borrow().config.clone())
+}
+
+#[update(name = "setCyclesConfig")]
+#[candid_method(update, rename = "setCyclesConfig")]
+pub fn set_cycles_config(config: CyclesConfig) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::ShardOperator).unwrap();
+    set_cycles_config_internal(config);
+}
+
+pub fn set_cycles_config_internal(config: CyclesConfig) {
+    CYCLES.with(|c| c.borrow_mut().config = config);
+}
+
+#[query(name = "getCyclesPool")]
+#[candid_method(query, rename = "getCyclesPool")]
+pub fn get_cycles_pool() -> u64 {
+    CYCLES.with(|c| c.borrow().pool)
+}
+
+/// Adds the cycles attached to the call to the pool that tops up the shards, e.g. cycles bought
+/// by a fee beneficiary with its fees. Returns the cycles accepted.
+#[update(name = "depositCycles")]
+#[candid_method(update, rename = "depositCycles")]
+pub fn deposit_cycles() -> u64 {
+    let accepted = env::msg_cycles_accept(u64::MAX);
+    CYCLES.with(|c| c.borrow_mut().pool += accepted);
+    accepted
+}
+
+/// Polls the shards once every `poll_interval`.
+pub async fn heartbeat() {
+    let now = env::time();
+    let due = CYCLES.with(|c| {
+        let mut c = c.borrow_mut();
+        let due = now >= c.last_poll.saturating_add(c.config.poll_interval);
+        if due {
+            c.last_poll = now;
+        }
+        due
+    });
+    if due {
+        poll_shards().await;
+    }
+}
+
+/// Shards are polled one after the other, so that each top-up sees the balance left by the
+/// previous ones.
+async fn poll_shards() {
+    for shard in get_shard_ids() {
+        let (sample, warning) = poll_shard(shard).await;
+        record_shard_cycles(shard, sample, warning);
+    }
+}
+
+async fn poll_shard(shard: Principal) -> (Option<CyclesSample>, Option<String>) {
+    let balance: CallResult<(u64, )> = env::call(shard, "shardGetCycles", ()).await;
+    let balance = match balance {
+        Ok((balance, )) => balance,
+        Err(err) => {
+            log::warning("pollShardCycles", log::digest((shard, )), &err);
+            return (None, Some(format!("failed to read the cycle balance: {:?}", err)));
+        }
+    };
+    let config = get_cycles_config();
+    let mut sample = CyclesSample {
+        time: env::time(),
+        balance,
+        top_up: 0,
+    };
+    if balance >= config.threshold {
+        return (Some(sample), None);
+    }
+    match top_up(shard, config.top_up_amount).await {
+        Ok(()) => {
+            sample.top_up = config.top_up_amount;
+            (Some(sample), None)
+        }
+        Err(reason) => {
+            let warning = format!(
+                "cycle balance {} is below the threshold of {}: {}",
+                balance, config.threshold, reason
+            );
+            (Some(sample), Some(warning))
+        }
+    }
+}
+
+/// Sends `amount` cycles to `shard`, taken from the pool first and then from the reserve.
+async fn top_up(shard: Principal, amount: u64) -> std::result::Result<(), String> {
+    let digest = log::digest((shard, amount));
+    let funds = CYCLES.with(|c| {
+        let mut c = c.borrow_mut();
+        let reserve = env::canister_balance()
+            .saturating_sub(c.pool)
+            .saturating_sub(c.config.min_reserve);
+        let available = c.pool.saturating_add(reserve);
+        if available < amount {
+            return Err(available);
+        }
+        let from_pool = c.pool.min(amount);
+        c.pool -= from_pool;
+        Ok(from_pool)
+    });
+    let from_pool = match funds {
+        Ok(from_pool) => from_pool,
+        Err(available) => {
+            let reason = format!("only {} cycles are available for top-ups", available);
+            log::warning("topUpShard", digest, &reason);
+            return Err(reason);
+        }
+    };
+
+    let result: CallResult<()> = env::call_with_payment(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIdRecord { canister_id: shard }, ),
+        amount,
+    )
+    .await;
+    match result {
+        Ok(()) => {
+            log::succeeded("topUpShard", digest);
+            Ok(())
+        }
+        Err(err) => {
+            // the cycles of a rejected call are refunded
+            CYCLES.with(|c| c.borrow_mut().pool += from_pool);
+            log::warning("topUpShard", digest, &err);
+            Err(format!("top-up failed: {:?}", err))
+        }
+    }
+}
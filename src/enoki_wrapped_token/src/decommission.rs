@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashMap};
+
+use candid::{candid_method, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::types::*;
+use enoki_wrapped_token_shared::{env, log};
+
+use crate::accounts::{get_user_account, move_account};
+use crate::governance::assert_governance_disabled;
+use crate::roles::assert_has_role;
+use crate::routing::route;
+use crate::shards::{
+    get_active_shards, get_lowest_utilization_shard, get_shard, push_registry, remove_shard,
+    set_shard_status, ShardStatus,
+};
+
+/// Accounts moved by one call to `drainShard` or `rebalanceShard`, which are called again until
+/// none are left.
+pub const MAX_MIGRATION_BATCH: usize = 100;
+
+fn assert_draining(id: Principal) -> Result<()> {
+    match get_shard(&id) {
+        None => Err(TxError::ShardDoesNotExist),
+        Some(shard) if shard.status != ShardStatus::Draining => Err(TxError::Other(
+            "Shard is not being decommissioned".to_string(),
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Starts decommissioning a shard: no new accounts are assigned to it, and it stops accepting
+/// wraps. Its accounts are then moved with `drainShard`.
+#[update(name = "startDecommission")]
+#[candid_method(update, rename = "startDecommission")]
+pub async fn start_decommission(id: Principal) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::ShardOperator)?;
+    start_decommission_internal(id).await
+}
+
+pub async fn start_decommission_internal(id: Principal) -> Result<()> {
+    let digest = log::digest((id, ));
+    let result = mark_draining(id).await;
+    log::finished("startDecommission", digest, &result);
+    result
+}
+
+async fn mark_draining(id: Principal) -> Result<()> {
+    let shard = get_shard(&id).ok_or(TxError::ShardDoesNotExist)?;
+    if shard.status != ShardStatus::Active {
+        return Err(TxError::Other("Shard is already being decommissioned".to_string()));
+    }
+    if get_active_shards().len() < 2 {
+        return Err(TxError::Other("The last active shard cannot be decommissioned".to_string()));
+    }
+
+    // set first, so that no account is assigned to the shard while it is told
+    set_shard_status(id, ShardStatus::Draining);
+    let response: Result<(Result<()>, )> = env::call(id, "setDraining", (true, ))
+        .await
+        .map_err(|err| err.into());
+    // returned instead of trapping, which would keep the shard draining
+    if let Err(error) = response.and_then(|res| res.0) {
+        set_shard_status(id, ShardStatus::Active);
+        return Err(error);
+    }
+    // the ring leaves the shard out; a failed push is logged, and `fixSiblings` retries it
+    let _ = push_registry().await;
+    Ok(())
+}
+
+/// Makes a draining shard active again. Accounts already moved stay on their new shard.
+#[update(name = "cancelDecommission")]
+#[candid_method(update, rename = "cancelDecommission")]
+pub async fn cancel_decommission(id: Principal) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::ShardOperator)?;
+    cancel_decommission_internal(id).await
+}
+
+pub async fn cancel_decommission_internal(id: Principal) -> Result<()> {
+    assert_draining(id)?;
+    let response: Result<(Result<()>, )> = env::call(id, "setDraining", (false, ))
+        .await
+        .map_err(|err| err.into());
+    response?.0?;
+    set_shard_status(id, ShardStatus::Active);
+    let _ = push_registry().await;
+    Ok(())
+}
+
+/// Moves up to `MAX_MIGRATION_BATCH` accounts off a draining shard, and returns how many it still
+/// holds. In the hashed routing mode, accounts go to the shard the ring places them on.
+/// Otherwise, owners registered on another shard, such as fee beneficiaries, join their account
+/// there, and the others go to the active shards with the fewest accounts. Accounts waiting for a
+/// transfer to settle are moved by a later call.
+#[update(name = "drainShard")]
+#[candid_method(update, rename = "drainShard")]
+pub async fn drain_shard(id: Principal) -> Result<u64> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::ShardOperator)?;
+    drain_shard_internal(id).await
+}
+
+pub async fn drain_shard_internal(id: Principal) -> Result<u64> {
+    let digest = log::digest((id, ));
+    let result = move_accounts(id).await;
+    log::finished("drainShard", digest, &result);
+    result
+}
+
+async fn move_accounts(id: Principal) -> Result<u64> {
+    assert_draining(id)?;
+    let owners = get_account_owners(id).await?;
+
+    let mut loads: HashMap<Principal, u64> = get_active_shards()
+        .into_iter()
+        .map(|shard| (shard.id, shard.num_accounts))
+        .collect();
+    let mut batches: BTreeMap<Principal, Vec<Principal>> = BTreeMap::new();
+    for &owner in owners.iter().take(MAX_MIGRATION_BATCH) {
+        let target = match (route(&owner), get_user_account(&owner)) {
+            (Some(target), _) => target,
+            (None, Some(account)) if loads.contains_key(&account.assigned_shard) => {
+                account.assigned_shard
+            }
+            (None, _) => {
+                let (&target, load) = loads
+                    .iter_mut()
+                    .min_by_key(|(shard, load)| (**load, shard.to_string()))
+                    .ok_or(TxError::ShardDoesNotExist)?;
+                *load += 1;
+                target
+            }
+        };
+        batches.entry(target).or_default().push(owner);
+    }
+
+    let moved = migrate_accounts(id, batches).await?;
+    Ok((owners.len() - moved) as u64)
+}
+
+pub async fn get_account_owners(id: Principal) -> Result<Vec<Principal>> {
+    let response: Result<(Vec<Principal>, )> = env::call(id, "shardGetAccountOwners", ())
+        .await
+        .map_err(|err| err.into());
+    Ok(response?.0)
+}
+
+/// Asks shard `id` to move each batch of owners to the shard it is keyed by, records where the
+/// moved owners are, and returns how many moved.
+pub async fn migrate_accounts(
+    id: Principal,
+    batches: BTreeMap<Principal, Vec<Principal>>,
+) -> Result<usize> {
+    let mut moved = 0;
+    for (target, batch) in batches {
+        let response: Result<(Result<Vec<Principal>>, )> =
+            env::call(id, "shardMigrateAccounts", (target, batch))
+                .await
+                .map_err(|err| err.into());
+        // accounts moved by the previous batches are already recorded
+        let migrated = response?.0?;
+        moved += migrated.len();
+        for owner in migrated {
+            move_account(owner, target);
+        }
+    }
+    Ok(moved)
+}
+
+/// Completes the decommission of a drained shard: its custody of the underlying token and its
+/// unowned fees go to an active shard, its cycles to the pool of this contract, and it is
+/// removed from the shards and from their siblings. The canister can be deleted afterwards.
+/// If some shard misses the new registry, the error is returned and `fixSiblings` sends it
+/// again.
+#[update(name = "finishDecommission")]
+#[candid_method(update, rename = "finishDecommission")]
+pub async fn finish_decommission(id: Principal) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::ShardOperator)?;
+    finish_decommission_internal(id).await
+}
+
+pub async fn finish_decommission_internal(id: Principal) -> Result<()> {
+    let digest = log::digest((id, ));
+    let result = remove_drained_shard(id).await;
+    log::finished("finishDecommission", digest, &result);
+    result
+}
+
+async fn remove_drained_shard(id: Principal) -> Result<()> {
+    assert_draining(id)?;
+    let target = get_lowest_utilization_shard();
+    let response: Result<(Result<()>, )> = env::call(id, "shardFinishDecommission", (target, ))
+        .await
+        .map_err(|err| err.into());
+    response?.0?;
+
+    remove_shard(id);
+    push_registry().await
+}
@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::accounts::locate_shard;
+use crate::governance::assert_governance_disabled;
+use crate::roles::assert_has_role;
+use crate::shards::update_block_frozen_recipients;
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct FreezeEvent {
+    pub account: Principal,
+    pub frozen: bool,
+    pub by: Principal,
+    pub time: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct FreezeState {
+    frozen: HashSet<Principal>,
+    block_recipients: bool,
+    events: Vec<FreezeEvent>,
+}
+
+thread_local! {
+    static FREEZE_STATE: RefCell<FreezeState> = RefCell::new(FreezeState::default());
+}
+
+pub fn export_stable_storage() -> (FreezeState, ) {
+    (FREEZE_STATE.with(|f| f.take()), )
+}
+
+pub fn import_stable_storage(freeze_state: FreezeState) {
+    FREEZE_STATE.with(|f| f.replace(freeze_state));
+}
+
+pub fn get_block_frozen_recipients() -> bool {
+    FREEZE_STATE.with(|f| f.borrow().block_recipients)
+}
+
+pub async fn set_account_frozen_internal(account: Principal, frozen: bool) -> Result<()> {
+    // freezing an account does not create it
+    let assigned_shard = locate_shard(&account).ok_or_else(|| TxError::AccountDoesNotExist {
+        shard: format!("main contract {}", env::id()),
+        user: account.to_string(),
+    })?;
+    let digest = log::digest((account, frozen));
+    log::started("setAccountFrozen", digest.clone());
+    let response: Result<(Result<()>,)> =
+        env::call(assigned_shard, "setAccountFrozen", (account, frozen))
+            .await
+            .map_err(|err| err.into());
+    let result = response.and_then(|(result, )| result);
+    log::finished("setAccountFrozen", digest, &result);
+    result?;
+
+    FREEZE_STATE.with(|f| {
+        let mut f = f.borrow_mut();
+        if frozen {
+            f.frozen.insert(account);
+        } else {
+            f.frozen.remove(&account);
+        }
+        f.events.push(FreezeEvent {
+            account,
+            frozen,
+            by: env::caller(),
+            time: env::time(),
+        });
+    });
+    Ok(())
+}
+
+#[update(name = "freezeAccount")]
+#[candid_method(update, rename = "freezeAccount")]
+pub async fn freeze_account(account: Principal) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::Admin).unwrap();
+    set_account_frozen_internal(account, true).await.unwrap();
+}
+
+#[update(name = "unfreezeAccount")]
+#[candid_method(update, rename = "unfreezeAccount")]
+pub async fn unfreeze_account(account: Principal) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::Admin).unwrap();
+    set_account_frozen_internal(account, false).await.unwrap();
+}
+
+/// When set, transfers to frozen accounts are rejected as well.
+#[update(name = "setBlockFrozenRecipients")]
+#[candid_method(update, rename = "setBlockFrozenRecipients")]
+pub async fn set_block_frozen_recipients(block_recipients: bool) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::Admin).unwrap();
+    set_block_frozen_recipients_internal(block_recipients)
+        .await
+        .unwrap();
+}
+
+pub async fn set_block_frozen_recipients_internal(block_recipients: bool) -> Result<()> {
+    FREEZE_STATE.with(|f| f.borrow_mut().block_recipients = block_recipients);
+    update_block_frozen_recipients(block_recipients).await
+}
+
+#[query(name = "getFrozenAccounts")]
+#[candid_method(query, rename = "getFrozenAccounts")]
+pub fn get_frozen_accounts() -> Vec<Principal> {
+    FREEZE_STATE.with(|f| f.borrow().frozen.iter().copied().collect())
+}
+
+#[query(name = "getFreezeEvents")]
+#[candid_method(query, rename = "getFreezeEvents")]
+pub fn get_freeze_events(start: u64, limit: u64) -> Vec<FreezeEvent> {
+    FREEZE_STATE.with(|f| {
+        f.borrow()
+            .events
+            .iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    })
+}
@@ -0,0 +1,455 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Deserialize, Principal, types::number::Nat};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::env;
+use enoki_wrapped_token_shared::types::*;
+
+use crate::backup::finish_shard_restore_internal;
+use crate::cycles::{set_cycles_config_internal, CyclesConfig};
+use crate::decommission::{
+    cancel_decommission_internal, drain_shard_internal, finish_decommission_internal,
+    start_decommission_internal,
+};
+use crate::finish_init_internal;
+use crate::freeze::{set_account_frozen_internal, set_block_frozen_recipients_internal};
+use crate::management::{
+    assert_is_owner, propose_owner_internal, set_fee_beneficiaries_internal, set_fee_internal,
+};
+use crate::pause::unpause_internal;
+use crate::roles::{grant_role_internal, revoke_role_internal};
+use crate::routing::{rebalance_shard_internal, set_routing_mode_internal, RoutingMode};
+use crate::shards::{
+    add_shard_internal, bump_registry_version, fix_siblings_internal, push_registry,
+    refund_held_transfer_internal,
+};
+
+/// Longest timelock a configuration may set: 30 days.
+pub const MAX_TIMELOCK: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Nanoseconds a proposal stays open, or executable once approved, before it expires: 30 days.
+pub const PROPOSAL_LIFETIME: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Executed, failed and cancelled proposals kept for `getProposals`, the oldest being pruned.
+pub const MAX_FINISHED_PROPOSALS: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct GovernanceConfig {
+    pub signers: Vec<Principal>,
+    pub threshold: u32,
+    // nanoseconds between the last required approval and execution
+    pub timelock: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ProposalAction {
+    FinishInit {
+        underlying_token: Principal,
+        logo: String,
+        name: String,
+        symbol: String,
+        decimals: u8,
+        fee: Nat,
+    },
+    SetFee(Nat),
+    SetFeeBeneficiaries(Vec<FeeBeneficiary>),
+    AddShard(Principal),
+    ProposeOwner(Principal),
+    GrantRole(Principal, Role),
+    RevokeRole(Principal, Role),
+    SetGovernance(GovernanceConfig),
+    SetRoutingMode(RoutingMode),
+    StartDecommission(Principal),
+    DrainShard(Principal),
+    FinishDecommission(Principal),
+    CancelDecommission(Principal),
+    FreezeAccount(Principal),
+    UnfreezeAccount(Principal),
+    SetBlockFrozenRecipients(bool),
+    Unpause(PauseScope),
+    SetCyclesConfig(CyclesConfig),
+    RebalanceShard(Principal),
+    FixSiblings,
+    FinishShardRestore(Principal),
+    RefundHeldTransfer(Principal, u64),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ProposalStatus {
+    Open,
+    Approved { executable_at: u64 },
+    Executing,
+    Executed { time: u64 },
+    Failed { time: u64, error: String },
+    Cancelled { time: u64, by: Principal },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Proposal {
+    pub id: u64,
+    pub action: ProposalAction,
+    pub proposer: Principal,
+    pub created_at: u64,
+    pub approvals: Vec<(Principal, u64)>,
+    pub status: ProposalStatus,
+}
+
+/// Proposals are kept by increasing id, and pruned once finished or expired.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct GovernanceState {
+    config: GovernanceConfig,
+    proposals: Vec<Proposal>,
+    /// Missing from states saved before proposals were pruned, when ids were their index.
+    next_id: Option<u64>,
+}
+
+impl GovernanceState {
+    fn next_id(&self) -> u64 {
+        self.next_id.unwrap_or(self.proposals.len() as u64)
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.proposals.binary_search_by_key(&id, |proposal| proposal.id).ok()
+    }
+
+    /// Drops the proposals that expired, and the oldest finished ones beyond
+    /// `MAX_FINISHED_PROPOSALS`.
+    fn prune(&mut self, now: u64) {
+        self.proposals.retain(|proposal| !is_expired(proposal, now));
+        let mut finished = self
+            .proposals
+            .iter()
+            .filter(|proposal| is_finished(proposal))
+            .count();
+        self.proposals.retain(|proposal| {
+            if finished > MAX_FINISHED_PROPOSALS && is_finished(proposal) {
+                finished -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+fn is_finished(proposal: &Proposal) -> bool {
+    matches!(
+        proposal.status,
+        ProposalStatus::Executed { .. }
+            | ProposalStatus::Failed { .. }
+            | ProposalStatus::Cancelled { .. }
+    )
+}
+
+fn is_expired(proposal: &Proposal, now: u64) -> bool {
+    let since = match proposal.status {
+        ProposalStatus::Open => proposal.created_at,
+        ProposalStatus::Approved { executable_at } => executable_at,
+        _ => return false,
+    };
+    now > since.saturating_add(PROPOSAL_LIFETIME)
+}
+
+thread_local! {
+    static GOVERNANCE: RefCell<GovernanceState> = RefCell::new(GovernanceState::default());
+}
+
+pub fn export_stable_storage() -> (GovernanceState, ) {
+    (GOVERNANCE.with(|g| g.take()), )
+}
+
+pub fn import_stable_storage(governance: GovernanceState) {
+    GOVERNANCE.with(|g| g.replace(governance));
+}
+
+pub fn is_governance_enabled() -> bool {
+    GOVERNANCE.with(|g| !g.borrow().config.signers.is_empty())
+}
+
+/// Once signers are configured, admin actions can only be executed through a proposal. Only
+/// `pause` is exempt.
+pub fn assert_governance_disabled() -> Result<()> {
+    if is_governance_enabled() {
+        Err(TxError::Other(
+            "this action must be submitted as a governance proposal".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn assert_is_signer() -> Result<()> {
+    if GOVERNANCE.with(|g| g.borrow().config.signers.contains(&env::caller())) {
+        Ok(())
+    } else {
+        Err(TxError::Unauthorized)
+    }
+}
+
+fn validate_config(config: &GovernanceConfig) -> Result<()> {
+    if config.timelock > MAX_TIMELOCK {
+        return Err(TxError::Other(format!(
+            "timelock must be at most {} nanoseconds",
+            MAX_TIMELOCK
+        )));
+    }
+    if config.signers.is_empty() {
+        return Ok(());
+    }
+    if config.threshold == 0 || config.threshold as usize > config.signers.len() {
+        return Err(TxError::Other(format!(
+            "threshold must be between 1 and {}",
+            config.signers.len()
+        )));
+    }
+    for (i, signer) in config.signers.iter().enumerate() {
+        if config.signers[..i].contains(signer) {
+            return Err(TxError::Other(format!("duplicate signer {}", signer)));
+        }
+    }
+    Ok(())
+}
+
+fn with_proposal<R, F: FnOnce(&mut Proposal, &GovernanceConfig) -> Result<R>>(
+    id: u64,
+    func: F,
+) -> Result<R> {
+    GOVERNANCE.with(|g| {
+        let mut g = g.borrow_mut();
+        let position = g
+            .position(id)
+            .ok_or_else(|| TxError::Other(format!("proposal {} does not exist", id)))?;
+        let GovernanceState { config, proposals, .. } = &mut *g;
+        let proposal = &mut proposals[position];
+        if is_expired(proposal, env::time()) {
+            return Err(TxError::Other(format!("proposal {} expired", id)));
+        }
+        func(proposal, config)
+    })
+}
+
+/// Whether the current signers approved the proposal, enough of them for the current threshold.
+fn has_approvals(proposal: &Proposal, config: &GovernanceConfig) -> bool {
+    let approvals = proposal
+        .approvals
+        .iter()
+        .filter(|(p, _)| config.signers.contains(p))
+        .count();
+    approvals >= config.threshold as usize
+}
+
+fn record_approval(proposal: &mut Proposal, config: &GovernanceConfig, signer: Principal) {
+    let now = env::time();
+    if !proposal.approvals.iter().any(|(p, _)| *p == signer) {
+        proposal.approvals.push((signer, now));
+    }
+    if let ProposalStatus::Open = proposal.status {
+        if has_approvals(proposal, config) {
+            proposal.status = ProposalStatus::Approved {
+                executable_at: now.saturating_add(config.timelock),
+            };
+        }
+    }
+}
+
+async fn execute_action(action: ProposalAction) -> Result<()> {
+    match action {
+        ProposalAction::FinishInit {
+            underlying_token,
+            logo,
+            name,
+            symbol,
+            decimals,
+            fee,
+        } => {
+            finish_init_internal(underlying_token, logo, name, symbol, decimals, fee);
+            Ok(())
+        }
+        ProposalAction::SetFee(fee) => set_fee_internal(fee).await,
+        ProposalAction::SetFeeBeneficiaries(beneficiaries) => {
+            set_fee_beneficiaries_internal(beneficiaries).await
+        }
+        ProposalAction::AddShard(id) => add_shard_internal(id).await,
+        ProposalAction::ProposeOwner(owner) => {
+            propose_owner_internal(owner);
+            Ok(())
+        }
+        ProposalAction::GrantRole(user, role) => grant_role_internal(user, role).await,
+        ProposalAction::RevokeRole(user, role) => revoke_role_internal(user, role).await,
+        ProposalAction::SetGovernance(config) => {
+            validate_config(&config)?;
+            set_config(config).await
+        }
+        ProposalAction::SetRoutingMode(mode) => set_routing_mode_internal(mode).await,
+        ProposalAction::StartDecommission(id) => start_decommission_internal(id).await,
+        // a drained shard holds no accounts, as `getShardsInfo` shows
+        ProposalAction::DrainShard(id) => drain_shard_internal(id).await.map(|_| ()),
+        ProposalAction::FinishDecommission(id) => finish_decommission_internal(id).await,
+        ProposalAction::CancelDecommission(id) => cancel_decommission_internal(id).await,
+        ProposalAction::FreezeAccount(account) => set_account_frozen_internal(account, true).await,
+        ProposalAction::UnfreezeAccount(account) => {
+            set_account_frozen_internal(account, false).await
+        }
+        ProposalAction::SetBlockFrozenRecipients(block_recipients) => {
+            set_block_frozen_recipients_internal(block_recipients).await
+        }
+        ProposalAction::Unpause(scope) => unpause_internal(scope).await,
+        ProposalAction::SetCyclesConfig(config) => {
+            set_cycles_config_internal(config);
+            Ok(())
+        }
+        // like drained shards, rebalanced ones hold no misplaced account
+        ProposalAction::RebalanceShard(id) => rebalance_shard_internal(id).await.map(|_| ()),
+        ProposalAction::FixSiblings => fix_siblings_internal().await,
+        ProposalAction::FinishShardRestore(id) => finish_shard_restore_internal(id).await,
+        ProposalAction::RefundHeldTransfer(id, held) => {
+            refund_held_transfer_internal(id, held).await
+        }
+    }
+}
+
+/// Replaces the configuration, and tells the shards when governance is enabled or disabled.
+async fn set_config(config: GovernanceConfig) -> Result<()> {
+    let was_enabled = is_governance_enabled();
+    GOVERNANCE.with(|g| g.borrow_mut().config = config);
+    if is_governance_enabled() == was_enabled {
+        return Ok(());
+    }
+    bump_registry_version();
+    push_registry().await
+}
+
+/// Enables governance. Afterwards, the configuration can only be changed through a proposal.
+/// If some shard misses the change, a `FixSiblings` proposal sends it again.
+#[update(name = "configureGovernance")]
+#[candid_method(update, rename = "configureGovernance")]
+pub async fn configure_governance(config: GovernanceConfig) {
+    assert_governance_disabled().unwrap();
+    assert_is_owner().unwrap();
+    validate_config(&config).unwrap();
+    set_config(config).await.unwrap();
+}
+
+#[query(name = "getGovernanceConfig")]
+#[candid_method(query, rename = "getGovernanceConfig")]
+pub fn get_governance_config() -> GovernanceConfig {
+    GOVERNANCE.with(|g| g.borrow().config.clone())
+}
+
+/// Prunes the expired and oldest finished proposals before adding the new one.
+#[update(name = "submitProposal")]
+#[candid_method(update, rename = "submitProposal")]
+pub fn submit_proposal(action: ProposalAction) -> u64 {
+    assert_is_signer().unwrap();
+    let caller = env::caller();
+    GOVERNANCE.with(|g| {
+        let mut g = g.borrow_mut();
+        g.prune(env::time());
+        let id = g.next_id();
+        g.next_id = Some(id + 1);
+        let mut proposal = Proposal {
+            id,
+            action,
+            proposer: caller,
+            created_at: env::time(),
+            approvals: vec![],
+            status: ProposalStatus::Open,
+        };
+        record_approval(&mut proposal, &g.config, caller);
+        g.proposals.push(proposal);
+        id
+    })
+}
+
+/// Approved proposals still take approvals, which replace those of signers removed since.
+#[update(name = "approveProposal")]
+#[candid_method(update, rename = "approveProposal")]
+pub fn approve_proposal(id: u64) {
+    assert_is_signer().unwrap();
+    with_proposal(id, |proposal, config| match proposal.status {
+        ProposalStatus::Open | ProposalStatus::Approved { .. } => {
+            record_approval(proposal, config, env::caller());
+            Ok(())
+        }
+        _ => Err(TxError::Other(format!("proposal {} is not open", id))),
+    })
+    .unwrap();
+}
+
+/// Any signer can veto a proposal that has not been executed yet. An execution that trapped
+/// leaves its proposal executing until it is cancelled.
+#[update(name = "cancelProposal")]
+#[candid_method(update, rename = "cancelProposal")]
+pub fn cancel_proposal(id: u64) {
+    assert_is_signer().unwrap();
+    with_proposal(id, |proposal, _| match proposal.status {
+        ProposalStatus::Open | ProposalStatus::Approved { .. } | ProposalStatus::Executing => {
+            proposal.status = ProposalStatus::Cancelled {
+                time: env::time(),
+                by: env::caller(),
+            };
+            Ok(())
+        }
+        _ => Err(TxError::Other(format!("proposal {} cannot be cancelled", id))),
+    })
+    .unwrap();
+}
+
+#[update(name = "executeProposal")]
+#[candid_method(update, rename = "executeProposal")]
+pub async fn execute_proposal(id: u64) -> ProposalStatus {
+    assert_is_signer().unwrap();
+    let action = with_proposal(id, |proposal, config| match proposal.status {
+        ProposalStatus::Approved { .. } if !has_approvals(proposal, config) => Err(
+            TxError::Other(format!("proposal {} lacks approvals from the current signers", id)),
+        ),
+        ProposalStatus::Approved { executable_at } if executable_at <= env::time() => {
+            proposal.status = ProposalStatus::Executing;
+            Ok(proposal.action.clone())
+        }
+        ProposalStatus::Approved { .. } => Err(TxError::Other(format!(
+            "proposal {} is still timelocked",
+            id
+        ))),
+        _ => Err(TxError::Other(format!("proposal {} is not approved", id))),
+    })
+    .unwrap();
+
+    let result = execute_action(action).await;
+    let time = env::time();
+    with_proposal(id, |proposal, _| {
+        proposal.status = match result {
+            Ok(_) => ProposalStatus::Executed { time },
+            Err(error) => ProposalStatus::Failed {
+                time,
+                error: format!("{:?}", error),
+            },
+        };
+        Ok(proposal.status.clone())
+    })
+    .unwrap()
+}
+
+#[query(name = "getProposal")]
+#[candid_method(query, rename = "getProposal")]
+pub fn get_proposal(id: u64) -> Option<Proposal> {
+    GOVERNANCE.with(|g| {
+        let g = g.borrow();
+        g.position(id).map(|position| g.proposals[position].clone())
+    })
+}
+
+/// Up to `limit` proposals from id `start` on, pruned ones excepted.
+#[query(name = "getProposals")]
+#[candid_method(query, rename = "getProposals")]
+pub fn get_proposals(start: u64, limit: u64) -> Vec<Proposal> {
+    GOVERNANCE.with(|g| {
+        g.borrow()
+            .proposals
+            .iter()
+            .skip_while(|proposal| proposal.id < start)
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    })
+}
@@ -0,0 +1,112 @@
+use std::string::String;
+
+use candid::{candid_method, types::number::Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::backup::BackupManifest;
+use enoki_wrapped_token_shared::env;
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::log::{LogEntry, LogLevel};
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::metrics::{HttpRequest, HttpResponse};
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::types::{
+    ByteBuf, FeeBeneficiary, HolderOrder, HoldersCursor, HoldersPage, PauseScope, Result, Role,
+    ShardRegistry,
+};
+
+#[allow(unused_imports)]
+use crate::cycles::CyclesConfig;
+#[allow(unused_imports)]
+use crate::freeze::FreezeEvent;
+use crate::governance::assert_governance_disabled;
+#[allow(unused_imports)]
+use crate::governance::{GovernanceConfig, Proposal, ProposalAction, ProposalStatus};
+use crate::management::init_fee;
+#[allow(unused_imports)]
+use crate::management::{init_management_data, Stats};
+use crate::metadata::{init_metadata, Metadata};
+use crate::roles::assert_has_role;
+#[allow(unused_imports)]
+use crate::routing::RoutingMode;
+#[allow(unused_imports)]
+use crate::shards::Shard;
+#[allow(unused_imports)]
+use crate::snapshots::Snapshot;
+use crate::types::ManagementStats;
+
+pub mod accounts;
+pub mod backup;
+pub mod cycles;
+pub mod decommission;
+pub mod freeze;
+pub mod governance;
+pub mod management;
+pub mod metadata;
+pub mod metrics;
+pub mod pause;
+pub mod roles;
+pub mod routing;
+pub mod shards;
+pub mod snapshots;
+pub mod stable;
+pub mod types;
+pub mod upgrade;
+
+#[init]
+#[candid_method(init)]
+pub fn init() {
+    init_management_data(ManagementStats {
+        owner: env::caller(),
+        pending_owner: None,
+        fee: Default::default(),
+        fee_beneficiaries: vec![],
+        deploy_time: env::time(),
+    });
+}
+
+#[update(name = "finishInit")]
+#[candid_method(update, rename = "finishInit")]
+pub fn finish_init(
+    underlying_token: Principal,
+    logo: String,
+    name: String,
+    symbol: String,
+    decimals: u8,
+    fee: Nat,
+) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::Admin).unwrap();
+    finish_init_internal(underlying_token, logo, name, symbol, decimals, fee);
+}
+
+pub fn finish_init_internal(
+    underlying_token: Principal,
+    logo: String,
+    name: String,
+    symbol: String,
+    decimals: u8,
+    fee: Nat,
+) {
+    init_metadata(Metadata {
+        logo,
+        name,
+        symbol,
+        decimals,
+        underlying_token,
+    });
+    init_fee(fee);
+}
+
+/// Polls the shards' cycle balances and tops them up.
+#[heartbeat]
+pub async fn heartbeat() {
+    cycles::heartbeat().await;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn export_candid() -> String {
+    candid::export_service!();
+    __export_service()
+}
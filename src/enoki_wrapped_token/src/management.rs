@@ -1,11 +1,16 @@
 use std::cell::RefCell;
 
 use candid::{candid_method, CandidType, Deserialize, Principal, types::number::Nat};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 
+use enoki_wrapped_token_shared::env;
+use enoki_wrapped_token_shared::log::{self, LogEntry, LogLevel};
 use enoki_wrapped_token_shared::types::*;
 
-use crate::shards::{total_supply, update_fee};
+use crate::accounts::try_register;
+use crate::governance::assert_governance_disabled;
+use crate::roles::{assert_has_role, get_role_assignments};
+use crate::shards::{total_supply, update_fee, update_fee_beneficiaries, update_roles};
 use crate::stable::StableManagementStats;
 use crate::types::ManagementStats;
 
@@ -44,7 +49,7 @@ impl From<ManagementStats> for Stats {
 }
 
 pub fn assert_is_owner() -> Result<()> {
-    if MANAGEMENT_STATS.with(|s| s.borrow().owner) == ic_cdk::caller() {
+    if MANAGEMENT_STATS.with(|s| s.borrow().owner) == env::caller() {
         Ok(())
     } else {
         Err(TxError::Unauthorized)
@@ -61,25 +66,43 @@ pub fn init_fee(fee: Nat) {
 
 #[update(name = "stats")]
 #[candid_method(update)]
-async fn stats() -> Stats {
+pub async fn stats() -> Stats {
     let mut stats: Stats = MANAGEMENT_STATS.with(|s| s.borrow().clone()).into();
     stats.total_supply = total_supply().await;
-    stats.cycles = ic_cdk::api::canister_balance();
+    stats.cycles = env::canister_balance();
     stats
 }
 
 #[query(name = "owner")]
-#[candid_method(query)]
-fn owner() -> Principal {
+#[candid_method(query, rename = "owner")]
+pub fn get_owner() -> Principal {
     MANAGEMENT_STATS.with(|s| s.borrow().owner)
 }
 
 #[update(name = "setFee")]
 #[candid_method(update, rename = "setFee")]
-async fn set_fee(fee: Nat) {
-    assert_is_owner().unwrap();
+pub async fn set_fee(fee: Nat) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::FeeManager).unwrap();
+    set_fee_internal(fee).await.unwrap();
+}
+
+pub async fn set_fee_internal(fee: Nat) -> Result<()> {
     MANAGEMENT_STATS.with(|s| s.borrow_mut().fee = fee.clone());
-    update_fee(fee).await.unwrap();
+    let digest = log::digest((fee.clone(), ));
+    log::started("setFee", digest.clone());
+    let result = update_fee(fee).await;
+    log::finished("setFee", digest, &result);
+    result
+}
+
+/// Entries of the main contract's event log from id `since`, with at least the given level. Each
+/// shard keeps its own log, which its `getLogs` returns.
+#[query(name = "getLogs")]
+#[candid_method(query, rename = "getLogs")]
+pub fn get_logs(since: u64, level: LogLevel) -> Vec<LogEntry> {
+    assert_has_role(Role::Admin).unwrap();
+    log::get_logs(since, level)
 }
 
 #[query(name = "getFee")]
@@ -88,9 +111,80 @@ pub fn get_fee() -> Nat {
     MANAGEMENT_STATS.with(|s| s.borrow().fee.clone())
 }
 
-#[update(name = "setOwner")]
-#[candid_method(update, rename = "setOwner")]
-fn set_owner(owner: Principal) {
+#[update(name = "setFeeBeneficiaries")]
+#[candid_method(update, rename = "setFeeBeneficiaries")]
+pub async fn set_fee_beneficiaries(beneficiaries: Vec<FeeBeneficiary>) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::FeeManager)?;
+    set_fee_beneficiaries_internal(beneficiaries).await
+}
+
+pub async fn set_fee_beneficiaries_internal(beneficiaries: Vec<FeeBeneficiary>) -> Result<()> {
+    validate_fee_beneficiaries(&beneficiaries)?;
+    // shards pay the shares out on the beneficiaries' shards, so all of them need one
+    for beneficiary in &beneficiaries {
+        try_register(beneficiary.id).await?;
+    }
+    MANAGEMENT_STATS.with(|s| s.borrow_mut().fee_beneficiaries = beneficiaries.clone());
+    update_fee_beneficiaries(beneficiaries).await
+}
+
+fn validate_fee_beneficiaries(beneficiaries: &[FeeBeneficiary]) -> Result<()> {
+    if beneficiaries.iter().any(|b| b.weight == 0) {
+        return Err(TxError::Other("fee beneficiary weight must be positive".to_string()));
+    }
+    if FeeBeneficiary::total_weight(beneficiaries).is_none() {
+        return Err(TxError::Other("fee beneficiary weights overflow".to_string()));
+    }
+    for (i, beneficiary) in beneficiaries.iter().enumerate() {
+        if beneficiaries[..i].iter().any(|b| b.id == beneficiary.id) {
+            return Err(TxError::Other(format!(
+                "duplicate fee beneficiary {}",
+                beneficiary.id
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[query(name = "getFeeBeneficiaries")]
+#[candid_method(query, rename = "getFeeBeneficiaries")]
+pub fn get_fee_beneficiaries() -> Vec<FeeBeneficiary> {
+    MANAGEMENT_STATS.with(|s| s.borrow().fee_beneficiaries.clone())
+}
+
+#[update(name = "proposeOwner")]
+#[candid_method(update, rename = "proposeOwner")]
+pub fn propose_owner(owner: Principal) {
+    assert_governance_disabled().unwrap();
     assert_is_owner().unwrap();
-    MANAGEMENT_STATS.with(|s| s.borrow_mut().owner = owner);
+    propose_owner_internal(owner);
+}
+
+pub fn propose_owner_internal(owner: Principal) {
+    MANAGEMENT_STATS.with(|s| s.borrow_mut().pending_owner = Some(owner));
+}
+
+#[query(name = "getPendingOwner")]
+#[candid_method(query, rename = "getPendingOwner")]
+pub fn get_pending_owner() -> Option<Principal> {
+    MANAGEMENT_STATS.with(|s| s.borrow().pending_owner)
+}
+
+/// Makes the caller, once proposed, the owner. The ownership is transferred even if some shard
+/// misses the new roles, which is returned and which `fixSiblings` sends again.
+#[update(name = "acceptOwnership")]
+#[candid_method(update, rename = "acceptOwnership")]
+pub async fn accept_ownership() -> Result<()> {
+    let caller = env::caller();
+    MANAGEMENT_STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        if stats.pending_owner != Some(caller) {
+            return Err(TxError::Unauthorized);
+        }
+        stats.owner = caller;
+        stats.pending_owner = None;
+        Ok(())
+    })?;
+    update_roles(get_role_assignments()).await
 }
@@ -1,10 +1,12 @@
 use std::cell::RefCell;
 
 use candid::{candid_method, CandidType, Principal};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 use serde::{Deserialize, Serialize};
 
-use crate::management::assert_is_owner;
+use enoki_wrapped_token_shared::types::Role;
+
+use crate::roles::assert_has_role;
 
 #[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
 pub struct Metadata {
@@ -49,37 +51,37 @@ pub fn get_underlying_token() -> Principal {
 
 #[query(name = "getLogo")]
 #[candid_method(query, rename = "getLogo")]
-fn get_logo() -> String {
+pub fn get_logo() -> String {
     METADATA.with(|d| d.borrow().logo.clone())
 }
 
 #[query(name = "name")]
 #[candid_method(query)]
-fn name() -> String {
+pub fn name() -> String {
     METADATA.with(|d| d.borrow().name.clone())
 }
 
 #[query(name = "symbol")]
 #[candid_method(query)]
-fn symbol() -> String {
+pub fn symbol() -> String {
     METADATA.with(|d| d.borrow().symbol.clone())
 }
 
 #[query(name = "decimals")]
 #[candid_method(query)]
-fn decimals() -> u8 {
+pub fn decimals() -> u8 {
     METADATA.with(|d| d.borrow().decimals)
 }
 
 #[query(name = "getMetadata")]
 #[candid_method(query, rename = "getMetadata")]
-fn get_metadata() -> Metadata {
+pub fn get_metadata() -> Metadata {
     METADATA.with(|d| d.borrow().clone())
 }
 
 #[update(name = "setLogo")]
 #[candid_method(update, rename = "setLogo")]
-fn set_logo(logo: String) {
-    assert_is_owner().unwrap();
+pub fn set_logo(logo: String) {
+    assert_has_role(Role::Admin).unwrap();
     METADATA.with(|d| d.borrow_mut().logo = logo);
 }
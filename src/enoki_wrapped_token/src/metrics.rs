@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Deserialize};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::metrics::{serve_metrics, HttpRequest, HttpResponse};
+
+use crate::cycles::get_cycles_pool;
+use crate::shards::get_shards_info;
+
+pub enum Counter {
+    Transfer,
+}
+
+/// Operations of the main contract since it was installed. Shards count their own operations,
+/// including failed calls to their siblings.
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct Counters {
+    transfers: u64,
+}
+
+thread_local! {
+    static COUNTERS: RefCell<Counters> = RefCell::new(Counters::default());
+}
+
+pub fn export_stable_storage() -> (Counters, ) {
+    (COUNTERS.with(|c| c.take()), )
+}
+
+pub fn import_stable_storage(counters: Counters) {
+    COUNTERS.with(|c| c.replace(counters));
+}
+
+pub fn record(counter: Counter) {
+    COUNTERS.with(|c| {
+        let mut c = c.borrow_mut();
+        match counter {
+            Counter::Transfer => c.transfers += 1,
+        }
+    })
+}
+
+/// Serves `GET /metrics` in the Prometheus text format. Balances are served by the shards
+/// themselves, since a query cannot call them.
+#[query(name = "http_request")]
+#[candid_method(query, rename = "http_request")]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    serve_metrics(request, |m| {
+        let counters = COUNTERS.with(|c| c.borrow().clone());
+        let shards = get_shards_info();
+        m.gauge("enoki_shards", "Shards of the token.", shards.len());
+        m.labeled_gauge(
+            "enoki_accounts",
+            "Accounts assigned to each shard.",
+            "shard",
+            shards
+                .iter()
+                .map(|shard| (shard.id.to_string(), shard.num_accounts)),
+        );
+        m.labeled_gauge(
+            "enoki_shard_cycles",
+            "Cycle balance of each shard at its last poll.",
+            "shard",
+            shards.iter().filter_map(|shard| {
+                let sample = shard.cycles_history.last()?;
+                Some((shard.id.to_string(), sample.balance))
+            }),
+        );
+        m.gauge(
+            "enoki_cycles_pool",
+            "Cycles deposited to top up the shards.",
+            get_cycles_pool(),
+        );
+        m.counter(
+            "enoki_transfers",
+            "Transfers sent through the main contract.",
+            counters.transfers,
+        );
+        m.canister_metrics();
+    })
+}
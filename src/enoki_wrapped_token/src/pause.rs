@@ -0,0 +1,51 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use candid::candid_method;
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::types::*;
+
+use crate::governance::assert_governance_disabled;
+use crate::roles::assert_has_role;
+use crate::shards::update_paused;
+
+thread_local! {
+    static PAUSED: RefCell<HashSet<PauseScope>> = RefCell::new(HashSet::default());
+}
+
+pub fn export_stable_storage() -> (Vec<PauseScope>, ) {
+    (PAUSED.with(|p| p.take()).into_iter().collect(), )
+}
+
+pub fn import_stable_storage(paused: Vec<PauseScope>) {
+    PAUSED.with(|p| p.replace(paused.into_iter().collect()));
+}
+
+#[query(name = "getPausedScopes")]
+#[candid_method(query, rename = "getPausedScopes")]
+pub fn get_paused_scopes() -> Vec<PauseScope> {
+    PAUSED.with(|p| p.borrow().iter().copied().collect())
+}
+
+/// The emergency stop, which a pauser can pull without a proposal under governance.
+#[update(name = "pause")]
+#[candid_method(update)]
+pub async fn pause(scope: PauseScope) {
+    assert_has_role(Role::Pauser).unwrap();
+    PAUSED.with(|p| p.borrow_mut().insert(scope));
+    update_paused(get_paused_scopes()).await.unwrap();
+}
+
+#[update(name = "unpause")]
+#[candid_method(update)]
+pub async fn unpause(scope: PauseScope) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::Pauser).unwrap();
+    unpause_internal(scope).await.unwrap();
+}
+
+pub async fn unpause_internal(scope: PauseScope) -> Result<()> {
+    PAUSED.with(|p| p.borrow_mut().remove(&scope));
+    update_paused(get_paused_scopes()).await
+}
@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use candid::{candid_method, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::env;
+use enoki_wrapped_token_shared::types::*;
+
+use crate::governance::assert_governance_disabled;
+use crate::management::{assert_is_owner, get_owner};
+use crate::shards::update_roles;
+
+pub type Roles = HashMap<Principal, HashSet<Role>>;
+
+const ALL_ROLES: [Role; 4] = [Role::Admin, Role::FeeManager, Role::ShardOperator, Role::Pauser];
+
+thread_local! {
+    static ROLES: RefCell<Roles> = RefCell::new(Roles::default());
+}
+
+pub fn export_stable_storage() -> (RoleAssignments, ) {
+    let roles = ROLES
+        .with(|r| r.take())
+        .into_iter()
+        .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+        .collect();
+    (roles, )
+}
+
+pub fn import_stable_storage(roles: RoleAssignments) {
+    ROLES.with(|r| {
+        r.replace(
+            roles
+                .into_iter()
+                .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+                .collect(),
+        )
+    });
+}
+
+fn has_role(user: &Principal, role: Role) -> bool {
+    if *user == get_owner() {
+        return true;
+    }
+    ROLES.with(|r| {
+        r.borrow()
+            .get(user)
+            .map(|roles| roles.contains(&role) || roles.contains(&Role::Admin))
+            .unwrap_or(false)
+    })
+}
+
+/// The owner and admins implicitly hold every role.
+pub fn assert_has_role(role: Role) -> Result<()> {
+    if has_role(&env::caller(), role) {
+        Ok(())
+    } else {
+        Err(TxError::Unauthorized)
+    }
+}
+
+fn assert_can_manage_role(role: Role) -> Result<()> {
+    if role == Role::Admin {
+        assert_is_owner()
+    } else {
+        assert_has_role(Role::Admin)
+    }
+}
+
+/// Roles as seen by the shards, with the owner listed as an admin.
+pub fn get_role_assignments() -> RoleAssignments {
+    let owner = get_owner();
+    let mut assignments: RoleAssignments = ROLES.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(&principal, _)| principal != owner)
+            .map(|(&principal, roles)| (principal, roles.iter().copied().collect()))
+            .collect()
+    });
+    assignments.push((owner, vec![Role::Admin]));
+    assignments
+}
+
+#[update(name = "grantRole")]
+#[candid_method(update, rename = "grantRole")]
+pub async fn grant_role(user: Principal, role: Role) {
+    assert_governance_disabled().unwrap();
+    assert_can_manage_role(role).unwrap();
+    grant_role_internal(user, role).await.unwrap();
+}
+
+pub async fn grant_role_internal(user: Principal, role: Role) -> Result<()> {
+    ROLES.with(|r| r.borrow_mut().entry(user).or_default().insert(role));
+    update_roles(get_role_assignments()).await
+}
+
+#[update(name = "revokeRole")]
+#[candid_method(update, rename = "revokeRole")]
+pub async fn revoke_role(user: Principal, role: Role) {
+    assert_governance_disabled().unwrap();
+    assert_can_manage_role(role).unwrap();
+    revoke_role_internal(user, role).await.unwrap();
+}
+
+pub async fn revoke_role_internal(user: Principal, role: Role) -> Result<()> {
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        if let Some(user_roles) = roles.get_mut(&user) {
+            user_roles.remove(&role);
+            if user_roles.is_empty() {
+                roles.remove(&user);
+            }
+        }
+    });
+    update_roles(get_role_assignments()).await
+}
+
+#[query(name = "getRoles")]
+#[candid_method(query, rename = "getRoles")]
+pub fn get_roles(user: Principal) -> Vec<Role> {
+    ALL_ROLES
+        .iter()
+        .copied()
+        .filter(|&role| has_role(&user, role))
+        .collect()
+}
@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{candid_method, CandidType, Principal};
+use enoki_wrapped_token_macros::*;
+use serde::{Deserialize, Serialize};
+
+use enoki_wrapped_token_shared::log;
+use enoki_wrapped_token_shared::routing::ShardRing;
+use enoki_wrapped_token_shared::types::*;
+
+use crate::accounts::{get_assigned_shards, get_user_account, move_account};
+use crate::decommission::{get_account_owners, migrate_accounts, MAX_MIGRATION_BATCH};
+use crate::governance::assert_governance_disabled;
+use crate::roles::assert_has_role;
+use crate::shards::{
+    bump_registry_version, get_registry_version, get_ring_shards, get_shard, get_shard_ids,
+    push_registry, ShardStatus,
+};
+
+/// How the shard of an account is found.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoutingMode {
+    /// Accounts are assigned to the least used shard when they register, and looked up with
+    /// `getAssignedShardId`.
+    #[default]
+    Directory,
+    /// Accounts live on the shard a `ShardRing` over the active shards places them on, which
+    /// clients compute from `getShardRegistry`. Shards take any account the ring places on them.
+    Hashed,
+}
+
+thread_local! {
+    static ROUTING_MODE: RefCell<RoutingMode> = RefCell::new(RoutingMode::default());
+    /// Ring of the registry version it was built for.
+    static RING: RefCell<Option<(u64, ShardRing)>> = const { RefCell::new(None) };
+}
+
+pub fn export_stable_storage() -> (RoutingMode, ) {
+    (ROUTING_MODE.with(|m| m.take()), )
+}
+
+pub fn import_stable_storage(mode: RoutingMode) {
+    ROUTING_MODE.with(|m| m.replace(mode));
+}
+
+#[query(name = "getRoutingMode")]
+#[candid_method(query, rename = "getRoutingMode")]
+pub fn get_routing_mode() -> RoutingMode {
+    ROUTING_MODE.with(|m| *m.borrow())
+}
+
+/// Switches the routing mode and pushes the new registry to the shards. Before hashed routing is
+/// enabled, the registered accounts are moved to the shard the ring places them on, so that
+/// clients computing their shard find their balance; if a move fails, the mode is unchanged and
+/// the call can be repeated. Before it is disabled, the accounts the ring placed are registered
+/// on the shard holding them.
+#[update(name = "setRoutingMode")]
+#[candid_method(update, rename = "setRoutingMode")]
+pub async fn set_routing_mode(mode: RoutingMode) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::ShardOperator)?;
+    set_routing_mode_internal(mode).await
+}
+
+pub async fn set_routing_mode_internal(mode: RoutingMode) -> Result<()> {
+    let digest = log::digest((mode, ));
+    let result = switch_routing_mode(mode).await;
+    log::finished("setRoutingMode", digest, &result);
+    result
+}
+
+async fn switch_routing_mode(mode: RoutingMode) -> Result<()> {
+    let previous = get_routing_mode();
+    if previous == mode {
+        return push_registry().await;
+    }
+    if mode == RoutingMode::Hashed {
+        move_registered_onto_ring().await?;
+    } else {
+        register_ring_accounts().await?;
+    }
+    ROUTING_MODE.with(|m| m.replace(mode));
+    bump_registry_version();
+    push_registry().await?;
+    if previous == RoutingMode::Hashed {
+        // accounts the ring placed while the directory was being filled
+        register_ring_accounts().await?;
+    }
+    Ok(())
+}
+
+/// Moves every registered account to the shard the ring over the active shards places it on.
+/// The last check and the mode switch that follows run without an await in between, so no
+/// account registered meanwhile is left behind. Fails when the accounts left cannot move, such
+/// as those with held transfers.
+async fn move_registered_onto_ring() -> Result<()> {
+    let ring = ShardRing::new(&get_ring_shards());
+    loop {
+        let mut batches: BTreeMap<Principal, BTreeMap<Principal, Vec<Principal>>> =
+            BTreeMap::new();
+        let mut misplaced = 0;
+        for (owner, shard) in get_assigned_shards() {
+            match ring.shard_for(&owner) {
+                Some(target) if target != shard && misplaced < MAX_MIGRATION_BATCH => {
+                    batches.entry(shard).or_default().entry(target).or_default().push(owner);
+                    misplaced += 1;
+                }
+                _ => {}
+            }
+        }
+        if batches.is_empty() {
+            return Ok(());
+        }
+        let mut moved = 0;
+        for (shard, batch) in batches {
+            moved += migrate_accounts(shard, batch).await?;
+        }
+        if moved == 0 {
+            return Err(TxError::Other(
+                "Some accounts cannot be moved onto the ring".to_string(),
+            ));
+        }
+    }
+}
+
+/// Registers the accounts held by each shard that the directory does not know yet.
+async fn register_ring_accounts() -> Result<()> {
+    for shard in get_shard_ids() {
+        for owner in get_account_owners(shard).await? {
+            if get_user_account(&owner).is_none() {
+                move_account(owner, shard);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The shard the ring places `owner` on, in the hashed routing mode.
+pub fn route(owner: &Principal) -> Option<Principal> {
+    if get_routing_mode() != RoutingMode::Hashed {
+        return None;
+    }
+    let version = get_registry_version();
+    RING.with(|r| {
+        let mut ring = r.borrow_mut();
+        if !matches!(*ring, Some((built, _)) if built == version) {
+            *ring = Some((version, ShardRing::new(&get_ring_shards())));
+        }
+        ring.as_ref().and_then(|(_, ring)| ring.shard_for(owner))
+    })
+}
+
+/// Moves up to `MAX_MIGRATION_BATCH` accounts the ring places elsewhere off an active shard, and
+/// returns how many are left. Called after shards are added in the hashed routing mode, until
+/// none are left.
+#[update(name = "rebalanceShard")]
+#[candid_method(update, rename = "rebalanceShard")]
+pub async fn rebalance_shard(id: Principal) -> Result<u64> {
+    assert_governance_disabled()?;
+    assert_has_role(Role::ShardOperator)?;
+    rebalance_shard_internal(id).await
+}
+
+pub async fn rebalance_shard_internal(id: Principal) -> Result<u64> {
+    let digest = log::digest((id, ));
+    let result = rebalance(id).await;
+    log::finished("rebalanceShard", digest, &result);
+    result
+}
+
+async fn rebalance(id: Principal) -> Result<u64> {
+    if get_routing_mode() != RoutingMode::Hashed {
+        return Err(TxError::Other(
+            "Shards are only rebalanced in the hashed routing mode".to_string(),
+        ));
+    }
+    match get_shard(&id) {
+        None => return Err(TxError::ShardDoesNotExist),
+        Some(shard) if shard.status != ShardStatus::Active => {
+            return Err(TxError::Other("Draining shards are emptied by drainShard".to_string()))
+        }
+        Some(_) => {}
+    }
+    let misplaced: Vec<(Principal, Principal)> = get_account_owners(id)
+        .await?
+        .into_iter()
+        .filter_map(|owner| route(&owner).map(|target| (owner, target)))
+        .filter(|&(_, target)| target != id)
+        .collect();
+
+    let mut batches: BTreeMap<Principal, Vec<Principal>> = BTreeMap::new();
+    for &(owner, target) in misplaced.iter().take(MAX_MIGRATION_BATCH) {
+        batches.entry(target).or_default().push(owner);
+    }
+    let moved = migrate_accounts(id, batches).await?;
+    Ok((misplaced.len() - moved) as u64)
+}
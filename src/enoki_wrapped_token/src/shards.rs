@@ -4,53 +4,117 @@ use std::collections::HashMap;
 
 use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{candid_method, types::number::Nat, CandidType, Principal};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 use serde::{Deserialize, Serialize};
 
+use enoki_wrapped_token_shared::{env, log};
 use enoki_wrapped_token_shared::types::*;
 
-use crate::accounts::{get_user_account, UserAccount};
-use crate::management::{assert_is_owner, get_fee};
+use crate::accounts::locate_shard;
+use crate::cycles::{CyclesSample, MAX_CYCLES_SAMPLES};
+use crate::freeze::get_block_frozen_recipients;
+use crate::governance::{assert_governance_disabled, is_governance_enabled};
+use crate::management::{get_fee, get_fee_beneficiaries};
 use crate::metadata::get_underlying_token;
+use crate::pause::get_paused_scopes;
+use crate::roles::{assert_has_role, get_role_assignments};
+use crate::routing::{get_routing_mode, RoutingMode};
+use crate::snapshots::get_latest_snapshot_id;
+use crate::stable::StableShards;
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShardStatus {
+    Active,
+    /// Being decommissioned: no accounts are assigned to it, and its accounts are moving to the
+    /// active shards.
+    Draining,
+}
 
 #[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
 pub struct Shard {
-    id: Principal,
-    num_accounts: u64,
+    pub id: Principal,
+    pub num_accounts: u64,
+    pub status: ShardStatus,
+    /// Latest polls of the shard's cycle balance, oldest first.
+    pub cycles_history: Vec<CyclesSample>,
+    /// Set when the last poll failed, or found the shard low on cycles and could not top it up.
+    pub cycles_warning: Option<String>,
 }
 
 pub type Shards = HashMap<Principal, Shard>;
 
 thread_local! {
     static SHARDS: RefCell<Shards> = RefCell::new(Shards::default());
+    /// Version of the shard registry, increased each time a shard is added, removed or changes
+    /// status, and when the routing mode changes.
+    static REGISTRY_VERSION: RefCell<u64> = const { RefCell::new(0) };
 }
 
-pub fn export_stable_storage() -> (Shards,) {
-    (SHARDS.with(|s| s.take()),)
+pub fn export_stable_storage() -> (StableShards, u64) {
+    let shards = SHARDS.with(|s| s.take());
+    let shards = shards.into_iter().map(|(id, shard)| (id, shard.into())).collect();
+    (shards, REGISTRY_VERSION.with(|v| v.take()))
 }
 
-pub fn import_stable_storage(shards: Shards) {
+pub fn import_stable_storage(shards: StableShards, registry_version: u64) {
+    let shards = shards.into_iter().map(|(id, shard)| (id, shard.into())).collect();
     SHARDS.with(|s| s.replace(shards));
+    REGISTRY_VERSION.with(|v| v.replace(registry_version));
 }
 
 #[query(name = "getShardIds")]
 #[candid_method(query, rename = "getShardIds")]
-fn get_shard_ids() -> Vec<Principal> {
+pub fn get_shard_ids() -> Vec<Principal> {
     SHARDS.with(|s| s.borrow().keys().copied().collect())
 }
 
 #[query(name = "getShardIdsUpdate")]
 #[candid_method(query, rename = "getShardIdsUpdate")]
-fn get_shard_ids_update() -> Vec<Principal> {
+pub fn get_shard_ids_update() -> Vec<Principal> {
     SHARDS.with(|s| s.borrow().keys().copied().collect())
 }
 
 #[query(name = "getShardsInfo")]
 #[candid_method(query, rename = "getShardsInfo")]
-fn get_shards_info() -> Vec<Shard> {
+pub fn get_shards_info() -> Vec<Shard> {
     SHARDS.with(|s| s.borrow().values().cloned().collect())
 }
 
+/// Every shard, draining ones included, with the version of the set. Pulled by shards called by
+/// a sibling they do not know yet.
+#[query(name = "getShardRegistry")]
+#[candid_method(query, rename = "getShardRegistry")]
+pub fn get_shard_registry() -> ShardRegistry {
+    let mut shards = get_shard_ids();
+    shards.sort();
+    let ring = match get_routing_mode() {
+        RoutingMode::Directory => None,
+        RoutingMode::Hashed => Some(get_ring_shards()),
+    };
+    ShardRegistry {
+        version: get_registry_version(),
+        shards,
+        ring,
+        latest_snapshot: get_latest_snapshot_id(),
+        governed: is_governance_enabled(),
+    }
+}
+
+pub fn get_registry_version() -> u64 {
+    REGISTRY_VERSION.with(|v| *v.borrow())
+}
+
+pub fn bump_registry_version() {
+    REGISTRY_VERSION.with(|v| *v.borrow_mut() += 1);
+}
+
+/// Shards accounts are hashed onto in the hashed routing mode: the active ones, sorted.
+pub fn get_ring_shards() -> Vec<Principal> {
+    let mut shards: Vec<Principal> = get_active_shards().into_iter().map(|s| s.id).collect();
+    shards.sort();
+    shards
+}
+
 #[update(name = "totalSupply")]
 #[candid_method(update, rename = "totalSupply")]
 pub async fn total_supply() -> Nat {
@@ -66,7 +130,7 @@ pub async fn total_supply() -> Nat {
 
 #[query(name = "getAccruedFees")]
 #[candid_method(query, rename = "getAccruedFees")]
-async fn get_accrued_fees() -> Nat {
+pub async fn get_accrued_fees() -> Nat {
     let values: Result<Vec<(Nat,)>> = foreach_shard("getAccruedFees", ()).await;
     values
         .map(|values| {
@@ -79,9 +143,9 @@ async fn get_accrued_fees() -> Nat {
 
 #[update(name = "balanceOf")]
 #[candid_method(update, rename = "balanceOf")]
-async fn balance_of(id: Principal) -> Nat {
-    if let Some(UserAccount { assigned_shard }) = get_user_account(&id) {
-        let balance: Result<(Nat,)> = ic_cdk::call(assigned_shard, "shardBalanceOf", (id,))
+pub async fn balance_of(id: Principal) -> Nat {
+    if let Some(assigned_shard) = locate_shard(&id) {
+        let balance: Result<(Nat,)> = env::call(assigned_shard, "shardBalanceOf", (id,))
             .await
             .map_err(|err| err.into());
 
@@ -91,6 +155,55 @@ async fn balance_of(id: Principal) -> Nat {
     }
 }
 
+/// A page of the holders of every shard, in `order`, from the one following `after`, which is
+/// the `next` cursor of the previous page. In the owner order, the balances an owner holds on
+/// several shards, such as a fee beneficiary, are summed; in the balance order, the owner is
+/// listed once per shard.
+#[update(name = "getHolders")]
+#[candid_method(update, rename = "getHolders")]
+pub async fn get_holders(
+    order: HolderOrder,
+    after: Option<HoldersCursor>,
+    limit: u64,
+) -> HoldersPage {
+    let limit = limit.min(MAX_HOLDERS_PAGE);
+    let shards = get_shard_ids();
+    let pages: Vec<std::result::Result<(Vec<Holder>,), _>> = futures::future::join_all(
+        shards
+            .iter()
+            .map(|&shard| env::call(shard, "shardGetHolders", (order, after.clone(), limit))),
+    )
+    .await;
+    let mut holders: Vec<(Holder, Principal)> = vec![];
+    for (&shard, page) in shards.iter().zip(pages) {
+        let (page,) = page.map_err(TxError::from).unwrap();
+        holders.extend(page.into_iter().map(|holder| (holder, shard)));
+    }
+    holders.sort_by(|(a, a_shard), (b, b_shard)| {
+        order.compare(a, b).then(a_shard.cmp(b_shard))
+    });
+    if order == HolderOrder::Owner {
+        holders.dedup_by(|(next, _), (first, _)| {
+            let same = next.owner == first.owner;
+            if same {
+                first.balance += next.balance.clone();
+            }
+            same
+        });
+    }
+    // each shard returned all its holders up to the last one kept
+    holders.truncate(limit as usize);
+    let next = match holders.last() {
+        Some((holder, shard)) if holders.len() == limit as usize => Some(HoldersCursor {
+            holder: holder.clone(),
+            shard: *shard,
+        }),
+        _ => None,
+    };
+    let holders = holders.into_iter().map(|(holder, _)| holder).collect();
+    HoldersPage { holders, next }
+}
+
 async fn foreach_shard<T: ArgumentEncoder + Clone, R: for<'a> ArgumentDecoder<'a>>(
     method: &str,
     args: T,
@@ -99,7 +212,7 @@ async fn foreach_shard<T: ArgumentEncoder + Clone, R: for<'a> ArgumentDecoder<'a
     let responses: Vec<std::result::Result<R, _>> = futures::future::join_all(
         shards
             .into_iter()
-            .map(|shard| ic_cdk::call(shard, method, args.clone())),
+            .map(|shard| env::call(shard, method, args.clone())),
     )
     .await;
     responses
@@ -110,22 +223,51 @@ async fn foreach_shard<T: ArgumentEncoder + Clone, R: for<'a> ArgumentDecoder<'a
 
 #[update(name = "addShard")]
 #[candid_method(update, rename = "addShard")]
-async fn add_shard(id: Principal) {
-    assert_is_owner().unwrap();
-    let sibling_shards = get_shard_ids();
+pub async fn add_shard(id: Principal) {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::ShardOperator).unwrap();
+    add_shard_internal(id).await.unwrap();
+}
 
-    let response: Result<()> = ic_cdk::call(
+pub async fn add_shard_internal(id: Principal) -> Result<()> {
+    let digest = log::digest((id,));
+    log::started("addShard", digest.clone());
+    let result = install_shard(id).await;
+    log::finished("addShard", digest, &result);
+    result
+}
+
+async fn install_shard(id: Principal) -> Result<()> {
+    let response: Result<()> = env::call(
         id,
         "initShard",
-        (get_underlying_token(), sibling_shards, get_fee()),
+        (get_underlying_token(), get_shard_registry(), get_fee()),
     )
     .await
     .map_err(|err| err.into());
-    response.unwrap();
+    response?;
+
+    let response: Result<(Result<()>,)> =
+        env::call(id, "setFeeBeneficiaries", (get_fee_beneficiaries(),))
+            .await
+            .map_err(|err| err.into());
+    response?.0?;
 
-    foreach_shard::<(Principal,), ()>("addSiblingShard", (id,))
+    let response: Result<(Result<()>,)> = env::call(id, "setRoles", (get_role_assignments(),))
         .await
-        .unwrap();
+        .map_err(|err| err.into());
+    response?.0?;
+
+    let response: Result<(Result<()>,)> = env::call(id, "setPaused", (get_paused_scopes(),))
+        .await
+        .map_err(|err| err.into());
+    response?.0?;
+
+    let response: Result<(Result<()>,)> =
+        env::call(id, "setBlockFrozenRecipients", (get_block_frozen_recipients(),))
+            .await
+            .map_err(|err| err.into());
+    response?.0?;
 
     SHARDS.with(|s| {
         s.borrow_mut().insert(
@@ -133,30 +275,94 @@ async fn add_shard(id: Principal) {
             Shard {
                 id,
                 num_accounts: 0,
+                status: ShardStatus::Active,
+                cycles_history: vec![],
+                cycles_warning: None,
             },
         )
     });
+    bump_registry_version();
+    // shards the push misses pull the registry when the new shard first calls them
+    let _ = push_registry().await;
+    Ok(())
 }
 
+/// Sends the shard registry and the roles to every shard again, e.g. after a push failed.
 #[update(name = "fixSiblings")]
 #[candid_method(update, rename = "fixSiblings")]
-async fn fix_siblings() {
-    assert_is_owner().unwrap();
-    let sibling_shards = get_shard_ids();
+pub async fn fix_siblings() {
+    assert_governance_disabled().unwrap();
+    assert_has_role(Role::ShardOperator).unwrap();
+    fix_siblings_internal().await.unwrap();
+}
+
+pub async fn fix_siblings_internal() -> Result<()> {
+    let registry = push_registry().await;
+    let roles = update_roles(get_role_assignments()).await;
+    registry.and(roles)
+}
 
-    for &shard in sibling_shards.iter() {
-        let siblings: Vec<Principal> = sibling_shards
+/// Has shard `id` refund transfer `held`, held for longer than its timeout, which shard
+/// operators do on the shard directly unless governance is enabled.
+pub async fn refund_held_transfer_internal(id: Principal, held: u64) -> Result<()> {
+    let response: Result<(Result<()>, )> = env::call(id, "refundHeldTransfer", (held, ))
+        .await
+        .map_err(|err| err.into());
+    response?.0
+}
+
+/// Sends the current registry to every shard with one call each. Shards that miss it keep their
+/// older registry, so every shard is tried, and the first failure is returned.
+pub async fn push_registry() -> Result<()> {
+    let registry = get_shard_registry();
+    let responses: Vec<Result<(Result<()>,)>> = futures::future::join_all(
+        registry
+            .shards
             .iter()
-            .filter(|&&s| s != shard)
-            .copied()
-            .collect();
-        for sibling in siblings {
-            let result: Result<()> = ic_cdk::call(shard, "addSiblingShard", (sibling,))
-                .await
-                .map_err(|e| e.into());
-            result.unwrap();
+            .map(|&shard| env::call(shard, "setShardRegistry", (registry.clone(),))),
+    )
+    .await
+    .into_iter()
+    .map(|response| response.map_err(|err| err.into()))
+    .collect();
+
+    let mut result = Ok(());
+    for (&shard, response) in registry.shards.iter().zip(responses) {
+        match response.and_then(|res| res.0) {
+            // a newer registry was pushed in the meantime
+            Ok(()) | Err(TxError::StaleRegistry { .. }) => {}
+            Err(error) => {
+                log::warning("pushShardRegistry", log::digest((shard, registry.version)), &error);
+                result = result.and(Err(error));
+            }
         }
     }
+    result
+}
+
+pub fn get_shard(id: &Principal) -> Option<Shard> {
+    SHARDS.with(|s| s.borrow().get(id).cloned())
+}
+
+pub fn set_shard_status(id: Principal, status: ShardStatus) {
+    SHARDS.with(|s| s.borrow_mut().get_mut(&id).unwrap().status = status);
+    bump_registry_version();
+}
+
+pub fn remove_shard(id: Principal) {
+    SHARDS.with(|s| s.borrow_mut().remove(&id));
+    bump_registry_version();
+}
+
+/// Shards new accounts can be assigned to.
+pub fn get_active_shards() -> Vec<Shard> {
+    SHARDS.with(|s| {
+        s.borrow()
+            .values()
+            .filter(|shard| shard.status == ShardStatus::Active)
+            .cloned()
+            .collect()
+    })
 }
 
 pub fn update_shard_accounts<TF: Fn(&mut u64)>(id: Principal, func: TF) {
@@ -167,11 +373,25 @@ pub fn update_shard_accounts<TF: Fn(&mut u64)>(id: Principal, func: TF) {
     })
 }
 
+pub fn record_shard_cycles(id: Principal, sample: Option<CyclesSample>, warning: Option<String>) {
+    SHARDS.with(|s| {
+        if let Some(shard) = s.borrow_mut().get_mut(&id) {
+            if let Some(sample) = sample {
+                shard.cycles_history.push(sample);
+                let excess = shard.cycles_history.len().saturating_sub(MAX_CYCLES_SAMPLES);
+                shard.cycles_history.drain(..excess);
+            }
+            shard.cycles_warning = warning;
+        }
+    })
+}
+
 pub fn get_lowest_utilization_shard() -> Principal {
     //TODO: choose by lowest transfer activity, not number of accounts
     SHARDS.with(|s| {
         s.borrow()
             .values()
+            .filter(|shard| shard.status == ShardStatus::Active)
             .min_by(|&a, &b| {
                 let comp = a.num_accounts.cmp(&b.num_accounts);
                 if let Ordering::Equal = comp {
@@ -190,3 +410,31 @@ pub async fn update_fee(new_fee: Nat) -> Result<()> {
 
     Ok(())
 }
+
+pub async fn update_fee_beneficiaries(beneficiaries: Vec<FeeBeneficiary>) -> Result<()> {
+    let responses =
+        foreach_shard::<(Vec<FeeBeneficiary>,), (Result<()>,)>("setFeeBeneficiaries", (beneficiaries,))
+            .await?;
+
+    responses.into_iter().try_for_each(|res| res.0)
+}
+
+pub async fn update_roles(roles: RoleAssignments) -> Result<()> {
+    let responses = foreach_shard::<(RoleAssignments,), (Result<()>,)>("setRoles", (roles,)).await?;
+
+    responses.into_iter().try_for_each(|res| res.0)
+}
+
+pub async fn update_paused(scopes: Vec<PauseScope>) -> Result<()> {
+    let responses = foreach_shard::<(Vec<PauseScope>,), (Result<()>,)>("setPaused", (scopes,)).await?;
+
+    responses.into_iter().try_for_each(|res| res.0)
+}
+
+pub async fn update_block_frozen_recipients(block_recipients: bool) -> Result<()> {
+    let responses =
+        foreach_shard::<(bool,), (Result<()>,)>("setBlockFrozenRecipients", (block_recipients,))
+            .await?;
+
+    responses.into_iter().try_for_each(|res| res.0)
+}
@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{candid_method, types::number::Nat, CandidType, Principal};
+use enoki_wrapped_token_macros::*;
+use serde::{Deserialize, Serialize};
+
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::roles::assert_has_role;
+use crate::shards::{get_shard, get_shard_ids};
+
+/// Balances as of a point in time, recorded by every shard.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Snapshot {
+    pub id: u64,
+    pub timestamp: u64,
+    /// Shards that recorded it, and hold its balances.
+    pub shards: Vec<Principal>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default)]
+pub struct SnapshotsState {
+    /// Id of the latest snapshot started, even if some shard failed to record it.
+    latest_id: u64,
+    /// Snapshots every shard recorded.
+    snapshots: BTreeMap<u64, Snapshot>,
+}
+
+thread_local! {
+    static SNAPSHOTS: RefCell<SnapshotsState> = RefCell::new(SnapshotsState::default());
+}
+
+pub fn export_stable_storage() -> (SnapshotsState, ) {
+    (SNAPSHOTS.with(|s| s.take()), )
+}
+
+pub fn import_stable_storage(snapshots: SnapshotsState) {
+    SNAPSHOTS.with(|s| s.replace(snapshots));
+}
+
+pub fn get_latest_snapshot_id() -> u64 {
+    SNAPSHOTS.with(|s| s.borrow().latest_id)
+}
+
+#[query(name = "getSnapshots")]
+#[candid_method(query, rename = "getSnapshots")]
+pub fn get_snapshots() -> Vec<Snapshot> {
+    SNAPSHOTS.with(|s| s.borrow().snapshots.values().cloned().collect())
+}
+
+/// Records the balance of every account on every shard, and returns the id of the snapshot.
+/// Transfers between shards are counted once, on the sender or the recipient, even when they
+/// are in flight. If a shard fails to record it, the snapshot is dropped and the error returned.
+#[update(name = "takeSnapshot")]
+#[candid_method(update, rename = "takeSnapshot")]
+pub async fn take_snapshot() -> Result<u64> {
+    let digest = log::digest(());
+    let result = take_snapshot_internal().await;
+    log::finished("takeSnapshot", digest, &result);
+    result
+}
+
+async fn take_snapshot_internal() -> Result<u64> {
+    assert_has_role(Role::Admin)?;
+    let id = SNAPSHOTS.with(|s| {
+        let mut s = s.borrow_mut();
+        s.latest_id += 1;
+        s.latest_id
+    });
+    let timestamp = env::time();
+    let mut shards = get_shard_ids();
+    shards.sort();
+    let responses: Vec<Result<(Result<()>, )>> = futures::future::join_all(
+        shards
+            .iter()
+            .map(|&shard| env::call(shard, "shardTakeSnapshot", (id, ))),
+    )
+    .await
+    .into_iter()
+    .map(|response| response.map_err(|err| err.into()))
+    .collect();
+
+    let mut result = Ok(());
+    for (&shard, response) in shards.iter().zip(responses) {
+        if let Err(error) = response.and_then(|res| res.0) {
+            log::warning("shardTakeSnapshot", log::digest((shard, id)), &error);
+            result = result.and(Err(error));
+        }
+    }
+    result?;
+    SNAPSHOTS.with(|s| {
+        s.borrow_mut().snapshots.insert(
+            id,
+            Snapshot {
+                id,
+                timestamp,
+                shards,
+            },
+        )
+    });
+    Ok(id)
+}
+
+/// Balance of `owner` at snapshot `id`, summed over its subaccounts and every shard. Fails once
+/// a shard that recorded the snapshot is decommissioned, since its balances went with it.
+#[update(name = "balanceAtSnapshot")]
+#[candid_method(update, rename = "balanceAtSnapshot")]
+pub async fn balance_at_snapshot(owner: Principal, id: u64) -> Result<Nat> {
+    let snapshot = SNAPSHOTS
+        .with(|s| s.borrow().snapshots.get(&id).cloned())
+        .ok_or(TxError::SnapshotDoesNotExist)?;
+    if let Some(removed) = snapshot.shards.iter().find(|shard| get_shard(shard).is_none()) {
+        return Err(TxError::Other(format!(
+            "Shard {} of snapshot {} was decommissioned",
+            removed, id
+        )));
+    }
+    let responses: Vec<std::result::Result<(Result<Nat>, ), _>> = futures::future::join_all(
+        snapshot
+            .shards
+            .iter()
+            .map(|&shard| env::call(shard, "shardBalanceAtSnapshot", (owner, id))),
+    )
+    .await;
+    let mut balance = Nat::from(0);
+    for response in responses {
+        let (shard_balance, ) = response.map_err(TxError::from)?;
+        balance += shard_balance?;
+    }
+    Ok(balance)
+}
@@ -1,12 +1,21 @@
+use std::collections::HashMap;
+
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
+use enoki_wrapped_token_shared::types::FeeBeneficiary;
+
+use crate::cycles::CyclesSample;
+use crate::shards::{Shard, ShardStatus};
 use crate::ManagementStats;
 
 #[derive(CandidType, Clone, Deserialize, Serialize)]
 pub struct StableManagementStats {
     pub owner: Principal,
+    pub pending_owner: Option<Principal>,
     pub fee: String,
+    /// Missing from contracts saved before fees were distributed.
+    pub fee_beneficiaries: Option<Vec<FeeBeneficiary>>,
     pub deploy_time: u64,
 }
 
@@ -14,7 +23,9 @@ impl From<StableManagementStats> for ManagementStats {
     fn from(s: StableManagementStats) -> Self {
         Self {
             owner: s.owner,
+            pending_owner: s.pending_owner,
             fee: s.fee.parse().unwrap(),
+            fee_beneficiaries: s.fee_beneficiaries.unwrap_or_default(),
             deploy_time: s.deploy_time,
         }
     }
@@ -24,8 +35,47 @@ impl From<ManagementStats> for StableManagementStats {
     fn from(s: ManagementStats) -> Self {
         Self {
             owner: s.owner,
+            pending_owner: s.pending_owner,
             fee: s.fee.to_string(),
+            fee_beneficiaries: Some(s.fee_beneficiaries),
             deploy_time: s.deploy_time,
         }
     }
 }
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct StableShard {
+    pub id: Principal,
+    pub num_accounts: u64,
+    /// Missing from shards saved before they could be decommissioned, which were all active.
+    pub status: Option<ShardStatus>,
+    /// Missing from shards saved before their cycles were polled.
+    pub cycles_history: Option<Vec<CyclesSample>>,
+    pub cycles_warning: Option<String>,
+}
+
+pub type StableShards = HashMap<Principal, StableShard>;
+
+impl From<StableShard> for Shard {
+    fn from(s: StableShard) -> Self {
+        Self {
+            id: s.id,
+            num_accounts: s.num_accounts,
+            status: s.status.unwrap_or(ShardStatus::Active),
+            cycles_history: s.cycles_history.unwrap_or_default(),
+            cycles_warning: s.cycles_warning,
+        }
+    }
+}
+
+impl From<Shard> for StableShard {
+    fn from(s: Shard) -> Self {
+        Self {
+            id: s.id,
+            num_accounts: s.num_accounts,
+            status: Some(s.status),
+            cycles_history: Some(s.cycles_history),
+            cycles_warning: s.cycles_warning,
+        }
+    }
+}
@@ -1,9 +1,13 @@
 use candid::{CandidType, Deserialize, Principal, types::number::Nat};
 
+use enoki_wrapped_token_shared::types::FeeBeneficiary;
+
 #[derive(Deserialize, CandidType, Clone, Debug)]
 pub struct ManagementStats {
     pub owner: Principal,
+    pub pending_owner: Option<Principal>,
     pub fee: Nat,
+    pub fee_beneficiaries: Vec<FeeBeneficiary>,
     pub deploy_time: u64,
 }
 
@@ -11,7 +15,9 @@ impl Default for ManagementStats {
     fn default() -> Self {
         ManagementStats {
             owner: Principal::anonymous(),
+            pending_owner: None,
             fee: Nat::from(0),
+            fee_beneficiaries: vec![],
             deploy_time: 0,
         }
     }
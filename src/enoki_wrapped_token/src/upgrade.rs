@@ -1,49 +1,146 @@
+use candid::de::IDLDeserialize;
 use candid::{CandidType, Deserialize};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 
-use crate::{accounts, management, metadata, shards};
+use enoki_wrapped_token_shared::log::{self, EventLog};
+use enoki_wrapped_token_shared::types::{PauseScope, RoleAssignments};
+
+use crate::{
+    accounts, cycles, freeze, governance, management, metadata, metrics, pause, roles, routing,
+    shards, snapshots,
+};
 use crate::accounts::UserAccounts;
+use crate::cycles::CyclesState;
+use crate::freeze::FreezeState;
+use crate::governance::GovernanceState;
 use crate::metadata::Metadata;
-use crate::shards::Shards;
-use crate::stable::StableManagementStats;
+use crate::metrics::Counters;
+use crate::routing::RoutingMode;
+use crate::snapshots::SnapshotsState;
+use crate::stable::{StableManagementStats, StableShards};
+
+/// Version of the layout of `UpgradePayload`, saved with it since version 13. It counts the
+/// changes to the layout since the first release.
+pub const PAYLOAD_VERSION: u32 = 13;
 
-#[derive(Deserialize, CandidType)]
-struct UpgradePayload {
+/// The state saved across upgrades. Fields added since the first release are optional, so that
+/// the payload saved by an earlier version still decodes, and start empty when it lacks them.
+#[derive(Deserialize, CandidType, Clone)]
+pub struct UpgradePayload {
+    version: Option<u32>,
     user_accounts: UserAccounts,
     management_stats: StableManagementStats,
     metadata: Metadata,
-    shards: Shards,
+    shards: StableShards,
+    registry_version: Option<u64>,
+    roles: Option<RoleAssignments>,
+    governance: Option<GovernanceState>,
+    paused: Option<Vec<PauseScope>>,
+    freeze_state: Option<FreezeState>,
+    counters: Option<Counters>,
+    logs: Option<EventLog>,
+    cycles: Option<CyclesState>,
+    routing_mode: Option<RoutingMode>,
+    snapshots: Option<SnapshotsState>,
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
+impl UpgradePayload {
+    /// The version that saved the payload, or `None` if it predates the tag.
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+/// Takes the whole canister state out of the thread locals.
+pub fn export_state() -> UpgradePayload {
     let (user_accounts, ) = accounts::export_stable_storage();
     let (management_stats, ) = management::export_stable_storage();
     let (metadata, ) = metadata::export_stable_storage();
-    let (shards, ) = shards::export_stable_storage();
-    let payload = UpgradePayload {
+    let (shards, registry_version) = shards::export_stable_storage();
+    let (roles, ) = roles::export_stable_storage();
+    let (governance, ) = governance::export_stable_storage();
+    let (paused, ) = pause::export_stable_storage();
+    let (freeze_state, ) = freeze::export_stable_storage();
+    let (counters, ) = metrics::export_stable_storage();
+    let (logs, ) = log::export_stable_storage();
+    let (cycles, ) = cycles::export_stable_storage();
+    let (routing_mode, ) = routing::export_stable_storage();
+    let (snapshots, ) = snapshots::export_stable_storage();
+    UpgradePayload {
+        version: Some(PAYLOAD_VERSION),
         user_accounts,
         management_stats,
         metadata,
         shards,
-    };
-    ic_cdk::storage::stable_save((payload, )).expect("failed to save to stable storage");
+        registry_version: Some(registry_version),
+        roles: Some(roles),
+        governance: Some(governance),
+        paused: Some(paused),
+        freeze_state: Some(freeze_state),
+        counters: Some(counters),
+        logs: Some(logs),
+        cycles: Some(cycles),
+        routing_mode: Some(routing_mode),
+        snapshots: Some(snapshots),
+    }
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    let (payload, ): (UpgradePayload, ) =
-        ic_cdk::storage::stable_restore().expect("failed to restore from stable storage");
-
+pub fn import_state(payload: UpgradePayload) {
     let UpgradePayload {
+        version: _,
         user_accounts,
         management_stats,
         metadata,
         shards,
+        registry_version,
+        roles,
+        governance,
+        paused,
+        freeze_state,
+        counters,
+        logs,
+        cycles,
+        routing_mode,
+        snapshots,
     } = payload;
 
     accounts::import_stable_storage(user_accounts);
     management::import_stable_storage(management_stats);
     metadata::import_stable_storage(metadata);
-    shards::import_stable_storage(shards);
+    shards::import_stable_storage(shards, registry_version.unwrap_or_default());
+    roles::import_stable_storage(roles.unwrap_or_default());
+    governance::import_stable_storage(governance.unwrap_or_default());
+    pause::import_stable_storage(paused.unwrap_or_default());
+    freeze::import_stable_storage(freeze_state.unwrap_or_default());
+    metrics::import_stable_storage(counters.unwrap_or_default());
+    log::import_stable_storage(logs.unwrap_or_default());
+    cycles::import_stable_storage(cycles.unwrap_or_default());
+    routing::import_stable_storage(routing_mode.unwrap_or_default());
+    snapshots::import_stable_storage(snapshots.unwrap_or_default());
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let payload = export_state();
+    ic_cdk::storage::stable_save((payload, )).expect("failed to save to stable storage");
+}
+
+/// Decodes the payload saved by `pre_upgrade` of this version or of an earlier one.
+pub fn decode_payload(stable: &[u8]) -> candid::Result<UpgradePayload> {
+    // stable memory is read whole, so whatever follows the payload is left undecoded
+    let payload: UpgradePayload = IDLDeserialize::new(stable)?.get_value()?;
+    match payload.version {
+        Some(version) if version > PAYLOAD_VERSION => Err(candid::Error::msg(format!(
+            "payload version {} is newer than {}",
+            version, PAYLOAD_VERSION
+        ))),
+        _ => Ok(payload),
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let payload = decode_payload(&ic_cdk::api::stable::stable_bytes())
+        .expect("failed to restore from stable storage");
+    import_state(payload);
 }
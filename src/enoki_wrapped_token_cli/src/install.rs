@@ -0,0 +1,73 @@
+use std::path::Path;
+use std::time::Duration;
+
+use candid::{encode_args, CandidType, Deserialize, Principal};
+use clap::ArgEnum;
+use ic_agent::Agent;
+
+use enoki_wrapped_token_shared::types::{ByteBuf, CanisterIdRecord};
+
+use crate::Result;
+
+#[derive(CandidType, Deserialize, ArgEnum, Clone, Copy, Debug)]
+pub enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
+#[derive(CandidType)]
+struct InstallCodeArgument {
+    mode: InstallMode,
+    canister_id: Principal,
+    wasm_module: ByteBuf,
+    arg: ByteBuf,
+}
+
+fn waiter() -> garcon::Delay {
+    garcon::Delay::builder()
+        .throttle(Duration::from_millis(500))
+        .timeout(Duration::from_secs(300))
+        .build()
+}
+
+/// Installs `wasm` into `canister`, which the agent's identity must control. Both canisters
+/// take no init arguments.
+pub async fn install_code(
+    agent: &Agent,
+    canister: Principal,
+    wasm: &Path,
+    mode: InstallMode,
+) -> Result<()> {
+    let wasm_module = tokio::fs::read(wasm).await?;
+    let argument = InstallCodeArgument {
+        mode,
+        canister_id: canister,
+        wasm_module: ByteBuf::from(wasm_module),
+        arg: ByteBuf::from(encode_args(())?),
+    };
+    agent
+        .update(&Principal::management_canister(), "install_code")
+        .with_effective_canister_id(canister)
+        .with_arg(encode_args((argument, ))?)
+        .call_and_wait(waiter())
+        .await?;
+    Ok(())
+}
+
+/// Stops and deletes `canister`, which the agent's identity must control. Its remaining cycles
+/// are lost.
+pub async fn delete_canister(agent: &Agent, canister: Principal) -> Result<()> {
+    for method in ["stop_canister", "delete_canister"] {
+        agent
+            .update(&Principal::management_canister(), method)
+            .with_effective_canister_id(canister)
+            .with_arg(encode_args((CanisterIdRecord { canister_id: canister }, ))?)
+            .call_and_wait(waiter())
+            .await?;
+    }
+    Ok(())
+}
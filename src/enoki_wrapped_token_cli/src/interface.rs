@@ -0,0 +1,36 @@
+use candid::parser::types::IDLProg;
+use candid::types::Type;
+use candid::{check_prog, IDLArgs, TypeEnv};
+
+const TOKEN_DID: &str = include_str!("../../enoki_wrapped_token/enoki_wrapped_token.did");
+const SHARD_DID: &str = include_str!("../../enoki_wrapped_token_shard/enoki_wrapped_token_shard.did");
+
+/// Candid interface of a canister, used to print replies with their field names, like dfx does.
+pub struct Interface {
+    env: TypeEnv,
+    actor: Type,
+}
+
+impl Interface {
+    fn new(did: &str) -> Self {
+        let prog: IDLProg = did.parse().expect("invalid candid interface");
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog)
+            .expect("invalid candid interface")
+            .expect("candid interface has no service");
+        Self { env, actor }
+    }
+
+    pub fn token() -> Self {
+        Self::new(TOKEN_DID)
+    }
+
+    pub fn shard() -> Self {
+        Self::new(SHARD_DID)
+    }
+
+    pub fn decode_reply(&self, method: &str, bytes: &[u8]) -> candid::Result<IDLArgs> {
+        let function = self.env.get_method(&self.actor, method)?;
+        IDLArgs::from_bytes_with_types(bytes, &self.env, &function.rets)
+    }
+}
@@ -0,0 +1,489 @@
+//! `enoki-token`: operates a deployment of the wrapped token from the command line.
+
+use std::path::PathBuf;
+
+use candid::utils::ArgumentEncoder;
+use candid::{decode_args, encode_args, CandidType, Deserialize, Nat, Principal};
+use clap::{ArgEnum, Parser, Subcommand};
+use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
+use ic_agent::identity::{AnonymousIdentity, BasicIdentity, Secp256k1Identity};
+use ic_agent::{Agent, Identity};
+
+use enoki_wrapped_token_client::{AgentTransport, TokenClient, Transport};
+use enoki_wrapped_token_shared::backup::BackupManifest;
+use enoki_wrapped_token_shared::types::{self, ByteBuf, HolderOrder, MAX_HOLDERS_PAGE};
+
+use crate::install::{delete_canister, install_code, InstallMode};
+use crate::interface::Interface;
+
+mod install;
+mod interface;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Parser)]
+#[clap(name = "enoki-token", version, about = "Operates a deployment of the Enoki wrapped token")]
+struct Opts {
+    /// Replica to send calls to. The root key is fetched from it, unless it is the IC mainnet.
+    #[clap(long, env = "ENOKI_URL", default_value = "http://127.0.0.1:8000")]
+    url: String,
+    /// PEM file of the identity to call with, e.g. `~/.config/dfx/identity/default/identity.pem`.
+    /// Calls are anonymous without it.
+    #[clap(long, env = "ENOKI_IDENTITY")]
+    identity: Option<PathBuf>,
+    /// Main contract of the token.
+    #[clap(long, env = "ENOKI_TOKEN")]
+    token: Principal,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Installs the main contract and its shards into canisters created beforehand, for example
+    /// with `dfx canister create`, and initializes them.
+    Deploy {
+        #[clap(long)]
+        wasm: PathBuf,
+        #[clap(long)]
+        shard_wasm: PathBuf,
+        #[clap(long, arg_enum, default_value = "install")]
+        mode: InstallMode,
+        /// DIP20 token being wrapped.
+        #[clap(long)]
+        underlying: Principal,
+        #[clap(long)]
+        name: String,
+        #[clap(long)]
+        symbol: String,
+        #[clap(long, default_value = "")]
+        logo: String,
+        #[clap(long, default_value = "8")]
+        decimals: u8,
+        #[clap(long)]
+        fee: Nat,
+        /// Canister of a shard. Can be repeated.
+        #[clap(long = "shard", required = true)]
+        shards: Vec<Principal>,
+    },
+    /// Adds a shard to the token, after installing it if `--wasm` is given.
+    AddShard {
+        shard: Principal,
+        #[clap(long)]
+        wasm: Option<PathBuf>,
+    },
+    /// Sends the list of shards to every shard again.
+    FixSiblings,
+    /// Moves every account off a shard and removes it from the token. Resumes a decommission
+    /// that was interrupted.
+    Decommission {
+        shard: Principal,
+        /// Stops and deletes the canister afterwards.
+        #[clap(long)]
+        delete: bool,
+    },
+    /// Switches between assigning accounts to shards when they register and placing them with
+    /// a consistent hash.
+    SetRouting {
+        #[clap(arg_enum)]
+        mode: Routing,
+    },
+    /// Moves the accounts of a shard that the hash ring places on other shards, after a shard was
+    /// added or hashed routing enabled.
+    Rebalance {
+        shard: Principal,
+    },
+    /// Sets the transfer fee, on the main contract and every shard.
+    SetFee {
+        fee: Nat,
+    },
+    /// Total supply, owner, fee and cycles of the main contract.
+    Stats,
+    /// Lists the shards, how many accounts they hold, and their latest cycle balances.
+    Shards,
+    /// Lists every holder and its balance, by owner or by decreasing balance.
+    Holders {
+        #[clap(long)]
+        by_balance: bool,
+    },
+    /// Records the balance of every account, and prints the id of the snapshot.
+    Snapshot,
+    /// Balance of an account, read from its shard.
+    Balance {
+        account: Principal,
+        /// Balance at a snapshot instead.
+        #[clap(long)]
+        at: Option<u64>,
+    },
+    /// Creates an account, for the caller by default, and prints its shard.
+    Register {
+        account: Option<Principal>,
+    },
+    /// Wraps underlying tokens. The caller's shard must be approved on the underlying token first.
+    Wrap {
+        amount: Nat,
+    },
+    /// Unwraps tokens, to the caller by default.
+    Unwrap {
+        amount: Nat,
+        #[clap(long)]
+        to: Option<Principal>,
+    },
+    /// Transfers from the caller's shard to the shard of `to`.
+    Transfer {
+        to: Principal,
+        amount: Nat,
+    },
+    /// Prints the public state of the main contract and of every shard, as candid values.
+    Export {
+        /// Writes to a file instead of stdout.
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+    /// Saves the whole state of the main contract or of a shard to a file.
+    Backup {
+        canister: Principal,
+        out: PathBuf,
+    },
+    /// Restores a backup onto a canister reinstalled with an empty state.
+    Restore {
+        canister: Principal,
+        file: PathBuf,
+    },
+}
+
+/// Routing modes of the main contract.
+#[derive(CandidType, ArgEnum, Clone, Copy, Debug)]
+enum Routing {
+    Directory,
+    Hashed,
+}
+
+/// Fields of the token metadata needed here.
+#[derive(CandidType, Deserialize)]
+struct Metadata {
+    underlying_token: Principal,
+}
+
+/// Sends calls to the main contract and the shards, and prints their replies.
+struct Cli {
+    client: TokenClient<AgentTransport>,
+    agent: Agent,
+    token_interface: Interface,
+    shard_interface: Interface,
+}
+
+impl Cli {
+    async fn new(opts: &Opts) -> Result<Self> {
+        let identity: Box<dyn Identity> = match &opts.identity {
+            None => Box::new(AnonymousIdentity),
+            Some(path) => match BasicIdentity::from_pem_file(path) {
+                Ok(identity) => Box::new(identity),
+                // dfx creates secp256k1 identities
+                Err(_) => Box::new(Secp256k1Identity::from_pem_file(path)?),
+            },
+        };
+        let agent = Agent::builder()
+            .with_transport(ReqwestHttpReplicaV2Transport::create(opts.url.clone())?)
+            .with_boxed_identity(identity)
+            .build()?;
+        if !opts.url.contains("ic0.app") {
+            agent.fetch_root_key().await?;
+        }
+        Ok(Self {
+            client: TokenClient::new(AgentTransport::new(agent.clone()), opts.token),
+            agent,
+            token_interface: Interface::token(),
+            shard_interface: Interface::shard(),
+        })
+    }
+
+    fn token(&self) -> Principal {
+        self.client.token()
+    }
+
+    fn interface(&self, canister: Principal) -> &Interface {
+        if canister == self.token() {
+            &self.token_interface
+        } else {
+            &self.shard_interface
+        }
+    }
+
+    /// Calls `method` and returns its reply as candid text.
+    async fn call<A: ArgumentEncoder>(
+        &self,
+        canister: Principal,
+        method: &str,
+        args: A,
+        query: bool,
+    ) -> Result<String> {
+        let args = encode_args(args)?;
+        let transport = self.client.transport();
+        let bytes = if query {
+            transport.query(canister, method, args).await?
+        } else {
+            transport.update(canister, method, args).await?
+        };
+        Ok(self.interface(canister).decode_reply(method, &bytes)?.to_string())
+    }
+
+    async fn add_shard(
+        &self,
+        shard: Principal,
+        wasm: Option<PathBuf>,
+        mode: InstallMode,
+    ) -> Result<()> {
+        if let Some(wasm) = wasm {
+            install_code(&self.agent, shard, &wasm, mode).await?;
+            let bytes = self
+                .client
+                .transport()
+                .query(self.token(), "getMetadata", encode_args(())?)
+                .await?;
+            let (metadata, ): (Metadata, ) = decode_args(&bytes)?;
+            self.call(shard, "finishInit", (self.token(), metadata.underlying_token), false)
+                .await?;
+        }
+        self.call(self.token(), "addShard", (shard, ), false).await?;
+        println!("added shard {}", shard);
+        Ok(())
+    }
+
+    /// Calls `method` and decodes its reply, which must be a `Result`.
+    async fn call_typed<A: ArgumentEncoder, R: for<'a> Deserialize<'a> + CandidType>(
+        &self,
+        canister: Principal,
+        method: &str,
+        args: A,
+    ) -> Result<R> {
+        let bytes = self
+            .client
+            .transport()
+            .update(canister, method, encode_args(args)?)
+            .await?;
+        let (result, ): (types::Result<R>, ) = decode_args(&bytes)?;
+        Ok(result.map_err(|err| format!("{} failed: {:?}", method, err))?)
+    }
+
+    async fn decommission(&self, shard: Principal, delete: bool) -> Result<()> {
+        let token = self.token();
+        let bytes = self
+            .client
+            .transport()
+            .query(shard, "isDraining", encode_args(())?)
+            .await?;
+        let (draining, ): (bool, ) = decode_args(&bytes)?;
+        if !draining {
+            self.call_typed::<_, ()>(token, "startDecommission", (shard, )).await?;
+        }
+        loop {
+            let remaining: u64 = self.call_typed(token, "drainShard", (shard, )).await?;
+            println!("{} accounts left on {}", remaining, shard);
+            if remaining == 0 {
+                break;
+            }
+        }
+        self.call_typed::<_, ()>(token, "finishDecommission", (shard, )).await?;
+        println!("decommissioned shard {}", shard);
+        if delete {
+            delete_canister(&self.agent, shard).await?;
+            println!("deleted canister {}", shard);
+        }
+        Ok(())
+    }
+
+    async fn export(&self) -> Result<String> {
+        let token = self.token();
+        let mut out = format!("// main contract {}\n", token);
+        for method in [
+            "getMetadata",
+            "getFee",
+            "getFeeBeneficiaries",
+            "getAccruedFees",
+            "getPausedScopes",
+            "getGovernanceConfig",
+            "getShardsInfo",
+            "getFrozenAccounts",
+            "getCyclesConfig",
+            "getCyclesPool",
+            "getShardRegistry",
+            "getSnapshots",
+        ] {
+            let reply = self.call(token, method, (), true).await?;
+            out.push_str(&format!("{} = {}\n", method, reply));
+        }
+        out.push_str(&format!("stats = {}\n", self.call(token, "stats", (), false).await?));
+
+        let bytes = self
+            .client
+            .transport()
+            .query(token, "getShardIds", encode_args(())?)
+            .await?;
+        let (shards, ): (Vec<Principal>, ) = decode_args(&bytes)?;
+        for shard in shards {
+            out.push_str(&format!("\n// shard {}\n", shard));
+            for method in [
+                "shardGetSupply",
+                "getAccruedFees",
+                "getFeeDistribution",
+                "getPausedScopes",
+                "getHeldTransfers",
+                "getPendingNotifications",
+            ] {
+                let reply = self.call(shard, method, (), true).await?;
+                out.push_str(&format!("{} = {}\n", method, reply));
+            }
+        }
+        Ok(out)
+    }
+
+    /// The manifest of a new backup of `canister`, and its encoded state.
+    async fn backup(&self, canister: Principal) -> Result<(BackupManifest, ByteBuf)> {
+        let manifest: BackupManifest = self.call_typed(canister, "beginBackup", ()).await?;
+        let mut bytes = Vec::with_capacity(manifest.size as usize);
+        for index in 0..manifest.chunk_count() {
+            let reply = self
+                .client
+                .transport()
+                .query(canister, "getBackupChunk", encode_args((index, ))?)
+                .await?;
+            let (chunk, ): (types::Result<ByteBuf>, ) = decode_args(&reply)?;
+            let chunk = chunk.map_err(|err| format!("getBackupChunk failed: {:?}", err))?;
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok((manifest, ByteBuf::from(bytes)))
+    }
+
+    async fn restore(
+        &self,
+        canister: Principal,
+        manifest: BackupManifest,
+        bytes: ByteBuf,
+    ) -> Result<()> {
+        let chunk_size = manifest.chunk_size.max(1) as usize;
+        self.call_typed::<_, ()>(canister, "beginRestore", (manifest, )).await?;
+        for (index, chunk) in bytes.chunks(chunk_size).enumerate() {
+            let chunk = ByteBuf::from(chunk.to_vec());
+            self.call_typed::<_, ()>(canister, "putRestoreChunk", (index as u64, chunk))
+                .await?;
+        }
+        self.call_typed::<_, ()>(canister, "finishRestore", ()).await?;
+        println!("restored {}", canister);
+        Ok(())
+    }
+
+    async fn run(&self, command: Command) -> Result<()> {
+        let token = self.token();
+        match command {
+            Command::Deploy {
+                wasm,
+                shard_wasm,
+                mode,
+                underlying,
+                name,
+                symbol,
+                logo,
+                decimals,
+                fee,
+                shards,
+            } => {
+                install_code(&self.agent, token, &wasm, mode).await?;
+                self.call(
+                    token,
+                    "finishInit",
+                    (underlying, logo, name, symbol, decimals, fee),
+                    false,
+                )
+                    .await?;
+                println!("installed main contract {}", token);
+                for shard in shards {
+                    self.add_shard(shard, Some(shard_wasm.clone()), mode).await?;
+                }
+            }
+            Command::AddShard { shard, wasm } => {
+                self.add_shard(shard, wasm, InstallMode::Install).await?
+            }
+            Command::FixSiblings => {
+                self.call(token, "fixSiblings", (), false).await?;
+            }
+            Command::Decommission { shard, delete } => self.decommission(shard, delete).await?,
+            Command::SetRouting { mode } => {
+                self.call_typed::<_, ()>(token, "setRoutingMode", (mode, )).await?
+            }
+            Command::Rebalance { shard } => loop {
+                let remaining: u64 = self.call_typed(token, "rebalanceShard", (shard, )).await?;
+                println!("{} accounts left to move off {}", remaining, shard);
+                if remaining == 0 {
+                    break;
+                }
+            },
+            Command::SetFee { fee } => {
+                self.call(token, "setFee", (fee, ), false).await?;
+            }
+            Command::Stats => println!("{}", self.call(token, "stats", (), false).await?),
+            Command::Shards => println!("{}", self.call(token, "getShardsInfo", (), true).await?),
+            Command::Holders { by_balance } => {
+                let order = if by_balance {
+                    HolderOrder::Balance
+                } else {
+                    HolderOrder::Owner
+                };
+                let mut after = None;
+                loop {
+                    let page = self.client.holders(order, after, MAX_HOLDERS_PAGE).await?;
+                    for holder in page.holders {
+                        println!("{} {}", holder.owner, holder.balance);
+                    }
+                    match page.next {
+                        Some(next) => after = Some(next),
+                        None => break,
+                    }
+                }
+            }
+            Command::Snapshot => {
+                let id: u64 = self.call_typed(token, "takeSnapshot", ()).await?;
+                println!("{}", id);
+            }
+            Command::Balance { account, at: None } => {
+                println!("{}", self.client.balance_of(account).await?)
+            }
+            Command::Balance { account, at: Some(snapshot) } => {
+                println!("{}", self.client.balance_at_snapshot(account, snapshot).await?)
+            }
+            Command::Register { account } => {
+                let account = account.unwrap_or_else(|| self.client.sender());
+                println!("{}", self.client.register(account).await?);
+            }
+            Command::Wrap { amount } => self.client.wrap(amount).await?,
+            Command::Unwrap { amount, to } => {
+                let to = to.unwrap_or_else(|| self.client.sender());
+                self.client.unwrap(amount, to).await?
+            }
+            Command::Transfer { to, amount } => self.client.transfer(to, amount).await?,
+            Command::Export { out } => {
+                let export = self.export().await?;
+                match out {
+                    Some(path) => tokio::fs::write(path, export).await?,
+                    None => print!("{}", export),
+                }
+            }
+            Command::Backup { canister, out } => {
+                let backup = self.backup(canister).await?;
+                tokio::fs::write(out, encode_args(backup)?).await?;
+            }
+            Command::Restore { canister, file } => {
+                let (manifest, bytes) = decode_args(&tokio::fs::read(file).await?)?;
+                self.restore(canister, manifest, bytes).await?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opts = Opts::parse();
+    let cli = Cli::new(&opts).await?;
+    cli.run(opts.command).await
+}
@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, Nat, Principal};
+
+use enoki_wrapped_token_shared::routing::ShardRing;
+use enoki_wrapped_token_shared::types::{
+    self, Account, ByteBuf, HolderOrder, HoldersCursor, HoldersPage, NotificationResponse,
+    ShardRegistry, Subaccount,
+};
+
+use crate::{Result, Transport};
+
+/// Client for one deployment of the token, identified by its main contract.
+pub struct TokenClient<T: Transport> {
+    transport: T,
+    token: Principal,
+    /// Shard of each account, as assigned by the main contract. Assignments only change when a
+    /// shard is decommissioned, see `forget_shards`.
+    shards: RefCell<HashMap<Principal, Principal>>,
+    /// `None` until the shard registry is read, then the ring if the token uses the hashed
+    /// routing mode.
+    ring: RefCell<Option<Option<ShardRing>>>,
+}
+
+impl<T: Transport> TokenClient<T> {
+    pub fn new(transport: T, token: Principal) -> Self {
+        Self {
+            transport,
+            token,
+            shards: RefCell::new(HashMap::new()),
+            ring: RefCell::new(None),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn token(&self) -> Principal {
+        self.token
+    }
+
+    /// The account calls are made from.
+    pub fn sender(&self) -> Principal {
+        self.transport.sender()
+    }
+
+    async fn query<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        canister: Principal,
+        method: &str,
+        args: A,
+    ) -> Result<R> {
+        let bytes = self
+            .transport
+            .query(canister, method, encode_args(args)?)
+            .await?;
+        Ok(decode_args(&bytes)?)
+    }
+
+    async fn update<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        canister: Principal,
+        method: &str,
+        args: A,
+    ) -> Result<R> {
+        let bytes = self
+            .transport
+            .update(canister, method, encode_args(args)?)
+            .await?;
+        Ok(decode_args(&bytes)?)
+    }
+
+    /// Creates an account for `account` if it has none, and returns its shard.
+    pub async fn register(&self, account: Principal) -> Result<Principal> {
+        let (shard, ): (Principal, ) = self.update(self.token, "register", (account, )).await?;
+        self.shards.borrow_mut().insert(account, shard);
+        Ok(shard)
+    }
+
+    /// The shard holding the balance of `account`. In the hashed routing mode, it is computed
+    /// from the shard registry, read once; otherwise `account` must be registered.
+    pub async fn shard_of(&self, account: Principal) -> Result<Principal> {
+        if let Some(shard) = self.known_shard_of(account).await? {
+            return Ok(shard);
+        }
+        let (shard, ): (Principal, ) = self
+            .query(self.token, "getAssignedShardId", (account, ))
+            .await?;
+        self.shards.borrow_mut().insert(account, shard);
+        Ok(shard)
+    }
+
+    /// Like `shard_of`, but registers `account` if it has no shard yet, as paying it requires.
+    pub async fn recipient_shard_of(&self, account: Principal) -> Result<Principal> {
+        match self.known_shard_of(account).await? {
+            Some(shard) => Ok(shard),
+            None => self.register(account).await,
+        }
+    }
+
+    /// The cached shard of `account`, or the one the ring places it on.
+    async fn known_shard_of(&self, account: Principal) -> Result<Option<Principal>> {
+        if let Some(shard) = self.shards.borrow().get(&account) {
+            return Ok(Some(*shard));
+        }
+        if self.ring.borrow().is_none() {
+            let (registry, ): (ShardRegistry, ) =
+                self.query(self.token, "getShardRegistry", ()).await?;
+            let ring = registry.ring.map(|shards| ShardRing::new(&shards));
+            self.ring.replace(Some(ring));
+        }
+        let routed = self
+            .ring
+            .borrow()
+            .as_ref()
+            .and_then(|ring| ring.as_ref()?.shard_for(&account));
+        Ok(routed)
+    }
+
+    /// Drops the cached shard assignments and ring, which are stale once shards are added or
+    /// decommissioned and accounts have moved.
+    pub fn forget_shards(&self) {
+        self.shards.borrow_mut().clear();
+        self.ring.replace(None);
+    }
+
+    pub async fn fee(&self) -> Result<Nat> {
+        let (fee, ) = self.query(self.token, "getFee", ()).await?;
+        Ok(fee)
+    }
+
+    pub async fn total_supply(&self) -> Result<Nat> {
+        let (supply, ) = self.update(self.token, "totalSupply", ()).await?;
+        Ok(supply)
+    }
+
+    /// A page of the holders of every shard, from the one following `after`, the `next` cursor
+    /// of the previous page.
+    pub async fn holders(
+        &self,
+        order: HolderOrder,
+        after: Option<HoldersCursor>,
+        limit: u64,
+    ) -> Result<HoldersPage> {
+        let (page, ) = self
+            .update(self.token, "getHolders", (order, after, limit))
+            .await?;
+        Ok(page)
+    }
+
+    /// Balance of `owner` at a snapshot, summed over its subaccounts and every shard.
+    pub async fn balance_at_snapshot(&self, owner: Principal, snapshot: u64) -> Result<Nat> {
+        let (result, ): (types::Result<Nat>, ) = self
+            .update(self.token, "balanceAtSnapshot", (owner, snapshot))
+            .await?;
+        Ok(result?)
+    }
+
+    pub async fn balance_of(&self, account: Principal) -> Result<Nat> {
+        let shard = self.shard_of(account).await?;
+        let (balance, ) = self.query(shard, "shardBalanceOf", (account, )).await?;
+        Ok(balance)
+    }
+
+    /// Balance of a subaccount, held by the shard of its owner.
+    pub async fn account_balance_of(&self, account: Account) -> Result<Nat> {
+        let shard = self.shard_of(account.owner).await?;
+        let (balance, ) = self
+            .query(shard, "shardAccountBalanceOf", (account, ))
+            .await?;
+        Ok(balance)
+    }
+
+    /// Wraps `amount` underlying tokens, which the sender's shard must be allowed to take.
+    pub async fn wrap(&self, amount: Nat) -> Result<()> {
+        let shard = self.shard_of(self.sender()).await?;
+        self.update(shard, "wrap", (amount, )).await
+    }
+
+    pub async fn unwrap(&self, amount: Nat, to: Principal) -> Result<()> {
+        let shard = self.shard_of(self.sender()).await?;
+        let (result, ): (types::Result<()>, ) = self.update(shard, "unwrap", (amount, to)).await?;
+        Ok(result?)
+    }
+
+    pub async fn transfer(&self, to: Principal, value: Nat) -> Result<()> {
+        let shard = self.shard_of(self.sender()).await?;
+        let to_shard = self.recipient_shard_of(to).await?;
+        let (result, ): (types::Result<()>, ) = self
+            .update(shard, "shardTransfer", (to_shard, to, value))
+            .await?;
+        Ok(result?)
+    }
+
+    /// Transfers from a subaccount of the sender, `None` being the default one.
+    pub async fn account_transfer(
+        &self,
+        from_subaccount: Option<Subaccount>,
+        to: Account,
+        value: Nat,
+    ) -> Result<()> {
+        let shard = self.shard_of(self.sender()).await?;
+        let to_shard = self.recipient_shard_of(to.owner).await?;
+        let (result, ): (types::Result<()>, ) = self
+            .update(
+                shard,
+                "shardAccountTransfer",
+                (from_subaccount, to_shard, to, value),
+            )
+            .await?;
+        Ok(result?)
+    }
+
+    /// Transfers from `from`, which has approved the sender with `addSpender`.
+    pub async fn spend(&self, from: Principal, to: Principal, value: Nat) -> Result<()> {
+        let shard = self.shard_of(from).await?;
+        let to_shard = self.recipient_shard_of(to).await?;
+        let (result, ): (types::Result<()>, ) = self
+            .update(shard, "shardSpend", (from, to_shard, to, value))
+            .await?;
+        Ok(result?)
+    }
+
+    pub async fn transfer_and_call(
+        &self,
+        to: Principal,
+        value: Nat,
+        notify_principal: Principal,
+        notify_method: &str,
+        data: Vec<u8>,
+    ) -> Result<NotificationResponse> {
+        let shard = self.shard_of(self.sender()).await?;
+        let to_shard = self.recipient_shard_of(to).await?;
+        let (result, ): (types::Result<NotificationResponse>, ) = self
+            .update(
+                shard,
+                "shardTransferAndCall",
+                (
+                    to_shard,
+                    to,
+                    value,
+                    notify_principal,
+                    notify_method.to_string(),
+                    ByteBuf::from(data),
+                ),
+            )
+            .await?;
+        Ok(result?)
+    }
+
+    /// Returns the id of the pending notification.
+    pub async fn transfer_and_notify(
+        &self,
+        to: Principal,
+        value: Nat,
+        notify_principal: Principal,
+        notify_method: &str,
+        data: Vec<u8>,
+    ) -> Result<u64> {
+        let shard = self.shard_of(self.sender()).await?;
+        let to_shard = self.recipient_shard_of(to).await?;
+        let (result, ): (types::Result<u64>, ) = self
+            .update(
+                shard,
+                "shardTransferAndNotify",
+                (
+                    to_shard,
+                    to,
+                    value,
+                    notify_principal,
+                    notify_method.to_string(),
+                    ByteBuf::from(data),
+                ),
+            )
+            .await?;
+        Ok(result?)
+    }
+
+    /// Pays every `(recipient, value)` in a single call. Returns the result of each transfer.
+    pub async fn batch_transfer(
+        &self,
+        transfers: Vec<(Principal, Nat)>,
+    ) -> Result<Vec<types::Result<()>>> {
+        let shard = self.shard_of(self.sender()).await?;
+        let mut routed = Vec::with_capacity(transfers.len());
+        for (to, value) in transfers {
+            routed.push((self.recipient_shard_of(to).await?, to, value));
+        }
+        let (result, ): (types::Result<Vec<types::Result<()>>>, ) = self
+            .update(shard, "shardBatchTransfer", (routed, ))
+            .await?;
+        Ok(result?)
+    }
+}
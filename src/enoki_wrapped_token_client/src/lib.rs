@@ -0,0 +1,53 @@
+//! Typed client for the sharded token. Finds the shard of each account through the main
+//! contract, caches it, and sends shard methods to the right canister.
+//!
+//! Calls go through a `Transport`: `CanisterTransport` from other canisters, or `AgentTransport`
+//! (feature `agent`) from off-chain programs.
+
+use std::fmt;
+
+use enoki_wrapped_token_shared::types::TxError;
+
+pub use client::TokenClient;
+#[cfg(feature = "agent")]
+pub use transport::agent::AgentTransport;
+pub use transport::{CanisterTransport, Transport, TransportFuture};
+
+mod client;
+mod transport;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The call was rejected, or did not reach the canister.
+    Transport(String),
+    /// The arguments or the response could not be (de)serialized.
+    Candid(String),
+    /// The token returned an error.
+    Token(TxError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(message) => write!(f, "call failed: {}", message),
+            ClientError::Candid(message) => write!(f, "candid error: {}", message),
+            ClientError::Token(error) => write!(f, "token error: {:?}", error),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<TxError> for ClientError {
+    fn from(error: TxError) -> Self {
+        ClientError::Token(error)
+    }
+}
+
+impl From<candid::Error> for ClientError {
+    fn from(error: candid::Error) -> Self {
+        ClientError::Candid(error.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
@@ -0,0 +1,104 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use candid::Principal;
+
+use enoki_wrapped_token_shared::env;
+
+use crate::{ClientError, Result};
+
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + 'a>>;
+
+/// Sends candid-encoded calls on behalf of `sender`.
+pub trait Transport {
+    fn sender(&self) -> Principal;
+    fn query(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_>;
+    fn update(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_>;
+}
+
+/// Calls from the current canister. Queries are sent as inter-canister calls.
+pub struct CanisterTransport;
+
+impl Transport for CanisterTransport {
+    fn sender(&self) -> Principal {
+        env::id()
+    }
+
+    fn query(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_> {
+        self.update(canister, method, args)
+    }
+
+    fn update(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_> {
+        let call = env::call_raw(canister, method, args);
+        Box::pin(async move {
+            call.await.map_err(|(code, message)| {
+                ClientError::Transport(format!("rejected with code {:?}: {}", code, message))
+            })
+        })
+    }
+}
+
+#[cfg(feature = "agent")]
+pub mod agent {
+    use std::time::Duration;
+
+    use candid::Principal;
+    use ic_agent::Agent;
+
+    use super::{Transport, TransportFuture};
+    use crate::ClientError;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+    const UPDATE_TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Calls through the IC HTTP interface, signed by the agent's identity.
+    pub struct AgentTransport {
+        agent: Agent,
+    }
+
+    impl AgentTransport {
+        pub fn new(agent: Agent) -> Self {
+            Self { agent }
+        }
+    }
+
+    fn transport_error(error: ic_agent::AgentError) -> ClientError {
+        ClientError::Transport(error.to_string())
+    }
+
+    impl Transport for AgentTransport {
+        fn sender(&self) -> Principal {
+            self.agent
+                .get_principal()
+                .expect("agent identity has no principal")
+        }
+
+        fn query(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_> {
+            let method = method.to_string();
+            Box::pin(async move {
+                self.agent
+                    .query(&canister, method)
+                    .with_arg(args)
+                    .call()
+                    .await
+                    .map_err(transport_error)
+            })
+        }
+
+        fn update(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_> {
+            let method = method.to_string();
+            Box::pin(async move {
+                let waiter = garcon::Delay::builder()
+                    .throttle(POLL_INTERVAL)
+                    .timeout(UPDATE_TIMEOUT)
+                    .build();
+                self.agent
+                    .update(&canister, method)
+                    .with_arg(args)
+                    .call_and_wait(waiter)
+                    .await
+                    .map_err(transport_error)
+            })
+        }
+    }
+}
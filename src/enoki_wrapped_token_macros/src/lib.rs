@@ -0,0 +1,50 @@
+//! Drop-in replacements for the `ic_cdk_macros` entry point attributes.
+//!
+//! The canister entry points are only exported when compiling to wasm. Native builds keep the
+//! annotated functions as they are, so that both canisters can be linked into the same test
+//! binary without their exported symbols colliding.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span};
+use quote::quote;
+
+fn export(method: &str, attr: TokenStream, item: TokenStream) -> TokenStream {
+    let method = Ident::new(method, Span::call_site());
+    let attr = proc_macro2::TokenStream::from(attr);
+    let item = proc_macro2::TokenStream::from(item);
+    TokenStream::from(quote! {
+        #[cfg_attr(target_arch = "wasm32", ::ic_cdk_macros::#method(#attr))]
+        #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+        #item
+    })
+}
+
+#[proc_macro_attribute]
+pub fn init(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export("init", attr, item)
+}
+
+#[proc_macro_attribute]
+pub fn pre_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export("pre_upgrade", attr, item)
+}
+
+#[proc_macro_attribute]
+pub fn post_upgrade(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export("post_upgrade", attr, item)
+}
+
+#[proc_macro_attribute]
+pub fn update(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export("update", attr, item)
+}
+
+#[proc_macro_attribute]
+pub fn query(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export("query", attr, item)
+}
+
+#[proc_macro_attribute]
+pub fn heartbeat(attr: TokenStream, item: TokenStream) -> TokenStream {
+    export("heartbeat", attr, item)
+}
@@ -1 +1 @@
-cargo run --bin "enoki_wrapped_token_shard" > "$(dirname "$0")"/enoki_wrapped_token_shard.did
\ No newline at end of file
+cargo run --bin "enoki_wrapped_token_shard_did" > "$(dirname "$0")"/enoki_wrapped_token_shard.did
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::backup::{Backup, BackupManifest, Restore};
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::balances::{get_account_counts, get_balances_total};
+use crate::escrow;
+use crate::fees::get_accrued_fees;
+use crate::management::{
+    assert_governance_disabled, assert_is_owner, get_manager_contract, has_role,
+};
+use crate::upgrade::{export_state, import_state, UpgradePayload};
+
+thread_local! {
+    static BACKUP: RefCell<Option<Backup>> = const { RefCell::new(None) };
+    static RESTORE: RefCell<Option<Restore>> = const { RefCell::new(None) };
+}
+
+/// The shard's owner, who restores it once reinstalled, and admins.
+fn assert_is_owner_or_admin() -> Result<()> {
+    if has_role(&env::caller(), Role::Admin) {
+        Ok(())
+    } else {
+        assert_is_owner()
+    }
+}
+
+/// Checked again when the restore finishes, in case `finishInit` was called since it began.
+fn assert_fresh() -> Result<()> {
+    if get_manager_contract() != Principal::anonymous() {
+        return Err(TxError::Other(
+            "Backups are only restored onto a fresh shard".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// What a restored shard must hold for its backup to be accepted.
+fn get_totals() -> Vec<(String, Nat)> {
+    let (accounts, subaccounts) = get_account_counts();
+    vec![
+        ("balances".to_string(), get_balances_total()),
+        ("escrow".to_string(), escrow::get_held_total()),
+        ("accrued_fees".to_string(), get_accrued_fees()),
+        ("accounts".to_string(), Nat::from(accounts)),
+        ("subaccounts".to_string(), Nat::from(subaccounts)),
+    ]
+}
+
+/// Encodes the whole state of the shard, to be read with `getBackupChunk`, and returns its
+/// manifest. Replaces the previous backup.
+#[update(name = "beginBackup")]
+#[candid_method(update, rename = "beginBackup")]
+pub fn begin_backup() -> Result<BackupManifest> {
+    assert_is_owner_or_admin()?;
+    let payload = export_state();
+    import_state(payload.clone());
+    let bytes = candid::encode_one(payload).map_err(|err| TxError::Other(err.to_string()))?;
+    let backup = Backup::new(bytes, env::time(), get_totals());
+    let manifest = backup.manifest.clone();
+    BACKUP.with(|b| b.replace(Some(backup)));
+    log::succeeded("beginBackup", log::digest((manifest.size, )));
+    Ok(manifest)
+}
+
+#[query(name = "getBackupChunk")]
+#[candid_method(query, rename = "getBackupChunk")]
+pub fn get_backup_chunk(index: u64) -> Result<ByteBuf> {
+    assert_is_owner_or_admin()?;
+    BACKUP.with(|b| match b.borrow().as_ref() {
+        Some(backup) => backup.chunk(index),
+        None => Err(TxError::Other("No backup was taken".to_string())),
+    })
+}
+
+/// Starts restoring a backup onto a shard that was just installed, before `finishInit`.
+#[update(name = "beginRestore")]
+#[candid_method(update, rename = "beginRestore")]
+pub fn begin_restore(manifest: BackupManifest) -> Result<()> {
+    assert_governance_disabled()?;
+    assert_is_owner_or_admin()?;
+    assert_fresh()?;
+    let restore = Restore::new(manifest)?;
+    RESTORE.with(|r| r.replace(Some(restore)));
+    Ok(())
+}
+
+#[update(name = "putRestoreChunk")]
+#[candid_method(update, rename = "putRestoreChunk")]
+pub fn put_restore_chunk(index: u64, chunk: ByteBuf) -> Result<()> {
+    assert_is_owner_or_admin()?;
+    RESTORE.with(|r| match r.borrow_mut().as_mut() {
+        Some(restore) => restore.put(index, chunk.into_vec()),
+        None => Err(TxError::Other("No restore was started".to_string())),
+    })
+}
+
+/// Replaces the state of the shard with the uploaded backup, once it matches its checksum, and
+/// keeps it only if its totals match those of the manifest. A backup of a shard whose main
+/// contract was governed is only restored by that contract, through a `FinishShardRestore`
+/// proposal.
+#[update(name = "finishRestore")]
+#[candid_method(update, rename = "finishRestore")]
+pub fn finish_restore() -> Result<()> {
+    let result = finish_restore_internal();
+    log::finished("finishRestore", log::digest(()), &result);
+    result
+}
+
+fn finish_restore_internal() -> Result<()> {
+    assert_fresh()?;
+    let (totals, bytes) = RESTORE
+        .with(|r| {
+            let restore = r.borrow();
+            let restore = restore.as_ref()?;
+            Some(restore.finish().map(|bytes| (restore.manifest.totals.clone(), bytes)))
+        })
+        .ok_or_else(|| TxError::Other("No restore was started".to_string()))??;
+    let payload: UpgradePayload =
+        candid::decode_one(&bytes).map_err(|err| TxError::Other(err.to_string()))?;
+    match payload.governed_by() {
+        Some(manager) if env::caller() != manager => return Err(TxError::Unauthorized),
+        Some(_) => {}
+        None => {
+            assert_governance_disabled()?;
+            assert_is_owner_or_admin()?;
+        }
+    }
+    RESTORE.with(|r| r.take());
+
+    let previous = export_state();
+    import_state(payload);
+    let restored = get_totals();
+    if restored != totals {
+        import_state(previous);
+        return Err(TxError::Other(format!(
+            "Restored totals {:?} do not match the backup's {:?}",
+            restored, totals
+        )));
+    }
+    Ok(())
+}
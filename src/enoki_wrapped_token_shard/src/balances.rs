@@ -1,18 +1,34 @@
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ops::{AddAssign, SubAssign};
 
 use candid::{candid_method, Principal, types::number::Nat};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 
+use enoki_wrapped_token_shared::{env, log};
 use enoki_wrapped_token_shared::types::*;
 
+use crate::decommission::{assert_not_draining, MigratedAccount};
+use crate::escrow;
 use crate::fees::{accept_fee, get_accrued_fees};
-use crate::management::{assert_is_manager_contract, assert_is_sibling, get_fee};
+use crate::freeze::{self, assert_can_receive, assert_not_frozen};
+use crate::management::{
+    assert_is_manager_contract, assert_is_sibling_or_pull, get_fee, route,
+};
+use crate::metrics::{self, Counter};
+use crate::pause::assert_not_paused;
+use crate::snapshots;
 use crate::stable::StableShardBalances;
+use crate::subscriptions;
 
-pub type ShardBalances = HashMap<Principal, Nat>;
+pub type ShardBalances = HashMap<Account, Nat>;
+/// Spenders may only spend from the default subaccount of the accounts that approved them.
 pub type ShardSpenders = HashMap<Principal, HashSet<Principal>>;
+/// Transfers of a batch going to the same sibling shard: their index in the batch, the recipient
+/// and the value left after the fee.
+type ShardBatch = Vec<(usize, Account, Nat)>;
+
+const MAX_BATCH_SIZE: usize = 1_000;
 
 #[derive(Default)]
 pub struct ShardBalancesState {
@@ -38,12 +54,16 @@ pub fn import_stable_storage(balances: StableShardBalances, spenders: ShardSpend
     });
 }
 
+/// Checks that `user` has an account on this shard. Its subaccounts exist once it does. In the
+/// hashed routing mode, every user the ring places on this shard has one.
 pub fn assert_is_customer(user: &Principal) -> Result<()> {
-    if STATE.with(|b| b.borrow().balances.contains_key(user)) {
+    if STATE.with(|b| b.borrow().balances.contains_key(&Account::from(*user)))
+        || route(user) == Some(env::id())
+    {
         Ok(())
     } else {
         Err(TxError::AccountDoesNotExist {
-            shard: ic_cdk::id().to_string(),
+            shard: env::id().to_string(),
             user: user.to_string(),
         })
     }
@@ -53,8 +73,10 @@ pub fn assert_is_customer(user: &Principal) -> Result<()> {
 #[candid_method(update, rename = "createAccount")]
 pub fn create_account(account: Principal) {
     assert_is_manager_contract().unwrap();
+    assert_not_draining().unwrap();
     STATE.with(|b| {
         let mut balances = b.borrow_mut();
+        let account = Account::from(account);
         if balances.balances.contains_key(&account) {
             panic!("{:?}", TxError::AccountAlreadyExists);
         }
@@ -62,38 +84,42 @@ pub fn create_account(account: Principal) {
     })
 }
 
-pub fn increase_balance(account: Principal, amount: Nat) {
+pub fn increase_balance(account: Account, amount: Nat, kind: CreditKind) {
     STATE.with(|b| {
         let mut balances = b.borrow_mut();
         let balance = balances.balances.entry(account).or_default();
-        balance.add_assign(amount);
+        balance.add_assign(amount.clone());
     });
+    subscriptions::emit(account, kind, amount);
 }
 
-pub fn decrease_balance(account: Principal, amount: Nat) -> Result<()> {
+/// Debits `account`. Subaccounts are dropped once empty, default accounts are kept.
+pub fn decrease_balance(account: Account, amount: Nat) -> Result<()> {
     STATE.with(|b| {
-        let mut balances = b.borrow_mut();
-        let balance = balances.balances.entry(account).or_default();
-        if *balance >= amount {
-            balance.sub_assign(amount);
-            Ok(())
-        } else {
-            Err(TxError::InsufficientBalance)
+        let balances = &mut b.borrow_mut().balances;
+        match balances.get_mut(&account) {
+            Some(balance) if *balance >= amount => balance.sub_assign(amount),
+            _ => return Err(TxError::InsufficientBalance),
         }
+        if account.subaccount.is_some() && balances[&account] == 0u64 {
+            balances.remove(&account);
+        }
+        Ok(())
     })
 }
 
 fn pre_transfer_check(
-    from: Principal,
+    from: Account,
     shard_id: Principal,
-    to: Principal,
+    to: Account,
     value: &Nat,
     fee: &Nat,
 ) -> Result<()> {
-    let check_to = shard_id == ic_cdk::id();
-    assert_is_customer(&from)?;
-    if check_to {
-        assert_is_customer(&to)?;
+    assert_is_customer(&from.owner)?;
+    assert_not_frozen(&from.owner)?;
+    if shard_id == env::id() {
+        assert_is_customer(&to.owner)?;
+        assert_can_receive(&to.owner)?;
     }
     if value <= fee {
         return Err(TxError::TransferValueTooSmall);
@@ -102,36 +128,32 @@ fn pre_transfer_check(
     STATE.with(|b| {
         if b.borrow().balances.get(&from).unwrap_or(&Nat::from(0)) < value {
             Err(TxError::InsufficientBalance)
-        } else if check_to && !b.borrow().balances.contains_key(&to) {
-            Err(TxError::AccountDoesNotExist {
-                shard: ic_cdk::id().to_string(),
-                user: to.to_string(),
-            })
         } else {
             Ok(())
         }
     })
 }
 
-fn charge_fee(user: Principal, fee: Nat) -> Result<()> {
-    STATE.with(|b| {
-        let mut balances = b.borrow_mut();
-        let balance = balances.balances.entry(user).or_default();
-        if *balance < fee {
-            return Err(TxError::InsufficientBalance);
-        }
-        balance.sub_assign(fee.clone());
-        Ok(())
-    })?;
+fn charge_fee(account: Account, fee: Nat) -> Result<()> {
+    decrease_balance(account, fee.clone())?;
     accept_fee(fee);
     Ok(())
 }
 
-async fn transfer_to_sibling_shard(shard_id: Principal, to: Principal, amount: Nat) -> Result<()> {
-    assert_is_sibling(&shard_id)?;
-    ic_cdk::call(shard_id, "shardReceiveTransfer", (to, amount))
+async fn transfer_to_sibling_shard(
+    shard_id: Principal,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    epoch: u64,
+) -> Result<()> {
+    assert_is_sibling_or_pull(&shard_id).await?;
+    env::call(shard_id, "shardReceiveTransfer", (from, to, amount, epoch))
         .await
-        .map_err(|err| err.into())
+        .map_err(|err| {
+            metrics::record(Counter::FailedSiblingCall);
+            err.into()
+        })
 }
 
 async fn transfer_and_call_to_sibling_shard(
@@ -139,96 +161,318 @@ async fn transfer_and_call_to_sibling_shard(
     notification: ShardedTransferNotification,
     notify_principal: Principal,
     notify_method: String,
-) -> Result<String> {
-    assert_is_sibling(&shard_id)?;
-    let result: Result<(String, )> = ic_cdk::call(
+    epoch: u64,
+) -> Result<NotificationResponse> {
+    assert_is_sibling_or_pull(&shard_id).await?;
+    let result: Result<(Result<NotificationResponse>, )> = env::call(
         shard_id,
         "shardReceiveTransferAndCall",
-        (notification, notify_principal, notify_method),
+        (notification, notify_principal, notify_method, epoch),
     )
         .await
-        .map_err(|err| err.into());
-    result.map(|res| res.0)
+        .map_err(|err| {
+            metrics::record(Counter::FailedSiblingCall);
+            err.into()
+        });
+    result.and_then(|res| res.0)
 }
 
+/// Credits a transfer from a sibling shard, which debited it when `epoch` was its latest
+/// snapshot.
 #[update(name = "shardReceiveTransfer")]
 #[candid_method(update, rename = "shardReceiveTransfer")]
-async fn receive_transfer(to: Principal, value: Nat) {
-    assert_is_sibling(&ic_cdk::caller()).unwrap();
-    assert_is_customer(&to).unwrap();
-    increase_balance(to, value);
+pub async fn receive_transfer(from: Account, to: Account, value: Nat, epoch: u64) {
+    let from_shard = env::caller();
+    assert_is_sibling_or_pull(&from_shard).await.unwrap();
+    assert_is_customer(&to.owner).unwrap();
+    assert_can_receive(&to.owner).unwrap();
+    snapshots::credited_since(to.owner, &value, epoch);
+    let from = from.owner;
+    increase_balance(to.normalized(), value, CreditKind::Transfer { from, from_shard });
+}
+
+/// Credits the transfers of a batch sent by a sibling shard, returning the result of each.
+#[update(name = "shardReceiveTransferBatch")]
+#[candid_method(update, rename = "shardReceiveTransferBatch")]
+pub async fn receive_transfer_batch(
+    from: Account,
+    transfers: Vec<(Account, Nat)>,
+    epoch: u64,
+) -> Vec<Result<()>> {
+    let from_shard = env::caller();
+    assert_is_sibling_or_pull(&from_shard).await.unwrap();
+    let from = from.owner;
+    transfers
+        .into_iter()
+        .map(|(to, value)| {
+            assert_is_customer(&to.owner)?;
+            assert_can_receive(&to.owner)?;
+            snapshots::credited_since(to.owner, &value, epoch);
+            increase_balance(to.normalized(), value, CreditKind::Transfer { from, from_shard });
+            Ok(())
+        })
+        .collect()
 }
 
 #[update(name = "shardReceiveTransferAndCall")]
 #[candid_method(update, rename = "shardReceiveTransferAndCall")]
-async fn receive_transfer_and_call(
+pub async fn receive_transfer_and_call(
     notification: ShardedTransferNotification,
     notify_principal: Principal,
     notify_method: String,
-) -> String {
-    assert_is_sibling(&ic_cdk::caller()).unwrap();
-    let to = notification.to;
+    epoch: u64,
+) -> Result<NotificationResponse> {
+    let digest = log::digest((notification.clone(), notify_principal, notify_method.clone()));
+    let result =
+        receive_transfer_and_call_internal(notification, notify_principal, notify_method, epoch)
+            .await;
+    log::finished("shardReceiveTransferAndCall", digest, &result);
+    result
+}
+
+async fn receive_transfer_and_call_internal(
+    notification: ShardedTransferNotification,
+    notify_principal: Principal,
+    notify_method: String,
+    epoch: u64,
+) -> Result<NotificationResponse> {
+    assert_is_sibling_or_pull(&env::caller()).await?;
+    let to = notification.to_account();
     let value = notification.value.clone();
-    assert_is_customer(&to).unwrap();
-
-    // notify recipient
-    let result: std::result::Result<(String, ), _> =
-        ic_cdk::call(notify_principal, &notify_method, (notification, )).await;
-    match result {
-        Ok(response) => {
-            // send funds to destination
-            increase_balance(to, value);
-            response.0
-        }
-        Err(error) => panic!("{:?}", error),
-    }
+    let kind = CreditKind::Transfer {
+        from: notification.from,
+        from_shard: notification.from_shard,
+    };
+    assert_is_customer(&to.owner)?;
+    assert_can_receive(&to.owner)?;
+
+    // notify recipient, the sending shard refunds what is not accepted
+    let (response, ) = notify(notify_principal, &notify_method, notification).await?;
+    // send funds to destination
+    let accepted = response.accepted(&value);
+    snapshots::credited_since(to.owner, &accepted, epoch);
+    increase_balance(to, accepted, kind);
+    Ok(response)
 }
 
-async fn transfer_internal(
-    from: Principal,
+async fn notify(
+    notify_principal: Principal,
+    notify_method: &str,
+    notification: ShardedTransferNotification,
+) -> Result<(NotificationResponse, )> {
+    env::call(notify_principal, notify_method, (notification, ))
+        .await
+        .map_err(|err| err.into())
+}
+
+pub async fn transfer_internal(
+    from: Account,
     to_shard: Principal,
-    to: Principal,
+    to: Account,
     value: Nat,
 ) -> Result<()> {
+    assert_not_paused(PauseScope::Transfers)?;
     let fee = get_fee();
     pre_transfer_check(from, to_shard, to, &value, &fee)?;
     charge_fee(from, fee.clone())?;
     let value = value - fee;
 
     decrease_balance(from, value.clone())?;
-
-    if to_shard == ic_cdk::id() {
-        increase_balance(to, value.clone());
-    } else {
-        if let Err(error) = transfer_to_sibling_shard(to_shard, to, value.clone()).await {
-            increase_balance(from, value);
-            return Err(error.into());
-        }
+    let epoch = snapshots::epoch();
+
+    if to_shard == env::id() {
+        let (from, from_shard) = (from.owner, env::id());
+        increase_balance(to, value, CreditKind::Transfer { from, from_shard });
+    } else if let Err(error) =
+        transfer_to_sibling_shard(to_shard, from, to, value.clone(), epoch).await
+    {
+        // returned instead of trapping, which would roll back the refund
+        snapshots::credited_since(from.owner, &value, epoch);
+        increase_balance(from, value, CreditKind::Refund);
+        return Err(error);
     }
 
+    metrics::record(Counter::Transfer);
     Ok(())
 }
 
 #[update(name = "shardTransfer")]
 #[candid_method(update, rename = "shardTransfer")]
-async fn transfer(to_shard: Principal, to: Principal, value: Nat) {
-    transfer_internal(ic_cdk::caller(), to_shard, to, value)
-        .await
-        .unwrap();
+pub async fn transfer(to_shard: Principal, to: Principal, value: Nat) -> Result<()> {
+    let digest = log::digest((to_shard, to, value.clone()));
+    let result = transfer_internal(env::caller().into(), to_shard, to.into(), value).await;
+    log::finished("shardTransfer", digest, &result);
+    result
+}
+
+/// Transfers from a subaccount of the caller to any account. Subaccounts of `to` live on the
+/// shard assigned to its owner.
+#[update(name = "shardAccountTransfer")]
+#[candid_method(update, rename = "shardAccountTransfer")]
+pub async fn account_transfer(
+    from_subaccount: Option<Subaccount>,
+    to_shard: Principal,
+    to: Account,
+    value: Nat,
+) -> Result<()> {
+    let digest = log::digest((from_subaccount, to_shard, to, value.clone()));
+    let from = Account::new(env::caller(), from_subaccount);
+    let result = transfer_internal(from, to_shard, to.normalized(), value).await;
+    log::finished("shardAccountTransfer", digest, &result);
+    result
 }
 
 #[update(name = "transferFromManager")]
 #[candid_method(update, rename = "transferFromManager")]
-async fn transfer_from_manager(from: Principal, to_shard: Principal, to: Principal, value: Nat) {
-    assert_is_manager_contract().unwrap();
-    transfer_internal(from, to_shard, to, value).await.unwrap();
+pub async fn transfer_from_manager(
+    from: Principal,
+    to_shard: Principal,
+    to: Principal,
+    value: Nat,
+) -> Result<()> {
+    let digest = log::digest((from, to_shard, to, value.clone()));
+    let result = async {
+        assert_is_manager_contract()?;
+        transfer_internal(from.into(), to_shard, to.into(), value).await
+    }
+        .await;
+    log::finished("transferFromManager", digest, &result);
+    result
+}
+
+/// Debits one transfer of a batch. Transfers to local accounts are credited right away, the
+/// value of the others is returned to be sent with the rest of the batch.
+fn debit_batch_item(
+    from: Account,
+    to_shard: Principal,
+    to: Account,
+    value: Nat,
+    fee: &Nat,
+) -> Result<Option<Nat>> {
+    pre_transfer_check(from, to_shard, to, &value, fee)?;
+    charge_fee(from, fee.clone())?;
+    let value = value - fee.clone();
+    decrease_balance(from, value.clone())?;
+    if to_shard == env::id() {
+        let (from, from_shard) = (from.owner, env::id());
+        increase_balance(to, value, CreditKind::Transfer { from, from_shard });
+        metrics::record(Counter::Transfer);
+        Ok(None)
+    } else {
+        Ok(Some(value))
+    }
+}
+
+/// Sends the transfers of a batch to a sibling shard in a single call, and refunds those that
+/// were not credited.
+async fn send_batch(
+    shard_id: Principal,
+    from: Account,
+    batch: ShardBatch,
+    epoch: u64,
+) -> Vec<(usize, Result<()>)> {
+    let transfers: Vec<(Account, Nat)> = batch
+        .iter()
+        .map(|(_, to, value)| (*to, value.clone()))
+        .collect();
+    let result: Result<(Vec<Result<()>>, )> =
+        env::call(shard_id, "shardReceiveTransferBatch", (from, transfers, epoch))
+            .await
+            .map_err(|err| err.into());
+    let mut results = match result {
+        Ok((results, )) => results,
+        Err(error) => {
+            metrics::record(Counter::FailedSiblingCall);
+            vec![Err(error); batch.len()]
+        }
+    };
+    // transfers the reply has no result for are failed, and refunded, rather than dropped
+    let missing = TxError::Other("The recipient shard returned no result".to_string());
+    results.resize(batch.len(), Err(missing));
+    batch
+        .into_iter()
+        .zip(results)
+        .map(|((index, _, value), result)| {
+            match result {
+                Ok(()) => metrics::record(Counter::Transfer),
+                Err(_) => {
+                    snapshots::credited_since(from.owner, &value, epoch);
+                    increase_balance(from, value, CreditKind::Refund)
+                }
+            }
+            (index, result)
+        })
+        .collect()
+}
+
+/// Transfers from the caller to many recipients, given as `(to_shard, to, value)`. The fee is
+/// charged for every transfer, and transfers to the same shard are sent in a single call. Returns
+/// the result of each transfer: failed transfers are refunded, except for their fee once charged.
+#[update(name = "shardBatchTransfer")]
+#[candid_method(update, rename = "shardBatchTransfer")]
+pub async fn batch_transfer(
+    transfers: Vec<(Principal, Principal, Nat)>,
+) -> Result<Vec<Result<()>>> {
+    let digest = log::digest((transfers.clone(), ));
+    let result = batch_transfer_internal(transfers).await;
+    log::finished("shardBatchTransfer", digest, &result);
+    result
+}
+
+async fn batch_transfer_internal(
+    transfers: Vec<(Principal, Principal, Nat)>,
+) -> Result<Vec<Result<()>>> {
+    let from = Account::from(env::caller());
+    assert_not_paused(PauseScope::Transfers)?;
+    if transfers.len() > MAX_BATCH_SIZE {
+        return Err(TxError::BatchTooLarge);
+    }
+    assert_is_customer(&from.owner)?;
+    assert_not_frozen(&from.owner)?;
+    // siblings added since the last registry push are only known once it is pulled
+    let mut unknown_shards: BTreeMap<Principal, TxError> = BTreeMap::new();
+    let to_shards: BTreeSet<Principal> =
+        transfers.iter().map(|(to_shard, _, _)| *to_shard).collect();
+    for to_shard in to_shards.into_iter().filter(|to_shard| *to_shard != env::id()) {
+        if let Err(error) = assert_is_sibling_or_pull(&to_shard).await {
+            unknown_shards.insert(to_shard, error);
+        }
+    }
+    let fee = get_fee();
+
+    let mut results: Vec<Result<()>> = vec![Ok(()); transfers.len()];
+    let mut batches: BTreeMap<Principal, ShardBatch> = BTreeMap::new();
+    for (index, (to_shard, to, value)) in transfers.into_iter().enumerate() {
+        let to = Account::from(to);
+        if let Some(error) = unknown_shards.get(&to_shard) {
+            results[index] = Err(error.clone());
+            continue;
+        }
+        match debit_batch_item(from, to_shard, to, value, &fee) {
+            Ok(Some(value)) => batches.entry(to_shard).or_default().push((index, to, value)),
+            Ok(None) => {}
+            Err(error) => results[index] = Err(error),
+        }
+    }
+
+    let epoch = snapshots::epoch();
+    let sent = futures::future::join_all(
+        batches
+            .into_iter()
+            .map(|(shard_id, batch)| send_batch(shard_id, from, batch, epoch)),
+    )
+        .await;
+    for (index, result) in sent.into_iter().flatten() {
+        results[index] = result;
+    }
+    Ok(results)
 }
 
 fn assert_is_spender(of_account: Principal) -> Result<()> {
     if STATE.with(|s| {
         let s = s.borrow();
         if let Some(spenders) = s.spenders.get(&of_account) {
-            if spenders.contains(&ic_cdk::caller()) {
+            if spenders.contains(&env::caller()) {
                 return true;
             }
         }
@@ -243,11 +487,11 @@ fn assert_is_spender(of_account: Principal) -> Result<()> {
 // This account is authorized to drain all your tokens
 #[update(name = "addSpender")]
 #[candid_method(update, rename = "addSpender")]
-async fn add_spender(account: Principal) {
+pub async fn add_spender(account: Principal) {
     STATE.with(|s| {
         s.borrow_mut()
             .spenders
-            .entry(ic_cdk::caller())
+            .entry(env::caller())
             .or_default()
             .insert(account)
     });
@@ -255,11 +499,11 @@ async fn add_spender(account: Principal) {
 
 #[update(name = "removeSpender")]
 #[candid_method(update, rename = "removeSpender")]
-async fn remove_spender(account: Principal) {
+pub async fn remove_spender(account: Principal) {
     STATE.with(|s| {
         s.borrow_mut()
             .spenders
-            .entry(ic_cdk::caller())
+            .entry(env::caller())
             .or_default()
             .remove(&account)
     });
@@ -267,122 +511,343 @@ async fn remove_spender(account: Principal) {
 
 #[update(name = "shardSpend")]
 #[candid_method(update, rename = "shardSpend")]
-async fn spend(from: Principal, to_shard: Principal, to: Principal, value: Nat) {
-    assert_is_spender(from).unwrap();
-    transfer_internal(from, to_shard, to, value).await.unwrap();
+pub async fn spend(from: Principal, to_shard: Principal, to: Principal, value: Nat) -> Result<()> {
+    let digest = log::digest((from, to_shard, to, value.clone()));
+    let result = async {
+        assert_is_spender(from)?;
+        assert_not_frozen(&env::caller())?;
+        transfer_internal(from.into(), to_shard, to.into(), value).await
+    }
+        .await;
+    log::finished("shardSpend", digest, &result);
+    result
 }
 
 async fn transfer_and_call_internal(
-    from: Principal,
+    from: Account,
     shard_id: Principal,
-    to: Principal,
+    to: Account,
     value: Nat,
     notify_principal: Principal,
     notify_method: String,
-    data: String,
-) -> Result<String> {
+    data: ByteBuf,
+) -> Result<NotificationResponse> {
+    assert_not_paused(PauseScope::Transfers)?;
+    assert_not_paused(PauseScope::Callbacks)?;
     let fee = get_fee();
     pre_transfer_check(from, shard_id, to, &value, &fee)?;
     charge_fee(from, fee.clone())?;
     let value = value - fee.clone();
 
-    decrease_balance(from, value.clone())?;
+    let held = escrow::hold(from, shard_id, to, value.clone())?;
+    let epoch = snapshots::epoch();
 
     let notification = ShardedTransferNotification {
-        from,
-        from_shard: ic_cdk::id(),
-        to,
+        from: from.owner,
+        from_shard: env::id(),
+        to: to.owner,
+        from_subaccount: from.subaccount,
+        to_subaccount: to.subaccount,
         fee_charged: fee,
         value: value.clone(),
         data,
     };
-    let result = if shard_id == ic_cdk::id() {
-        let result: Result<(String, )> =
-            ic_cdk::call(notify_principal, &notify_method, (notification, ))
-                .await
-                .map_err(|err| err.into());
-        result.map(|res| {
-            // send funds to destination
-            increase_balance(to, value.clone());
-            res.0
-        })
+    let result = if shard_id == env::id() {
+        notify(notify_principal, &notify_method, notification)
+            .await
+            .map(|res| res.0)
     } else {
-        transfer_and_call_to_sibling_shard(shard_id, notification, notify_principal, notify_method)
+        transfer_and_call_to_sibling_shard(
+            shard_id,
+            notification,
+            notify_principal,
+            notify_method,
+            epoch,
+        )
             .await
     };
 
-    result
+    // returned instead of trapping, which would roll back the settlement
+    let settled = match &result {
+        Ok(response) => escrow::settle(held, response.accepted(&value))
+            .map(|()| metrics::record(Counter::Transfer)),
+        Err(_) => escrow::refund(held),
+    };
+    settled.and(result)
 }
 
 #[update(name = "shardTransferAndCall")]
 #[candid_method(update, rename = "shardTransferAndCall")]
-async fn transfer_and_call(
+pub async fn transfer_and_call(
     shard_id: Principal,
     to: Principal,
     value: Nat,
     notify_principal: Principal,
     notify_method: String,
-    data: String,
-) -> String {
-    let from = ic_cdk::caller();
-    transfer_and_call_internal(
-        from,
+    data: ByteBuf,
+) -> Result<NotificationResponse> {
+    let digest = log::digest((
         shard_id,
         to,
+        value.clone(),
+        notify_principal,
+        notify_method.clone(),
+        data.clone(),
+    ));
+    let result = transfer_and_call_internal(
+        env::caller().into(),
+        shard_id,
+        to.into(),
         value,
         notify_principal,
         notify_method,
         data,
     )
-        .await
-        .unwrap()
+        .await;
+    log::finished("shardTransferAndCall", digest, &result);
+    result
+}
+
+/// Like `shardTransferAndCall`, between subaccounts. The notification carries both subaccounts,
+/// so that exchanges can tell which deposit account was credited.
+#[update(name = "shardAccountTransferAndCall")]
+#[candid_method(update, rename = "shardAccountTransferAndCall")]
+pub async fn account_transfer_and_call(
+    from_subaccount: Option<Subaccount>,
+    shard_id: Principal,
+    to: Account,
+    value: Nat,
+    notify_principal: Principal,
+    notify_method: String,
+    data: ByteBuf,
+) -> Result<NotificationResponse> {
+    let digest = log::digest((
+        from_subaccount,
+        shard_id,
+        to,
+        value.clone(),
+        notify_principal,
+        notify_method.clone(),
+        data.clone(),
+    ));
+    let result = transfer_and_call_internal(
+        Account::new(env::caller(), from_subaccount),
+        shard_id,
+        to.normalized(),
+        value,
+        notify_principal,
+        notify_method,
+        data,
+    )
+        .await;
+    log::finished("shardAccountTransferAndCall", digest, &result);
+    result
 }
 
 #[update(name = "shardSpendAndCall")]
 #[candid_method(update, rename = "shardSpendAndCall")]
-async fn spend_and_call(
+pub async fn spend_and_call(
     from: Principal,
     shard_id: Principal,
     to: Principal,
     value: Nat,
     notify_principal: Principal,
     notify_method: String,
-    data: String,
-) -> String {
-    assert_is_spender(from).unwrap();
-    transfer_and_call_internal(
+    data: ByteBuf,
+) -> Result<NotificationResponse> {
+    let digest = log::digest((
         from,
         shard_id,
         to,
-        value,
+        value.clone(),
         notify_principal,
-        notify_method,
-        data,
-    )
-        .await
-        .unwrap()
+        notify_method.clone(),
+        data.clone(),
+    ));
+    let result = async {
+        assert_is_spender(from)?;
+        assert_not_frozen(&env::caller())?;
+        transfer_and_call_internal(
+            from.into(),
+            shard_id,
+            to.into(),
+            value,
+            notify_principal,
+            notify_method,
+            data,
+        )
+            .await
+    }
+        .await;
+    log::finished("shardSpendAndCall", digest, &result);
+    result
 }
 
 #[query(name = "shardGetSupply")]
 #[candid_method(query, rename = "shardGetSupply")]
-fn shard_get_supply() -> Nat {
+pub fn shard_get_supply() -> Nat {
+    get_balances_total() + escrow::get_held_total() + get_accrued_fees()
+}
+
+pub fn get_balances_total() -> Nat {
     STATE.with(|b| {
         b.borrow()
             .balances
             .values()
             .cloned()
             .fold(Nat::from(0), |sum, next| sum + next)
-    }) + get_accrued_fees()
+    })
+}
+
+/// Number of accounts, and of subaccounts holding a balance.
+pub fn get_account_counts() -> (usize, usize) {
+    STATE.with(|b| {
+        let b = b.borrow();
+        let accounts = b.balances.keys().filter(|a| a.subaccount.is_none()).count();
+        (accounts, b.balances.len() - accounts)
+    })
 }
 
 #[query(name = "shardBalanceOf")]
 #[candid_method(query, rename = "shardBalanceOf")]
-fn balance_of(account: Principal) -> Nat {
+pub fn balance_of(account: Principal) -> Nat {
     STATE
-        .with(|b| b.borrow().balances.get(&account).cloned())
+        .with(|b| b.borrow().balances.get(&Account::from(account)).cloned())
         .ok_or(TxError::AccountDoesNotExist {
-            shard: ic_cdk::id().to_string(),
+            shard: env::id().to_string(),
             user: account.to_string(),
         })
         .unwrap()
 }
+
+/// Owners of the accounts held by the shard, including those only holding subaccounts.
+#[query(name = "shardGetAccountOwners")]
+#[candid_method(query, rename = "shardGetAccountOwners")]
+pub fn get_account_owners() -> Vec<Principal> {
+    STATE.with(|b| {
+        let owners: BTreeSet<Principal> = b.borrow().balances.keys().map(|a| a.owner).collect();
+        owners.into_iter().collect()
+    })
+}
+
+/// Balance of every owner holding one, summed over its subaccounts.
+pub fn get_owner_balances() -> HashMap<Principal, Nat> {
+    STATE.with(|b| {
+        let mut totals: HashMap<Principal, Nat> = HashMap::new();
+        for (account, balance) in b.borrow().balances.iter().filter(|(_, b)| **b > 0u64) {
+            totals.entry(account.owner).or_default().add_assign(balance.clone());
+        }
+        totals
+    })
+}
+
+/// Owners holding a balance, with their balance summed over subaccounts, in `order`.
+fn get_sorted_holders(order: HolderOrder) -> Vec<Holder> {
+    let mut holders: Vec<Holder> = get_owner_balances()
+        .into_iter()
+        .map(|(owner, balance)| Holder { owner, balance })
+        .collect();
+    holders.sort_by(|a, b| order.compare(a, b));
+    holders
+}
+
+/// Holders of the shard by decreasing balance, from position `start`, as DIP20 tokens list them.
+#[query(name = "getHolders")]
+#[candid_method(query, rename = "getHolders")]
+pub fn get_holders(start: u64, limit: u64) -> Vec<(Principal, Nat)> {
+    get_sorted_holders(HolderOrder::Balance)
+        .into_iter()
+        .skip(start as usize)
+        .take(limit.min(MAX_HOLDERS_PAGE) as usize)
+        .map(|holder| (holder.owner, holder.balance))
+        .collect()
+}
+
+/// Holders of the shard in `order`, from the one following `after`. Merged by the main contract's
+/// `getHolders` into pages over every shard.
+#[query(name = "shardGetHolders")]
+#[candid_method(query, rename = "shardGetHolders")]
+pub fn get_holders_after(
+    order: HolderOrder,
+    after: Option<HoldersCursor>,
+    limit: u64,
+) -> Vec<Holder> {
+    get_sorted_holders(order)
+        .into_iter()
+        .filter(|holder| after.as_ref().is_none_or(|after| after.precedes(order, holder, env::id())))
+        .take(limit.min(MAX_HOLDERS_PAGE) as usize)
+        .collect()
+}
+
+/// Removes the balances and spenders of `owner`, to move them to another shard. `None` if the
+/// shard does not hold any of its accounts.
+pub fn take_account(owner: Principal) -> Option<MigratedAccount> {
+    STATE.with(|b| {
+        let mut b = b.borrow_mut();
+        let accounts: Vec<Account> = b
+            .balances
+            .keys()
+            .filter(|a| a.owner == owner)
+            .copied()
+            .collect();
+        if accounts.is_empty() {
+            return None;
+        }
+        let balances = accounts
+            .into_iter()
+            .map(|account| {
+                let balance = b.balances.remove(&account).unwrap();
+                (account.subaccount, balance)
+            })
+            .collect();
+        let spenders = b.spenders.remove(&owner).unwrap_or_default();
+        Some(MigratedAccount {
+            owner,
+            balances,
+            spenders: spenders.into_iter().collect(),
+            frozen: freeze::take_frozen(&owner),
+            epoch: snapshots::epoch(),
+        })
+    })
+}
+
+/// Adds the balances and spenders of an account moved from another shard to those the shard
+/// may already hold for the same owner, and keeps it frozen if it was.
+pub fn merge_account(account: MigratedAccount) {
+    let total = account
+        .balances
+        .iter()
+        .fold(Nat::from(0), |sum, (_, balance)| sum + balance.clone());
+    snapshots::credited_since(account.owner, &total, account.epoch);
+    let (owner, frozen) = (account.owner, account.frozen);
+    STATE.with(|b| {
+        let mut b = b.borrow_mut();
+        b.balances.entry(Account::from(owner)).or_default();
+        for (subaccount, balance) in account.balances {
+            b.balances
+                .entry(Account::new(owner, subaccount))
+                .or_default()
+                .add_assign(balance);
+        }
+        if !account.spenders.is_empty() {
+            b.spenders.entry(owner).or_default().extend(account.spenders);
+        }
+    });
+    if frozen {
+        freeze::restore_frozen(owner);
+    }
+}
+
+/// Balance of a subaccount, zero if it was never credited. Its owner must have an account.
+#[query(name = "shardAccountBalanceOf")]
+#[candid_method(query, rename = "shardAccountBalanceOf")]
+pub fn account_balance_of(account: Account) -> Nat {
+    assert_is_customer(&account.owner).unwrap();
+    STATE.with(|b| {
+        b.borrow()
+            .balances
+            .get(&account.normalized())
+            .cloned()
+            .unwrap_or_default()
+    })
+}
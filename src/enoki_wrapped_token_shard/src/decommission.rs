@@ -0,0 +1,211 @@
+use std::cell::RefCell;
+use std::cmp::min;
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token_shared::types::*;
+use enoki_wrapped_token_shared::{env, log};
+
+use crate::balances::{get_account_owners, merge_account, take_account};
+use crate::management::{
+    assert_is_manager_contract, assert_is_sibling_or_pull, get_manager_contract, route,
+};
+use crate::mint::get_underlying_token_and_fee;
+use crate::{escrow, fees, notifications};
+
+/// Cycles a decommissioned shard keeps for itself, enough to be stopped and deleted.
+const CYCLES_KEPT: u64 = 100_000_000_000;
+
+/// The balances, spenders and frozen flag of one owner, moved from a draining shard to a sibling.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct MigratedAccount {
+    pub owner: Principal,
+    /// Balance of the default account and of each subaccount.
+    pub balances: Vec<(Option<Subaccount>, Nat)>,
+    pub spenders: Vec<Principal>,
+    /// Whether the owner was frozen on the shard it was taken from.
+    pub frozen: bool,
+    /// Latest snapshot of the shard it was taken from.
+    pub epoch: u64,
+}
+
+thread_local! {
+    static DRAINING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+pub fn export_stable_storage() -> (bool, ) {
+    (DRAINING.with(|d| d.take()), )
+}
+
+pub fn import_stable_storage(draining: bool) {
+    DRAINING.with(|d| d.replace(draining));
+}
+
+/// A draining shard accepts no new accounts and no wraps, since its accounts are being moved.
+pub fn assert_not_draining() -> Result<()> {
+    if is_draining() {
+        Err(TxError::ShardDraining)
+    } else {
+        Ok(())
+    }
+}
+
+fn assert_draining() -> Result<()> {
+    if is_draining() {
+        Ok(())
+    } else {
+        Err(TxError::Other("Shard is not draining".to_string()))
+    }
+}
+
+#[query(name = "isDraining")]
+#[candid_method(query, rename = "isDraining")]
+pub fn is_draining() -> bool {
+    DRAINING.with(|d| *d.borrow())
+}
+
+#[update(name = "setDraining")]
+#[candid_method(update, rename = "setDraining")]
+pub fn set_draining(draining: bool) -> Result<()> {
+    assert_is_manager_contract()?;
+    DRAINING.with(|d| d.replace(draining));
+    Ok(())
+}
+
+/// Moves the accounts of `owners` to `to_shard`, and returns the owners moved. A draining shard
+/// moves any account; an active one keeps those the ring of the hashed routing mode places on
+/// it. Accounts with a transfer held in escrow are left for a later call, as are
+/// owners the shard does not hold.
+#[update(name = "shardMigrateAccounts")]
+#[candid_method(update, rename = "shardMigrateAccounts")]
+pub async fn migrate_accounts(
+    to_shard: Principal,
+    owners: Vec<Principal>,
+) -> Result<Vec<Principal>> {
+    let digest = log::digest((to_shard, owners.clone()));
+    let result = migrate_accounts_internal(to_shard, owners).await;
+    log::finished("shardMigrateAccounts", digest, &result);
+    result
+}
+
+async fn migrate_accounts_internal(
+    to_shard: Principal,
+    owners: Vec<Principal>,
+) -> Result<Vec<Principal>> {
+    assert_is_manager_contract()?;
+    assert_is_sibling_or_pull(&to_shard).await?;
+    let draining = is_draining();
+    let accounts: Vec<MigratedAccount> = owners
+        .into_iter()
+        .filter(|owner| draining || route(owner) != Some(env::id()))
+        .filter(|owner| !escrow::involves(*owner))
+        .filter_map(take_account)
+        .collect();
+    if accounts.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let result: Result<(Result<()>, )> = env::call(
+        to_shard,
+        "shardImportAccounts",
+        (accounts.clone(), Nat::from(0)),
+    )
+        .await
+        .map_err(|err| err.into());
+    match result.and_then(|res| res.0) {
+        Ok(()) => Ok(accounts.into_iter().map(|account| account.owner).collect()),
+        Err(error) => {
+            // returned instead of trapping, which would roll back the restore
+            for account in accounts {
+                merge_account(account);
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Credits the accounts and the unowned fees moved from a draining sibling.
+#[update(name = "shardImportAccounts")]
+#[candid_method(update, rename = "shardImportAccounts")]
+pub async fn import_accounts(accounts: Vec<MigratedAccount>, accrued_fees: Nat) -> Result<()> {
+    assert_is_sibling_or_pull(&env::caller()).await?;
+    assert_not_draining()?;
+    for account in accounts {
+        merge_account(account);
+    }
+    fees::add_accrued_fees(accrued_fees);
+    Ok(())
+}
+
+/// Hands what is left on a drained shard over to `to_shard`: the fees owned by no account, and
+/// the underlying tokens in custody. The fee of the underlying transfer is paid out of those
+/// fees. The cycles of the shard, except those needed to delete it, go to the cycles pool of the
+/// main contract.
+#[update(name = "shardFinishDecommission")]
+#[candid_method(update, rename = "shardFinishDecommission")]
+pub async fn finish_decommission(to_shard: Principal) -> Result<()> {
+    let digest = log::digest((to_shard, ));
+    let result = finish_decommission_internal(to_shard).await;
+    log::finished("shardFinishDecommission", digest, &result);
+    result
+}
+
+async fn finish_decommission_internal(to_shard: Principal) -> Result<()> {
+    assert_is_manager_contract()?;
+    assert_draining()?;
+    assert_is_sibling_or_pull(&to_shard).await?;
+    if !get_account_owners().is_empty()
+        || escrow::get_held_count() > 0
+        || notifications::get_undelivered_count() > 0
+        || fees::get_owed_total() > 0u64
+    {
+        return Err(TxError::Other(
+            "Shard still holds accounts, transfers, notifications or fee shares".to_string(),
+        ));
+    }
+
+    let (token, underlying_fee) = get_underlying_token_and_fee().await;
+    let custody = token.balance_of(env::id()).await;
+    let accrued_fees = fees::take_accrued_fees();
+    let fee_paid = if custody > underlying_fee {
+        min(accrued_fees.clone(), underlying_fee.clone())
+    } else {
+        Nat::from(0)
+    };
+    // the custody goes first, so that the fees are only handed over with what backs them
+    if custody > underlying_fee {
+        let transfer = token.transfer(to_shard, custody - underlying_fee).await;
+        if transfer.is_err() {
+            fees::add_accrued_fees(accrued_fees);
+            return Err(TxError::UnderlyingTransferFailure);
+        }
+    }
+
+    // skipped when retrying, since `to_shard` may no longer accept calls from this shard
+    let accrued_fees = accrued_fees - fee_paid;
+    if accrued_fees > 0u64 {
+        let result: Result<(Result<()>, )> = env::call(
+            to_shard,
+            "shardImportAccounts",
+            (Vec::<MigratedAccount>::new(), accrued_fees.clone()),
+        )
+            .await
+            .map_err(|err| err.into());
+        if let Err(error) = result.and_then(|res| res.0) {
+            // the custody is gone, but the fee of its transfer is paid, so a retry imports the rest
+            fees::add_accrued_fees(accrued_fees);
+            return Err(error);
+        }
+    }
+
+    let cycles = env::canister_balance().saturating_sub(CYCLES_KEPT);
+    let manager = get_manager_contract();
+    let reclaimed: CallResult<(u64, )> =
+        env::call_with_payment(manager, "depositCycles", (), cycles).await;
+    if let Err(error) = reclaimed {
+        log::warning("reclaimCycles", log::digest((manager, cycles)), error);
+    }
+    Ok(())
+}
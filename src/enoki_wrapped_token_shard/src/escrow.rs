@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::balances::{decrease_balance, increase_balance};
+use crate::management::{assert_governance_disabled, assert_is_manager_contract, has_role};
+use crate::snapshots;
+use crate::stable::StableEscrowState;
+
+/// Nanoseconds after which a transfer still held can be refunded by `refundHeldTransfer`: a day,
+/// far longer than a notification takes unless its response was lost.
+pub const HOLD_TIMEOUT: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Value debited by a transferAndCall, waiting for the recipient to be notified.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct HeldTransfer {
+    pub from: Account,
+    pub to_shard: Principal,
+    pub to: Account,
+    pub value: Nat,
+    /// Latest snapshot when the value was held.
+    pub epoch: u64,
+    pub held_at: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct EscrowState {
+    pub next_id: u64,
+    pub held: BTreeMap<u64, HeldTransfer>,
+}
+
+thread_local! {
+    static ESCROW: RefCell<EscrowState> = RefCell::new(EscrowState::default());
+}
+
+pub fn export_stable_storage() -> (StableEscrowState, ) {
+    (ESCROW.with(|e| e.take()).into(), )
+}
+
+pub fn import_stable_storage(escrow: StableEscrowState) {
+    ESCROW.with(|e| e.replace(escrow.into()));
+}
+
+/// Debits `from` and holds the value until the transfer is either delivered or refunded.
+pub fn hold(from: Account, to_shard: Principal, to: Account, value: Nat) -> Result<u64> {
+    decrease_balance(from, value.clone())?;
+    Ok(ESCROW.with(|e| {
+        let mut e = e.borrow_mut();
+        let id = e.next_id;
+        e.next_id += 1;
+        e.held.insert(
+            id,
+            HeldTransfer {
+                from,
+                to_shard,
+                to,
+                value,
+                epoch: snapshots::epoch(),
+                held_at: env::time(),
+            },
+        );
+        id
+    }))
+}
+
+fn release(id: u64) -> Result<HeldTransfer> {
+    ESCROW
+        .with(|e| e.borrow_mut().held.remove(&id))
+        .ok_or_else(|| TxError::Other(format!("transfer {} is not held", id)))
+}
+
+/// The recipient accepted `accepted` out of the value: credits it, unless the recipient's shard
+/// already did, and refunds the rest to the sender. Fails if the transfer was refunded by
+/// `refundHeldTransfer` in the meantime.
+pub fn settle(id: u64, accepted: Nat) -> Result<()> {
+    let transfer = release(id)?;
+    if transfer.to_shard == env::id() {
+        let (from, from_shard) = (transfer.from.owner, env::id());
+        increase_balance(
+            transfer.to,
+            accepted.clone(),
+            CreditKind::Transfer { from, from_shard },
+        );
+    } else {
+        snapshots::delivered_since(transfer.from.owner, &accepted, transfer.epoch);
+    }
+    if transfer.value > accepted {
+        increase_balance(transfer.from, transfer.value - accepted, CreditKind::Refund);
+    }
+    Ok(())
+}
+
+/// The notification failed: returns the value to the sender. The fee is not refunded.
+pub fn refund(id: u64) -> Result<()> {
+    let transfer = release(id)?;
+    increase_balance(transfer.from, transfer.value, CreditKind::Refund);
+    Ok(())
+}
+
+/// Refunds a transfer held for longer than `HOLD_TIMEOUT`, whose notification never returned,
+/// to its sender; the recipient gets nothing even if the notification returns later. Transfers
+/// to another shard are refused, since that shard may still credit them. Called by shard
+/// operators, or by the main contract for a `RefundHeldTransfer` proposal under governance.
+#[update(name = "refundHeldTransfer")]
+#[candid_method(update, rename = "refundHeldTransfer")]
+pub fn refund_held_transfer(id: u64) -> Result<()> {
+    let result = refund_held_transfer_internal(id);
+    log::finished("refundHeldTransfer", log::digest((id, )), &result);
+    result
+}
+
+fn refund_held_transfer_internal(id: u64) -> Result<()> {
+    if assert_is_manager_contract().is_err() {
+        assert_governance_disabled()?;
+        if !has_role(&env::caller(), Role::ShardOperator) {
+            return Err(TxError::Unauthorized);
+        }
+    }
+    let (to_shard, held_at) = ESCROW
+        .with(|e| e.borrow().held.get(&id).map(|transfer| (transfer.to_shard, transfer.held_at)))
+        .ok_or_else(|| TxError::Other(format!("transfer {} is not held", id)))?;
+    if to_shard != env::id() {
+        return Err(TxError::Other(format!("transfer {} is credited by {}", id, to_shard)));
+    }
+    if env::time() < held_at.saturating_add(HOLD_TIMEOUT) {
+        return Err(TxError::Other(format!("transfer {} is still being notified", id)));
+    }
+    refund(id)
+}
+
+/// Whether a held transfer will credit or refund an account of `owner` on this shard.
+pub fn involves(owner: Principal) -> bool {
+    ESCROW.with(|e| {
+        e.borrow().held.values().any(|transfer| {
+            transfer.from.owner == owner
+                || (transfer.to_shard == env::id() && transfer.to.owner == owner)
+        })
+    })
+}
+
+/// Value held for each sender, which snapshots count as theirs until it is delivered.
+pub fn get_held_by_owner() -> HashMap<Principal, Nat> {
+    ESCROW.with(|e| {
+        let mut held: HashMap<Principal, Nat> = HashMap::new();
+        for transfer in e.borrow().held.values() {
+            *held.entry(transfer.from.owner).or_default() += transfer.value.clone();
+        }
+        held
+    })
+}
+
+pub fn get_held_count() -> usize {
+    ESCROW.with(|e| e.borrow().held.len())
+}
+
+pub fn get_held_total() -> Nat {
+    ESCROW.with(|e| {
+        e.borrow()
+            .held
+            .values()
+            .fold(Nat::from(0), |sum, transfer| sum + transfer.value.clone())
+    })
+}
+
+#[query(name = "getHeldTransfers")]
+#[candid_method(query, rename = "getHeldTransfers")]
+pub fn get_held_transfers() -> Vec<(u64, HeldTransfer)> {
+    ESCROW.with(|e| {
+        e.borrow()
+            .held
+            .iter()
+            .map(|(id, transfer)| (*id, transfer.clone()))
+            .collect()
+    })
+}
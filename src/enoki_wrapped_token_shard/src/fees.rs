@@ -1,33 +1,226 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ops::AddAssign;
 
-use candid::{candid_method, CandidType, Deserialize, types::number::Nat};
-use ic_cdk_macros::*;
+use candid::{candid_method, CandidType, Deserialize, Principal, types::number::Nat};
+use enoki_wrapped_token_macros::*;
 
-use crate::stable::StableFeeBalance;
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::balances::{assert_is_customer, increase_balance};
+use crate::management::{
+    assert_is_manager_contract, assert_is_sibling_or_pull, get_manager_contract, route,
+};
+use crate::metrics::{self, Counter};
+use crate::pause::assert_not_paused;
+use crate::snapshots;
+use crate::stable::{StableFeeBalance, StableFeeDistribution};
+
+/// Nanoseconds between two payouts of the shares owed to beneficiaries of other shards, so that
+/// each fee does not cost a call.
+const PAYOUT_INTERVAL: u64 = 60_000_000_000;
+/// Beneficiaries paid by a single heartbeat.
+const MAX_PAYOUTS_PER_HEARTBEAT: usize = 16;
 
 thread_local! {
     static ACCRUED_FEES: RefCell<FeeBalance> = RefCell::new(FeeBalance::default());
+    static FEE_DISTRIBUTION: RefCell<FeeDistribution> = RefCell::new(FeeDistribution::default());
 }
 
 #[derive(Deserialize, CandidType, Clone, Debug, Default)]
 pub struct FeeBalance(pub Nat);
 
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct FeeDistribution {
+    pub beneficiaries: Vec<FeeBeneficiary>,
+    // remainder of the integer division, carried over to the next fee
+    pub dust: Nat,
+    /// Shares of the beneficiaries held by other shards, until the heartbeat pays them out there.
+    pub owed: BTreeMap<Principal, Nat>,
+    /// Time of the latest payout, which the heartbeat repeats every `PAYOUT_INTERVAL`.
+    pub last_payout: u64,
+}
+
 pub fn accept_fee(value: Nat) {
+    let shares = FEE_DISTRIBUTION.with(|d| {
+        let mut d = d.borrow_mut();
+        let total_weight: u128 = d.beneficiaries.iter().map(|b| u128::from(b.weight)).sum();
+        if total_weight == 0 {
+            return None;
+        }
+        let to_distribute = value.clone() + d.dust.clone();
+        let shares: Vec<(Principal, Nat)> = d
+            .beneficiaries
+            .iter()
+            .map(|b| {
+                (
+                    b.id,
+                    to_distribute.clone() * Nat::from(b.weight) / Nat::from(total_weight),
+                )
+            })
+            .collect();
+        let distributed = shares
+            .iter()
+            .fold(Nat::from(0), |sum, (_, share)| sum + share.clone());
+        d.dust = to_distribute - distributed;
+        Some(shares)
+    });
+
+    match shares {
+        Some(shares) => {
+            for (beneficiary, share) in shares {
+                if share == 0u64 {
+                    continue;
+                }
+                if assert_is_customer(&beneficiary).is_ok() {
+                    increase_balance(beneficiary.into(), share, CreditKind::FeeShare);
+                } else {
+                    owe(beneficiary, share);
+                }
+            }
+        }
+        None => ACCRUED_FEES.with(|f| f.borrow_mut().0.add_assign(value)),
+    }
+}
+
+fn owe(beneficiary: Principal, share: Nat) {
+    FEE_DISTRIBUTION.with(|d| d.borrow_mut().owed.entry(beneficiary).or_default().add_assign(share));
+}
+
+pub fn get_owed_total() -> Nat {
+    FEE_DISTRIBUTION.with(|d| {
+        d.borrow()
+            .owed
+            .values()
+            .fold(Nat::from(0), |sum, share| sum + share.clone())
+    })
+}
+
+/// The shard holding the accounts of `beneficiary`, which the main contract registered when it
+/// was made a beneficiary.
+async fn locate_beneficiary(beneficiary: Principal) -> Result<Principal> {
+    if let Some(routed) = route(&beneficiary) {
+        return Ok(routed);
+    }
+    let response: Result<(Principal, )> =
+        env::call(get_manager_contract(), "getAssignedShardId", (beneficiary, ))
+            .await
+            .map_err(|err| err.into());
+    response.map(|(shard, )| shard)
+}
+
+async fn pay_out(beneficiary: Principal, share: Nat) {
+    let epoch = snapshots::epoch();
+    let result = async {
+        let shard = locate_beneficiary(beneficiary).await?;
+        if shard == env::id() {
+            // the beneficiary moved to this shard since the share was collected
+            increase_balance(beneficiary.into(), share.clone(), CreditKind::FeeShare);
+            return Ok(());
+        }
+        assert_is_sibling_or_pull(&shard).await?;
+        let response: Result<(Result<()>, )> =
+            env::call(shard, "shardReceiveFeeShare", (beneficiary, share.clone(), epoch))
+                .await
+                .map_err(|err| {
+                    metrics::record(Counter::FailedSiblingCall);
+                    err.into()
+                });
+        response.and_then(|res| res.0)
+    }
+        .await;
+    if let Err(error) = result {
+        log::warning("payFeeShare", log::digest((beneficiary, share.clone())), error);
+        owe(beneficiary, share);
+    }
+}
+
+/// Pays the shares owed to beneficiaries of other shards on their shards.
+pub async fn heartbeat() {
+    if assert_not_paused(PauseScope::Transfers).is_err() {
+        return;
+    }
+    let now = env::time();
+    let owed: Vec<(Principal, Nat)> = FEE_DISTRIBUTION.with(|d| {
+        let mut d = d.borrow_mut();
+        if now < d.last_payout.saturating_add(PAYOUT_INTERVAL) {
+            return vec![];
+        }
+        d.last_payout = now;
+        let owed = &mut d.owed;
+        let beneficiaries: Vec<Principal> =
+            owed.keys().take(MAX_PAYOUTS_PER_HEARTBEAT).copied().collect();
+        beneficiaries
+            .into_iter()
+            .filter_map(|beneficiary| owed.remove_entry(&beneficiary))
+            .collect()
+    });
+    futures::future::join_all(
+        owed.into_iter()
+            .map(|(beneficiary, share)| pay_out(beneficiary, share)),
+    )
+        .await;
+}
+
+/// Credits the share of a fee collected by a sibling shard, which it held when `epoch` was its
+/// latest snapshot.
+#[update(name = "shardReceiveFeeShare")]
+#[candid_method(update, rename = "shardReceiveFeeShare")]
+pub async fn receive_fee_share(beneficiary: Principal, value: Nat, epoch: u64) -> Result<()> {
+    assert_is_sibling_or_pull(&env::caller()).await?;
+    assert_is_customer(&beneficiary)?;
+    snapshots::credited_since(beneficiary, &value, epoch);
+    increase_balance(beneficiary.into(), value, CreditKind::FeeShare);
+    Ok(())
+}
+
+/// Takes the fees owned by no account, to move them to another shard.
+/// Takes the fees owned by no account, the rounding dust of the distribution included.
+pub fn take_accrued_fees() -> Nat {
+    let dust = FEE_DISTRIBUTION.with(|d| std::mem::take(&mut d.borrow_mut().dust));
+    ACCRUED_FEES.with(|f| f.take().0) + dust
+}
+
+pub fn add_accrued_fees(value: Nat) {
     ACCRUED_FEES.with(|f| f.borrow_mut().0.add_assign(value));
 }
 
-pub fn export_stable_storage() -> (StableFeeBalance, ) {
+pub fn export_stable_storage() -> (StableFeeBalance, StableFeeDistribution) {
     let fee_balance: StableFeeBalance = ACCRUED_FEES.with(|b| b.take()).into();
-    (fee_balance, )
+    let fee_distribution: StableFeeDistribution = FEE_DISTRIBUTION.with(|d| d.take()).into();
+    (fee_balance, fee_distribution)
 }
 
-pub fn import_stable_storage(fee_balance: StableFeeBalance) {
+pub fn import_stable_storage(fee_balance: StableFeeBalance, fee_distribution: StableFeeDistribution) {
     ACCRUED_FEES.with(|b| b.replace(fee_balance.into()));
+    FEE_DISTRIBUTION.with(|d| d.replace(fee_distribution.into()));
 }
 
+/// Fees that are not credited to any account: those collected while no beneficiaries were set,
+/// the rounding dust of the distribution, and the shares not paid out to beneficiaries of other
+/// shards yet.
 #[query(name = "getAccruedFees")]
 #[candid_method(query, rename = "getAccruedFees")]
 pub fn get_accrued_fees() -> Nat {
     ACCRUED_FEES.with(|d| d.borrow().0.clone())
-}
\ No newline at end of file
+        + FEE_DISTRIBUTION.with(|d| d.borrow().dust.clone())
+        + get_owed_total()
+}
+
+#[query(name = "getFeeDistribution")]
+#[candid_method(query, rename = "getFeeDistribution")]
+pub fn get_fee_distribution() -> FeeDistribution {
+    FEE_DISTRIBUTION.with(|d| d.borrow().clone())
+}
+
+#[update(name = "setFeeBeneficiaries")]
+#[candid_method(update, rename = "setFeeBeneficiaries")]
+pub fn set_fee_beneficiaries(beneficiaries: Vec<FeeBeneficiary>) -> Result<()> {
+    assert_is_manager_contract()?;
+    if FeeBeneficiary::total_weight(&beneficiaries).is_none() {
+        return Err(TxError::Other("fee beneficiary weights overflow".to_string()));
+    }
+    FEE_DISTRIBUTION.with(|d| d.borrow_mut().beneficiaries = beneficiaries);
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::types::*;
+
+use crate::management::assert_is_manager_contract;
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct FreezeState {
+    frozen: HashSet<Principal>,
+    block_recipients: bool,
+}
+
+thread_local! {
+    static FREEZE_STATE: RefCell<FreezeState> = RefCell::new(FreezeState::default());
+}
+
+pub fn export_stable_storage() -> (FreezeState, ) {
+    (FREEZE_STATE.with(|f| f.take()), )
+}
+
+pub fn import_stable_storage(freeze_state: FreezeState) {
+    FREEZE_STATE.with(|f| f.replace(freeze_state));
+}
+
+pub fn assert_not_frozen(user: &Principal) -> Result<()> {
+    if FREEZE_STATE.with(|f| f.borrow().frozen.contains(user)) {
+        Err(TxError::AccountFrozen {
+            user: user.to_string(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+pub fn assert_can_receive(user: &Principal) -> Result<()> {
+    if FREEZE_STATE.with(|f| f.borrow().block_recipients) {
+        assert_not_frozen(user)
+    } else {
+        Ok(())
+    }
+}
+
+#[query(name = "isFrozen")]
+#[candid_method(query, rename = "isFrozen")]
+pub fn is_frozen(user: Principal) -> bool {
+    FREEZE_STATE.with(|f| f.borrow().frozen.contains(&user))
+}
+
+fn set_frozen(user: Principal, frozen: bool) {
+    FREEZE_STATE.with(|f| {
+        let mut f = f.borrow_mut();
+        if frozen {
+            f.frozen.insert(user);
+        } else {
+            f.frozen.remove(&user);
+        }
+    });
+}
+
+/// Unfreezes `user` as its accounts leave the shard, and returns whether it was frozen.
+pub fn take_frozen(user: &Principal) -> bool {
+    FREEZE_STATE.with(|f| f.borrow_mut().frozen.remove(user))
+}
+
+/// Freezes `user` as its frozen accounts arrive from another shard.
+pub fn restore_frozen(user: Principal) {
+    set_frozen(user, true);
+}
+
+#[update(name = "setAccountFrozen")]
+#[candid_method(update, rename = "setAccountFrozen")]
+pub fn set_account_frozen(user: Principal, frozen: bool) -> Result<()> {
+    assert_is_manager_contract()?;
+    set_frozen(user, frozen);
+    Ok(())
+}
+
+#[update(name = "setBlockFrozenRecipients")]
+#[candid_method(update, rename = "setBlockFrozenRecipients")]
+pub fn set_block_frozen_recipients(block_recipients: bool) -> Result<()> {
+    assert_is_manager_contract()?;
+    FREEZE_STATE.with(|f| f.borrow_mut().block_recipients = block_recipients);
+    Ok(())
+}
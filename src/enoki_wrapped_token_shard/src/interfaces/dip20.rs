@@ -1,5 +1,7 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 
+use enoki_wrapped_token_shared::env;
+
 pub struct DIP20 {
     principal: Principal,
 }
@@ -38,9 +40,10 @@ impl DIP20 {
 
     pub async fn transfer(&self, target: Principal, amount: Nat) -> TxReceipt {
         let call_result: Result<(TxReceipt, ), _> =
-            ic_cdk::api::call::call(self.principal, "transfer", (target, amount)).await;
+            env::call(self.principal, "transfer", (target, amount)).await;
 
-        call_result.unwrap().0
+        // a rejected call did not transfer anything
+        call_result.map(|res| res.0).unwrap_or(Err(TxError::LedgerTrap))
     }
 
     pub async fn transfer_from(
@@ -50,21 +53,28 @@ impl DIP20 {
         amount: Nat,
     ) -> TxReceipt {
         let call_result: Result<(TxReceipt, ), _> =
-            ic_cdk::api::call::call(self.principal, "transferFrom", (source, target, amount)).await;
+            env::call(self.principal, "transferFrom", (source, target, amount)).await;
 
         call_result.unwrap().0
     }
 
     pub async fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
         let call_result: Result<(Nat, ), _> =
-            ic_cdk::api::call::call(self.principal, "allowance", (owner, spender)).await;
+            env::call(self.principal, "allowance", (owner, spender)).await;
+
+        call_result.unwrap().0
+    }
+
+    pub async fn balance_of(&self, who: Principal) -> Nat {
+        let call_result: Result<(Nat, ), _> =
+            env::call(self.principal, "balanceOf", (who, )).await;
 
         call_result.unwrap().0
     }
 
     pub async fn get_metadata(&self) -> Metadata {
         let call_result: Result<(Metadata, ), _> =
-            ic_cdk::api::call::call(self.principal, "getMetadata", ()).await;
+            env::call(self.principal, "getMetadata", ()).await;
 
         call_result.unwrap().0
     }
@@ -0,0 +1,86 @@
+#[allow(unused_imports)]
+use candid::{candid_method, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::backup::BackupManifest;
+use enoki_wrapped_token_shared::env;
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::log::{LogEntry, LogLevel};
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::metrics::{HttpRequest, HttpResponse};
+#[allow(unused_imports)]
+use enoki_wrapped_token_shared::types::{
+    Account, BalanceEvent, ByteBuf, FeeBeneficiary, Holder, HolderOrder, HoldersCursor,
+    NotificationResponse, PauseScope, Result, Role, RoleAssignments, ShardRegistry,
+    ShardedTransferNotification, Subaccount,
+};
+
+#[allow(unused_imports)]
+use crate::decommission::MigratedAccount;
+#[allow(unused_imports)]
+use crate::escrow::HeldTransfer;
+#[allow(unused_imports)]
+use crate::fees::FeeDistribution;
+#[allow(unused_imports)]
+use crate::notifications::PendingNotification;
+#[allow(unused_imports)]
+use crate::subscriptions::Subscription;
+use crate::management::{assert_is_owner, ManagerContractData};
+
+pub mod backup;
+pub mod balances;
+pub mod decommission;
+pub mod escrow;
+pub mod fees;
+pub mod freeze;
+pub mod interfaces;
+pub mod management;
+pub mod metrics;
+pub mod mint;
+pub mod notifications;
+pub mod pause;
+pub mod snapshots;
+pub mod stable;
+pub mod subscriptions;
+pub mod upgrade;
+
+#[init]
+#[candid_method(init)]
+pub fn init() {
+    management::init_manager_data(ManagerContractData {
+        owner: env::caller(),
+        manager_contract: Principal::anonymous(),
+        fee: Default::default(),
+        underlying_token: Principal::anonymous(),
+        sibling_shards: Default::default(),
+        registry_version: 0,
+        ring: None,
+        governed: false,
+        roles: Default::default(),
+        deploy_time: env::time(),
+    });
+}
+
+#[update(name = "finishInit")]
+#[candid_method(update, rename = "finishInit")]
+pub fn finish_init(manager_contract: Principal, underlying_token: Principal) {
+    assert_is_owner().unwrap();
+    management::init_manager_and_token(manager_contract, underlying_token);
+}
+
+/// Delivers queued notifications and balance events, and pays out fee shares.
+#[heartbeat]
+pub async fn heartbeat() {
+    futures::join!(
+        notifications::heartbeat(),
+        subscriptions::heartbeat(),
+        fees::heartbeat()
+    );
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn export_candid() -> String {
+    candid::export_service!();
+    __export_service()
+}
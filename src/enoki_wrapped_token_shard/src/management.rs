@@ -1,28 +1,48 @@
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 
 use candid::{candid_method, types::number::Nat, CandidType, Deserialize, Principal};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
+use ic_cdk::api::call::CallResult;
 
+use enoki_wrapped_token_shared::env;
+use enoki_wrapped_token_shared::log::{self, LogEntry, LogLevel};
+use enoki_wrapped_token_shared::routing::ShardRing;
 use enoki_wrapped_token_shared::types::*;
 
+use crate::snapshots;
 use crate::stable::StableManagerContractData;
 
+/// Nanoseconds between two pulls of the shard registry, so that calls from unknown canisters
+/// cannot make the shard call the main contract each time.
+const REGISTRY_PULL_INTERVAL: u64 = 60_000_000_000;
+
 pub fn assert_is_owner() -> Result<()> {
-    if MANAGER_CONTRACT_DATA.with(|s| s.borrow().owner) == ic_cdk::caller() {
+    if MANAGER_CONTRACT_DATA.with(|s| s.borrow().owner) == env::caller() {
         Ok(())
     } else {
         Err(TxError::Unauthorized)
     }
 }
 pub fn assert_is_manager_contract() -> Result<()> {
-    if MANAGER_CONTRACT_DATA.with(|s| s.borrow().manager_contract) == ic_cdk::caller() {
+    if MANAGER_CONTRACT_DATA.with(|s| s.borrow().manager_contract) == env::caller() {
         Ok(())
     } else {
         Err(TxError::Unauthorized)
     }
 }
 
+/// Like the main contract's, for the admin actions called on the shard directly.
+pub fn assert_governance_disabled() -> Result<()> {
+    if MANAGER_CONTRACT_DATA.with(|d| d.borrow().governed) {
+        Err(TxError::Other(
+            "this action must be submitted as a governance proposal".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 pub fn assert_is_sibling(id: &Principal) -> Result<()> {
     if MANAGER_CONTRACT_DATA.with(|s| s.borrow().sibling_shards.contains(id)) {
         Ok(())
@@ -31,6 +51,33 @@ pub fn assert_is_sibling(id: &Principal) -> Result<()> {
     }
 }
 
+/// Like `assert_is_sibling`, but pulls the registry from the main contract first when `id` is
+/// unknown, in case the shard missed the push that added it.
+pub async fn assert_is_sibling_or_pull(id: &Principal) -> Result<()> {
+    if assert_is_sibling(id).is_ok() {
+        return Ok(());
+    }
+    let now = env::time();
+    let due = LAST_REGISTRY_PULL.with(|last| {
+        let due = now >= last.get().saturating_add(REGISTRY_PULL_INTERVAL);
+        if due {
+            last.set(now);
+        }
+        due
+    });
+    if due {
+        let manager = get_manager_contract();
+        let response: CallResult<(ShardRegistry, )> =
+            env::call(manager, "getShardRegistry", ()).await;
+        match response {
+            // a stale registry means a newer one was pushed in the meantime
+            Ok((registry, )) => apply_registry(registry).unwrap_or_default(),
+            Err(error) => log::warning("pullShardRegistry", log::digest((manager, )), error),
+        }
+    }
+    assert_is_sibling(id)
+}
+
 #[derive(Deserialize, CandidType, Clone, Debug)]
 pub struct ManagerContractData {
     pub owner: Principal,
@@ -38,6 +85,14 @@ pub struct ManagerContractData {
     pub fee: Nat,
     pub underlying_token: Principal,
     pub sibling_shards: HashSet<Principal>,
+    /// Version of the shard registry the siblings come from.
+    pub registry_version: u64,
+    /// Ring of the hashed routing mode, from the same registry.
+    pub ring: Option<ShardRing>,
+    /// Whether the main contract is administered by governance proposals, from the same
+    /// registry.
+    pub governed: bool,
+    pub roles: HashMap<Principal, HashSet<Role>>,
     pub deploy_time: u64,
 }
 
@@ -49,6 +104,10 @@ impl Default for ManagerContractData {
             fee: Default::default(),
             underlying_token: Principal::anonymous(),
             sibling_shards: Default::default(),
+            registry_version: 0,
+            ring: None,
+            governed: false,
+            roles: Default::default(),
             deploy_time: 0,
         }
     }
@@ -68,32 +127,45 @@ pub fn init_manager_and_token(manager: Principal, token: Principal) {
     });
 }
 
+/// The shard the ring places `owner` on, in the hashed routing mode.
+pub fn route(owner: &Principal) -> Option<Principal> {
+    MANAGER_CONTRACT_DATA.with(|d| {
+        let data = d.borrow();
+        data.ring.as_ref().and_then(|ring| ring.shard_for(owner))
+    })
+}
+
+pub fn get_manager_contract() -> Principal {
+    MANAGER_CONTRACT_DATA.with(|d| d.borrow().manager_contract)
+}
+
 pub fn get_underlying() -> Principal {
     MANAGER_CONTRACT_DATA.with(|d| d.borrow().underlying_token)
 }
 
 thread_local! {
     static MANAGER_CONTRACT_DATA: RefCell<ManagerContractData> = RefCell::new(ManagerContractData::default());
+    static LAST_REGISTRY_PULL: Cell<u64> = const { Cell::new(0) };
 }
 
 #[query(name = "getManagementDetails")]
 #[candid_method(query, rename = "getManagementDetails")]
-fn get_management_details() -> ManagerContractData {
+pub fn get_management_details() -> ManagerContractData {
     MANAGER_CONTRACT_DATA.with(|d| d.borrow().clone())
 }
 
 #[query(name = "getOwner")]
 #[candid_method(query, rename = "getOwner")]
-fn get_owner() -> Principal {
+pub fn get_owner() -> Principal {
     MANAGER_CONTRACT_DATA.with(|d| d.borrow().owner)
 }
 
 #[update(name = "setOwner")]
 #[candid_method(update, rename = "setOwner")]
-fn set_owner(new_owner: Principal) -> Result<()> {
+pub fn set_owner(new_owner: Principal) -> Result<()> {
     MANAGER_CONTRACT_DATA.with(|d| {
         let owner = &mut d.borrow_mut().owner;
-        if ic_cdk::caller() == *owner {
+        if env::caller() == *owner {
             *owner = new_owner;
             Ok(())
         } else {
@@ -110,10 +182,10 @@ pub fn get_fee() -> Nat {
 
 #[update(name = "setFee")]
 #[candid_method(update, rename = "setFee")]
-fn set_fee(new_fee: Nat) -> Result<()> {
+pub fn set_fee(new_fee: Nat) -> Result<()> {
     MANAGER_CONTRACT_DATA.with(|d| {
         let mut data = d.borrow_mut();
-        if ic_cdk::caller() == data.manager_contract {
+        if env::caller() == data.manager_contract {
             data.fee = new_fee;
             Ok(())
         } else {
@@ -122,48 +194,107 @@ fn set_fee(new_fee: Nat) -> Result<()> {
     })
 }
 
+#[update(name = "setRoles")]
+#[candid_method(update, rename = "setRoles")]
+pub fn set_roles(roles: RoleAssignments) -> Result<()> {
+    MANAGER_CONTRACT_DATA.with(|d| {
+        let mut data = d.borrow_mut();
+        if env::caller() == data.manager_contract {
+            data.roles = roles
+                .into_iter()
+                .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+                .collect();
+            Ok(())
+        } else {
+            Err(TxError::Unauthorized)
+        }
+    })
+}
+
+/// Roles granted on the manager contract. Admins implicitly hold every role.
+#[query(name = "getRoles")]
+#[candid_method(query, rename = "getRoles")]
+pub fn get_roles(user: Principal) -> Vec<Role> {
+    MANAGER_CONTRACT_DATA.with(|d| {
+        let data = d.borrow();
+        match data.roles.get(&user) {
+            Some(roles) if roles.contains(&Role::Admin) => vec![
+                Role::Admin,
+                Role::FeeManager,
+                Role::ShardOperator,
+                Role::Pauser,
+            ],
+            Some(roles) => roles.iter().copied().collect(),
+            None => vec![],
+        }
+    })
+}
+
+pub fn has_role(user: &Principal, role: Role) -> bool {
+    get_roles(*user).contains(&role)
+}
+
+/// Entries of the shard's event log from id `since`, with at least the given level. Admins only.
+#[query(name = "getLogs")]
+#[candid_method(query, rename = "getLogs")]
+pub fn get_logs(since: u64, level: LogLevel) -> Vec<LogEntry> {
+    if !has_role(&env::caller(), Role::Admin) {
+        panic!("{:?}", TxError::Unauthorized);
+    }
+    log::get_logs(since, level)
+}
+
+/// The shard's cycle balance, polled by the main contract to top it up.
+#[query(name = "shardGetCycles")]
+#[candid_method(query, rename = "shardGetCycles")]
+pub fn shard_get_cycles() -> u64 {
+    env::canister_balance()
+}
+
 #[update(name = "initShard")]
 #[candid_method(update, rename = "initShard")]
-fn init_shard(underlying_token: Principal, sibling_shards: Vec<Principal>, fee: Nat) {
+pub fn init_shard(underlying_token: Principal, registry: ShardRegistry, fee: Nat) {
     MANAGER_CONTRACT_DATA.with(|d| {
         let mut data = d.borrow_mut();
-        if ic_cdk::caller() == data.manager_contract {
+        if env::caller() == data.manager_contract {
             if data.underlying_token != underlying_token {
                 panic!("{:?}", TxError::Other("Incompatible shard".to_string()));
             }
-            for shard in sibling_shards {
-                data.sibling_shards.insert(shard);
-            }
             data.fee = fee;
         } else {
             panic!("{:?}", TxError::Unauthorized)
         }
     });
+    snapshots::start_after(registry.latest_snapshot);
+    apply_registry(registry).unwrap();
 }
 
-#[update(name = "addSiblingShard")]
-#[candid_method(update, rename = "addSiblingShard")]
-fn add_sibling_shard(new_shard: Principal) {
-    MANAGER_CONTRACT_DATA.with(|d| {
-        let mut data = d.borrow_mut();
-        if ic_cdk::caller() == data.manager_contract {
-            data.sibling_shards.insert(new_shard);
-        } else {
-            panic!("{:?}", TxError::Unauthorized)
-        }
-    })
+/// Replaces the siblings of the shard with every other shard of `registry`, unless the shard
+/// already holds a newer registry. Pushed by the main contract whenever its shards change.
+#[update(name = "setShardRegistry")]
+#[candid_method(update, rename = "setShardRegistry")]
+pub fn set_shard_registry(registry: ShardRegistry) -> Result<()> {
+    assert_is_manager_contract()?;
+    apply_registry(registry)
 }
 
-#[update(name = "removeSiblingShard")]
-#[candid_method(update, rename = "removeSiblingShard")]
-fn remove_sibling_shard(shard: Principal) {
+fn apply_registry(registry: ShardRegistry) -> Result<()> {
     MANAGER_CONTRACT_DATA.with(|d| {
         let mut data = d.borrow_mut();
-        if ic_cdk::caller() == data.manager_contract {
-            data.sibling_shards.remove(&shard);
-        } else {
-            panic!("{:?}", TxError::Unauthorized)
+        if registry.version < data.registry_version {
+            return Err(TxError::StaleRegistry {
+                version: data.registry_version,
+            });
         }
+        data.registry_version = registry.version;
+        data.sibling_shards = registry
+            .shards
+            .into_iter()
+            .filter(|&shard| shard != env::id())
+            .collect();
+        data.ring = registry.ring.map(|shards| ShardRing::new(&shards));
+        data.governed = registry.governed;
+        Ok(())
     })
 }
 
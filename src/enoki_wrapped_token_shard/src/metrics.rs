@@ -0,0 +1,112 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Deserialize};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::metrics::{serve_metrics, HttpRequest, HttpResponse};
+
+use crate::fees::get_accrued_fees;
+use crate::{balances, escrow, notifications};
+
+pub enum Counter {
+    Transfer,
+    Wrap,
+    Unwrap,
+    FailedSiblingCall,
+}
+
+/// Operations completed by the shard since it was installed.
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct Counters {
+    transfers: u64,
+    wraps: u64,
+    unwraps: u64,
+    failed_sibling_calls: u64,
+}
+
+thread_local! {
+    static COUNTERS: RefCell<Counters> = RefCell::new(Counters::default());
+}
+
+pub fn export_stable_storage() -> (Counters, ) {
+    (COUNTERS.with(|c| c.take()), )
+}
+
+pub fn import_stable_storage(counters: Counters) {
+    COUNTERS.with(|c| c.replace(counters));
+}
+
+pub fn record(counter: Counter) {
+    COUNTERS.with(|c| {
+        let mut c = c.borrow_mut();
+        match counter {
+            Counter::Transfer => c.transfers += 1,
+            Counter::Wrap => c.wraps += 1,
+            Counter::Unwrap => c.unwraps += 1,
+            Counter::FailedSiblingCall => c.failed_sibling_calls += 1,
+        }
+    })
+}
+
+/// Serves `GET /metrics` in the Prometheus text format.
+#[query(name = "http_request")]
+#[candid_method(query, rename = "http_request")]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    serve_metrics(request, |m| {
+        let counters = COUNTERS.with(|c| c.borrow().clone());
+        let (accounts, subaccounts) = balances::get_account_counts();
+        m.gauge(
+            "enoki_shard_accounts",
+            "Accounts assigned to the shard.",
+            accounts,
+        );
+        m.gauge(
+            "enoki_shard_subaccounts",
+            "Non-empty subaccounts held by the shard.",
+            subaccounts,
+        );
+        m.amount(
+            "enoki_shard_balances",
+            "Sum of the balances held by the shard.",
+            &balances::get_balances_total(),
+        );
+        m.amount(
+            "enoki_shard_held",
+            "Value held by transfers waiting for a notification.",
+            &escrow::get_held_total(),
+        );
+        m.amount(
+            "enoki_shard_accrued_fees",
+            "Fees not distributed to beneficiaries.",
+            &get_accrued_fees(),
+        );
+        m.counter(
+            "enoki_shard_transfers",
+            "Transfers sent from the shard.",
+            counters.transfers,
+        );
+        m.counter("enoki_shard_wraps", "Wraps into the shard.", counters.wraps);
+        m.counter(
+            "enoki_shard_unwraps",
+            "Unwraps from the shard.",
+            counters.unwraps,
+        );
+        m.counter(
+            "enoki_shard_failed_sibling_calls",
+            "Calls to sibling shards that were rejected.",
+            counters.failed_sibling_calls,
+        );
+        m.gauge(
+            "enoki_shard_held_transfers",
+            "Transfers waiting for the recipient's notification to return.",
+            escrow::get_held_count(),
+        );
+        m.labeled_gauge(
+            "enoki_shard_pending_notifications",
+            "Notifications of shardTransferAndNotify not delivered yet.",
+            "status",
+            notifications::get_pending_counts(),
+        );
+        m.canister_metrics();
+    })
+}
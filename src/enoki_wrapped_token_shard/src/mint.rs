@@ -1,67 +1,97 @@
 use candid::{candid_method, Principal, types::number::Nat};
-use ic_cdk_macros::*;
+use enoki_wrapped_token_macros::*;
 
+use enoki_wrapped_token_shared::{env, log};
 use enoki_wrapped_token_shared::types::*;
 
 use crate::balances::{decrease_balance, increase_balance};
+use crate::decommission::assert_not_draining;
 use crate::fees::accept_fee;
+use crate::freeze::assert_not_frozen;
 use crate::interfaces::dip20::DIP20;
 use crate::management;
+use crate::metrics::{self, Counter};
+use crate::pause::assert_not_paused;
+use crate::snapshots;
 
 // FOR TESTING ONLY
 #[update(name = "mint")]
 #[candid_method(update)]
-async fn mint(amount: Nat) {
-    let caller = ic_cdk::caller();
-    increase_balance(caller, amount);
+pub async fn mint(amount: Nat) {
+    assert_not_paused(PauseScope::Wrap).unwrap();
+    let caller = env::caller();
+    increase_balance(caller.into(), amount, CreditKind::Wrap);
 }
 // FOR TESTING ONLY
 
 #[update(name = "wrap")]
 #[candid_method(update)]
-async fn wrap(amount: Nat) {
-    let caller = ic_cdk::caller();
+pub async fn wrap(amount: Nat) {
+    assert_not_paused(PauseScope::Wrap).unwrap();
+    assert_not_draining().unwrap();
+    let caller = env::caller();
+    // failures trap, so only the start survives them
+    let digest = log::digest((amount.clone(), ));
+    log::started("wrap", digest.clone());
     let (token, underlying_fee) = get_underlying_token_and_fee().await;
     let amount_to_credit = deposit_token(caller, amount, token, underlying_fee).await.unwrap();
-    increase_balance(caller, amount_to_credit);
+    increase_balance(caller.into(), amount_to_credit, CreditKind::Wrap);
+    metrics::record(Counter::Wrap);
+    log::succeeded("wrap", digest);
 }
 
 #[update(name = "unwrap")]
 #[candid_method(update)]
-async fn unwrap(amount: Nat, to: Principal) {
-    let caller = ic_cdk::caller();
+pub async fn unwrap(amount: Nat, to: Principal) -> Result<()> {
+    let digest = log::digest((amount.clone(), to));
+    let result = unwrap_internal(amount, to).await;
+    log::finished("unwrap", digest, &result);
+    result
+}
+
+async fn unwrap_internal(amount: Nat, to: Principal) -> Result<()> {
+    assert_not_paused(PauseScope::Unwrap)?;
+    let caller = env::caller();
+    assert_not_frozen(&caller)?;
+    let (token, underlying_fee) = get_underlying_token_and_fee().await;
     let fee = management::get_fee();
-    if amount <= fee {
-        panic!("{:?}", TxError::InsufficientBalance);
+    if amount <= fee.clone() + underlying_fee.clone() {
+        return Err(TxError::TransferValueTooSmall);
     }
 
-    decrease_balance(caller, amount.clone()).unwrap();
+    decrease_balance(caller.into(), amount.clone())?;
+    let epoch = snapshots::epoch();
     accept_fee(fee.clone());
     let amount = amount - fee; // when reverting, do not refund fee
 
-    let (token, underlying_fee) = get_underlying_token_and_fee().await;
-
-    if let Err(_) = withdraw_token(amount.clone(), to, token, underlying_fee).await {
-        increase_balance(caller, amount);
-        panic!("{:?}", TxError::UnderlyingTransferFailure);
+    // errors after this point are returned instead of trapping, which would roll back the refund
+    if withdraw_token(amount.clone(), to, token, underlying_fee).await.is_err() {
+        snapshots::credited_since(caller, &amount, epoch);
+        increase_balance(caller.into(), amount, CreditKind::Refund);
+        return Err(TxError::UnderlyingTransferFailure);
     }
+    metrics::record(Counter::Unwrap);
+    Ok(())
 }
 
-async fn get_underlying_token_and_fee() -> (DIP20, Nat) {
+pub async fn get_underlying_token_and_fee() -> (DIP20, Nat) {
     let token = DIP20::new(management::get_underlying());
     let dip_fee = token.get_metadata().await.fee;
     (token, dip_fee)
 }
 
 async fn deposit_token(caller: Principal, amount: Nat, token: DIP20, fee: Nat) -> Result<Nat> {
-    let allowance = token.allowance(caller, ic_cdk::api::id()).await;
+    let allowance = token.allowance(caller, env::id()).await;
     if allowance < amount {
         return Err(TxError::InsufficientBalance);
     }
+    if amount <= fee {
+        return Err(TxError::TransferValueTooSmall);
+    }
     let amount = amount - fee;
 
     token
-        .transfer_from(caller, ic_cdk::api::id(), amount.clone())
+        .transfer_from(caller, env::id(), amount.clone())
         .await
         .map_err(|_| TxError::UnderlyingTransferFailure)?;
 
@@ -0,0 +1,335 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::balances::transfer_internal;
+use crate::management::{get_fee, has_role};
+use crate::pause::assert_not_paused;
+
+/// Delay before the first retry, doubled after each failed attempt.
+const RETRY_BASE_DELAY: u64 = 1_000_000_000;
+/// Failed attempts after which a notification is moved to the dead-letter list.
+const MAX_ATTEMPTS: u32 = 10;
+/// Deliveries started by a single heartbeat.
+const MAX_DELIVERIES_PER_HEARTBEAT: usize = 16;
+/// Dead letters kept until acknowledged, beyond which the oldest are dropped.
+pub const MAX_DEAD_LETTERS: usize = 1_000;
+
+#[derive(Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationStatus {
+    Scheduled,
+    InFlight,
+    DeadLetter,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct PendingNotification {
+    pub id: u64,
+    pub notification: ShardedTransferNotification,
+    pub notify_principal: Principal,
+    pub notify_method: String,
+    pub status: NotificationStatus,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct NotificationsState {
+    pub next_id: u64,
+    pub pending: BTreeMap<u64, PendingNotification>,
+}
+
+/// Index of the pending notifications, rebuilt from them after an upgrade.
+#[derive(Default)]
+struct NotificationIndex {
+    /// Scheduled notifications by the time of their next attempt.
+    due: BTreeSet<(u64, u64)>,
+    /// Dead letters by id, the oldest first.
+    dead: BTreeSet<u64>,
+}
+
+impl NotificationIndex {
+    fn insert(&mut self, pending: &PendingNotification) {
+        match pending.status {
+            NotificationStatus::Scheduled => {
+                self.due.insert((pending.next_attempt, pending.id));
+            }
+            NotificationStatus::DeadLetter => {
+                self.dead.insert(pending.id);
+            }
+            NotificationStatus::InFlight => {}
+        }
+    }
+
+    fn remove(&mut self, pending: &PendingNotification) {
+        self.due.remove(&(pending.next_attempt, pending.id));
+        self.dead.remove(&pending.id);
+    }
+}
+
+thread_local! {
+    static NOTIFICATIONS: RefCell<NotificationsState> =
+        RefCell::new(NotificationsState::default());
+    static INDEX: RefCell<NotificationIndex> = RefCell::new(NotificationIndex::default());
+}
+
+pub fn export_stable_storage() -> (NotificationsState, ) {
+    (NOTIFICATIONS.with(|n| n.take()), )
+}
+
+pub fn import_stable_storage(mut notifications: NotificationsState) {
+    // no call can be outstanding across an upgrade, so deliveries that never completed are
+    // attempted again
+    for pending in notifications.pending.values_mut() {
+        if pending.status == NotificationStatus::InFlight {
+            pending.status = NotificationStatus::Scheduled;
+        }
+    }
+    let mut index = NotificationIndex::default();
+    for pending in notifications.pending.values() {
+        index.insert(pending);
+    }
+    INDEX.with(|i| i.replace(index));
+    NOTIFICATIONS.with(|n| n.replace(notifications));
+}
+
+fn enqueue(
+    notification: ShardedTransferNotification,
+    notify_principal: Principal,
+    notify_method: String,
+) -> u64 {
+    NOTIFICATIONS.with(|n| {
+        let mut n = n.borrow_mut();
+        let id = n.next_id;
+        n.next_id += 1;
+        let pending = PendingNotification {
+            id,
+            notification,
+            notify_principal,
+            notify_method,
+            status: NotificationStatus::Scheduled,
+            attempts: 0,
+            next_attempt: env::time(),
+            last_error: None,
+        };
+        INDEX.with(|i| i.borrow_mut().insert(&pending));
+        n.pending.insert(id, pending);
+        id
+    })
+}
+
+/// Marks a notification as in flight and returns what is needed to deliver it.
+fn start_delivery(id: u64) -> (ShardedTransferNotification, Principal, String) {
+    NOTIFICATIONS.with(|n| {
+        let mut n = n.borrow_mut();
+        let pending = n.pending.get_mut(&id).expect("notification not found");
+        INDEX.with(|i| i.borrow_mut().remove(pending));
+        pending.status = NotificationStatus::InFlight;
+        (
+            pending.notification.clone(),
+            pending.notify_principal,
+            pending.notify_method.clone(),
+        )
+    })
+}
+
+fn finish_delivery(id: u64, result: &Result<()>) {
+    NOTIFICATIONS.with(|n| {
+        let mut n = n.borrow_mut();
+        match result {
+            Ok(()) => {
+                n.pending.remove(&id);
+            }
+            Err(error) => {
+                let pending = n.pending.get_mut(&id).expect("notification not found");
+                pending.attempts += 1;
+                pending.last_error = Some(format!("{:?}", error));
+                if pending.attempts >= MAX_ATTEMPTS {
+                    pending.status = NotificationStatus::DeadLetter;
+                } else {
+                    pending.status = NotificationStatus::Scheduled;
+                    pending.next_attempt =
+                        env::time() + (RETRY_BASE_DELAY << (pending.attempts - 1));
+                }
+                let pending = pending.clone();
+                INDEX.with(|i| {
+                    let mut index = i.borrow_mut();
+                    index.insert(&pending);
+                    while index.dead.len() > MAX_DEAD_LETTERS {
+                        let oldest = index.dead.pop_first().unwrap();
+                        n.pending.remove(&oldest);
+                    }
+                });
+            }
+        }
+    })
+}
+
+async fn deliver(id: u64) -> Result<()> {
+    let (notification, notify_principal, notify_method) = start_delivery(id);
+    // the value was already credited, so the response only confirms the delivery
+    let result: Result<(NotificationResponse, )> =
+        env::call(notify_principal, &notify_method, (notification, ))
+            .await
+            .map_err(|err| err.into());
+    let result = result.map(|_| ());
+    finish_delivery(id, &result);
+    if let Err(error) = &result {
+        log::warning("deliverNotification", log::digest((id, )), error);
+    }
+    result
+}
+
+/// Transfers like `shardTransfer`, then notifies the recipient asynchronously. Unlike
+/// `shardTransferAndCall`, the transfer does not depend on the notification: failed deliveries
+/// are retried with exponential backoff, and end up in `getPendingNotifications`.
+#[update(name = "shardTransferAndNotify")]
+#[candid_method(update, rename = "shardTransferAndNotify")]
+pub async fn transfer_and_notify(
+    shard_id: Principal,
+    to: Principal,
+    value: Nat,
+    notify_principal: Principal,
+    notify_method: String,
+    data: ByteBuf,
+) -> Result<u64> {
+    let digest = log::digest((
+        shard_id,
+        to,
+        value.clone(),
+        notify_principal,
+        notify_method.clone(),
+        data.clone(),
+    ));
+    let from = env::caller();
+    let fee = get_fee();
+    let result = transfer_internal(from.into(), shard_id, to.into(), value.clone())
+        .await
+        .map(|()| {
+            let notification = ShardedTransferNotification {
+                from,
+                from_shard: env::id(),
+                to,
+                from_subaccount: None,
+                to_subaccount: None,
+                value: value - fee.clone(),
+                fee_charged: fee,
+                data,
+            };
+            enqueue(notification, notify_principal, notify_method)
+        });
+    log::finished("shardTransferAndNotify", digest, &result);
+    result
+}
+
+/// Notifications still to be delivered, not counting the dead-letter list.
+pub fn get_undelivered_count() -> usize {
+    NOTIFICATIONS.with(|n| {
+        n.borrow()
+            .pending
+            .values()
+            .filter(|p| p.status != NotificationStatus::DeadLetter)
+            .count()
+    })
+}
+
+/// Number of undelivered notifications in each status.
+pub fn get_pending_counts() -> Vec<(String, usize)> {
+    NOTIFICATIONS.with(|n| {
+        let n = n.borrow();
+        [
+            NotificationStatus::Scheduled,
+            NotificationStatus::InFlight,
+            NotificationStatus::DeadLetter,
+        ]
+        .iter()
+        .map(|status| {
+            let count = n.pending.values().filter(|p| p.status == *status).count();
+            (format!("{:?}", status), count)
+        })
+        .collect()
+    })
+}
+
+/// Notifications that have not been delivered yet, including the dead-letter list.
+#[query(name = "getPendingNotifications")]
+#[candid_method(query, rename = "getPendingNotifications")]
+pub fn get_pending_notifications() -> Vec<PendingNotification> {
+    NOTIFICATIONS.with(|n| n.borrow().pending.values().cloned().collect())
+}
+
+/// The sender, the recipient, the notified canister and shard operators.
+fn assert_can_manage(pending: &PendingNotification, caller: &Principal) -> Result<()> {
+    if *caller == pending.notification.from
+        || *caller == pending.notification.to
+        || *caller == pending.notify_principal
+        || has_role(caller, Role::ShardOperator)
+    {
+        Ok(())
+    } else {
+        Err(TxError::Unauthorized)
+    }
+}
+
+/// Attempts to deliver a notification immediately, resetting its retries. Can be called by the
+/// sender, the recipient, the notified canister and shard operators.
+#[update(name = "retryNotification")]
+#[candid_method(update, rename = "retryNotification")]
+pub async fn retry_notification(id: u64) -> Result<()> {
+    let caller = env::caller();
+    NOTIFICATIONS.with(|n| {
+        let mut n = n.borrow_mut();
+        let pending = n.pending.get_mut(&id).ok_or(TxError::NotificationNotFound)?;
+        assert_can_manage(pending, &caller)?;
+        if pending.status == NotificationStatus::InFlight {
+            return Err(TxError::Other("notification is being delivered".to_string()));
+        }
+        pending.attempts = 0;
+        Ok(())
+    })?;
+    deliver(id).await
+}
+
+/// Drops a dead letter once its delivery is given up. Can be called by the same callers as
+/// `retryNotification`.
+#[update(name = "acknowledgeNotification")]
+#[candid_method(update, rename = "acknowledgeNotification")]
+pub fn acknowledge_notification(id: u64) -> Result<()> {
+    let caller = env::caller();
+    NOTIFICATIONS.with(|n| {
+        let mut n = n.borrow_mut();
+        let pending = n.pending.get(&id).ok_or(TxError::NotificationNotFound)?;
+        assert_can_manage(pending, &caller)?;
+        if pending.status != NotificationStatus::DeadLetter {
+            return Err(TxError::Other("notification is still being delivered".to_string()));
+        }
+        INDEX.with(|i| i.borrow_mut().remove(pending));
+        n.pending.remove(&id);
+        Ok(())
+    })
+}
+
+pub async fn heartbeat() {
+    if assert_not_paused(PauseScope::Callbacks).is_err() {
+        return;
+    }
+    let now = env::time();
+    let due: Vec<u64> = INDEX.with(|i| {
+        i.borrow()
+            .due
+            .range(..=(now, u64::MAX))
+            .map(|&(_, id)| id)
+            .take(MAX_DELIVERIES_PER_HEARTBEAT)
+            .collect()
+    });
+    if due.is_empty() {
+        return;
+    }
+    futures::future::join_all(due.into_iter().map(deliver)).await;
+}
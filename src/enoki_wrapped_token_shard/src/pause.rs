@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use candid::candid_method;
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::types::*;
+
+use crate::management::assert_is_manager_contract;
+
+thread_local! {
+    static PAUSED: RefCell<HashSet<PauseScope>> = RefCell::new(HashSet::default());
+}
+
+pub fn export_stable_storage() -> (Vec<PauseScope>, ) {
+    (PAUSED.with(|p| p.take()).into_iter().collect(), )
+}
+
+pub fn import_stable_storage(paused: Vec<PauseScope>) {
+    PAUSED.with(|p| p.replace(paused.into_iter().collect()));
+}
+
+pub fn assert_not_paused(scope: PauseScope) -> Result<()> {
+    if PAUSED.with(|p| {
+        let p = p.borrow();
+        p.contains(&PauseScope::All) || p.contains(&scope)
+    }) {
+        Err(TxError::Paused)
+    } else {
+        Ok(())
+    }
+}
+
+#[query(name = "getPausedScopes")]
+#[candid_method(query, rename = "getPausedScopes")]
+pub fn get_paused_scopes() -> Vec<PauseScope> {
+    PAUSED.with(|p| p.borrow().iter().copied().collect())
+}
+
+#[update(name = "setPaused")]
+#[candid_method(update, rename = "setPaused")]
+pub fn set_paused(scopes: Vec<PauseScope>) -> Result<()> {
+    assert_is_manager_contract()?;
+    PAUSED.with(|p| p.replace(scopes.into_iter().collect()));
+    Ok(())
+}
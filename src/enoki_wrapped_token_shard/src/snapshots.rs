@@ -0,0 +1,148 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{AddAssign, SubAssign};
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::log;
+use enoki_wrapped_token_shared::types::*;
+
+use crate::balances::get_owner_balances;
+use crate::escrow;
+use crate::management::assert_is_manager_contract;
+
+/// Balances of the shard's owners at each snapshot taken by the main contract.
+///
+/// A value sent to a sibling shard belongs to the recipient in the snapshots its sender took
+/// after debiting it, and to the sender in those taken before. Sibling calls carry the latest
+/// snapshot of the sender when it debited the value, so that the recipient can tell them apart
+/// from its own, whichever shard took each snapshot first.
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct SnapshotsState {
+    /// Id of the latest snapshot the shard took, or started after when it was added.
+    epoch: u64,
+    /// Balance of each owner at each snapshot, summed over its subaccounts and the transfers it
+    /// holds in escrow.
+    snapshots: BTreeMap<u64, HashMap<Principal, Nat>>,
+    /// Values credited to an owner by a sibling that debited them after taking snapshots this
+    /// shard has not taken yet, up to the given one: deducted from those once taken.
+    deductions: Vec<(u64, Principal, Nat)>,
+}
+
+thread_local! {
+    static SNAPSHOTS: RefCell<SnapshotsState> = RefCell::new(SnapshotsState::default());
+}
+
+pub fn export_stable_storage() -> (SnapshotsState, ) {
+    (SNAPSHOTS.with(|s| s.take()), )
+}
+
+pub fn import_stable_storage(snapshots: SnapshotsState) {
+    SNAPSHOTS.with(|s| s.replace(snapshots));
+}
+
+/// Id of the latest snapshot taken, to tag the values the shard debits.
+pub fn epoch() -> u64 {
+    SNAPSHOTS.with(|s| s.borrow().epoch)
+}
+
+/// A shard added after snapshot `id` holds no balance at any snapshot up to it.
+pub fn start_after(id: u64) {
+    SNAPSHOTS.with(|s| {
+        let mut s = s.borrow_mut();
+        s.epoch = s.epoch.max(id);
+    });
+}
+
+/// `value` is credited to `owner` after being debited, here or on a sibling, when `epoch` was the
+/// latest snapshot there. The snapshots this shard took since then add it, and those it has yet
+/// to take up to `epoch` leave it out.
+pub fn credited_since(owner: Principal, value: &Nat, epoch: u64) {
+    SNAPSHOTS.with(|s| {
+        let mut s = s.borrow_mut();
+        if epoch > s.epoch {
+            s.deductions.push((epoch, owner, value.clone()));
+        }
+        for balances in s.snapshots.range_mut(epoch + 1..).map(|(_, b)| b) {
+            balances.entry(owner).or_default().add_assign(value.clone());
+        }
+    });
+}
+
+/// `value`, held in escrow for `owner` since `epoch` was the latest snapshot, was delivered to a
+/// sibling, which counts it from there on: the snapshots taken since then leave it out.
+pub fn delivered_since(owner: Principal, value: &Nat, epoch: u64) {
+    SNAPSHOTS.with(|s| {
+        let mut s = s.borrow_mut();
+        for balances in s.snapshots.range_mut(epoch + 1..).map(|(_, b)| b) {
+            if let Some(balance) = balances.get_mut(&owner) {
+                deduct(balance, value);
+            }
+        }
+    });
+}
+
+fn deduct(balance: &mut Nat, value: &Nat) {
+    if *balance > *value {
+        balance.sub_assign(value.clone());
+    } else {
+        *balance = Nat::from(0);
+    }
+}
+
+/// Records the balance of every owner as snapshot `id`. Called by the main contract on every
+/// shard with increasing ids; retrying the latest one succeeds without recording it again.
+#[update(name = "shardTakeSnapshot")]
+#[candid_method(update, rename = "shardTakeSnapshot")]
+pub fn take_snapshot(id: u64) -> Result<()> {
+    let digest = log::digest((id, ));
+    let result = take_snapshot_internal(id);
+    log::finished("shardTakeSnapshot", digest, &result);
+    result
+}
+
+fn take_snapshot_internal(id: u64) -> Result<()> {
+    assert_is_manager_contract()?;
+    let mut balances = get_owner_balances();
+    for (owner, value) in escrow::get_held_by_owner() {
+        balances.entry(owner).or_default().add_assign(value);
+    }
+    SNAPSHOTS.with(|s| {
+        let mut s = s.borrow_mut();
+        if id == s.epoch && s.snapshots.contains_key(&id) {
+            return Ok(());
+        }
+        if id <= s.epoch {
+            return Err(TxError::Other(format!(
+                "Snapshot {} is not newer than snapshot {}",
+                id, s.epoch
+            )));
+        }
+        for (upto, owner, value) in &s.deductions {
+            if *upto >= id {
+                if let Some(balance) = balances.get_mut(owner) {
+                    deduct(balance, value);
+                }
+            }
+        }
+        s.deductions.retain(|(upto, _, _)| *upto > id);
+        balances.retain(|_, balance| *balance > 0u64);
+        s.snapshots.insert(id, balances);
+        s.epoch = id;
+        Ok(())
+    })
+}
+
+/// Balance `owner` held on this shard at snapshot `id`.
+#[query(name = "shardBalanceAtSnapshot")]
+#[candid_method(query, rename = "shardBalanceAtSnapshot")]
+pub fn balance_at_snapshot(owner: Principal, id: u64) -> Result<Nat> {
+    SNAPSHOTS.with(|s| {
+        s.borrow()
+            .snapshots
+            .get(&id)
+            .map(|balances| balances.get(&owner).cloned().unwrap_or_default())
+            .ok_or(TxError::SnapshotDoesNotExist)
+    })
+}
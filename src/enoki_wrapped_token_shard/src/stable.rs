@@ -1,18 +1,101 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use serde::{Deserialize, Serialize};
 
+use enoki_wrapped_token_shared::routing::ShardRing;
+use enoki_wrapped_token_shared::types::{
+    Account, ByteBuf, FeeBeneficiary, Role, RoleAssignments, ShardedTransferNotification,
+};
+
 use crate::balances::ShardBalances;
-use crate::fees::FeeBalance;
+use crate::escrow::{EscrowState, HeldTransfer};
+use crate::fees::{FeeBalance, FeeDistribution};
+use crate::notifications::{NotificationStatus, NotificationsState, PendingNotification};
 use crate::ManagerContractData;
 
 #[derive(CandidType, Clone, Deserialize, Serialize)]
-pub struct StableShardBalances(Vec<(Principal, String)>);
+pub struct StableShardBalances(Vec<(Account, String)>);
+
+/// Balances as saved before they were keyed by subaccount, which all belong to the default
+/// subaccount of their owner.
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LegacyShardBalances(Vec<(Principal, String)>);
 
 #[derive(CandidType, Clone, Deserialize, Serialize)]
 pub struct StableFeeBalance(String);
 
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct StableFeeDistribution {
+    pub beneficiaries: Vec<FeeBeneficiary>,
+    pub dust: String,
+    /// Missing from shards saved before shares were paid out on the beneficiaries' shards.
+    pub owed: Option<Vec<(Principal, String)>>,
+    pub last_payout: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Default, Deserialize)]
+pub struct StableEscrowState {
+    pub next_id: u64,
+    pub held: BTreeMap<u64, StableHeldTransfer>,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct StableHeldTransfer {
+    pub from: Account,
+    pub to_shard: Principal,
+    pub to: Account,
+    pub value: Nat,
+    /// Missing from transfers held before snapshots were added, which none can predate.
+    pub epoch: Option<u64>,
+    /// Missing from transfers held before they could time out, which are refundable.
+    pub held_at: Option<u64>,
+}
+
+/// Held transfers as saved before they were keyed by subaccount, like `LegacyShardBalances`.
+#[derive(CandidType, Clone, Deserialize)]
+pub struct LegacyEscrowState {
+    pub next_id: u64,
+    pub held: BTreeMap<u64, LegacyHeldTransfer>,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct LegacyHeldTransfer {
+    pub from: Principal,
+    pub to_shard: Principal,
+    pub to: Principal,
+    pub value: Nat,
+}
+
+/// Notifications as saved before their data was a blob, when it was text.
+#[derive(CandidType, Clone, Deserialize)]
+pub struct LegacyNotificationsState {
+    pub next_id: u64,
+    pub pending: BTreeMap<u64, LegacyPendingNotification>,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct LegacyPendingNotification {
+    pub id: u64,
+    pub notification: LegacyTransferNotification,
+    pub notify_principal: Principal,
+    pub notify_method: String,
+    pub status: NotificationStatus,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct LegacyTransferNotification {
+    pub from: Principal,
+    pub from_shard: Principal,
+    pub to: Principal,
+    pub fee_charged: Nat,
+    pub value: Nat,
+    pub data: String,
+}
+
 #[derive(CandidType, Clone, Deserialize, Serialize)]
 pub struct StableManagerContractData {
     pub owner: Principal,
@@ -20,6 +103,14 @@ pub struct StableManagerContractData {
     pub fee: String,
     pub underlying_token: Principal,
     pub sibling_shards: HashSet<Principal>,
+    /// Missing from shards saved before the registry was versioned.
+    pub registry_version: Option<u64>,
+    pub ring: Option<ShardRing>,
+    /// Missing from shards saved before governance was synced to the shards.
+    pub governed: Option<bool>,
+    /// Missing from shards saved before roles were granted, when the owner alone administered
+    /// the shard.
+    pub roles: Option<RoleAssignments>,
     pub deploy_time: u64,
 }
 
@@ -28,7 +119,7 @@ impl From<StableShardBalances> for ShardBalances {
         balances
             .0
             .into_iter()
-            .map(|(principal, balance)| (principal, balance.parse().unwrap()))
+            .map(|(account, balance)| (account, balance.parse().unwrap()))
             .collect()
     }
 }
@@ -38,7 +129,7 @@ impl From<ShardBalances> for StableShardBalances {
         Self(
             balances
                 .into_iter()
-                .map(|(principal, balance)| (principal, balance.to_string()))
+                .map(|(account, balance)| (account, balance.to_string()))
                 .collect(),
         )
     }
@@ -56,6 +147,97 @@ impl From<FeeBalance> for StableFeeBalance {
     }
 }
 
+impl From<StableFeeDistribution> for FeeDistribution {
+    fn from(distribution: StableFeeDistribution) -> Self {
+        Self {
+            beneficiaries: distribution.beneficiaries,
+            dust: distribution.dust.parse().unwrap(),
+            owed: distribution
+                .owed
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(beneficiary, share)| (beneficiary, share.parse().unwrap()))
+                .collect(),
+            last_payout: distribution.last_payout.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<FeeDistribution> for StableFeeDistribution {
+    fn from(distribution: FeeDistribution) -> Self {
+        Self {
+            beneficiaries: distribution.beneficiaries,
+            dust: distribution.dust.to_string(),
+            owed: Some(
+                distribution
+                    .owed
+                    .into_iter()
+                    .map(|(beneficiary, share)| (beneficiary, share.to_string()))
+                    .collect(),
+            ),
+            last_payout: Some(distribution.last_payout),
+        }
+    }
+}
+
+impl From<LegacyShardBalances> for StableShardBalances {
+    fn from(balances: LegacyShardBalances) -> Self {
+        Self(
+            balances
+                .0
+                .into_iter()
+                .map(|(owner, balance)| (owner.into(), balance))
+                .collect(),
+        )
+    }
+}
+
+impl From<StableEscrowState> for EscrowState {
+    fn from(escrow: StableEscrowState) -> Self {
+        Self {
+            next_id: escrow.next_id,
+            held: escrow
+                .held
+                .into_iter()
+                .map(|(id, transfer)| {
+                    let transfer = HeldTransfer {
+                        from: transfer.from,
+                        to_shard: transfer.to_shard,
+                        to: transfer.to,
+                        value: transfer.value,
+                        epoch: transfer.epoch.unwrap_or_default(),
+                        held_at: transfer.held_at.unwrap_or_default(),
+                    };
+                    (id, transfer)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<EscrowState> for StableEscrowState {
+    fn from(escrow: EscrowState) -> Self {
+        Self {
+            next_id: escrow.next_id,
+            held: escrow
+                .held
+                .into_iter()
+                .map(|(id, transfer)| {
+                    let transfer = StableHeldTransfer {
+                        from: transfer.from,
+                        to_shard: transfer.to_shard,
+                        to: transfer.to,
+                        value: transfer.value,
+                        epoch: Some(transfer.epoch),
+                        held_at: Some(transfer.held_at),
+                    };
+                    (id, transfer)
+                })
+                .collect(),
+        }
+    }
+}
+
 impl From<StableManagerContractData> for ManagerContractData {
     fn from(data: StableManagerContractData) -> Self {
         Self {
@@ -64,6 +246,15 @@ impl From<StableManagerContractData> for ManagerContractData {
             fee: data.fee.parse().unwrap(),
             underlying_token: data.underlying_token,
             sibling_shards: data.sibling_shards,
+            registry_version: data.registry_version.unwrap_or_default(),
+            ring: data.ring,
+            governed: data.governed.unwrap_or_default(),
+            roles: data
+                .roles
+                .unwrap_or(vec![(data.owner, vec![Role::Admin])])
+                .into_iter()
+                .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+                .collect(),
             deploy_time: data.deploy_time,
         }
     }
@@ -77,7 +268,74 @@ impl From<ManagerContractData> for StableManagerContractData {
             fee: data.fee.to_string(),
             underlying_token: data.underlying_token,
             sibling_shards: data.sibling_shards,
+            registry_version: Some(data.registry_version),
+            ring: data.ring,
+            governed: Some(data.governed),
+            roles: Some(
+                data.roles
+                    .into_iter()
+                    .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+                    .collect(),
+            ),
             deploy_time: data.deploy_time,
         }
     }
 }
+
+impl From<LegacyEscrowState> for StableEscrowState {
+    fn from(escrow: LegacyEscrowState) -> Self {
+        Self {
+            next_id: escrow.next_id,
+            held: escrow
+                .held
+                .into_iter()
+                .map(|(id, transfer)| {
+                    let transfer = StableHeldTransfer {
+                        from: transfer.from.into(),
+                        to_shard: transfer.to_shard,
+                        to: transfer.to.into(),
+                        value: transfer.value,
+                        epoch: None,
+                        held_at: None,
+                    };
+                    (id, transfer)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<LegacyNotificationsState> for NotificationsState {
+    fn from(notifications: LegacyNotificationsState) -> Self {
+        Self {
+            next_id: notifications.next_id,
+            pending: notifications
+                .pending
+                .into_iter()
+                .map(|(id, pending)| {
+                    let notification = pending.notification;
+                    let pending = PendingNotification {
+                        id: pending.id,
+                        notification: ShardedTransferNotification {
+                            from: notification.from,
+                            from_shard: notification.from_shard,
+                            to: notification.to,
+                            from_subaccount: None,
+                            to_subaccount: None,
+                            fee_charged: notification.fee_charged,
+                            value: notification.value,
+                            data: ByteBuf::from(notification.data.into_bytes()),
+                        },
+                        notify_principal: pending.notify_principal,
+                        notify_method: pending.notify_method,
+                        status: pending.status,
+                        attempts: pending.attempts,
+                        next_attempt: pending.next_attempt,
+                        last_error: pending.last_error,
+                    };
+                    (id, pending)
+                })
+                .collect(),
+        }
+    }
+}
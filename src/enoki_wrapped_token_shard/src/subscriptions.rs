@@ -0,0 +1,308 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use enoki_wrapped_token_macros::*;
+
+use enoki_wrapped_token_shared::{env, log};
+use enoki_wrapped_token_shared::types::*;
+
+use crate::management::has_role;
+use crate::pause::assert_not_paused;
+
+const MAX_SUBSCRIPTIONS: usize = 1_000;
+/// Keeps a single subscriber from taking every subscription of the shard.
+pub const MAX_SUBSCRIPTIONS_PER_SUBSCRIBER: usize = 10;
+/// Deliveries started by a heartbeat. The next heartbeat starts from the following subscription,
+/// so every subscription gets its turn.
+pub const MAX_DELIVERIES_PER_HEARTBEAT: usize = 20;
+/// Events kept for subscribers that are behind. Older events are dropped, which subscribers can
+/// detect from the gap in event ids.
+const MAX_EVENTS: usize = 10_000;
+const MAX_EVENTS_PER_DELIVERY: usize = 100;
+/// Delay after the first failed delivery, doubled after each failure up to `MAX_RETRY_DELAY`.
+const RETRY_BASE_DELAY: u64 = 1_000_000_000;
+const MAX_RETRY_DELAY: u64 = 3_600_000_000_000;
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct Subscription {
+    pub id: u64,
+    pub subscriber: Principal,
+    /// `None` subscribes to every account of the shard. Covers all the subaccounts of the account.
+    pub account: Option<Principal>,
+    /// Called with `(vec BalanceEvent)`.
+    pub method: String,
+    /// Id of the first event not delivered yet.
+    pub cursor: u64,
+    pub in_flight: bool,
+    pub failures: u32,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+impl Subscription {
+    fn matches(&self, account: &Principal) -> bool {
+        self.account.is_none_or(|a| a == *account)
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct SubscriptionsState {
+    next_subscription_id: u64,
+    subscriptions: BTreeMap<u64, Subscription>,
+    next_event_id: u64,
+    events: Vec<BalanceEvent>,
+}
+
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<SubscriptionsState> =
+        RefCell::new(SubscriptionsState::default());
+    /// Id of the first subscription the next heartbeat delivers to.
+    static NEXT_DELIVERY: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn export_stable_storage() -> (SubscriptionsState, ) {
+    (SUBSCRIPTIONS.with(|s| s.take()), )
+}
+
+pub fn import_stable_storage(mut subscriptions: SubscriptionsState) {
+    // deliveries interrupted by the upgrade are sent again
+    for subscription in subscriptions.subscriptions.values_mut() {
+        subscription.in_flight = false;
+    }
+    SUBSCRIPTIONS.with(|s| s.replace(subscriptions));
+}
+
+/// Records a credit to `account`, if anyone is subscribed to its owner.
+pub fn emit(account: Account, kind: CreditKind, amount: Nat) {
+    SUBSCRIPTIONS.with(|s| {
+        let mut s = s.borrow_mut();
+        if !s.subscriptions.values().any(|sub| sub.matches(&account.owner)) {
+            return;
+        }
+        let id = s.next_event_id;
+        s.next_event_id += 1;
+        s.events.push(BalanceEvent {
+            id,
+            account: account.owner,
+            subaccount: account.subaccount,
+            kind,
+            amount,
+            time: env::time(),
+        });
+        if s.events.len() > MAX_EVENTS {
+            let excess = s.events.len() - MAX_EVENTS;
+            s.events.drain(..excess);
+        }
+    })
+}
+
+fn assert_is_subscriber_or_operator(subscription: &Subscription) -> Result<()> {
+    let caller = env::caller();
+    if caller == subscription.subscriber || has_role(&caller, Role::ShardOperator) {
+        Ok(())
+    } else {
+        Err(TxError::Unauthorized)
+    }
+}
+
+/// Subscribes the caller to the credits of `account`, starting with the next one. Subscribing to
+/// every account (`None`) requires the `ShardOperator` role.
+#[update(name = "subscribe")]
+#[candid_method(update)]
+pub fn subscribe(account: Option<Principal>, method: String) -> Result<u64> {
+    let caller = env::caller();
+    if account.is_none() && !has_role(&caller, Role::ShardOperator) {
+        return Err(TxError::Unauthorized);
+    }
+    SUBSCRIPTIONS.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(TxError::TooManySubscriptions);
+        }
+        let owned = s
+            .subscriptions
+            .values()
+            .filter(|sub| sub.subscriber == caller)
+            .count();
+        if owned >= MAX_SUBSCRIPTIONS_PER_SUBSCRIBER {
+            return Err(TxError::TooManySubscriptions);
+        }
+        let id = s.next_subscription_id;
+        s.next_subscription_id += 1;
+        let cursor = s.next_event_id;
+        s.subscriptions.insert(
+            id,
+            Subscription {
+                id,
+                subscriber: caller,
+                account,
+                method,
+                cursor,
+                in_flight: false,
+                failures: 0,
+                next_attempt: 0,
+                last_error: None,
+            },
+        );
+        Ok(id)
+    })
+}
+
+#[update(name = "unsubscribe")]
+#[candid_method(update)]
+pub fn unsubscribe(id: u64) -> Result<()> {
+    SUBSCRIPTIONS.with(|s| -> Result<()> {
+        let mut s = s.borrow_mut();
+        let subscription = s
+            .subscriptions
+            .get(&id)
+            .ok_or(TxError::SubscriptionNotFound)?;
+        assert_is_subscriber_or_operator(subscription)?;
+        s.subscriptions.remove(&id);
+        Ok(())
+    })?;
+    prune_events();
+    Ok(())
+}
+
+/// The caller's subscriptions, or all of them for shard operators.
+#[query(name = "getSubscriptions")]
+#[candid_method(query, rename = "getSubscriptions")]
+pub fn get_subscriptions() -> Vec<Subscription> {
+    let caller = env::caller();
+    let is_operator = has_role(&caller, Role::ShardOperator);
+    SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .subscriptions
+            .values()
+            .filter(|sub| is_operator || sub.subscriber == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Events still kept by the shard, from id `start`, optionally only those of `account`. Lets
+/// subscribers catch up without waiting for deliveries.
+#[query(name = "getBalanceEvents")]
+#[candid_method(query, rename = "getBalanceEvents")]
+pub fn get_balance_events(account: Option<Principal>, start: u64, limit: u64) -> Vec<BalanceEvent> {
+    SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .events
+            .iter()
+            .filter(|e| e.id >= start && account.is_none_or(|a| a == e.account))
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Drops the events that every subscriber has received.
+fn prune_events() {
+    SUBSCRIPTIONS.with(|s| {
+        let mut s = s.borrow_mut();
+        let delivered = s
+            .subscriptions
+            .values()
+            .map(|sub| sub.cursor)
+            .min()
+            .unwrap_or(s.next_event_id);
+        let count = s.events.iter().take_while(|e| e.id < delivered).count();
+        s.events.drain(..count);
+    })
+}
+
+/// Picks the events to send to a subscription, and marks it in flight. Subscriptions without
+/// new events are moved to the end of the log.
+fn start_delivery(id: u64, now: u64) -> Option<(Principal, String, Vec<BalanceEvent>)> {
+    SUBSCRIPTIONS.with(|s| {
+        let mut s = s.borrow_mut();
+        let SubscriptionsState {
+            subscriptions,
+            events,
+            next_event_id,
+            ..
+        } = &mut *s;
+        let subscription = subscriptions.get_mut(&id)?;
+        if subscription.in_flight || subscription.next_attempt > now {
+            return None;
+        }
+        let batch: Vec<BalanceEvent> = events
+            .iter()
+            .filter(|e| e.id >= subscription.cursor && subscription.matches(&e.account))
+            .take(MAX_EVENTS_PER_DELIVERY)
+            .cloned()
+            .collect();
+        if batch.is_empty() {
+            subscription.cursor = *next_event_id;
+            return None;
+        }
+        subscription.in_flight = true;
+        Some((subscription.subscriber, subscription.method.clone(), batch))
+    })
+}
+
+fn finish_delivery(id: u64, last_event: u64, result: Result<()>) {
+    SUBSCRIPTIONS.with(|s| {
+        // the subscription is gone if it was cancelled during the delivery
+        if let Some(subscription) = s.borrow_mut().subscriptions.get_mut(&id) {
+            subscription.in_flight = false;
+            match result {
+                Ok(()) => {
+                    subscription.cursor = last_event + 1;
+                    subscription.failures = 0;
+                    subscription.last_error = None;
+                }
+                Err(error) => {
+                    subscription.failures += 1;
+                    subscription.last_error = Some(format!("{:?}", error));
+                    let delay = RETRY_BASE_DELAY
+                        .checked_shl(subscription.failures - 1)
+                        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+                    subscription.next_attempt = env::time() + delay;
+                }
+            }
+        }
+    });
+}
+
+async fn deliver(id: u64, subscriber: Principal, method: String, batch: Vec<BalanceEvent>) {
+    let last_event = batch.last().expect("empty batch").id;
+    let result: Result<()> = env::call(subscriber, &method, (batch, ))
+        .await
+        .map_err(|err| err.into());
+    if let Err(error) = &result {
+        log::warning("deliverBalanceEvents", log::digest((id, )), error);
+    }
+    finish_delivery(id, last_event, result);
+}
+
+pub async fn heartbeat() {
+    if assert_not_paused(PauseScope::Callbacks).is_err() {
+        return;
+    }
+    let now = env::time();
+    let start = NEXT_DELIVERY.with(|next| next.get());
+    let ids: Vec<u64> = SUBSCRIPTIONS.with(|s| {
+        let s = s.borrow();
+        s.subscriptions
+            .range(start..)
+            .chain(s.subscriptions.range(..start))
+            .map(|(&id, _)| id)
+            .collect()
+    });
+    let mut deliveries = vec![];
+    for id in ids {
+        if deliveries.len() == MAX_DELIVERIES_PER_HEARTBEAT {
+            NEXT_DELIVERY.with(|next| next.set(id));
+            break;
+        }
+        if let Some((subscriber, method, batch)) = start_delivery(id, now) {
+            deliveries.push(deliver(id, subscriber, method, batch));
+        }
+    }
+    prune_events();
+    futures::future::join_all(deliveries).await;
+    prune_events();
+}
@@ -1,45 +1,221 @@
-use candid::{CandidType, Deserialize};
-use ic_cdk_macros::*;
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Deserialize, Principal};
+use serde::de::DeserializeOwned;
+use enoki_wrapped_token_macros::*;
 
-use crate::{balances, fees, management};
+use enoki_wrapped_token_shared::log::{self, EventLog};
+use enoki_wrapped_token_shared::types::PauseScope;
+
+use crate::{
+    balances, decommission, escrow, fees, freeze, management, metrics, notifications, pause,
+    snapshots, subscriptions,
+};
 use crate::balances::ShardSpenders;
-use crate::stable::{StableFeeBalance, StableManagerContractData, StableShardBalances};
+use crate::fees::FeeDistribution;
+use crate::freeze::FreezeState;
+use crate::metrics::Counters;
+use crate::notifications::NotificationsState;
+use crate::snapshots::SnapshotsState;
+use crate::subscriptions::SubscriptionsState;
+use crate::stable::{
+    LegacyEscrowState, LegacyNotificationsState, LegacyShardBalances, StableEscrowState, StableFeeBalance,
+    StableFeeDistribution, StableManagerContractData, StableShardBalances,
+};
 
-#[derive(Deserialize, CandidType)]
-struct UpgradePayload {
+/// Version of the layout of `UpgradePayload`, saved with it since version 16. It counts the
+/// changes to the layout since the first release; earlier payloads are told apart by their types
+/// in `decode_payload`.
+pub const PAYLOAD_VERSION: u32 = 16;
+
+/// The state saved across upgrades. Fields added since the first release are optional, so that
+/// the payload saved by an earlier version still decodes, and start empty when it lacks them.
+#[derive(Deserialize, CandidType, Clone)]
+pub struct UpgradePayload {
+    version: Option<u32>,
     shard_balances: StableShardBalances,
     shard_spenders: ShardSpenders,
+    escrow: Option<StableEscrowState>,
     fee_balance: StableFeeBalance,
+    fee_distribution: Option<StableFeeDistribution>,
     manager_data: StableManagerContractData,
+    paused: Option<Vec<PauseScope>>,
+    freeze_state: Option<FreezeState>,
+    notifications: Option<NotificationsState>,
+    subscriptions: Option<SubscriptionsState>,
+    counters: Option<Counters>,
+    logs: Option<EventLog>,
+    draining: Option<bool>,
+    snapshots: Option<SnapshotsState>,
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
+impl UpgradePayload {
+    /// The main contract of the saved shard, if it was administered by governance proposals.
+    pub fn governed_by(&self) -> Option<Principal> {
+        let data = &self.manager_data;
+        data.governed.unwrap_or_default().then_some(data.manager_contract)
+    }
+
+    /// The version that saved the payload, or `None` if it predates the tag.
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
+
+/// The payload saved before balances were keyed by subaccount (before version 9), with the
+/// notifications of the time: `NotificationsState`, or `LegacyNotificationsState` from before
+/// their data was a blob (before version 7).
+#[derive(Deserialize, CandidType)]
+struct LegacyUpgradePayload<N> {
+    shard_balances: LegacyShardBalances,
+    shard_spenders: ShardSpenders,
+    escrow: Option<LegacyEscrowState>,
+    fee_balance: StableFeeBalance,
+    fee_distribution: Option<StableFeeDistribution>,
+    manager_data: StableManagerContractData,
+    paused: Option<Vec<PauseScope>>,
+    freeze_state: Option<FreezeState>,
+    notifications: Option<N>,
+    subscriptions: Option<SubscriptionsState>,
+}
+
+impl<N: Into<NotificationsState>> From<LegacyUpgradePayload<N>> for UpgradePayload {
+    fn from(payload: LegacyUpgradePayload<N>) -> Self {
+        Self {
+            version: None,
+            shard_balances: payload.shard_balances.into(),
+            shard_spenders: payload.shard_spenders,
+            escrow: payload.escrow.map(Into::into),
+            fee_balance: payload.fee_balance,
+            fee_distribution: payload.fee_distribution,
+            manager_data: payload.manager_data,
+            paused: payload.paused,
+            freeze_state: payload.freeze_state,
+            notifications: payload.notifications.map(Into::into),
+            subscriptions: payload.subscriptions,
+            counters: None,
+            logs: None,
+            draining: None,
+            snapshots: None,
+        }
+    }
+}
+
+/// Takes the whole shard state out of the thread locals.
+pub fn export_state() -> UpgradePayload {
     let (shard_balances, shard_spenders) = balances::export_stable_storage();
-    let (fee_balance, ) = fees::export_stable_storage();
+    let (escrow, ) = escrow::export_stable_storage();
+    let (fee_balance, fee_distribution) = fees::export_stable_storage();
     let (manager_data, ) = management::export_stable_storage();
-    let payload = UpgradePayload {
+    let (paused, ) = pause::export_stable_storage();
+    let (freeze_state, ) = freeze::export_stable_storage();
+    let (notifications, ) = notifications::export_stable_storage();
+    let (subscriptions, ) = subscriptions::export_stable_storage();
+    let (counters, ) = metrics::export_stable_storage();
+    let (logs, ) = log::export_stable_storage();
+    let (draining, ) = decommission::export_stable_storage();
+    let (snapshots, ) = snapshots::export_stable_storage();
+    UpgradePayload {
+        version: Some(PAYLOAD_VERSION),
         shard_balances,
         shard_spenders,
+        escrow: Some(escrow),
         fee_balance,
+        fee_distribution: Some(fee_distribution),
         manager_data,
-    };
-    ic_cdk::storage::stable_save((payload, )).expect("failed to save to stable storage");
+        paused: Some(paused),
+        freeze_state: Some(freeze_state),
+        notifications: Some(notifications),
+        subscriptions: Some(subscriptions),
+        counters: Some(counters),
+        logs: Some(logs),
+        draining: Some(draining),
+        snapshots: Some(snapshots),
+    }
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    let (payload, ): (UpgradePayload, ) =
-        ic_cdk::storage::stable_restore().expect("failed to restore from stable storage");
-
+pub fn import_state(payload: UpgradePayload) {
     let UpgradePayload {
+        version: _,
         shard_balances,
         shard_spenders,
+        escrow,
         fee_balance,
+        fee_distribution,
         manager_data,
+        paused,
+        freeze_state,
+        notifications,
+        subscriptions,
+        counters,
+        logs,
+        draining,
+        snapshots,
     } = payload;
 
     balances::import_stable_storage(shard_balances, shard_spenders);
-    fees::import_stable_storage(fee_balance);
+    escrow::import_stable_storage(escrow.unwrap_or_default());
+    let fee_distribution =
+        fee_distribution.unwrap_or_else(|| FeeDistribution::default().into());
+    fees::import_stable_storage(fee_balance, fee_distribution);
     management::import_stable_storage(manager_data);
+    pause::import_stable_storage(paused.unwrap_or_default());
+    freeze::import_stable_storage(freeze_state.unwrap_or_default());
+    notifications::import_stable_storage(notifications.unwrap_or_default());
+    subscriptions::import_stable_storage(subscriptions.unwrap_or_default());
+    metrics::import_stable_storage(counters.unwrap_or_default());
+    log::import_stable_storage(logs.unwrap_or_default());
+    decommission::import_stable_storage(draining.unwrap_or_default());
+    snapshots::import_stable_storage(snapshots.unwrap_or_default());
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let payload = export_state();
+    ic_cdk::storage::stable_save((payload, )).expect("failed to save to stable storage");
+}
+
+/// Decodes the payload saved by `pre_upgrade` of this version or of an earlier one.
+///
+/// A tagged payload has the current layout. Untagged ones are tried in a fixed order, newest
+/// layout first. Candid checks the types of required fields before any value, so legacy balances
+/// never decode as the current ones, even when empty. An optional field of another type decodes
+/// as missing instead, so the two legacy layouts, which only differ in their notifications, are
+/// told apart by whether the text notifications decode.
+pub fn decode_payload(stable: &[u8]) -> candid::Result<UpgradePayload> {
+    // stable memory is read whole, so whatever follows the payload is left undecoded
+    let error = match IDLDeserialize::new(stable)?.get_value::<UpgradePayload>() {
+        Ok(payload) => match payload.version {
+            Some(version) if version > PAYLOAD_VERSION => {
+                return Err(candid::Error::msg(format!(
+                    "payload version {} is newer than {}",
+                    version, PAYLOAD_VERSION
+                )));
+            }
+            // versions 9 to 15 lack the tag
+            _ => return Ok(payload),
+        },
+        Err(error) => error,
+    };
+    // versions 7 and 8 hold blob notifications, earlier ones text notifications or none, which
+    // both legacy layouts decode the same
+    match decode_legacy::<LegacyNotificationsState>(stable) {
+        Ok(legacy) if legacy.notifications.is_some() => Ok(legacy.into()),
+        _ => decode_legacy::<NotificationsState>(stable)
+            .map(Into::into)
+            .map_err(|_| error),
+    }
+}
+
+fn decode_legacy<N>(stable: &[u8]) -> candid::Result<LegacyUpgradePayload<N>>
+where
+    N: CandidType + DeserializeOwned,
+{
+    IDLDeserialize::new(stable)?.get_value()
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let payload = decode_payload(&ic_cdk::api::stable::stable_bytes())
+        .expect("failed to restore from stable storage");
+    import_state(payload);
 }
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Deserialize, Nat};
+use sha2::{Digest, Sha256};
+
+use crate::types::{ByteBuf, Result, TxError};
+
+/// Version of the backup format, increased whenever the state of either canister changes
+/// shape. Backups of other versions are refused.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// Bytes per chunk, well below the size limit of a reply.
+pub const BACKUP_CHUNK_SIZE: u64 = 1_000_000;
+
+/// Describes a backup of a canister's whole state: its format, how it is split into chunks, and
+/// what it holds, to verify it against once restored.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub format_version: u32,
+    pub timestamp: u64,
+    /// Bytes of the encoded state.
+    pub size: u64,
+    pub chunk_size: u64,
+    /// SHA-256 of the encoded state.
+    pub checksum: ByteBuf,
+    /// Totals of the state, such as its balances or accounts, by name.
+    pub totals: Vec<(String, Nat)>,
+}
+
+impl BackupManifest {
+    pub fn chunk_count(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size)
+    }
+}
+
+/// A backup taken by `beginBackup`, read with `getBackupChunk`.
+pub struct Backup {
+    pub manifest: BackupManifest,
+    bytes: Vec<u8>,
+}
+
+impl Backup {
+    pub fn new(bytes: Vec<u8>, timestamp: u64, totals: Vec<(String, Nat)>) -> Self {
+        let manifest = BackupManifest {
+            format_version: BACKUP_FORMAT_VERSION,
+            timestamp,
+            size: bytes.len() as u64,
+            chunk_size: BACKUP_CHUNK_SIZE,
+            checksum: ByteBuf::from(Sha256::digest(&bytes).to_vec()),
+            totals,
+        };
+        Self { manifest, bytes }
+    }
+
+    pub fn chunk(&self, index: u64) -> Result<ByteBuf> {
+        if index >= self.manifest.chunk_count() {
+            return Err(TxError::Other(format!("Backup has no chunk {}", index)));
+        }
+        let start = (index * self.manifest.chunk_size) as usize;
+        let end = (start + self.manifest.chunk_size as usize).min(self.bytes.len());
+        Ok(ByteBuf::from(self.bytes[start..end].to_vec()))
+    }
+}
+
+/// A backup being uploaded with `putRestoreChunk`, in any order.
+pub struct Restore {
+    pub manifest: BackupManifest,
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Restore {
+    pub fn new(manifest: BackupManifest) -> Result<Self> {
+        if manifest.format_version != BACKUP_FORMAT_VERSION {
+            return Err(TxError::Other(format!(
+                "Backup format {} is not supported, expected {}",
+                manifest.format_version, BACKUP_FORMAT_VERSION
+            )));
+        }
+        if manifest.chunk_size == 0 {
+            return Err(TxError::Other("Backup chunks are empty".to_string()));
+        }
+        Ok(Self {
+            manifest,
+            chunks: BTreeMap::new(),
+        })
+    }
+
+    pub fn put(&mut self, index: u64, chunk: Vec<u8>) -> Result<()> {
+        let count = self.manifest.chunk_count();
+        if index >= count {
+            return Err(TxError::Other(format!("Backup has no chunk {} of {}", index, count)));
+        }
+        let expected = if index + 1 == count {
+            self.manifest.size - index * self.manifest.chunk_size
+        } else {
+            self.manifest.chunk_size
+        };
+        if chunk.len() as u64 != expected {
+            return Err(TxError::Other(format!(
+                "Chunk {} of {} should have {} bytes",
+                index, count, expected
+            )));
+        }
+        self.chunks.insert(index, chunk);
+        Ok(())
+    }
+
+    /// The encoded state, once every chunk was uploaded and matches the checksum.
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        let missing = self.manifest.chunk_count() - self.chunks.len() as u64;
+        if missing > 0 {
+            return Err(TxError::Other(format!("{} chunks are missing", missing)));
+        }
+        let bytes: Vec<u8> = self.chunks.values().flatten().copied().collect();
+        if Sha256::digest(&bytes).as_slice() != self.manifest.checksum.as_slice() {
+            return Err(TxError::Other("Backup does not match its checksum".to_string()));
+        }
+        Ok(bytes)
+    }
+}
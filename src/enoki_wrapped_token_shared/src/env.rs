@@ -0,0 +1,149 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, Principal};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+
+pub type CallFuture = Pin<Box<dyn Future<Output = CallResult<Vec<u8>>>>>;
+
+/// Everything canister logic needs from the system API. On the IC this is `CanisterEnvironment`;
+/// tests can install another implementation to run the canisters natively.
+pub trait Environment {
+    fn caller(&self) -> Principal;
+    fn id(&self) -> Principal;
+    fn time(&self) -> u64;
+    fn canister_balance(&self) -> u64;
+    /// Instructions executed so far by the current message.
+    fn instruction_counter(&self) -> u64 {
+        0
+    }
+    fn call_raw(&self, id: Principal, method: &str, args: Vec<u8>) -> CallFuture {
+        self.call_with_payment(id, method, args, 0)
+    }
+    /// Calls `id`, attaching `cycles` taken from the canister's balance. Cycles the callee does
+    /// not accept are refunded.
+    fn call_with_payment(
+        &self,
+        id: Principal,
+        method: &str,
+        args: Vec<u8>,
+        cycles: u64,
+    ) -> CallFuture;
+    /// Moves up to `max` of the cycles attached to the current call to the canister's balance,
+    /// returning the amount moved.
+    fn msg_cycles_accept(&self, max: u64) -> u64;
+}
+
+pub struct CanisterEnvironment;
+
+impl Environment for CanisterEnvironment {
+    fn caller(&self) -> Principal {
+        ic_cdk::caller()
+    }
+
+    fn id(&self) -> Principal {
+        ic_cdk::id()
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn canister_balance(&self) -> u64 {
+        ic_cdk::api::canister_balance()
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn instruction_counter(&self) -> u64 {
+        #[link(wasm_import_module = "ic0")]
+        extern "C" {
+            fn performance_counter(counter_type: u32) -> u64;
+        }
+        unsafe { performance_counter(0) }
+    }
+
+    fn call_with_payment(
+        &self,
+        id: Principal,
+        method: &str,
+        args: Vec<u8>,
+        cycles: u64,
+    ) -> CallFuture {
+        Box::pin(ic_cdk::api::call::call_raw(id, method, args, cycles))
+    }
+
+    fn msg_cycles_accept(&self, max: u64) -> u64 {
+        ic_cdk::api::call::msg_cycles_accept(max)
+    }
+}
+
+thread_local! {
+    static ENVIRONMENT: RefCell<Rc<dyn Environment>> = RefCell::new(Rc::new(CanisterEnvironment));
+}
+
+pub fn set_environment(environment: Rc<dyn Environment>) {
+    ENVIRONMENT.with(|e| e.replace(environment));
+}
+
+fn environment() -> Rc<dyn Environment> {
+    ENVIRONMENT.with(|e| e.borrow().clone())
+}
+
+pub fn caller() -> Principal {
+    environment().caller()
+}
+
+pub fn id() -> Principal {
+    environment().id()
+}
+
+pub fn time() -> u64 {
+    environment().time()
+}
+
+pub fn canister_balance() -> u64 {
+    environment().canister_balance()
+}
+
+pub fn instruction_counter() -> u64 {
+    environment().instruction_counter()
+}
+
+pub fn call_raw(id: Principal, method: &str, args: Vec<u8>) -> CallFuture {
+    environment().call_raw(id, method, args)
+}
+
+pub fn msg_cycles_accept(max: u64) -> u64 {
+    environment().msg_cycles_accept(max)
+}
+
+/// Same as `ic_cdk::call`, but routed through the installed environment.
+pub async fn call<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    id: Principal,
+    method: &str,
+    args: T,
+) -> CallResult<R> {
+    call_with_payment(id, method, args, 0).await
+}
+
+/// Same as `ic_cdk::api::call::call_with_payment`, but routed through the installed environment.
+pub async fn call_with_payment<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    id: Principal,
+    method: &str,
+    args: T,
+    cycles: u64,
+) -> CallResult<R> {
+    let args_raw = encode_args(args).expect("failed to encode arguments");
+    let bytes = environment()
+        .call_with_payment(id, method, args_raw, cycles)
+        .await?;
+    decode_args(&bytes).map_err(|err| {
+        (
+            RejectionCode::CanisterError,
+            format!("failed to decode response: {}", err),
+        )
+    })
+}
@@ -1 +1,6 @@
-pub mod types;
\ No newline at end of file
+pub mod backup;
+pub mod env;
+pub mod log;
+pub mod metrics;
+pub mod routing;
+pub mod types;
@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+use candid::utils::ArgumentEncoder;
+use candid::{encode_args, CandidType, Deserialize, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::env;
+
+/// Entries kept by each canister. Older entries are dropped, which readers can detect from the
+/// gap in entry ids.
+const MAX_LOG_ENTRIES: usize = 1_000;
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum LogOutcome {
+    /// Logged before a call whose failure would trap and roll the log back with it.
+    Started,
+    Succeeded,
+    Failed(String),
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct LogEntry {
+    pub id: u64,
+    pub time: u64,
+    pub level: LogLevel,
+    pub operation: String,
+    pub caller: Principal,
+    /// See `digest`.
+    pub args_digest: String,
+    pub outcome: LogOutcome,
+    /// Instructions executed by the message that logged the entry, i.e. since the last await
+    /// of an async operation.
+    pub instructions: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct EventLog {
+    next_id: u64,
+    entries: VecDeque<LogEntry>,
+}
+
+thread_local! {
+    static LOG: RefCell<EventLog> = RefCell::new(EventLog::default());
+}
+
+pub fn export_stable_storage() -> (EventLog, ) {
+    (LOG.with(|l| l.take()), )
+}
+
+pub fn import_stable_storage(log: EventLog) {
+    LOG.with(|l| l.replace(log));
+}
+
+/// First 8 bytes of the SHA-256 of the candid encoding of `args`, in hex. A canister forwarding
+/// an operation to another one logs the digest of the arguments it sends, so that both entries
+/// can be matched.
+pub fn digest<A: ArgumentEncoder>(args: A) -> String {
+    let bytes = encode_args(args).expect("failed to encode arguments");
+    Sha256::digest(&bytes)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn log(level: LogLevel, operation: &str, args_digest: String, outcome: LogOutcome) {
+    let entry = LogEntry {
+        id: 0,
+        time: env::time(),
+        level,
+        operation: operation.to_string(),
+        caller: env::caller(),
+        args_digest,
+        outcome,
+        instructions: env::instruction_counter(),
+    };
+    LOG.with(|l| {
+        let mut l = l.borrow_mut();
+        let id = l.next_id;
+        l.next_id += 1;
+        l.entries.push_back(LogEntry { id, ..entry });
+        if l.entries.len() > MAX_LOG_ENTRIES {
+            l.entries.pop_front();
+        }
+    })
+}
+
+pub fn started(operation: &str, args_digest: String) {
+    log(LogLevel::Debug, operation, args_digest, LogOutcome::Started);
+}
+
+pub fn succeeded(operation: &str, args_digest: String) {
+    log(LogLevel::Info, operation, args_digest, LogOutcome::Succeeded);
+}
+
+/// Logs the result of an operation: `Info` if it succeeded, `Error` otherwise.
+pub fn finished<T, E: Debug>(operation: &str, args_digest: String, result: &Result<T, E>) {
+    match result {
+        Ok(_) => succeeded(operation, args_digest),
+        Err(error) => log(
+            LogLevel::Error,
+            operation,
+            args_digest,
+            LogOutcome::Failed(format!("{:?}", error)),
+        ),
+    }
+}
+
+/// A failure that will be retried.
+pub fn warning(operation: &str, args_digest: String, error: impl Debug) {
+    log(
+        LogLevel::Warning,
+        operation,
+        args_digest,
+        LogOutcome::Failed(format!("{:?}", error)),
+    );
+}
+
+/// Entries from id `since` with at least the given level, oldest first.
+pub fn get_logs(since: u64, level: LogLevel) -> Vec<LogEntry> {
+    LOG.with(|l| {
+        l.borrow()
+            .entries
+            .iter()
+            .filter(|e| e.id >= since && e.level >= level)
+            .cloned()
+            .collect()
+    })
+}
@@ -0,0 +1,154 @@
+use std::fmt::{Display, Write};
+
+use candid::{CandidType, Deserialize, Nat};
+
+use crate::env;
+use crate::types::ByteBuf;
+
+/// Argument of the `http_request` query, sent by the HTTP gateway.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+impl HttpResponse {
+    fn text(status_code: u16, content_type: &str, body: String) -> Self {
+        Self {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+            ],
+            body: ByteBuf::from(body.into_bytes()),
+        }
+    }
+}
+
+/// Writes metrics in the Prometheus text exposition format. Every sample is stamped with the
+/// canister time, so that scrapes of a stale replica are not mistaken for current values.
+pub struct MetricsEncoder {
+    out: String,
+    timestamp_ms: u64,
+}
+
+impl MetricsEncoder {
+    pub fn new() -> Self {
+        Self {
+            out: String::new(),
+            timestamp_ms: env::time() / 1_000_000,
+        }
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: impl Display) {
+        writeln!(self.out, "{}{} {} {}", name, labels, value, self.timestamp_ms).unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.header(name, "gauge", help);
+        self.sample(name, "", value);
+    }
+
+    /// Counters are named `<name>_total`.
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        let name = format!("{}_total", name);
+        self.header(&name, "counter", help);
+        self.sample(&name, "", value);
+    }
+
+    /// A gauge with one sample for each value of `label`.
+    pub fn labeled_gauge<V: Display>(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        samples: impl IntoIterator<Item = (String, V)>,
+    ) {
+        self.header(name, "gauge", help);
+        for (label_value, value) in samples {
+            self.sample(name, &format!("{{{}=\"{}\"}}", label, label_value), value);
+        }
+    }
+
+    /// Token amounts, without the digit separators of `Nat`'s `Display`.
+    pub fn amount(&mut self, name: &str, help: &str, value: &Nat) {
+        self.gauge(name, help, &value.0);
+    }
+
+    /// Memory and cycles, reported by both canisters.
+    pub fn canister_metrics(&mut self) {
+        self.gauge(
+            "enoki_heap_memory_bytes",
+            "Size of the wasm heap.",
+            heap_memory_size(),
+        );
+        self.gauge(
+            "enoki_stable_memory_bytes",
+            "Size of the stable memory.",
+            stable_memory_size(),
+        );
+        self.gauge(
+            "enoki_cycle_balance",
+            "Cycles held by the canister.",
+            env::canister_balance(),
+        );
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+impl Default for MetricsEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_size() -> u64 {
+    core::arch::wasm32::memory_size::<0>() as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_size() -> u64 {
+    0
+}
+
+#[cfg(target_arch = "wasm32")]
+fn stable_memory_size() -> u64 {
+    ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn stable_memory_size() -> u64 {
+    0
+}
+
+/// Answers `GET /metrics` with the metrics written by `encode`, and anything else with a 404.
+pub fn serve_metrics(request: HttpRequest, encode: impl FnOnce(&mut MetricsEncoder)) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    if request.method != "GET" || path != "/metrics" {
+        return HttpResponse::text(404, "text/plain", "not found".to_string());
+    }
+    let mut encoder = MetricsEncoder::new();
+    encode(&mut encoder);
+    HttpResponse::text(200, "text/plain; version=0.0.4", encoder.finish())
+}
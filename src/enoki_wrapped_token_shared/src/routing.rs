@@ -0,0 +1,49 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Points each shard takes on the ring, so that accounts spread evenly over few shards.
+const POINTS_PER_SHARD: u8 = 64;
+
+/// Consistent hash of accounts over a set of shards, used by the hashed routing mode. Adding or
+/// removing a shard only moves the accounts between it and its neighbours on the ring.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ShardRing {
+    /// Points of every shard, sorted.
+    points: Vec<(u64, Principal)>,
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = Sha256::digest(bytes);
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+impl ShardRing {
+    pub fn new(shards: &[Principal]) -> Self {
+        let mut points: Vec<(u64, Principal)> = shards
+            .iter()
+            .flat_map(|shard| {
+                (0..POINTS_PER_SHARD).map(move |point| {
+                    let mut bytes = shard.as_slice().to_vec();
+                    bytes.push(point);
+                    (hash(&bytes), *shard)
+                })
+            })
+            .collect();
+        points.sort();
+        Self { points }
+    }
+
+    /// The shard holding the accounts of `owner`: the first point at or after its hash. `None`
+    /// if the ring is empty.
+    pub fn shard_for(&self, owner: &Principal) -> Option<Principal> {
+        let key = hash(owner.as_slice());
+        let index = self.points.partition_point(|&(point, _)| point < key);
+        self.points
+            .get(index)
+            .or_else(|| self.points.first())
+            .map(|&(_, shard)| shard)
+    }
+}
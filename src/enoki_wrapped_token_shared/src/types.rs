@@ -1,9 +1,13 @@
+use std::cmp::Ordering;
+use std::fmt;
 use std::string::String;
 
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::call::RejectionCode;
+use serde::Serialize;
+pub use serde_bytes::ByteBuf;
 
-#[derive(CandidType, Debug, Deserialize)]
+#[derive(CandidType, Clone, Debug, Deserialize)]
 pub enum TxError {
     InsufficientBalance,
     Unauthorized,
@@ -13,6 +17,18 @@ pub enum TxError {
     TransferValueTooSmall,
     TransferCallbackError(String),
     UnderlyingTransferFailure,
+    Paused,
+    AccountFrozen { user: String },
+    NotificationNotFound,
+    SubscriptionNotFound,
+    TooManySubscriptions,
+    BatchTooLarge,
+    /// The shard is being decommissioned, and its accounts moved to other shards.
+    ShardDraining,
+    /// A shard registry older than the one the shard holds, which has the given version.
+    StaleRegistry { version: u64 },
+    /// No snapshot was completed with this id.
+    SnapshotDoesNotExist,
     Other(String),
 }
 
@@ -24,12 +40,222 @@ impl From<(RejectionCode, String)> for TxError {
 
 pub type Result<T> = std::result::Result<T, TxError>;
 
-#[derive(CandidType, Debug, Deserialize)]
+pub type Subaccount = [u8; 32];
+
+/// A balance on a shard: a principal, and one of its subaccounts. Subaccounts live on the shard
+/// assigned to their owner. DIP20 methods use the default subaccount, `None`, which is the same
+/// as the all-zero subaccount.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Self {
+            owner,
+            subaccount: subaccount.filter(|subaccount| *subaccount != [0; 32]),
+        }
+    }
+
+    /// Maps the all-zero subaccount of accounts received in calls to the default one.
+    pub fn normalized(self) -> Self {
+        Self::new(self.owner, self.subaccount)
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.owner)?;
+        if let Some(subaccount) = &self.subaccount {
+            write!(f, ".")?;
+            for byte in subaccount {
+                write!(f, "{:02x}", byte)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
 pub struct ShardedTransferNotification {
     pub from: Principal,
     pub from_shard: Principal,
     pub to: Principal,
+    pub from_subaccount: Option<Subaccount>,
+    pub to_subaccount: Option<Subaccount>,
     pub fee_charged: Nat,
     pub value: Nat,
-    pub data: String,
+    pub data: ByteBuf,
+}
+
+impl ShardedTransferNotification {
+    pub fn from_account(&self) -> Account {
+        Account::new(self.from, self.from_subaccount)
+    }
+
+    pub fn to_account(&self) -> Account {
+        Account::new(self.to, self.to_subaccount)
+    }
+}
+
+/// What the notified canister returns from its notify method.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum NotificationResponse {
+    Accept,
+    /// Accepts only part of the value. The remainder is refunded to the sender.
+    PartialAccept(Nat),
+    Reject(String),
+}
+
+impl NotificationResponse {
+    /// Part of `value` that goes to the recipient, the rest is refunded.
+    pub fn accepted(&self, value: &Nat) -> Nat {
+        match self {
+            Self::Accept => value.clone(),
+            Self::PartialAccept(amount) => amount.min(value).clone(),
+            Self::Reject(_) => Nat::from(0),
+        }
+    }
+}
+
+/// Why an account was credited.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum CreditKind {
+    Transfer { from: Principal, from_shard: Principal },
+    Wrap,
+    /// Value returned after a failed or partially accepted transfer.
+    Refund,
+    FeeShare,
+}
+
+/// Sent to the subscribers of an account when it is credited.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct BalanceEvent {
+    pub id: u64,
+    pub account: Principal,
+    pub subaccount: Option<Subaccount>,
+    pub kind: CreditKind,
+    pub amount: Nat,
+    pub time: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct FeeBeneficiary {
+    pub id: Principal,
+    pub weight: u64,
+}
+
+impl FeeBeneficiary {
+    /// Sum of the weights of `beneficiaries`, `None` if it does not fit in a `u64`.
+    pub fn total_weight(beneficiaries: &[FeeBeneficiary]) -> Option<u64> {
+        beneficiaries
+            .iter()
+            .try_fold(0u64, |sum, beneficiary| sum.checked_add(beneficiary.weight))
+    }
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Role {
+    Admin,
+    FeeManager,
+    ShardOperator,
+    Pauser,
+}
+
+pub type RoleAssignments = Vec<(Principal, Vec<Role>)>;
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum PauseScope {
+    All,
+    Wrap,
+    Unwrap,
+    Transfers,
+    Callbacks,
+}
+
+/// Holders returned by one call to `getHolders`, at most.
+pub const MAX_HOLDERS_PAGE: u64 = 1_000;
+
+/// An owner and its balance, summed over its subaccounts.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct Holder {
+    pub owner: Principal,
+    pub balance: Nat,
+}
+
+/// Order in which `getHolders` lists holders: by owner, or by decreasing balance and then by
+/// owner.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub enum HolderOrder {
+    Owner,
+    Balance,
+}
+
+impl HolderOrder {
+    pub fn compare(self, a: &Holder, b: &Holder) -> Ordering {
+        match self {
+            Self::Owner => a.owner.cmp(&b.owner),
+            Self::Balance => b.balance.cmp(&a.balance).then(a.owner.cmp(&b.owner)),
+        }
+    }
+}
+
+/// Position in the holders of every shard: the last holder listed, and its shard, which orders
+/// the balances an owner holds on several shards.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct HoldersCursor {
+    pub holder: Holder,
+    pub shard: Principal,
+}
+
+impl HoldersCursor {
+    /// Whether `holder`, held by `shard`, comes after the cursor in `order`. In the owner order,
+    /// owners are listed once over all shards.
+    pub fn precedes(&self, order: HolderOrder, holder: &Holder, shard: Principal) -> bool {
+        match order.compare(holder, &self.holder) {
+            Ordering::Greater => true,
+            Ordering::Equal => order == HolderOrder::Balance && shard > self.shard,
+            Ordering::Less => false,
+        }
+    }
+}
+
+/// A page of holders. `next` is the cursor of the following page, set when this one is full.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HoldersPage {
+    pub holders: Vec<Holder>,
+    pub next: Option<HoldersCursor>,
+}
+
+/// Every shard of the token, as pushed by the main contract to each shard. The version grows
+/// with each change, so that a shard can tell an outdated registry from the current one.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ShardRegistry {
+    pub version: u64,
+    pub shards: Vec<Principal>,
+    /// Set in the hashed routing mode: the active shards, over which accounts are placed by a
+    /// `ShardRing`.
+    pub ring: Option<Vec<Principal>>,
+    /// Id of the latest snapshot, which shards added later hold no balance at.
+    pub latest_snapshot: u64,
+    /// Whether the main contract is administered by governance proposals, which restoring a
+    /// backup of the shard then goes through.
+    pub governed: bool,
+}
+
+/// Argument of the management canister's `deposit_cycles`.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize)]
+pub struct CanisterIdRecord {
+    pub canister_id: Principal,
 }
@@ -0,0 +1,380 @@
+use std::marker::PhantomData;
+
+use candid::{decode_args, encode_args, encode_one, CandidType, Nat, Principal};
+
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction};
+use enoki_wrapped_token_shared::backup::BackupManifest;
+use enoki_wrapped_token_shared::log::LogLevel;
+use enoki_wrapped_token_shared::metrics::HttpRequest;
+use enoki_wrapped_token_shared::types::*;
+
+use crate::simulator::{Canister, MethodFuture};
+
+/// Canister code that keeps its state in thread locals, like the token canisters.
+pub trait CanisterCode {
+    type State: Clone + CandidType;
+
+    fn export_state() -> Self::State;
+    fn import_state(state: Self::State);
+    /// Decodes the state saved to stable memory by this version of the code or an earlier one.
+    fn decode_state(stable: &[u8]) -> candid::Result<Self::State>;
+    fn init(args: Vec<u8>) -> MethodFuture;
+    fn dispatch(method: &str, args: Vec<u8>) -> Option<MethodFuture>;
+
+    fn heartbeat() -> Option<MethodFuture> {
+        None
+    }
+}
+
+/// One installed instance of some canister code. Several instances of the same code share the
+/// thread locals, so each instance loads its state before running and saves it afterwards.
+pub struct Instance<C: CanisterCode> {
+    state: C::State,
+    code: PhantomData<C>,
+}
+
+impl<C: CanisterCode> Default for Instance<C> {
+    fn default() -> Self {
+        Self {
+            // the thread locals are left empty after each execution
+            state: C::export_state(),
+            code: PhantomData,
+        }
+    }
+}
+
+impl<C: CanisterCode> Instance<C> {
+    pub fn state(&self) -> &C::State {
+        &self.state
+    }
+}
+
+impl<C: CanisterCode> Canister for Instance<C> {
+    fn dispatch(&mut self, method: &str, args: Vec<u8>) -> Option<MethodFuture> {
+        C::dispatch(method, args)
+    }
+
+    fn init(&mut self, args: Vec<u8>) -> Option<MethodFuture> {
+        Some(C::init(args))
+    }
+
+    fn heartbeat(&mut self) -> Option<MethodFuture> {
+        C::heartbeat()
+    }
+
+    fn enter(&mut self) {
+        C::import_state(self.state.clone());
+    }
+
+    fn exit(&mut self, commit: bool) {
+        let state = C::export_state();
+        if commit {
+            self.state = state;
+        }
+    }
+
+    fn pre_upgrade(&mut self) -> Vec<u8> {
+        encode_args((self.state.clone(), )).expect("failed to save the state")
+    }
+
+    fn post_upgrade(&mut self, stable: Vec<u8>) -> std::result::Result<(), String> {
+        self.state = C::decode_state(&stable).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+}
+
+macro_rules! endpoint {
+    (sync $func:path, $args:expr, ($($arg:ident: $ty:ty),*)) => {{
+        let args = $args;
+        Box::pin(async move {
+            let ($($arg,)*): ($($ty,)*) = decode_args(&args).expect("failed to decode arguments");
+            Ok(encode_one($func($($arg),*)).unwrap())
+        }) as MethodFuture
+    }};
+    (async $func:path, $args:expr, ($($arg:ident: $ty:ty),*)) => {{
+        let args = $args;
+        Box::pin(async move {
+            let ($($arg,)*): ($($ty,)*) = decode_args(&args).expect("failed to decode arguments");
+            Ok(encode_one($func($($arg),*).await).unwrap())
+        }) as MethodFuture
+    }};
+}
+
+/// Maps candid method names to the endpoints of a canister crate.
+macro_rules! endpoints {
+    ($method:expr, $args:expr, {
+        $($name:literal => $kind:ident $($func:ident)::+ ($($arg:ident: $ty:ty),*);)*
+    }) => {
+        match $method {
+            $($name => Some(endpoint!($kind $($func)::+, $args, ($($arg: $ty),*))),)*
+            _ => None,
+        }
+    };
+}
+
+pub struct Token;
+
+impl CanisterCode for Token {
+    type State = enoki_wrapped_token::upgrade::UpgradePayload;
+
+    fn export_state() -> Self::State {
+        enoki_wrapped_token::upgrade::export_state()
+    }
+
+    fn import_state(state: Self::State) {
+        enoki_wrapped_token::upgrade::import_state(state)
+    }
+
+    fn decode_state(stable: &[u8]) -> candid::Result<Self::State> {
+        enoki_wrapped_token::upgrade::decode_payload(stable)
+    }
+
+    fn init(args: Vec<u8>) -> MethodFuture {
+        endpoint!(sync enoki_wrapped_token::init, args, ())
+    }
+
+    fn dispatch(method: &str, args: Vec<u8>) -> Option<MethodFuture> {
+        use enoki_wrapped_token::*;
+        endpoints!(method, args, {
+            "finishInit" => sync finish_init(
+                underlying_token: Principal, logo: String, name: String, symbol: String,
+                decimals: u8, fee: Nat
+            );
+            "getLogo" => sync metadata::get_logo();
+            "name" => sync metadata::name();
+            "symbol" => sync metadata::symbol();
+            "decimals" => sync metadata::decimals();
+            "getMetadata" => sync metadata::get_metadata();
+            "setLogo" => sync metadata::set_logo(logo: String);
+            "stats" => async management::stats();
+            "http_request" => sync metrics::http_request(request: HttpRequest);
+            "getLogs" => sync management::get_logs(since: u64, level: LogLevel);
+            "owner" => sync management::get_owner();
+            "setFee" => async management::set_fee(fee: Nat);
+            "getFee" => sync management::get_fee();
+            "setFeeBeneficiaries" => async management::set_fee_beneficiaries(
+                beneficiaries: Vec<FeeBeneficiary>
+            );
+            "getFeeBeneficiaries" => sync management::get_fee_beneficiaries();
+            "proposeOwner" => sync management::propose_owner(owner: Principal);
+            "getPendingOwner" => sync management::get_pending_owner();
+            "acceptOwnership" => async management::accept_ownership();
+            "register" => async accounts::register(address: Principal);
+            "getAssignedShardId" => sync accounts::get_assigned_shard_id(address: Principal);
+            "transfer" => async accounts::transfer(to: Principal, amount: Nat);
+            "getPausedScopes" => sync pause::get_paused_scopes();
+            "pause" => async pause::pause(scope: PauseScope);
+            "unpause" => async pause::unpause(scope: PauseScope);
+            "freezeAccount" => async freeze::freeze_account(account: Principal);
+            "unfreezeAccount" => async freeze::unfreeze_account(account: Principal);
+            "setBlockFrozenRecipients" => async freeze::set_block_frozen_recipients(
+                block_recipients: bool
+            );
+            "getFrozenAccounts" => sync freeze::get_frozen_accounts();
+            "getFreezeEvents" => sync freeze::get_freeze_events(start: u64, limit: u64);
+            "configureGovernance" => async governance::configure_governance(
+                config: GovernanceConfig
+            );
+            "getGovernanceConfig" => sync governance::get_governance_config();
+            "submitProposal" => sync governance::submit_proposal(action: ProposalAction);
+            "approveProposal" => sync governance::approve_proposal(id: u64);
+            "cancelProposal" => sync governance::cancel_proposal(id: u64);
+            "executeProposal" => async governance::execute_proposal(id: u64);
+            "getProposal" => sync governance::get_proposal(id: u64);
+            "getProposals" => sync governance::get_proposals(start: u64, limit: u64);
+            "getShardIds" => sync shards::get_shard_ids();
+            "getShardIdsUpdate" => sync shards::get_shard_ids_update();
+            "getShardsInfo" => sync shards::get_shards_info();
+            "getCyclesConfig" => sync cycles::get_cycles_config();
+            "setCyclesConfig" => sync cycles::set_cycles_config(config: cycles::CyclesConfig);
+            "getCyclesPool" => sync cycles::get_cycles_pool();
+            "depositCycles" => sync cycles::deposit_cycles();
+            "startDecommission" => async decommission::start_decommission(id: Principal);
+            "cancelDecommission" => async decommission::cancel_decommission(id: Principal);
+            "drainShard" => async decommission::drain_shard(id: Principal);
+            "finishDecommission" => async decommission::finish_decommission(id: Principal);
+            "totalSupply" => async shards::total_supply();
+            "getHolders" => async shards::get_holders(
+                order: HolderOrder, after: Option<HoldersCursor>, limit: u64
+            );
+            "getAccruedFees" => async shards::get_accrued_fees();
+            "balanceOf" => async shards::balance_of(id: Principal);
+            "addShard" => async shards::add_shard(id: Principal);
+            "fixSiblings" => async shards::fix_siblings();
+            "getShardRegistry" => sync shards::get_shard_registry();
+            "getRoutingMode" => sync routing::get_routing_mode();
+            "setRoutingMode" => async routing::set_routing_mode(mode: routing::RoutingMode);
+            "rebalanceShard" => async routing::rebalance_shard(id: Principal);
+            "takeSnapshot" => async snapshots::take_snapshot();
+            "getSnapshots" => sync snapshots::get_snapshots();
+            "balanceAtSnapshot" => async snapshots::balance_at_snapshot(owner: Principal, id: u64);
+            "grantRole" => async roles::grant_role(user: Principal, role: Role);
+            "revokeRole" => async roles::revoke_role(user: Principal, role: Role);
+            "getRoles" => sync roles::get_roles(user: Principal);
+            "beginBackup" => sync backup::begin_backup();
+            "getBackupChunk" => sync backup::get_backup_chunk(index: u64);
+            "beginRestore" => sync backup::begin_restore(manifest: BackupManifest);
+            "putRestoreChunk" => sync backup::put_restore_chunk(index: u64, chunk: ByteBuf);
+            "finishRestore" => sync backup::finish_restore();
+        })
+    }
+
+    fn heartbeat() -> Option<MethodFuture> {
+        Some(Box::pin(async {
+            enoki_wrapped_token::heartbeat().await;
+            Ok(vec![])
+        }))
+    }
+}
+
+pub struct Shard;
+
+impl CanisterCode for Shard {
+    type State = enoki_wrapped_token_shard::upgrade::UpgradePayload;
+
+    fn export_state() -> Self::State {
+        enoki_wrapped_token_shard::upgrade::export_state()
+    }
+
+    fn import_state(state: Self::State) {
+        enoki_wrapped_token_shard::upgrade::import_state(state)
+    }
+
+    fn decode_state(stable: &[u8]) -> candid::Result<Self::State> {
+        enoki_wrapped_token_shard::upgrade::decode_payload(stable)
+    }
+
+    fn init(args: Vec<u8>) -> MethodFuture {
+        endpoint!(sync enoki_wrapped_token_shard::init, args, ())
+    }
+
+    fn dispatch(method: &str, args: Vec<u8>) -> Option<MethodFuture> {
+        use enoki_wrapped_token_shard::*;
+        endpoints!(method, args, {
+            "finishInit" => sync finish_init(manager_contract: Principal, underlying_token: Principal);
+            "getAccruedFees" => sync fees::get_accrued_fees();
+            "getFeeDistribution" => sync fees::get_fee_distribution();
+            "setFeeBeneficiaries" => sync fees::set_fee_beneficiaries(
+                beneficiaries: Vec<FeeBeneficiary>
+            );
+            "shardReceiveFeeShare" => async fees::receive_fee_share(
+                beneficiary: Principal, value: Nat, epoch: u64
+            );
+            "getManagementDetails" => sync management::get_management_details();
+            "getOwner" => sync management::get_owner();
+            "setOwner" => sync management::set_owner(new_owner: Principal);
+            "getFee" => sync management::get_fee();
+            "setFee" => sync management::set_fee(new_fee: Nat);
+            "setRoles" => sync management::set_roles(roles: RoleAssignments);
+            "getRoles" => sync management::get_roles(user: Principal);
+            "initShard" => sync management::init_shard(
+                underlying_token: Principal, registry: ShardRegistry, fee: Nat
+            );
+            "setShardRegistry" => sync management::set_shard_registry(registry: ShardRegistry);
+            "createAccount" => sync balances::create_account(account: Principal);
+            "shardReceiveTransfer" => async balances::receive_transfer(
+                from: Account, to: Account, value: Nat, epoch: u64
+            );
+            "shardReceiveTransferBatch" => async balances::receive_transfer_batch(
+                from: Account, transfers: Vec<(Account, Nat)>, epoch: u64
+            );
+            "shardReceiveTransferAndCall" => async balances::receive_transfer_and_call(
+                notification: ShardedTransferNotification, notify_principal: Principal,
+                notify_method: String, epoch: u64
+            );
+            "shardTransfer" => async balances::transfer(
+                to_shard: Principal, to: Principal, value: Nat
+            );
+            "shardAccountTransfer" => async balances::account_transfer(
+                from_subaccount: Option<Subaccount>, to_shard: Principal, to: Account, value: Nat
+            );
+            "shardBatchTransfer" => async balances::batch_transfer(
+                transfers: Vec<(Principal, Principal, Nat)>
+            );
+            "transferFromManager" => async balances::transfer_from_manager(
+                from: Principal, to_shard: Principal, to: Principal, value: Nat
+            );
+            "addSpender" => async balances::add_spender(account: Principal);
+            "removeSpender" => async balances::remove_spender(account: Principal);
+            "shardSpend" => async balances::spend(
+                from: Principal, to_shard: Principal, to: Principal, value: Nat
+            );
+            "shardTransferAndCall" => async balances::transfer_and_call(
+                shard_id: Principal, to: Principal, value: Nat, notify_principal: Principal,
+                notify_method: String, data: ByteBuf
+            );
+            "shardAccountTransferAndCall" => async balances::account_transfer_and_call(
+                from_subaccount: Option<Subaccount>, shard_id: Principal, to: Account, value: Nat,
+                notify_principal: Principal, notify_method: String, data: ByteBuf
+            );
+            "shardSpendAndCall" => async balances::spend_and_call(
+                from: Principal, shard_id: Principal, to: Principal, value: Nat,
+                notify_principal: Principal, notify_method: String, data: ByteBuf
+            );
+            "shardGetSupply" => sync balances::shard_get_supply();
+            "shardGetCycles" => sync management::shard_get_cycles();
+            "getHolders" => sync balances::get_holders(start: u64, limit: u64);
+            "shardGetHolders" => sync balances::get_holders_after(
+                order: HolderOrder, after: Option<HoldersCursor>, limit: u64
+            );
+            "shardGetAccountOwners" => sync balances::get_account_owners();
+            "isDraining" => sync decommission::is_draining();
+            "setDraining" => sync decommission::set_draining(draining: bool);
+            "shardMigrateAccounts" => async decommission::migrate_accounts(
+                to_shard: Principal, owners: Vec<Principal>
+            );
+            "shardImportAccounts" => async decommission::import_accounts(
+                accounts: Vec<decommission::MigratedAccount>, accrued_fees: Nat
+            );
+            "shardFinishDecommission" => async decommission::finish_decommission(
+                to_shard: Principal
+            );
+            "shardTakeSnapshot" => sync snapshots::take_snapshot(id: u64);
+            "shardBalanceAtSnapshot" => sync snapshots::balance_at_snapshot(
+                owner: Principal, id: u64
+            );
+            "beginBackup" => sync backup::begin_backup();
+            "getBackupChunk" => sync backup::get_backup_chunk(index: u64);
+            "beginRestore" => sync backup::begin_restore(manifest: BackupManifest);
+            "putRestoreChunk" => sync backup::put_restore_chunk(index: u64, chunk: ByteBuf);
+            "finishRestore" => sync backup::finish_restore();
+            "http_request" => sync metrics::http_request(request: HttpRequest);
+            "getLogs" => sync management::get_logs(since: u64, level: LogLevel);
+            "getHeldTransfers" => sync escrow::get_held_transfers();
+            "refundHeldTransfer" => sync escrow::refund_held_transfer(id: u64);
+            "shardBalanceOf" => sync balances::balance_of(account: Principal);
+            "shardAccountBalanceOf" => sync balances::account_balance_of(account: Account);
+            "getPausedScopes" => sync pause::get_paused_scopes();
+            "setPaused" => sync pause::set_paused(scopes: Vec<PauseScope>);
+            "isFrozen" => sync freeze::is_frozen(user: Principal);
+            "setAccountFrozen" => sync freeze::set_account_frozen(user: Principal, frozen: bool);
+            "setBlockFrozenRecipients" => sync freeze::set_block_frozen_recipients(
+                block_recipients: bool
+            );
+            "mint" => async mint::mint(amount: Nat);
+            "wrap" => async mint::wrap(amount: Nat);
+            "unwrap" => async mint::unwrap(amount: Nat, to: Principal);
+            "shardTransferAndNotify" => async notifications::transfer_and_notify(
+                shard_id: Principal, to: Principal, value: Nat, notify_principal: Principal,
+                notify_method: String, data: ByteBuf
+            );
+            "getPendingNotifications" => sync notifications::get_pending_notifications();
+            "retryNotification" => async notifications::retry_notification(id: u64);
+            "acknowledgeNotification" => sync notifications::acknowledge_notification(id: u64);
+            "subscribe" => sync subscriptions::subscribe(account: Option<Principal>, method: String);
+            "unsubscribe" => sync subscriptions::unsubscribe(id: u64);
+            "getSubscriptions" => sync subscriptions::get_subscriptions();
+            "getBalanceEvents" => sync subscriptions::get_balance_events(
+                account: Option<Principal>, start: u64, limit: u64
+            );
+        })
+    }
+
+    fn heartbeat() -> Option<MethodFuture> {
+        Some(Box::pin(async {
+            enoki_wrapped_token_shard::heartbeat().await;
+            Ok(vec![])
+        }))
+    }
+}
+
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use candid::{decode_args, encode_one, Nat, Principal};
+
+use enoki_wrapped_token_shard::interfaces::dip20::{Metadata, TxError, TxReceipt};
+use enoki_wrapped_token_shared::env;
+
+use crate::simulator::{Canister, MethodFuture};
+
+/// In-process DIP20 token, used as the underlying token of the wrapped token.
+pub struct MockDip20 {
+    metadata: Metadata,
+    balances: HashMap<Principal, Nat>,
+    allowances: HashMap<(Principal, Principal), Nat>,
+    history_size: Nat,
+}
+
+impl MockDip20 {
+    pub fn new(owner: Principal, fee: Nat) -> Self {
+        Self {
+            metadata: Metadata {
+                logo: "".to_string(),
+                name: "Mock Token".to_string(),
+                symbol: "MOCK".to_string(),
+                decimals: 8,
+                totalSupply: Nat::from(0),
+                owner,
+                fee,
+            },
+            balances: Default::default(),
+            allowances: Default::default(),
+            history_size: Nat::from(0),
+        }
+    }
+
+    fn balance_of(&self, who: &Principal) -> Nat {
+        self.balances.get(who).cloned().unwrap_or_default()
+    }
+
+    fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
+        self.allowances
+            .get(&(owner, spender))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn move_funds(&mut self, from: Principal, to: Principal, value: Nat) -> TxReceipt {
+        let fee = self.metadata.fee.clone();
+        let balance = self.balance_of(&from);
+        if balance < value.clone() + fee.clone() {
+            return Err(TxError::InsufficientBalance);
+        }
+        self.balances
+            .insert(from, balance - value.clone() - fee.clone());
+        *self.balances.entry(to).or_default() += value;
+        *self.balances.entry(self.metadata.owner).or_default() += fee;
+        self.history_size += 1u64;
+        Ok(self.history_size.clone())
+    }
+
+    fn transfer(&mut self, to: Principal, value: Nat) -> TxReceipt {
+        self.move_funds(env::caller(), to, value)
+    }
+
+    fn transfer_from(&mut self, from: Principal, to: Principal, value: Nat) -> TxReceipt {
+        let spender = env::caller();
+        let allowance = self.allowance(from, spender);
+        let required = value.clone() + self.metadata.fee.clone();
+        if allowance < required {
+            return Err(TxError::InsufficientAllowance);
+        }
+        let receipt = self.move_funds(from, to, value)?;
+        self.allowances.insert((from, spender), allowance - required);
+        Ok(receipt)
+    }
+
+    fn approve(&mut self, spender: Principal, value: Nat) -> TxReceipt {
+        self.allowances.insert((env::caller(), spender), value);
+        self.history_size += 1u64;
+        Ok(self.history_size.clone())
+    }
+
+    fn mint(&mut self, to: Principal, value: Nat) -> TxReceipt {
+        if env::caller() != self.metadata.owner {
+            return Err(TxError::Unauthorized);
+        }
+        *self.balances.entry(to).or_default() += value.clone();
+        self.metadata.totalSupply += value;
+        self.history_size += 1u64;
+        Ok(self.history_size.clone())
+    }
+}
+
+impl Canister for MockDip20 {
+    fn dispatch(&mut self, method: &str, args: Vec<u8>) -> Option<MethodFuture> {
+        let reply = match method {
+            "getMetadata" => encode_one(self.metadata.clone()),
+            "balanceOf" => {
+                let (who, ): (Principal, ) = decode_args(&args).unwrap();
+                encode_one(self.balance_of(&who))
+            }
+            "allowance" => {
+                let (owner, spender): (Principal, Principal) = decode_args(&args).unwrap();
+                encode_one(self.allowance(owner, spender))
+            }
+            "transfer" => {
+                let (to, value): (Principal, Nat) = decode_args(&args).unwrap();
+                encode_one(self.transfer(to, value))
+            }
+            "transferFrom" => {
+                let (from, to, value): (Principal, Principal, Nat) = decode_args(&args).unwrap();
+                encode_one(self.transfer_from(from, to, value))
+            }
+            "approve" => {
+                let (spender, value): (Principal, Nat) = decode_args(&args).unwrap();
+                encode_one(self.approve(spender, value))
+            }
+            "mint" => {
+                let (to, value): (Principal, Nat) = decode_args(&args).unwrap();
+                encode_one(self.mint(to, value))
+            }
+            _ => return None,
+        }
+        .unwrap();
+        Some(Box::pin(async move { Ok(reply) }))
+    }
+}
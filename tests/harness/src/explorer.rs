@@ -0,0 +1,132 @@
+use crate::simulator::Simulator;
+
+/// A scenario for the `Explorer`: some canisters with concurrent messages already submitted.
+pub trait Model {
+    fn simulator(&mut self) -> &mut Simulator;
+
+    /// Invariant that must hold after every delivered message.
+    fn check_step(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Invariant that must hold once every message has been delivered.
+    fn check_final(&mut self) -> Result<(), String>;
+}
+
+#[derive(Debug)]
+pub struct Violation {
+    /// Choices to pass to `replay` to reproduce the violation.
+    pub schedule: Vec<usize>,
+    /// The messages delivered, in order.
+    pub trace: Vec<String>,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub schedules: usize,
+    /// False if the exploration stopped at `max_schedules`.
+    pub complete: bool,
+    pub violations: Vec<Violation>,
+}
+
+/// Enumerates the orders in which the messages of a model can be delivered, i.e. every way
+/// concurrent executions can interleave at their await points, and checks the model's
+/// invariants for each of them.
+///
+/// Simulators cannot be cloned, so the explorer is stateless: each schedule is replayed from a
+/// fresh model built by `setup`, depth first, backtracking on the last choice that has untried
+/// alternatives.
+pub struct Explorer<M, F: Fn() -> M> {
+    setup: F,
+    max_schedules: usize,
+}
+
+impl<M: Model, F: Fn() -> M> Explorer<M, F> {
+    pub fn new(setup: F) -> Self {
+        Self {
+            setup,
+            max_schedules: 10_000,
+        }
+    }
+
+    pub fn max_schedules(mut self, max_schedules: usize) -> Self {
+        self.max_schedules = max_schedules;
+        self
+    }
+
+    pub fn explore(&self) -> Report {
+        let mut report = Report::default();
+        // for each step of the current schedule: the choice taken, and how many there were
+        let mut choices: Vec<(usize, usize)> = vec![];
+        loop {
+            if report.schedules == self.max_schedules {
+                return report;
+            }
+            report.schedules += 1;
+            let prefix: Vec<usize> = choices.iter().map(|&(choice, _)| choice).collect();
+            let (explored, violation) = self.run(&prefix);
+            choices = explored;
+            if let Some(violation) = violation {
+                report.violations.push(violation);
+            }
+
+            while let Some((choice, options)) = choices.pop() {
+                if choice + 1 < options {
+                    choices.push((choice + 1, options));
+                    break;
+                }
+            }
+            if choices.is_empty() {
+                report.complete = true;
+                return report;
+            }
+        }
+    }
+
+    /// Runs one schedule that starts with `prefix` and then always picks the first deliverable
+    /// message.
+    fn run(&self, prefix: &[usize]) -> (Vec<(usize, usize)>, Option<Violation>) {
+        let mut model = (self.setup)();
+        let mut choices = vec![];
+        let mut trace = vec![];
+        let violation = |choices: &[(usize, usize)], trace: Vec<String>, error| {
+            Some(Violation {
+                schedule: choices.iter().map(|&(choice, _)| choice).collect(),
+                trace,
+                error,
+            })
+        };
+
+        loop {
+            let deliverable = model.simulator().deliverable();
+            if deliverable.is_empty() {
+                break;
+            }
+            let choice = prefix.get(choices.len()).copied().unwrap_or(0);
+            choices.push((choice, deliverable.len()));
+            trace.push(model.simulator().describe(deliverable[choice]));
+            model.simulator().step(deliverable[choice]);
+            if let Err(error) = model.check_step() {
+                let violation = violation(&choices, trace, error);
+                return (choices, violation);
+            }
+        }
+        match model.check_final() {
+            Ok(()) => (choices, None),
+            Err(error) => {
+                let violation = violation(&choices, trace, error);
+                (choices, violation)
+            }
+        }
+    }
+}
+
+/// Rebuilds a model and delivers its messages in the order given by a `Violation::schedule`.
+pub fn replay<M: Model>(mut model: M, schedule: &[usize]) -> M {
+    for &choice in schedule {
+        let deliverable = model.simulator().deliverable();
+        model.simulator().step(deliverable[choice]);
+    }
+    model
+}
@@ -0,0 +1,37 @@
+//! Runs the token canisters natively, without dfx.
+//!
+//! The canister crates access the system API through `enoki_wrapped_token_shared::env`, which the
+//! `Simulator` replaces with an in-process implementation. `TokenSystem` deploys the main
+//! canister, a set of shards and a mock DIP20 underlying token on top of it.
+
+use candid::Principal;
+
+pub mod canisters;
+pub mod dip20;
+pub mod explorer;
+pub mod simulator;
+pub mod system;
+pub mod transport;
+pub mod upgrade;
+
+pub use explorer::{Explorer, Model};
+pub use simulator::{Canister, NativeCanister, Reply, Schedule, Simulator};
+pub use system::TokenSystem;
+pub use transport::SimulatorTransport;
+pub use upgrade::SavedState;
+
+fn principal(n: u64, class: u8) -> Principal {
+    let mut bytes = n.to_be_bytes().to_vec();
+    bytes.push(class);
+    Principal::from_slice(&bytes)
+}
+
+/// Deterministic principal of the n-th simulated canister.
+pub fn canister_id(n: u64) -> Principal {
+    principal(n, 0x01)
+}
+
+/// Deterministic principal of the n-th simulated user.
+pub fn user_id(n: u64) -> Principal {
+    principal(n, 0x02)
+}
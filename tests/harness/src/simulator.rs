@@ -0,0 +1,811 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Once;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, Principal};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+
+use enoki_wrapped_token_shared::env::{self, CallFuture, Environment};
+use enoki_wrapped_token_shared::types::CanisterIdRecord;
+
+pub type Reply = CallResult<Vec<u8>>;
+pub type MethodFuture = Pin<Box<dyn Future<Output = Reply>>>;
+
+type ReplySlot = Rc<RefCell<Option<Reply>>>;
+type FaultInjector = Box<dyn FnMut(Principal, Principal, &str) -> bool>;
+
+const START_TIME: u64 = 1_650_000_000_000_000_000;
+const DEFAULT_TIME_STEP: u64 = 1_000_000;
+const DEFAULT_CYCLES: u64 = 10_000_000_000_000;
+const HEARTBEAT: &str = "canister_heartbeat";
+const DEPOSIT_CYCLES: &str = "deposit_cycles";
+
+/// A canister installed in the simulator.
+pub trait Canister {
+    /// Starts executing an update call. Returns `None` if the canister does not export `method`.
+    fn dispatch(&mut self, method: &str, args: Vec<u8>) -> Option<MethodFuture>;
+
+    /// The `#[init]` entry point, run once when the canister is installed.
+    fn init(&mut self, _args: Vec<u8>) -> Option<MethodFuture> {
+        None
+    }
+
+    /// The `#[heartbeat]` entry point, run by `Simulator::heartbeat`.
+    fn heartbeat(&mut self) -> Option<MethodFuture> {
+        None
+    }
+
+    /// Called before each message execution, to load the canister's state.
+    fn enter(&mut self) {}
+
+    /// Called after each message execution. If the execution trapped, `commit` is false and
+    /// the changes made since `enter` must be discarded.
+    fn exit(&mut self, _commit: bool) {}
+
+    /// The `#[pre_upgrade]` entry point: encodes the state saved to stable memory.
+    fn pre_upgrade(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// The `#[post_upgrade]` entry point: restores the state from stable memory.
+    fn post_upgrade(&mut self, _stable: Vec<u8>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A stateless canister implemented by a closure, useful to mock callbacks and inject failures.
+pub struct NativeCanister<F>(pub F);
+
+impl<F: FnMut(Principal, &str, Vec<u8>) -> Reply> Canister for NativeCanister<F> {
+    fn dispatch(&mut self, method: &str, args: Vec<u8>) -> Option<MethodFuture> {
+        let reply = (self.0)(env::caller(), method, args);
+        Some(Box::pin(async move { reply }))
+    }
+}
+
+#[derive(Clone)]
+enum ReplyTo {
+    Ingress(usize),
+    Call { context: usize, slot: ReplySlot },
+    /// Heartbeats, whose result is dropped.
+    System,
+}
+
+enum Message {
+    Request {
+        from: Principal,
+        to: Principal,
+        method: String,
+        args: Vec<u8>,
+        cycles: u64,
+        reply_to: ReplyTo,
+    },
+    Response {
+        to: Principal,
+        context: usize,
+        slot: ReplySlot,
+        reply: Reply,
+    },
+}
+
+struct CallContext {
+    canister: Principal,
+    caller: Principal,
+    future: MethodFuture,
+    reply_to: ReplyTo,
+    /// Attached cycles not accepted yet, refunded to the caller with the reply.
+    cycles_available: u64,
+}
+
+struct OutgoingCall {
+    to: Principal,
+    method: String,
+    args: Vec<u8>,
+    cycles: u64,
+    slot: ReplySlot,
+}
+
+struct SimEnvironment {
+    caller: Principal,
+    id: Principal,
+    time: u64,
+    cycles: Cell<u64>,
+    cycles_available: Cell<u64>,
+    outbox: RefCell<Vec<OutgoingCall>>,
+}
+
+struct ReplyFuture(ReplySlot);
+
+impl Future for ReplyFuture {
+    type Output = Reply;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Reply> {
+        match self.0.borrow_mut().take() {
+            Some(reply) => Poll::Ready(reply),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Environment for SimEnvironment {
+    fn caller(&self) -> Principal {
+        self.caller
+    }
+
+    fn id(&self) -> Principal {
+        self.id
+    }
+
+    fn time(&self) -> u64 {
+        self.time
+    }
+
+    fn canister_balance(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    fn call_with_payment(
+        &self,
+        id: Principal,
+        method: &str,
+        args: Vec<u8>,
+        cycles: u64,
+    ) -> CallFuture {
+        let balance = self.cycles.get();
+        if cycles > balance {
+            let reply = Err((
+                RejectionCode::CanisterReject,
+                "insufficient cycles".to_string(),
+            ));
+            return Box::pin(async move { reply });
+        }
+        self.cycles.set(balance - cycles);
+        let slot = ReplySlot::default();
+        self.outbox.borrow_mut().push(OutgoingCall {
+            to: id,
+            method: method.to_string(),
+            args,
+            cycles,
+            slot: slot.clone(),
+        });
+        Box::pin(ReplyFuture(slot))
+    }
+
+    fn msg_cycles_accept(&self, max: u64) -> u64 {
+        let accepted = max.min(self.cycles_available.get());
+        self.cycles_available.set(self.cycles_available.get() - accepted);
+        self.cycles.set(self.cycles.get() + accepted);
+        accepted
+    }
+}
+
+/// How `run` picks the next message among the deliverable ones.
+#[derive(Clone, Copy, Debug)]
+pub enum Schedule {
+    Fifo,
+    Random(u64),
+}
+
+/// A deterministic, single threaded model of the IC: canisters exchange messages through a
+/// queue, and every message execution either commits or, if it traps, is rolled back.
+///
+/// Requests between the same pair of canisters are delivered in order, everything else can be
+/// interleaved arbitrarily, either by `run` (following the `Schedule`) or by calling `step`.
+///
+/// Cycles attached to a call leave the caller when the call is sent, and those the callee did
+/// not accept are refunded as soon as it replies. The management canister only implements
+/// `deposit_cycles`.
+pub struct Simulator {
+    canisters: BTreeMap<Principal, Box<dyn Canister>>,
+    cycles: HashMap<Principal, u64>,
+    queue: Vec<Message>,
+    contexts: HashMap<usize, CallContext>,
+    next_context: usize,
+    ingress: Vec<Option<Reply>>,
+    schedule: Schedule,
+    rng: u64,
+    time: u64,
+    time_step: u64,
+    fault_injector: Option<FaultInjector>,
+    traps: Vec<(Principal, String)>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new(Schedule::Fifo)
+    }
+}
+
+impl Simulator {
+    pub fn new(schedule: Schedule) -> Self {
+        let rng = match schedule {
+            Schedule::Fifo => 0,
+            Schedule::Random(seed) => seed,
+        };
+        Self {
+            canisters: Default::default(),
+            cycles: Default::default(),
+            queue: vec![],
+            contexts: Default::default(),
+            next_context: 0,
+            ingress: vec![],
+            schedule,
+            rng,
+            time: START_TIME,
+            time_step: DEFAULT_TIME_STEP,
+            fault_injector: None,
+            traps: vec![],
+        }
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn advance_time(&mut self, nanoseconds: u64) {
+        self.time += nanoseconds;
+    }
+
+    /// Time that passes with each delivered message.
+    pub fn set_time_step(&mut self, nanoseconds: u64) {
+        self.time_step = nanoseconds;
+    }
+
+    pub fn cycles(&self, id: &Principal) -> u64 {
+        self.cycles.get(id).copied().unwrap_or(DEFAULT_CYCLES)
+    }
+
+    pub fn set_cycles(&mut self, id: Principal, cycles: u64) {
+        self.cycles.insert(id, cycles);
+    }
+
+    /// Decides, for every inter-canister request `(from, to, method)`, whether it is rejected with
+    /// `SysTransient` instead of being delivered.
+    pub fn set_fault_injector<F: FnMut(Principal, Principal, &str) -> bool + 'static>(
+        &mut self,
+        injector: F,
+    ) {
+        self.fault_injector = Some(Box::new(injector));
+    }
+
+    pub fn clear_fault_injector(&mut self) {
+        self.fault_injector = None;
+    }
+
+    /// Every trap so far, with the canister that trapped.
+    pub fn traps(&self) -> &[(Principal, String)] {
+        &self.traps
+    }
+
+    /// Installs a canister, running its init entry point as if called by `installer`.
+    pub fn install<A: ArgumentEncoder>(
+        &mut self,
+        id: Principal,
+        mut canister: Box<dyn Canister>,
+        installer: Principal,
+        args: A,
+    ) {
+        let args = encode_args(args).expect("failed to encode init arguments");
+        let environment = self.enter(id, installer, 0);
+        canister.enter();
+        let init = canister.init(args);
+        let result = init.map(|mut future| run_canister_code(|| poll_once(&mut future)));
+        match result {
+            None | Some(Ok(Poll::Ready(Ok(_)))) => canister.exit(true),
+            Some(Ok(Poll::Pending)) => panic!("init of {} made inter-canister calls", id),
+            Some(Ok(Poll::Ready(Err((_, message))))) | Some(Err(message)) => {
+                panic!("init of {} failed: {}", id, message)
+            }
+        }
+        assert!(environment.outbox.borrow().is_empty());
+        self.canisters.insert(id, canister);
+    }
+
+    /// Upgrades a canister to the same code. The state it saves goes through `rewrite` before it
+    /// is restored, e.g. to turn it into the state an earlier version saved.
+    pub fn upgrade<F: FnOnce(Vec<u8>) -> Vec<u8>>(&mut self, id: Principal, rewrite: F) {
+        let canister = self.canisters.get_mut(&id).expect("canister is not installed");
+        let stable = rewrite(canister.pre_upgrade());
+        if let Err(message) = canister.post_upgrade(stable) {
+            panic!("post_upgrade of {} failed: {}", id, message)
+        }
+    }
+
+    /// Submits an ingress message. Its reply is available through `ingress_reply` once executed.
+    pub fn submit(
+        &mut self,
+        caller: Principal,
+        canister: Principal,
+        method: &str,
+        args: Vec<u8>,
+    ) -> usize {
+        let id = self.ingress.len();
+        self.ingress.push(None);
+        self.queue.push(Message::Request {
+            from: caller,
+            to: canister,
+            method: method.to_string(),
+            args,
+            cycles: 0,
+            reply_to: ReplyTo::Ingress(id),
+        });
+        id
+    }
+
+    /// Same as `update`, but sent by the canister `from` with `cycles` attached. Ingress messages
+    /// cannot carry cycles.
+    pub fn update_with_payment<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &mut self,
+        from: Principal,
+        canister: Principal,
+        method: &str,
+        args: T,
+        cycles: u64,
+    ) -> CallResult<R> {
+        let balance = self.cycles(&from);
+        assert!(balance >= cycles, "{} does not have {} cycles", from, cycles);
+        self.cycles.insert(from, balance - cycles);
+        let id = self.ingress.len();
+        self.ingress.push(None);
+        self.queue.push(Message::Request {
+            from,
+            to: canister,
+            method: method.to_string(),
+            args: encode_args(args).expect("failed to encode arguments"),
+            cycles,
+            reply_to: ReplyTo::Ingress(id),
+        });
+        self.run();
+        decode_reply(self.ingress[id].take())
+    }
+
+    pub fn ingress_reply(&self, id: usize) -> Option<&Reply> {
+        self.ingress[id].as_ref()
+    }
+
+    /// Submits an ingress message, runs until the system is idle, and decodes the reply.
+    pub fn update<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &mut self,
+        caller: Principal,
+        canister: Principal,
+        method: &str,
+        args: T,
+    ) -> CallResult<R> {
+        let args = encode_args(args).expect("failed to encode arguments");
+        decode_reply(Some(self.update_raw(caller, canister, method, args)))
+    }
+
+    /// Same as `update`, with encoded arguments and reply.
+    pub fn update_raw(
+        &mut self,
+        caller: Principal,
+        canister: Principal,
+        method: &str,
+        args: Vec<u8>,
+    ) -> Reply {
+        let id = self.submit(caller, canister, method, args);
+        self.run();
+        self.ingress[id].take().unwrap_or_else(|| {
+            Err((
+                RejectionCode::CanisterError,
+                "Canister did not reply to the call".to_string(),
+            ))
+        })
+    }
+
+    /// Runs a method outside of the message queue and discards its state changes, like a query
+    /// call. Used to inspect canisters between steps without affecting the schedule.
+    pub fn query<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &mut self,
+        caller: Principal,
+        canister: Principal,
+        method: &str,
+        args: T,
+    ) -> CallResult<R> {
+        let args = encode_args(args).expect("failed to encode arguments");
+        decode_reply(Some(self.query_raw(caller, canister, method, args)))
+    }
+
+    /// Same as `query`, with encoded arguments and reply.
+    pub fn query_raw(
+        &mut self,
+        caller: Principal,
+        canister: Principal,
+        method: &str,
+        args: Vec<u8>,
+    ) -> Reply {
+        let environment = self.enter(canister, caller, 0);
+        let instance = self.canisters.get_mut(&canister).ok_or_else(|| {
+            (
+                RejectionCode::DestinationInvalid,
+                format!("Canister {} not found", canister),
+            )
+        })?;
+        instance.enter();
+        let result = run_canister_code(|| {
+            instance
+                .dispatch(method, args)
+                .map(|mut future| poll_once(&mut future))
+        });
+        instance.exit(false);
+        let reply = match result {
+            Ok(Some(Poll::Ready(reply))) => reply,
+            Ok(Some(Poll::Pending)) => panic!("query {} made inter-canister calls", method),
+            Ok(None) => Err((
+                RejectionCode::CanisterError,
+                format!("Canister {} has no query method '{}'", canister, method),
+            )),
+            Err(message) => Err((RejectionCode::CanisterError, message)),
+        };
+        assert!(environment.outbox.borrow().is_empty());
+        reply
+    }
+
+    /// Schedules a heartbeat on every canister, as the system does at the start of each round.
+    pub fn heartbeat(&mut self) {
+        let ids: Vec<Principal> = self.canisters.keys().copied().collect();
+        for id in ids {
+            self.queue.push(Message::Request {
+                from: Principal::management_canister(),
+                to: id,
+                method: HEARTBEAT.to_string(),
+                args: vec![],
+                cycles: 0,
+                reply_to: ReplyTo::System,
+            });
+        }
+    }
+
+    /// Number of messages waiting to be delivered.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Indices (as accepted by `step`) of the messages that can be delivered next.
+    pub fn deliverable(&self) -> Vec<usize> {
+        (0..self.queue.len())
+            .filter(|&i| match &self.queue[i] {
+                Message::Request { from, to, .. } => !self.queue[..i].iter().any(|m| {
+                    matches!(m, Message::Request { from: f, to: t, .. } if f == from && t == to)
+                }),
+                Message::Response { .. } => true,
+            })
+            .collect()
+    }
+
+    /// Human readable description of the message at `index`.
+    pub fn describe(&self, index: usize) -> String {
+        match &self.queue[index] {
+            Message::Request { from, to, method, .. } => {
+                format!("request {} -> {}.{}", from, to, method)
+            }
+            Message::Response { to, reply, .. } => match reply {
+                Ok(_) => format!("reply -> {}", to),
+                Err((code, message)) => format!("reject -> {} ({:?}: {})", to, code, message),
+            },
+        }
+    }
+
+    /// Runs until there are no messages left, picking messages according to the schedule.
+    pub fn run(&mut self) {
+        loop {
+            let deliverable = self.deliverable();
+            if deliverable.is_empty() {
+                break;
+            }
+            let choice = match self.schedule {
+                Schedule::Fifo => 0,
+                Schedule::Random(_) => (self.next_random() % deliverable.len() as u64) as usize,
+            };
+            self.step(deliverable[choice]);
+        }
+    }
+
+    /// Delivers the message at `index` of the queue.
+    pub fn step(&mut self, index: usize) {
+        self.time += self.time_step;
+        match self.queue.remove(index) {
+            Message::Request {
+                from,
+                to,
+                method,
+                args,
+                cycles,
+                reply_to,
+            } => self.deliver_request(from, to, method, args, cycles, reply_to),
+            Message::Response {
+                context,
+                slot,
+                reply,
+                ..
+            } => {
+                // the context is gone if it trapped while this call was in flight
+                if let Some(call_context) = self.contexts.remove(&context) {
+                    slot.replace(Some(reply));
+                    self.execute(context, call_context);
+                }
+            }
+        }
+    }
+
+    fn deliver_request(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        method: String,
+        args: Vec<u8>,
+        cycles: u64,
+        reply_to: ReplyTo,
+    ) {
+        if let (ReplyTo::Call { .. }, Some(injector)) = (&reply_to, self.fault_injector.as_mut()) {
+            if injector(from, to, &method) {
+                self.refund(from, cycles);
+                let reply = Err((RejectionCode::SysTransient, "injected failure".to_string()));
+                return self.reply(reply_to, reply);
+            }
+        }
+        if to == Principal::management_canister() && method == DEPOSIT_CYCLES {
+            let reply = self.deposit_cycles(args, cycles);
+            if reply.is_err() {
+                self.refund(from, cycles);
+            }
+            return self.reply(reply_to, reply);
+        }
+        let environment = self.enter(to, from, cycles);
+        let canister = match self.canisters.get_mut(&to) {
+            Some(canister) => canister,
+            None => {
+                self.refund(from, cycles);
+                let reply = Err((
+                    RejectionCode::DestinationInvalid,
+                    format!("Canister {} not found", to),
+                ));
+                return self.reply(reply_to, reply);
+            }
+        };
+        canister.enter();
+        let dispatched = run_canister_code(|| match method.as_str() {
+            HEARTBEAT => canister.heartbeat(),
+            _ => canister.dispatch(&method, args),
+        });
+        match dispatched {
+            Ok(Some(future)) => {
+                let context = self.next_context;
+                self.next_context += 1;
+                let call_context = CallContext {
+                    canister: to,
+                    caller: from,
+                    future,
+                    reply_to,
+                    cycles_available: cycles,
+                };
+                self.execute_entered(context, call_context, environment);
+            }
+            Ok(None) => {
+                canister.exit(false);
+                self.refund(from, cycles);
+                let reply = Err((
+                    RejectionCode::CanisterError,
+                    format!("Canister {} has no update method '{}'", to, method),
+                ));
+                self.reply(reply_to, reply);
+            }
+            Err(message) => {
+                canister.exit(false);
+                self.refund(from, cycles);
+                self.traps.push((to, message.clone()));
+                self.reply(reply_to, Err((RejectionCode::CanisterError, message)));
+            }
+        }
+    }
+
+    fn execute(&mut self, context: usize, call_context: CallContext) {
+        let environment = self.enter(
+            call_context.canister,
+            call_context.caller,
+            call_context.cycles_available,
+        );
+        self.canisters
+            .get_mut(&call_context.canister)
+            .expect("canister was removed")
+            .enter();
+        self.execute_entered(context, call_context, environment);
+    }
+
+    /// Polls a call context whose canister state is already loaded.
+    fn execute_entered(
+        &mut self,
+        context: usize,
+        mut call_context: CallContext,
+        environment: Rc<SimEnvironment>,
+    ) {
+        let result = run_canister_code(|| poll_once(&mut call_context.future));
+        let commit = result.is_ok();
+        self.canisters
+            .get_mut(&call_context.canister)
+            .expect("canister was removed")
+            .exit(commit);
+
+        if commit {
+            self.cycles
+                .insert(call_context.canister, environment.cycles.get());
+            call_context.cycles_available = environment.cycles_available.get();
+            let mut outbox = environment.outbox.take();
+            // calls issued in the same execution are ordered by destination, so that runs do not
+            // depend on the iteration order of hash maps inside the canisters
+            outbox.sort_by_key(|call| call.to);
+            for call in outbox {
+                self.queue.push(Message::Request {
+                    from: call_context.canister,
+                    to: call.to,
+                    method: call.method,
+                    args: call.args,
+                    cycles: call.cycles,
+                    reply_to: ReplyTo::Call {
+                        context,
+                        slot: call.slot,
+                    },
+                });
+            }
+        }
+
+        match result {
+            Ok(Poll::Pending) => {
+                self.contexts.insert(context, call_context);
+            }
+            Ok(Poll::Ready(reply)) => {
+                self.refund(call_context.caller, call_context.cycles_available);
+                self.reply(call_context.reply_to, reply)
+            }
+            Err(message) => {
+                self.refund(call_context.caller, call_context.cycles_available);
+                self.traps.push((call_context.canister, message.clone()));
+                self.reply(
+                    call_context.reply_to,
+                    Err((RejectionCode::CanisterError, message)),
+                )
+            }
+        }
+    }
+
+    fn reply(&mut self, reply_to: ReplyTo, reply: Reply) {
+        match reply_to {
+            ReplyTo::Ingress(id) => self.ingress[id] = Some(reply),
+            ReplyTo::System => {}
+            ReplyTo::Call { context, slot } => {
+                let to = self
+                    .contexts
+                    .get(&context)
+                    .map(|c| c.canister)
+                    .unwrap_or_else(Principal::anonymous);
+                self.queue.push(Message::Response {
+                    to,
+                    context,
+                    slot,
+                    reply,
+                })
+            }
+        }
+    }
+
+    /// Handles `deposit_cycles` calls to the management canister.
+    fn deposit_cycles(&mut self, args: Vec<u8>, cycles: u64) -> Reply {
+        let (CanisterIdRecord { canister_id }, ) = decode_args(&args).map_err(|err| {
+            (
+                RejectionCode::CanisterReject,
+                format!("failed to decode arguments: {}", err),
+            )
+        })?;
+        if !self.canisters.contains_key(&canister_id) {
+            return Err((
+                RejectionCode::DestinationInvalid,
+                format!("Canister {} not found", canister_id),
+            ));
+        }
+        self.refund(canister_id, cycles);
+        Ok(encode_args(()).unwrap())
+    }
+
+    /// Credits cycles to `id`, unless it is not a canister.
+    fn refund(&mut self, id: Principal, cycles: u64) {
+        if cycles > 0 && self.canisters.contains_key(&id) {
+            let balance = self.cycles(&id);
+            self.cycles.insert(id, balance + cycles);
+        }
+    }
+
+    fn enter(
+        &mut self,
+        id: Principal,
+        caller: Principal,
+        cycles_available: u64,
+    ) -> Rc<SimEnvironment> {
+        let environment = Rc::new(SimEnvironment {
+            caller,
+            id,
+            time: self.time,
+            cycles: Cell::new(self.cycles(&id)),
+            cycles_available: Cell::new(cycles_available),
+            outbox: Default::default(),
+        });
+        env::set_environment(environment.clone());
+        environment
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+pub fn decode_reply<R: for<'a> ArgumentDecoder<'a>>(reply: Option<Reply>) -> CallResult<R> {
+    let bytes = reply.unwrap_or_else(|| {
+        Err((
+            RejectionCode::CanisterError,
+            "Canister did not reply to the call".to_string(),
+        ))
+    })?;
+    decode_args(&bytes).map_err(|err| {
+        (
+            RejectionCode::CanisterError,
+            format!("failed to decode reply: {}", err),
+        )
+    })
+}
+
+thread_local! {
+    static IN_CANISTER: Cell<bool> = const { Cell::new(false) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Runs canister code, turning panics into traps. Panics are not printed while canister code
+/// runs, since traps are expected.
+fn run_canister_code<R, F: FnOnce() -> R>(func: F) -> Result<R, String> {
+    PANIC_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IN_CANISTER.with(|c| c.get()) {
+                default_hook(info)
+            }
+        }));
+    });
+
+    IN_CANISTER.with(|c| c.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(func));
+    IN_CANISTER.with(|c| c.set(false));
+    result.map_err(|payload| {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+        format!("Canister trapped: {}", message)
+    })
+}
+
+fn poll_once(future: &mut MethodFuture) -> Poll<Reply> {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    future.as_mut().poll(&mut cx)
+}
+
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
@@ -0,0 +1,222 @@
+use candid::{Nat, Principal};
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token_shard::interfaces::dip20::TxReceipt;
+use enoki_wrapped_token_shared::types::{ByteBuf, NotificationResponse, Result};
+
+use crate::canisters::{Instance, Shard, Token};
+use crate::dip20::MockDip20;
+use crate::simulator::{Schedule, Simulator};
+use crate::{canister_id, user_id};
+
+/// The main canister, its shards and the underlying DIP20, deployed and initialized the same way
+/// as `scripts/install.sh` does on a local replica.
+pub struct TokenSystem {
+    pub sim: Simulator,
+    pub owner: Principal,
+    pub token: Principal,
+    pub underlying: Principal,
+    pub shards: Vec<Principal>,
+}
+
+impl TokenSystem {
+    pub fn new(schedule: Schedule, num_shards: u64, fee: u64, underlying_fee: u64) -> Self {
+        let mut sim = Simulator::new(schedule);
+        let owner = user_id(0);
+        let token = canister_id(0);
+        let underlying = canister_id(1);
+
+        sim.install(
+            underlying,
+            Box::new(MockDip20::new(owner, Nat::from(underlying_fee))),
+            owner,
+            (),
+        );
+        sim.install(token, Box::new(Instance::<Token>::default()), owner, ());
+        let () = sim
+            .update(
+                owner,
+                token,
+                "finishInit",
+                (
+                    underlying,
+                    "".to_string(),
+                    "Wrapped Mock Token".to_string(),
+                    "eMOCK".to_string(),
+                    8u8,
+                    Nat::from(fee),
+                ),
+            )
+            .expect("finishInit failed");
+
+        let mut system = Self {
+            sim,
+            owner,
+            token,
+            underlying,
+            shards: vec![],
+        };
+        for _ in 0..num_shards {
+            system.add_shard();
+        }
+        system
+    }
+
+    pub fn add_shard(&mut self) -> Principal {
+        let shard = canister_id(100 + self.shards.len() as u64);
+        self.sim
+            .install(shard, Box::new(Instance::<Shard>::default()), self.owner, ());
+        let () = self
+            .sim
+            .update(self.owner, shard, "finishInit", (self.token, self.underlying))
+            .expect("finishInit failed");
+        let () = self
+            .sim
+            .update(self.owner, self.token, "addShard", (shard, ))
+            .expect("addShard failed");
+        self.shards.push(shard);
+        shard
+    }
+
+    /// Registers `user` and returns its assigned shard.
+    pub fn register(&mut self, user: Principal) -> Principal {
+        let (shard, ): (Principal, ) = self
+            .sim
+            .update(user, self.token, "register", (user, ))
+            .expect("register failed");
+        shard
+    }
+
+    /// Gives `user` underlying tokens, minted by the DIP20 owner.
+    pub fn mint_underlying(&mut self, user: Principal, amount: u64) {
+        let (receipt, ): (TxReceipt, ) = self
+            .sim
+            .update(self.owner, self.underlying, "mint", (user, Nat::from(amount)))
+            .unwrap();
+        receipt.unwrap();
+    }
+
+    pub fn underlying_balance(&mut self, id: Principal) -> Nat {
+        let (balance, ): (Nat, ) = self
+            .sim
+            .update(id, self.underlying, "balanceOf", (id, ))
+            .unwrap();
+        balance
+    }
+
+    /// Approves the user's shard and wraps `amount` underlying tokens.
+    pub fn wrap(&mut self, user: Principal, amount: u64) -> CallResult<()> {
+        let shard = self.register(user);
+        let (receipt, ): (TxReceipt, ) = self.sim.update(
+            user,
+            self.underlying,
+            "approve",
+            (shard, Nat::from(amount)),
+        )?;
+        receipt.unwrap();
+        self.sim.update(user, shard, "wrap", (Nat::from(amount), ))
+    }
+
+    pub fn unwrap(&mut self, user: Principal, amount: u64, to: Principal) -> CallResult<Result<()>> {
+        let shard = self.register(user);
+        let (result, ) = self
+            .sim
+            .update(user, shard, "unwrap", (Nat::from(amount), to))?;
+        Ok(result)
+    }
+
+    /// Transfers from the sender's shard, as clients are expected to.
+    pub fn shard_transfer(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        value: u64,
+    ) -> CallResult<Result<()>> {
+        let from_shard = self.register(from);
+        let to_shard = self.register(to);
+        let (result, ) = self.sim.update(
+            from,
+            from_shard,
+            "shardTransfer",
+            (to_shard, to, Nat::from(value)),
+        )?;
+        Ok(result)
+    }
+
+    /// Transfers from the sender's shard and notifies `callback` with the `deposit` method.
+    pub fn shard_transfer_and_call(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        value: u64,
+        callback: Principal,
+        data: &[u8],
+    ) -> CallResult<Result<NotificationResponse>> {
+        let from_shard = self.register(from);
+        let to_shard = self.register(to);
+        let (result, ) = self.sim.update(
+            from,
+            from_shard,
+            "shardTransferAndCall",
+            (
+                to_shard,
+                to,
+                Nat::from(value),
+                callback,
+                "deposit".to_string(),
+                ByteBuf::from(data),
+            ),
+        )?;
+        Ok(result)
+    }
+
+    pub fn balance(&mut self, user: Principal) -> Nat {
+        let (balance, ): (Nat, ) = self
+            .sim
+            .update(user, self.token, "balanceOf", (user, ))
+            .unwrap();
+        balance
+    }
+
+    /// Balances plus accrued fees, summed over all shards. Read without sending any message.
+    pub fn wrapped_supply(&mut self) -> Nat {
+        let owner = self.owner;
+        let shards = self.shards.clone();
+        shards.into_iter().fold(Nat::from(0), |sum, shard| {
+            let (supply, ): (Nat, ) = self
+                .sim
+                .query(owner, shard, "shardGetSupply", ())
+                .unwrap();
+            sum + supply
+        })
+    }
+
+    /// Underlying tokens held by the shards, i.e. wrapped minus unwrapped.
+    pub fn underlying_custody(&mut self) -> Nat {
+        let (owner, underlying) = (self.owner, self.underlying);
+        let shards = self.shards.clone();
+        shards.into_iter().fold(Nat::from(0), |sum, shard| {
+            let (balance, ): (Nat, ) = self
+                .sim
+                .query(owner, underlying, "balanceOf", (shard, ))
+                .unwrap();
+            sum + balance
+        })
+    }
+
+    pub fn total_supply(&mut self) -> Nat {
+        let (supply, ): (Nat, ) = self
+            .sim
+            .update(self.owner, self.token, "totalSupply", ())
+            .unwrap();
+        supply
+    }
+
+    pub fn accrued_fees(&mut self) -> Nat {
+        let (fees, ): (Nat, ) = self
+            .sim
+            .update(self.owner, self.token, "getAccruedFees", ())
+            .unwrap();
+        fees
+    }
+}
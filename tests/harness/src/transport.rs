@@ -0,0 +1,60 @@
+use std::cell::RefCell;
+
+use candid::Principal;
+
+use enoki_wrapped_token_client::{ClientError, Transport, TransportFuture};
+
+use crate::simulator::{Reply, Simulator};
+
+/// Client transport sending ingress messages to the simulator. Every call runs the simulator
+/// until it is idle, so the returned futures are always ready.
+pub struct SimulatorTransport<'a> {
+    sim: RefCell<&'a mut Simulator>,
+    sender: Principal,
+    calls: RefCell<Vec<String>>,
+}
+
+impl<'a> SimulatorTransport<'a> {
+    pub fn new(sim: &'a mut Simulator, sender: Principal) -> Self {
+        Self {
+            sim: RefCell::new(sim),
+            sender,
+            calls: Default::default(),
+        }
+    }
+
+    /// Methods called so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    fn ready(&self, method: &str, reply: Reply) -> TransportFuture<'_> {
+        self.calls.borrow_mut().push(method.to_string());
+        let result = reply.map_err(|(code, message)| {
+            ClientError::Transport(format!("rejected with code {:?}: {}", code, message))
+        });
+        Box::pin(async move { result })
+    }
+}
+
+impl Transport for SimulatorTransport<'_> {
+    fn sender(&self) -> Principal {
+        self.sender
+    }
+
+    fn query(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_> {
+        let reply = self
+            .sim
+            .borrow_mut()
+            .query_raw(self.sender, canister, method, args);
+        self.ready(method, reply)
+    }
+
+    fn update(&self, canister: Principal, method: &str, args: Vec<u8>) -> TransportFuture<'_> {
+        let reply = self
+            .sim
+            .borrow_mut()
+            .update_raw(self.sender, canister, method, args);
+        self.ready(method, reply)
+    }
+}
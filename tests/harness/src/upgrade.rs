@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use candid::parser::value::{IDLField, IDLValue};
+use candid::types::{Field, Label, Type};
+use candid::{idl_hash, CandidType, IDLArgs, TypeEnv};
+
+/// The state a canister saves to stable memory before an upgrade, decoded without its type so
+/// that tests can turn it into the state an earlier version of the canister saved.
+pub struct SavedState {
+    value: IDLValue,
+    /// The type the state was saved with, which gives the types of empty vectors and options.
+    ty: Type,
+}
+
+impl SavedState {
+    pub fn decode<T: CandidType>(bytes: &[u8]) -> Self {
+        let mut args = IDLArgs::from_bytes(bytes).expect("failed to decode the saved state");
+        assert_eq!(args.args.len(), 1, "the state is saved as a single value");
+        Self {
+            value: args.args.remove(0),
+            ty: T::ty(),
+        }
+    }
+
+    /// Encodes the state with the types of its values, as the version that saved it would.
+    pub fn encode(&self) -> Vec<u8> {
+        let (env, ty) = (TypeEnv::new(), infer(&self.value, Some(&self.ty)));
+        // variants are encoded by their index in the type, which the decoded values lack
+        let value = self.value.annotate_type(false, &env, &ty).expect("the state has its type");
+        IDLArgs::new(&[value])
+            .to_bytes_with_types(&env, &[ty])
+            .expect("failed to encode the saved state")
+    }
+
+    /// Removes fields of the state.
+    pub fn remove(&mut self, fields: &[&str]) -> &mut Self {
+        if let IDLValue::Record(record) = &mut self.value {
+            record.retain(|field| !fields.iter().any(|name| is(&field.id, name)));
+        }
+        self
+    }
+
+    /// Removes `fields` from every record held in field `field` of the state.
+    pub fn remove_nested(&mut self, field: &str, fields: &[&str]) -> &mut Self {
+        self.rewrite(field, |value| {
+            if let IDLValue::Record(record) = value {
+                record.retain(|field| !fields.iter().any(|name| is(&field.id, name)));
+            }
+        })
+    }
+
+    /// Sets the type field `field` was saved with, after `rewrite` changed its shape, so that
+    /// the empty vectors and options it holds are encoded with the type the decoder expects.
+    pub fn set_type<T: CandidType>(&mut self, field: &str) -> &mut Self {
+        if let Type::Record(fields) = &mut self.ty {
+            if let Some(saved) = fields.iter_mut().find(|saved| is(&saved.id, field)) {
+                saved.ty = T::ty();
+            }
+        }
+        self
+    }
+
+    /// Calls `f` on every value held in field `field` of the state, innermost first.
+    pub fn rewrite<F: FnMut(&mut IDLValue)>(&mut self, field: &str, mut f: F) -> &mut Self {
+        if let Some(value) = get_mut(&mut self.value, field) {
+            visit(value, &mut f);
+        }
+        self
+    }
+}
+
+/// Field `name` of a record.
+pub fn get_mut<'a>(value: &'a mut IDLValue, name: &str) -> Option<&'a mut IDLValue> {
+    match value {
+        IDLValue::Record(record) => record
+            .iter_mut()
+            .find(|field| is(&field.id, name))
+            .map(|field| &mut field.val),
+        _ => None,
+    }
+}
+
+fn is(label: &Label, name: &str) -> bool {
+    label.get_id() == idl_hash(name)
+}
+
+fn visit<F: FnMut(&mut IDLValue)>(value: &mut IDLValue, f: &mut F) {
+    match value {
+        IDLValue::Opt(inner) => visit(inner, f),
+        IDLValue::Vec(values) => values.iter_mut().for_each(|value| visit(value, f)),
+        IDLValue::Record(record) => record.iter_mut().for_each(|field| visit(&mut field.val, f)),
+        IDLValue::Variant(variant) => visit(&mut variant.0.val, f),
+        _ => {}
+    }
+    f(value)
+}
+
+/// Type of a value. Unlike `IDLValue::value_ty`, it covers every element of a vector, which may
+/// be different variants, or options that are only set on some elements. Empty vectors and
+/// options take their type from `hint`, the type the value was saved with, where it has one.
+fn infer(value: &IDLValue, hint: Option<&Type>) -> Type {
+    match value {
+        IDLValue::None => match hint {
+            Some(Type::Opt(ty)) => Type::Opt(ty.clone()),
+            _ => Type::Opt(Box::new(Type::Empty)),
+        },
+        IDLValue::Opt(inner) => {
+            let hint = match hint {
+                Some(Type::Opt(ty)) => Some(ty.as_ref()),
+                _ => None,
+            };
+            Type::Opt(Box::new(infer(inner, hint)))
+        }
+        IDLValue::Vec(values) => {
+            let hint = match hint {
+                Some(Type::Vec(ty)) => Some(ty.as_ref()),
+                _ => None,
+            };
+            let ty = values.iter().map(|value| infer(value, hint)).fold(Type::Empty, merge);
+            match (ty, hint) {
+                (Type::Empty, Some(ty)) => Type::Vec(Box::new(ty.clone())),
+                (ty, _) => Type::Vec(Box::new(ty)),
+            }
+        }
+        IDLValue::Record(record) => {
+            Type::Record(fields(record.iter().map(|field| field_type(field, hint))))
+        }
+        IDLValue::Variant(variant) => Type::Variant(vec![field_type(&variant.0, hint)]),
+        value => value.value_ty(),
+    }
+}
+
+fn field_type(field: &IDLField, hint: Option<&Type>) -> Field {
+    let id = field.id.get_id();
+    let hint = match hint {
+        Some(Type::Record(fields) | Type::Variant(fields)) => fields
+            .iter()
+            .find(|field| field.id.get_id() == id)
+            .map(|field| &field.ty),
+        _ => None,
+    };
+    Field {
+        id: Label::Id(id),
+        ty: infer(&field.val, hint),
+    }
+}
+
+/// Fields ordered by id, merging the types of fields with the same id.
+fn fields<I: IntoIterator<Item = Field>>(fields: I) -> Vec<Field> {
+    let mut merged = BTreeMap::new();
+    for field in fields {
+        let id = field.id.get_id();
+        let ty = match merged.remove(&id) {
+            Some(ty) => merge(ty, field.ty),
+            None => field.ty,
+        };
+        merged.insert(id, ty);
+    }
+    merged
+        .into_iter()
+        .map(|(id, ty)| Field {
+            id: Label::Id(id),
+            ty,
+        })
+        .collect()
+}
+
+fn merge(a: Type, b: Type) -> Type {
+    match (a, b) {
+        (Type::Empty, ty) | (ty, Type::Empty) => ty,
+        (Type::Opt(a), Type::Opt(b)) => Type::Opt(Box::new(merge(*a, *b))),
+        (Type::Vec(a), Type::Vec(b)) => Type::Vec(Box::new(merge(*a, *b))),
+        (Type::Record(a), Type::Record(b)) => Type::Record(fields(a.into_iter().chain(b))),
+        (Type::Variant(a), Type::Variant(b)) => Type::Variant(fields(a.into_iter().chain(b))),
+        (a, _) => a,
+    }
+}
@@ -0,0 +1,266 @@
+use candid::{Nat, Principal};
+
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction, ProposalStatus};
+use enoki_wrapped_token_harness::canisters::{Instance, Shard, Token};
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shard::management::ManagerContractData;
+use enoki_wrapped_token_shared::backup::{
+    Backup, BackupManifest, Restore, BACKUP_CHUNK_SIZE, BACKUP_FORMAT_VERSION,
+};
+use enoki_wrapped_token_shared::types::{Account, ByteBuf, Result, TxError};
+
+const FEE: u64 = 10;
+
+fn backup(system: &mut TokenSystem, canister: Principal) -> (BackupManifest, Vec<ByteBuf>) {
+    let owner = system.owner;
+    let (manifest, ): (Result<BackupManifest>, ) = system
+        .sim
+        .update(owner, canister, "beginBackup", ())
+        .unwrap();
+    let manifest = manifest.unwrap();
+    let chunks = (0..manifest.chunk_count())
+        .map(|index| {
+            let (chunk, ): (Result<ByteBuf>, ) = system
+                .sim
+                .query(owner, canister, "getBackupChunk", (index, ))
+                .unwrap();
+            chunk.unwrap()
+        })
+        .collect();
+    (manifest, chunks)
+}
+
+fn restore(
+    system: &mut TokenSystem,
+    canister: Principal,
+    manifest: BackupManifest,
+    chunks: Vec<ByteBuf>,
+) -> Result<()> {
+    let owner = system.owner;
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, canister, "beginRestore", (manifest, ))
+        .unwrap();
+    result?;
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let (result, ): (Result<()>, ) = system
+            .sim
+            .update(owner, canister, "putRestoreChunk", (index as u64, chunk))
+            .unwrap();
+        result?;
+    }
+    let (result, ) = system.sim.update(owner, canister, "finishRestore", ()).unwrap();
+    result
+}
+
+fn reinstall_shard(system: &mut TokenSystem, shard: Principal) {
+    let owner = system.owner;
+    system
+        .sim
+        .install(shard, Box::new(Instance::<Shard>::default()), owner, ());
+}
+
+fn details(system: &mut TokenSystem, shard: Principal) -> ManagerContractData {
+    let owner = system.owner;
+    let (details, ) = system
+        .sim
+        .query(owner, shard, "getManagementDetails", ())
+        .unwrap();
+    details
+}
+
+/// Alice and Bob on different shards, with a subaccount and a fee beneficiary.
+fn system() -> (TokenSystem, Principal, Principal) {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let shard = system.register(alice);
+    system.register(bob);
+    system.shard_transfer(alice, bob, 300).unwrap().unwrap();
+    let savings = Account::new(alice, Some([1; 32]));
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(
+            alice,
+            shard,
+            "shardAccountTransfer",
+            (None::<[u8; 32]>, shard, savings, Nat::from(200)),
+        )
+        .unwrap();
+    result.unwrap();
+    (system, alice, bob)
+}
+
+#[test]
+fn shards_are_restored_from_a_backup() {
+    let (mut system, alice, bob) = system();
+    let shard = system.register(alice);
+    let before = details(&mut system, shard);
+    let supply = system.total_supply();
+    let (manifest, chunks) = backup(&mut system, shard);
+    assert_eq!(manifest.format_version, BACKUP_FORMAT_VERSION);
+    assert!(manifest.totals.contains(&("subaccounts".to_string(), Nat::from(1))));
+
+    reinstall_shard(&mut system, shard);
+    restore(&mut system, shard, manifest, chunks).unwrap();
+    let after = details(&mut system, shard);
+    assert_eq!(after.manager_contract, before.manager_contract);
+    assert_eq!(after.sibling_shards, before.sibling_shards);
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 - 200);
+    assert_eq!(system.total_supply(), supply);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    assert_eq!(system.balance(bob), 300 - FEE + 100 - FEE);
+}
+
+#[test]
+fn the_main_contract_is_restored_from_a_backup() {
+    let (mut system, alice, bob) = system();
+    let (owner, token) = (system.owner, system.token);
+    let (assigned, ): (Principal, ) = system
+        .sim
+        .query(owner, token, "getAssignedShardId", (alice, ))
+        .unwrap();
+    let (manifest, chunks) = backup(&mut system, token);
+
+    system
+        .sim
+        .install(token, Box::new(Instance::<Token>::default()), owner, ());
+    restore(&mut system, token, manifest, chunks).unwrap();
+    let (restored, ): (Principal, ) = system
+        .sim
+        .query(owner, token, "getAssignedShardId", (alice, ))
+        .unwrap();
+    assert_eq!(restored, assigned);
+    let (shards, ): (Vec<Principal>, ) = system.sim.query(owner, token, "getShardIds", ()).unwrap();
+    assert_eq!(shards.len(), 2);
+
+    let () = system
+        .sim
+        .update(alice, token, "transfer", (bob, Nat::from(100)))
+        .unwrap();
+    assert_eq!(system.balance(bob), 300 - FEE + 100 - FEE);
+}
+
+#[test]
+fn corrupted_backups_are_refused() {
+    let (mut system, alice, _) = system();
+    let shard = system.register(alice);
+    let (manifest, chunks) = backup(&mut system, shard);
+
+    // only fresh shards are restored
+    let result = restore(&mut system, shard, manifest.clone(), chunks.clone());
+    assert!(matches!(result, Err(TxError::Other(_))));
+
+    reinstall_shard(&mut system, shard);
+    let mut corrupted = chunks.clone();
+    let last = corrupted[0].len() - 1;
+    corrupted[0][last] ^= 1;
+    let result = restore(&mut system, shard, manifest.clone(), corrupted);
+    assert!(matches!(result, Err(TxError::Other(message)) if message.contains("checksum")));
+
+    let mut wrong_totals = manifest.clone();
+    wrong_totals.totals[0].1 += 1u64;
+    assert!(restore(&mut system, shard, wrong_totals, chunks.clone()).is_err());
+    assert_eq!(details(&mut system, shard).manager_contract, Principal::anonymous());
+
+    let mut future = manifest.clone();
+    future.format_version += 1;
+    assert!(restore(&mut system, shard, future, chunks.clone()).is_err());
+
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(user_id(9), shard, "beginRestore", (manifest.clone(), ))
+        .unwrap();
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+
+    restore(&mut system, shard, manifest, chunks).unwrap();
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 - 200);
+}
+
+#[test]
+fn backups_are_split_into_chunks() {
+    let bytes: Vec<u8> = (0..2 * BACKUP_CHUNK_SIZE + 3).map(|n| n as u8).collect();
+    let backup = Backup::new(bytes.clone(), 0, vec![]);
+    assert_eq!(backup.manifest.chunk_count(), 3);
+    assert!(backup.chunk(3).is_err());
+
+    let mut restore = Restore::new(backup.manifest.clone()).unwrap();
+    for index in (0..3).rev() {
+        restore.put(index, backup.chunk(index).unwrap().into_vec()).unwrap();
+    }
+    assert!(restore.put(1, vec![0; 3]).is_err());
+    assert_eq!(restore.finish().unwrap(), bytes);
+
+    let mut restore = Restore::new(backup.manifest.clone()).unwrap();
+    assert!(restore.put(u64::MAX, vec![]).is_err());
+    assert!(restore.put(3, vec![0; 3]).is_err());
+
+    let mut partial = Restore::new(backup.manifest.clone()).unwrap();
+    partial.put(0, backup.chunk(0).unwrap().into_vec()).unwrap();
+    assert!(partial.finish().is_err());
+}
+
+#[test]
+fn governed_shards_are_restored_by_proposal() {
+    let (mut system, alice, _) = system();
+    let (owner, token) = (system.owner, system.token);
+    let config = GovernanceConfig {
+        signers: vec![owner],
+        threshold: 1,
+        timelock: 0,
+    };
+    let () = system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+        .unwrap();
+    let shard = system.register(alice);
+    let (manifest, chunks) = backup(&mut system, shard);
+
+    reinstall_shard(&mut system, shard);
+    let result = restore(&mut system, shard, manifest, chunks);
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+    let action = ProposalAction::FinishShardRestore(shard);
+    let (id, ): (u64, ) = system
+        .sim
+        .update(owner, token, "submitProposal", (action, ))
+        .unwrap();
+    let (status, ): (ProposalStatus, ) = system
+        .sim
+        .update(owner, token, "executeProposal", (id, ))
+        .unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert_eq!(details(&mut system, shard).manager_contract, token);
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 - 200);
+}
+
+#[test]
+fn shards_initialized_during_a_restore_are_not_restored() {
+    let (mut system, alice, _) = system();
+    let (owner, token, underlying) = (system.owner, system.token, system.underlying);
+    let shard = system.register(alice);
+    let (manifest, chunks) = backup(&mut system, shard);
+    reinstall_shard(&mut system, shard);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, shard, "beginRestore", (manifest, ))
+        .unwrap();
+    result.unwrap();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let (result, ): (Result<()>, ) = system
+            .sim
+            .update(owner, shard, "putRestoreChunk", (index as u64, chunk))
+            .unwrap();
+        result.unwrap();
+    }
+
+    let () = system
+        .sim
+        .update(owner, shard, "finishInit", (token, underlying))
+        .unwrap();
+    let (result, ): (Result<()>, ) = system.sim.update(owner, shard, "finishRestore", ()).unwrap();
+    assert!(matches!(result, Err(TxError::Other(message)) if message.contains("fresh")));
+    assert_eq!(system.balance(alice), 0u64);
+}
@@ -0,0 +1,118 @@
+use candid::{Nat, Principal};
+use futures::executor::block_on;
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token_client::{ClientError, TokenClient};
+use enoki_wrapped_token_harness::{user_id, Schedule, SimulatorTransport, TokenSystem};
+use enoki_wrapped_token_shared::types::TxError;
+
+const FEE: u64 = 10;
+
+/// Alice and bob each wrapped 1_000 tokens, and are on different shards.
+fn system() -> TokenSystem {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    for user in [user_id(1), user_id(2)] {
+        system.mint_underlying(user, 1_000);
+        system.wrap(user, 1_000).unwrap();
+    }
+    assert_ne!(system.register(user_id(1)), system.register(user_id(2)));
+    system
+}
+
+#[test]
+fn transfers_are_routed_to_the_shards_of_both_accounts() {
+    let mut system = system();
+    let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+    let token = system.token;
+    let transport = SimulatorTransport::new(&mut system.sim, alice);
+    let client = TokenClient::new(transport, token);
+
+    block_on(async {
+        client.register(carol).await.unwrap();
+        client.transfer(bob, Nat::from(100)).await.unwrap();
+        client.transfer(carol, Nat::from(200)).await.unwrap();
+        client.transfer(bob, Nat::from(300)).await.unwrap();
+        assert_eq!(client.balance_of(alice).await.unwrap(), 999u64 - 600);
+        assert_eq!(client.balance_of(bob).await.unwrap(), 1_399u64 - 2 * FEE);
+        assert_eq!(client.balance_of(carol).await.unwrap(), 200u64 - FEE);
+    });
+
+    // registering cached carol, the sender is looked up once and bob registered once
+    let calls = client.transport().calls();
+    let lookups = calls.iter().filter(|m| *m == "getAssignedShardId").count();
+    assert_eq!(lookups, 1);
+    let registrations = calls.iter().filter(|m| *m == "register").count();
+    assert_eq!(registrations, 2);
+}
+
+#[test]
+fn token_errors_are_decoded() {
+    let mut system = system();
+    let (alice, bob) = (user_id(1), user_id(2));
+    let token = system.token;
+    let client = TokenClient::new(SimulatorTransport::new(&mut system.sim, alice), token);
+
+    block_on(async {
+        assert!(matches!(
+            client.transfer(bob, Nat::from(10_000)).await,
+            Err(ClientError::Token(TxError::InsufficientBalance))
+        ));
+        assert!(matches!(
+            client.transfer(bob, Nat::from(FEE)).await,
+            Err(ClientError::Token(TxError::TransferValueTooSmall))
+        ));
+        // unregistered senders have no shard
+        assert!(matches!(
+            client.balance_of(user_id(66)).await,
+            Err(ClientError::Transport(_))
+        ));
+    });
+}
+
+#[test]
+fn batch_transfers_resolve_every_recipient() {
+    let mut system = system();
+    let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+    system.register(carol);
+    let token = system.token;
+    let client = TokenClient::new(SimulatorTransport::new(&mut system.sim, alice), token);
+
+    block_on(async {
+        let results = client
+            .batch_transfer(vec![
+                (bob, Nat::from(100)),
+                (carol, Nat::from(100)),
+                (bob, Nat::from(FEE)),
+            ])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok() && results[1].is_ok());
+        assert!(matches!(results[2], Err(TxError::TransferValueTooSmall)));
+        assert_eq!(client.balance_of(carol).await.unwrap(), 100u64 - FEE);
+    });
+}
+
+#[test]
+fn unregistered_recipients_are_registered() {
+    let mut system = system();
+    let (alice, dave, erin) = (user_id(1), user_id(4), user_id(5));
+    let token = system.token;
+    let client = TokenClient::new(SimulatorTransport::new(&mut system.sim, alice), token);
+
+    block_on(async {
+        client.transfer(dave, Nat::from(100)).await.unwrap();
+        assert_eq!(client.balance_of(dave).await.unwrap(), 100u64 - FEE);
+        let results = client
+            .batch_transfer(vec![(erin, Nat::from(100))])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert_eq!(client.balance_of(erin).await.unwrap(), 100u64 - FEE);
+    });
+    let owner = system.owner;
+    for user in [dave, erin] {
+        let assigned: CallResult<(Principal, )> =
+            system.sim.query(owner, token, "getAssignedShardId", (user, ));
+        assert!(assigned.is_ok());
+    }
+}
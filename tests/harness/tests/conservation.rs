@@ -0,0 +1,253 @@
+use candid::{decode_args, encode_args, encode_one, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+use proptest::prelude::*;
+
+use enoki_wrapped_token_harness::{
+    canister_id, user_id, NativeCanister, Reply, Schedule, TokenSystem,
+};
+use enoki_wrapped_token_shard::interfaces::dip20::TxReceipt;
+use enoki_wrapped_token_shared::types::{
+    ByteBuf, NotificationResponse, ShardedTransferNotification,
+};
+
+const USERS: usize = 4;
+const SHARDS: u64 = 3;
+const FEE: u64 = 10;
+const UNDERLYING_FEE: u64 = 1;
+const INITIAL_UNDERLYING: u64 = 1_000_000;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Wrap { user: usize, amount: u64 },
+    Unwrap { user: usize, amount: u64 },
+    Transfer { from: usize, to: usize, amount: u64 },
+    // spent by the next user, who is a spender of every account
+    Spend { from: usize, to: usize, amount: u64 },
+    TransferAndCall { from: usize, to: usize, amount: u64, response: Callback },
+}
+
+/// How the notified canister responds to a transferAndCall, passed to it as `data`.
+#[derive(Clone, Copy, Debug)]
+enum Callback {
+    Accept,
+    AcceptHalf,
+    Reject,
+    Trap,
+}
+
+impl Callback {
+    fn encode(self) -> ByteBuf {
+        ByteBuf::from(vec![self as u8])
+    }
+
+    fn reply(data: &[u8], value: Nat) -> Reply {
+        let response = match data {
+            [0] => NotificationResponse::Accept,
+            [1] => NotificationResponse::PartialAccept(value / 2u64),
+            [2] => NotificationResponse::Reject("rejected".to_string()),
+            _ => return Err((RejectionCode::CanisterError, "trapped".to_string())),
+        };
+        Ok(encode_one(response).unwrap())
+    }
+}
+
+fn callback() -> impl Strategy<Value = Callback> {
+    prop_oneof![
+        Just(Callback::Accept),
+        Just(Callback::AcceptHalf),
+        Just(Callback::Reject),
+        Just(Callback::Trap),
+    ]
+}
+
+fn user() -> impl Strategy<Value = usize> {
+    0..USERS
+}
+
+fn amount() -> impl Strategy<Value = u64> {
+    0..2_000u64
+}
+
+fn op(with_callbacks: bool) -> BoxedStrategy<Op> {
+    let ops = prop_oneof![
+        (user(), amount()).prop_map(|(user, amount)| Op::Wrap { user, amount }),
+        (user(), amount()).prop_map(|(user, amount)| Op::Unwrap { user, amount }),
+        (user(), user(), amount()).prop_map(|(from, to, amount)| Op::Transfer { from, to, amount }),
+        (user(), user(), amount()).prop_map(|(from, to, amount)| Op::Spend { from, to, amount }),
+    ];
+    if with_callbacks {
+        prop_oneof![
+            ops,
+            (user(), user(), amount(), callback()).prop_map(|(from, to, amount, response)| {
+                Op::TransferAndCall {
+                    from,
+                    to,
+                    amount,
+                    response,
+                }
+            }),
+        ]
+        .boxed()
+    } else {
+        ops.boxed()
+    }
+}
+
+/// Batches of operations submitted concurrently, a failure rate (in percent) for inter-canister
+/// calls, and the seed that drives both the schedule and the injected failures.
+fn scenario(with_callbacks: bool) -> impl Strategy<Value = (Vec<Vec<Op>>, u64, u64)> {
+    (
+        prop::collection::vec(prop::collection::vec(op(with_callbacks), 1..5), 1..6),
+        0..40u64,
+        any::<u64>(),
+    )
+}
+
+struct World {
+    system: TokenSystem,
+    users: Vec<Principal>,
+    shards: Vec<Principal>,
+    callback: Principal,
+}
+
+impl World {
+    fn new(seed: u64) -> Self {
+        let mut system = TokenSystem::new(Schedule::Random(seed | 1), SHARDS, FEE, UNDERLYING_FEE);
+        let users: Vec<Principal> = (1..=USERS as u64).map(user_id).collect();
+        let shards: Vec<Principal> = users.iter().map(|&u| system.register(u)).collect();
+        for (i, &user) in users.iter().enumerate() {
+            system.mint_underlying(user, INITIAL_UNDERLYING);
+            let (receipt, ): (TxReceipt, ) = system
+                .sim
+                .update(
+                    user,
+                    system.underlying,
+                    "approve",
+                    (shards[i], Nat::from(INITIAL_UNDERLYING)),
+                )
+                .unwrap();
+            receipt.unwrap();
+            let () = system
+                .sim
+                .update(user, shards[i], "addSpender", (users[(i + 1) % USERS], ))
+                .unwrap();
+        }
+
+        let callback = canister_id(50);
+        system.sim.install(
+            callback,
+            Box::new(NativeCanister(|_, _: &str, args: Vec<u8>| {
+                let (notification, ): (ShardedTransferNotification, ) =
+                    decode_args(&args).unwrap();
+                Callback::reply(&notification.data, notification.value)
+            })),
+            system.owner,
+            (),
+        );
+
+        Self {
+            system,
+            users,
+            shards,
+            callback,
+        }
+    }
+
+    fn submit(&mut self, op: &Op) {
+        let (caller, shard, method, args) = match *op {
+            Op::Wrap { user, amount } => (
+                self.users[user],
+                self.shards[user],
+                "wrap",
+                encode_args((Nat::from(amount), )),
+            ),
+            Op::Unwrap { user, amount } => (
+                self.users[user],
+                self.shards[user],
+                "unwrap",
+                encode_args((Nat::from(amount), self.users[user])),
+            ),
+            Op::Transfer { from, to, amount } => (
+                self.users[from],
+                self.shards[from],
+                "shardTransfer",
+                encode_args((self.shards[to], self.users[to], Nat::from(amount))),
+            ),
+            Op::Spend { from, to, amount } => (
+                self.users[(from + 1) % USERS],
+                self.shards[from],
+                "shardSpend",
+                encode_args((
+                    self.users[from],
+                    self.shards[to],
+                    self.users[to],
+                    Nat::from(amount),
+                )),
+            ),
+            Op::TransferAndCall {
+                from,
+                to,
+                amount,
+                response,
+            } => (
+                self.users[from],
+                self.shards[from],
+                "shardTransferAndCall",
+                encode_args((
+                    self.shards[to],
+                    self.users[to],
+                    Nat::from(amount),
+                    self.callback,
+                    "deposit".to_string(),
+                    response.encode(),
+                )),
+            ),
+        };
+        self.system.sim.submit(caller, shard, method, args.unwrap());
+    }
+}
+
+fn check_conservation(batches: Vec<Vec<Op>>, failure_percent: u64, seed: u64) {
+    let mut world = World::new(seed);
+    let mut rng = seed;
+    world.system.sim.set_fault_injector(move |_, _, _| {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng % 100 < failure_percent
+    });
+
+    for batch in batches.iter() {
+        for op in batch.iter() {
+            world.submit(op);
+        }
+        world.system.sim.run();
+
+        for (canister, message) in world.system.sim.traps() {
+            assert!(
+                !message.contains("subtract"),
+                "balance went negative on {}: {}",
+                canister,
+                message
+            );
+        }
+        assert_eq!(
+            world.system.wrapped_supply(),
+            world.system.underlying_custody()
+        );
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn supply_is_conserved((batches, failure_percent, seed) in scenario(false)) {
+        check_conservation(batches, failure_percent, seed);
+    }
+
+    #[test]
+    fn supply_is_conserved_with_callbacks((batches, failure_percent, seed) in scenario(true)) {
+        check_conservation(batches, failure_percent, seed);
+    }
+}
@@ -0,0 +1,150 @@
+use candid::Principal;
+
+use enoki_wrapped_token::cycles::{CyclesConfig, CyclesSample};
+use enoki_wrapped_token::shards::Shard;
+use enoki_wrapped_token_harness::{canister_id, user_id, NativeCanister, Schedule, TokenSystem};
+
+const T: u64 = 1_000_000_000_000;
+const HOUR: u64 = 3_600_000_000_000;
+
+fn system() -> TokenSystem {
+    TokenSystem::new(Schedule::Fifo, 2, 10, 1)
+}
+
+fn poll_after(system: &mut TokenSystem, nanoseconds: u64) {
+    system.sim.advance_time(nanoseconds);
+    system.sim.heartbeat();
+    system.sim.run();
+}
+
+fn shard_info(system: &mut TokenSystem, shard: Principal) -> Shard {
+    let (owner, token) = (system.owner, system.token);
+    let (shards, ): (Vec<Shard>, ) = system.sim.query(owner, token, "getShardsInfo", ()).unwrap();
+    shards.into_iter().find(|s| s.id == shard).unwrap()
+}
+
+/// A canister holding cycles, e.g. a fee beneficiary that bought them with its fees.
+fn wallet(system: &mut TokenSystem) -> Principal {
+    let wallet = canister_id(50);
+    let owner = system.owner;
+    system.sim.install(
+        wallet,
+        Box::new(NativeCanister(|_, _: &str, _: Vec<u8>| Ok(vec![]))),
+        owner,
+        (),
+    );
+    wallet
+}
+
+fn deposit(system: &mut TokenSystem, from: Principal, cycles: u64) -> u64 {
+    let token = system.token;
+    let (accepted, ) = system
+        .sim
+        .update_with_payment(from, token, "depositCycles", (), cycles)
+        .unwrap();
+    accepted
+}
+
+#[test]
+fn shards_below_the_threshold_are_topped_up_from_the_reserve() {
+    let mut system = system();
+    let (token, low, high) = (system.token, system.shards[0], system.shards[1]);
+    system.sim.set_cycles(low, T / 2);
+    let reserve = system.sim.cycles(&token);
+
+    poll_after(&mut system, 0);
+
+    assert_eq!(system.sim.cycles(&low), T / 2 + 2 * T);
+    assert_eq!(system.sim.cycles(&token), reserve - 2 * T);
+    let low_info = shard_info(&mut system, low);
+    assert_eq!(low_info.cycles_history.len(), 1);
+    assert_eq!(low_info.cycles_history[0].balance, T / 2);
+    assert_eq!(low_info.cycles_history[0].top_up, 2 * T);
+    assert_eq!(low_info.cycles_warning, None);
+    let high_info = shard_info(&mut system, high);
+    assert_eq!(high_info.cycles_history[0].top_up, 0);
+}
+
+#[test]
+fn shards_are_polled_once_per_interval_with_a_bounded_history() {
+    let mut system = system();
+    let shard = system.shards[0];
+    poll_after(&mut system, 0);
+    poll_after(&mut system, HOUR / 2);
+    assert_eq!(shard_info(&mut system, shard).cycles_history.len(), 1);
+    poll_after(&mut system, HOUR / 2);
+    assert_eq!(shard_info(&mut system, shard).cycles_history.len(), 2);
+
+    for _ in 0..30 {
+        poll_after(&mut system, HOUR);
+    }
+    let history: Vec<CyclesSample> = shard_info(&mut system, shard).cycles_history;
+    assert_eq!(history.len(), 24);
+    assert!(history.windows(2).all(|w| w[1].time - w[0].time >= HOUR));
+}
+
+#[test]
+fn the_pool_is_spent_before_the_reserve() {
+    let mut system = system();
+    let (token, low) = (system.token, system.shards[0]);
+    let wallet = wallet(&mut system);
+    let reserve = system.sim.cycles(&token);
+    let wallet_cycles = system.sim.cycles(&wallet);
+
+    assert_eq!(deposit(&mut system, wallet, 3 * T), 3 * T);
+    assert_eq!(system.sim.cycles(&wallet), wallet_cycles - 3 * T);
+    system.sim.set_cycles(low, 0);
+    poll_after(&mut system, 0);
+
+    let owner = system.owner;
+    let (pool, ): (u64, ) = system.sim.query(owner, token, "getCyclesPool", ()).unwrap();
+    assert_eq!(pool, T);
+    assert_eq!(system.sim.cycles(&token), reserve + T);
+    assert_eq!(system.sim.cycles(&low), 2 * T);
+}
+
+#[test]
+fn shards_that_cannot_be_topped_up_carry_a_warning() {
+    let mut system = system();
+    let (token, low) = (system.token, system.shards[0]);
+    // 1T above the minimum reserve, less than a top-up
+    system.sim.set_cycles(token, 3 * T);
+    system.sim.set_cycles(low, T / 2);
+
+    poll_after(&mut system, 0);
+    assert_eq!(system.sim.cycles(&low), T / 2);
+    assert_eq!(system.sim.cycles(&token), 3 * T);
+    let warning = shard_info(&mut system, low).cycles_warning.unwrap();
+    assert!(warning.contains("below the threshold"), "{}", warning);
+
+    let wallet = wallet(&mut system);
+    deposit(&mut system, wallet, T);
+    poll_after(&mut system, HOUR);
+    assert_eq!(system.sim.cycles(&low), T / 2 + 2 * T);
+    assert_eq!(shard_info(&mut system, low).cycles_warning, None);
+}
+
+#[test]
+fn shard_operators_configure_the_top_ups() {
+    let mut system = system();
+    let (owner, token, low) = (system.owner, system.token, system.shards[0]);
+    let config = CyclesConfig {
+        threshold: 5 * T,
+        top_up_amount: T,
+        min_reserve: 0,
+        poll_interval: HOUR,
+    };
+    let result: Result<(), _> =
+        system
+            .sim
+            .update(user_id(1), token, "setCyclesConfig", (config.clone(), ));
+    assert!(result.is_err());
+    let () = system
+        .sim
+        .update(owner, token, "setCyclesConfig", (config, ))
+        .unwrap();
+
+    system.sim.set_cycles(low, 4 * T);
+    poll_after(&mut system, 0);
+    assert_eq!(system.sim.cycles(&low), 5 * T);
+}
@@ -0,0 +1,292 @@
+use candid::{Nat, Principal};
+
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction, ProposalStatus};
+use enoki_wrapped_token::shards::{Shard, ShardStatus};
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{Account, FeeBeneficiary, Result, TxError};
+
+const FEE: u64 = 10;
+
+fn call<R: for<'a> candid::utils::ArgumentDecoder<'a>>(
+    system: &mut TokenSystem,
+    method: &str,
+    shard: Principal,
+) -> R {
+    let (owner, token) = (system.owner, system.token);
+    system.sim.update(owner, token, method, (shard, )).unwrap()
+}
+
+fn start(system: &mut TokenSystem, shard: Principal) -> Result<()> {
+    call::<(Result<()>, )>(system, "startDecommission", shard).0
+}
+
+fn drain(system: &mut TokenSystem, shard: Principal) -> Result<u64> {
+    call::<(Result<u64>, )>(system, "drainShard", shard).0
+}
+
+fn finish(system: &mut TokenSystem, shard: Principal) -> Result<()> {
+    call::<(Result<()>, )>(system, "finishDecommission", shard).0
+}
+
+fn shards_info(system: &mut TokenSystem) -> Vec<Shard> {
+    let (owner, token) = (system.owner, system.token);
+    let (shards, ) = system.sim.query(owner, token, "getShardsInfo", ()).unwrap();
+    shards
+}
+
+fn assigned_shard(system: &mut TokenSystem, user: Principal) -> Principal {
+    let (owner, token) = (system.owner, system.token);
+    let (shard, ) = system
+        .sim
+        .query(owner, token, "getAssignedShardId", (user, ))
+        .unwrap();
+    shard
+}
+
+#[test]
+fn accounts_and_custody_move_off_a_decommissioned_shard() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob, carol, dave) = (user_id(1), user_id(2), user_id(3), user_id(4));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let old = system.register(alice);
+    assert_ne!(system.register(bob), old);
+    let savings = Account::new(alice, Some([1; 32]));
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(
+            alice,
+            old,
+            "shardAccountTransfer",
+            (None::<[u8; 32]>, old, savings, Nat::from(100)),
+        )
+        .unwrap();
+    result.unwrap();
+    let () = system.sim.update(alice, old, "addSpender", (carol, )).unwrap();
+
+    start(&mut system, old).unwrap();
+    let (draining, ): (bool, ) = system.sim.query(alice, old, "isDraining", ()).unwrap();
+    assert!(draining);
+    assert_ne!(system.register(dave), old);
+    system.mint_underlying(alice, 1_000);
+    assert!(system.wrap(alice, 1_000).is_err());
+
+    assert_eq!(drain(&mut system, old).unwrap(), 0);
+    let new = assigned_shard(&mut system, alice);
+    assert_ne!(new, old);
+    assert_eq!(system.balance(alice), 899u64);
+    let (saved, ): (Nat, ) = system
+        .sim
+        .query(alice, new, "shardAccountBalanceOf", (savings, ))
+        .unwrap();
+    assert_eq!(saved, 90u64);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(carol, new, "shardSpend", (alice, new, bob, Nat::from(100)))
+        .unwrap();
+    result.unwrap();
+
+    let token = system.token;
+    let (pool_before, ): (u64, ) = system.sim.query(alice, token, "getCyclesPool", ()).unwrap();
+    finish(&mut system, old).unwrap();
+    let shards = shards_info(&mut system);
+    assert!(shards.iter().all(|shard| shard.id != old));
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+    let (pool, ): (u64, ) = system.sim.query(alice, token, "getCyclesPool", ()).unwrap();
+    assert!(pool > pool_before);
+
+    // the custody moved with the accounts
+    let before = system.underlying_balance(alice);
+    system.unwrap(alice, 500, alice).unwrap().unwrap();
+    assert_eq!(system.underlying_balance(alice), before + 500u64 - FEE - 1);
+}
+
+#[test]
+fn large_shards_are_drained_over_several_calls() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let old = system.shards[0];
+    let users: Vec<Principal> = (1..=250).map(user_id).collect();
+    for &user in &users {
+        system.register(user);
+    }
+    let on_old = shards_info(&mut system)
+        .into_iter()
+        .find(|shard| shard.id == old)
+        .unwrap()
+        .num_accounts;
+    assert!(on_old > 100);
+
+    start(&mut system, old).unwrap();
+    assert_eq!(drain(&mut system, old).unwrap(), on_old - 100);
+    assert!(matches!(finish(&mut system, old), Err(TxError::Other(_))));
+    assert_eq!(drain(&mut system, old).unwrap(), 0);
+    finish(&mut system, old).unwrap();
+
+    let shards = shards_info(&mut system);
+    assert_eq!(shards.len(), 1);
+    assert_eq!(shards[0].num_accounts, 250);
+    for user in users {
+        assert_eq!(assigned_shard(&mut system, user), shards[0].id);
+    }
+}
+
+#[test]
+fn decommissions_can_be_refused_and_cancelled() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, token) = (user_id(1), system.token);
+    let (first, second) = (system.shards[0], system.shards[1]);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(alice, token, "startDecommission", (first, ))
+        .unwrap();
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+
+    start(&mut system, first).unwrap();
+    assert!(start(&mut system, second).is_err());
+    assert!(start(&mut system, first).is_err());
+    let status = |system: &mut TokenSystem| {
+        shards_info(system)
+            .into_iter()
+            .find(|shard| shard.id == first)
+            .unwrap()
+            .status
+    };
+    assert_eq!(status(&mut system), ShardStatus::Draining);
+
+    call::<(Result<()>, )>(&mut system, "cancelDecommission", first)
+        .0
+        .unwrap();
+    assert_eq!(status(&mut system), ShardStatus::Active);
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+}
+
+#[test]
+fn frozen_accounts_stay_frozen_on_their_new_shard() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let old = system.register(alice);
+    assert_ne!(system.register(bob), old);
+    let (owner, token) = (system.owner, system.token);
+    let () = system
+        .sim
+        .update(owner, token, "freezeAccount", (alice, ))
+        .unwrap();
+
+    start(&mut system, old).unwrap();
+    assert_eq!(drain(&mut system, old).unwrap(), 0);
+    let new = assigned_shard(&mut system, alice);
+    assert_ne!(new, old);
+    let (frozen, ): (bool, ) = system.sim.query(alice, new, "isFrozen", (alice, )).unwrap();
+    assert!(frozen);
+    assert!(matches!(
+        system.shard_transfer(alice, bob, 100).unwrap(),
+        Err(TxError::AccountFrozen { .. })
+    ));
+    finish(&mut system, old).unwrap();
+
+    let () = system
+        .sim
+        .update(owner, token, "unfreezeAccount", (alice, ))
+        .unwrap();
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    assert_eq!(system.balance(bob), 100 - FEE);
+}
+
+#[test]
+fn decommissions_are_proposals_under_governance() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, old) = (system.owner, system.token, system.shards[0]);
+    let alice = user_id(1);
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let config = GovernanceConfig {
+        signers: vec![owner],
+        threshold: 1,
+        timelock: 0,
+    };
+    let () = system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+        .unwrap();
+
+    for method in ["startDecommission", "finishDecommission", "cancelDecommission"] {
+        let (result, ): (Result<()>, ) =
+            system.sim.update(owner, token, method, (old, )).unwrap();
+        assert!(result.is_err(), "{} is not gated", method);
+    }
+    let (result, ): (Result<u64>, ) =
+        system.sim.update(owner, token, "drainShard", (old, )).unwrap();
+    assert!(result.is_err(), "drainShard is not gated");
+
+    let mut execute = |action: ProposalAction| {
+        let (id, ): (u64, ) = system
+            .sim
+            .update(owner, token, "submitProposal", (action, ))
+            .unwrap();
+        let (status, ): (ProposalStatus, ) = system
+            .sim
+            .update(owner, token, "executeProposal", (id, ))
+            .unwrap();
+        status
+    };
+    let executed = |status| matches!(status, ProposalStatus::Executed { .. });
+    assert!(executed(execute(ProposalAction::StartDecommission(old))));
+    assert!(executed(execute(ProposalAction::CancelDecommission(old))));
+    assert!(executed(execute(ProposalAction::StartDecommission(old))));
+    assert!(executed(execute(ProposalAction::DrainShard(old))));
+    assert!(executed(execute(ProposalAction::FinishDecommission(old))));
+    assert!(matches!(
+        execute(ProposalAction::FinishDecommission(old)),
+        ProposalStatus::Failed { .. }
+    ));
+
+    assert!(shards_info(&mut system).iter().all(|shard| shard.id != old));
+    assert_eq!(system.balance(alice), 999u64);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn fees_move_only_with_the_custody() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, underlying) = (system.owner, system.token, system.underlying);
+    let (alice, bob, carol, dave) = (user_id(1), user_id(2), user_id(3), user_id(4));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let old = system.register(alice);
+    assert_ne!(system.register(bob), old);
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    // a third of a fee is left as dust
+    let beneficiaries = vec![
+        FeeBeneficiary { id: carol, weight: 1 },
+        FeeBeneficiary { id: dave, weight: 2 },
+    ];
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "setFeeBeneficiaries", (beneficiaries, ))
+        .unwrap();
+    result.unwrap();
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    system.sim.advance_time(60_000_000_000);
+    system.sim.heartbeat();
+    system.sim.run();
+    let fees = system.accrued_fees();
+    assert_eq!(fees, FEE + 1);
+
+    start(&mut system, old).unwrap();
+    drain(&mut system, old).unwrap();
+    system.sim.set_fault_injector(move |from, to, method| {
+        from == old && to == underlying && method == "transfer"
+    });
+    assert!(matches!(finish(&mut system, old), Err(TxError::UnderlyingTransferFailure)));
+    system.sim.clear_fault_injector();
+    assert_eq!(system.accrued_fees(), fees);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+
+    finish(&mut system, old).unwrap();
+    // the fee of the custody transfer is paid out of the fees
+    assert_eq!(system.accrued_fees(), fees - 1u64);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
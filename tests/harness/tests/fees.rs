@@ -0,0 +1,182 @@
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shard::fees::FeeDistribution;
+use enoki_wrapped_token_shared::types::{FeeBeneficiary, Result, Role, TxError};
+
+const FEE: u64 = 10;
+/// Nanoseconds between two payouts of the fee shares owed to other shards.
+const PAYOUT_INTERVAL: u64 = 60_000_000_000;
+
+fn set_beneficiaries(
+    system: &mut TokenSystem,
+    caller: Principal,
+    beneficiaries: &[(Principal, u64)],
+) -> Result<()> {
+    let beneficiaries: Vec<FeeBeneficiary> = beneficiaries
+        .iter()
+        .map(|&(id, weight)| FeeBeneficiary { id, weight })
+        .collect();
+    let token = system.token;
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(caller, token, "setFeeBeneficiaries", (beneficiaries, ))
+        .unwrap();
+    result
+}
+
+fn pay_out(system: &mut TokenSystem) {
+    system.sim.advance_time(PAYOUT_INTERVAL);
+    system.sim.heartbeat();
+    system.sim.run();
+}
+
+/// A user with 1_000 on another shard than `user`.
+fn sender_away_from(system: &mut TokenSystem, user: Principal) -> Principal {
+    let shard = system.register(user);
+    let sender = (1..)
+        .map(user_id)
+        .find(|&sender| sender != user && system.register(sender) != shard)
+        .unwrap();
+    system.mint_underlying(sender, 1_000);
+    system.wrap(sender, 1_000).unwrap();
+    sender
+}
+
+#[test]
+fn fee_shares_are_paid_out_on_the_beneficiary_shard() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, carol) = (system.owner, user_id(50));
+    // setting the beneficiary registers it
+    set_beneficiaries(&mut system, owner, &[(carol, 1)]).unwrap();
+    let (token, carol_shard) = (system.token, system.register(carol));
+    let (shard, ): (Principal, ) =
+        system.sim.query(owner, token, "getAssignedShardId", (carol, )).unwrap();
+    assert_eq!(shard, carol_shard);
+
+    let alice = sender_away_from(&mut system, carol);
+    let alice_shard = system.register(alice);
+    system.shard_transfer(alice, carol, 100).unwrap().unwrap();
+    // owed until the next payout
+    assert_eq!(system.balance(carol), 100 - FEE);
+    assert_eq!(system.accrued_fees(), FEE);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+
+    pay_out(&mut system);
+    assert_eq!(system.balance(carol), 100);
+    assert_eq!(system.accrued_fees(), 0u64);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+    let (distribution, ): (FeeDistribution, ) = system
+        .sim
+        .query(owner, alice_shard, "getFeeDistribution", ())
+        .unwrap();
+    assert!(distribution.owed.is_empty());
+
+    // beneficiaries on the charging shard are credited right away
+    let dave = user_id(51);
+    let bob = (52..)
+        .map(user_id)
+        .find(|&bob| system.register(bob) == carol_shard)
+        .unwrap();
+    system.mint_underlying(bob, 1_000);
+    system.wrap(bob, 1_000).unwrap();
+    system.shard_transfer(bob, dave, 100).unwrap().unwrap();
+    assert_eq!(system.balance(carol), 100 + FEE);
+}
+
+#[test]
+fn fees_are_split_by_weight() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, carol, dave) = (system.owner, user_id(50), user_id(51));
+    set_beneficiaries(&mut system, owner, &[(carol, 1), (dave, 2)]).unwrap();
+    let alice = sender_away_from(&mut system, carol);
+    let bob = user_id(52);
+
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    pay_out(&mut system);
+    assert_eq!(system.balance(carol), 3u64);
+    assert_eq!(system.balance(dave), 6u64);
+    // the dust is carried over to the next fee
+    assert_eq!(system.accrued_fees(), 1u64);
+
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    pay_out(&mut system);
+    assert_eq!(system.balance(carol), 3u64 + 3);
+    assert_eq!(system.balance(dave), 6u64 + 7);
+    assert_eq!(system.accrued_fees(), 1u64);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn fees_accrue_without_beneficiaries() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    pay_out(&mut system);
+    assert_eq!(system.accrued_fees(), FEE);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn fee_beneficiaries_are_set_by_fee_managers() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice, carol) = (system.owner, system.token, user_id(1), user_id(50));
+    assert!(matches!(
+        set_beneficiaries(&mut system, alice, &[(carol, 1)]),
+        Err(TxError::Unauthorized)
+    ));
+
+    let () = system
+        .sim
+        .update(owner, token, "grantRole", (alice, Role::FeeManager))
+        .unwrap();
+    set_beneficiaries(&mut system, alice, &[(carol, 1)]).unwrap();
+    let (beneficiaries, ): (Vec<FeeBeneficiary>, ) =
+        system.sim.query(alice, token, "getFeeBeneficiaries", ()).unwrap();
+    assert_eq!(beneficiaries.len(), 1);
+    assert_eq!(beneficiaries[0].id, carol);
+}
+
+#[test]
+fn invalid_fee_beneficiaries_are_rejected() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, carol, dave) = (system.owner, user_id(50), user_id(51));
+    assert!(set_beneficiaries(&mut system, owner, &[(carol, 0)]).is_err());
+    assert!(set_beneficiaries(&mut system, owner, &[(carol, 1), (carol, 2)]).is_err());
+    assert!(set_beneficiaries(&mut system, owner, &[(carol, u64::MAX), (dave, 1)]).is_err());
+    assert!(set_beneficiaries(&mut system, owner, &[(carol, 1), (dave, 1)]).is_ok());
+
+    // only the main contract sets them on the shards
+    let shard = system.shards[0];
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, shard, "setFeeBeneficiaries", (Vec::<FeeBeneficiary>::new(), ))
+        .unwrap();
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+}
+
+#[test]
+fn beneficiaries_that_cannot_be_registered_are_not_set() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, carol) = (system.owner, system.token, user_id(50));
+    system
+        .sim
+        .set_fault_injector(|_, _, method| method == "createAccount");
+    assert!(set_beneficiaries(&mut system, owner, &[(carol, 1)]).is_err());
+    system.sim.clear_fault_injector();
+
+    let (beneficiaries, ): (Vec<FeeBeneficiary>, ) =
+        system.sim.query(owner, token, "getFeeBeneficiaries", ()).unwrap();
+    assert!(beneficiaries.is_empty());
+    // carol is left unassigned, and registers normally afterwards
+    let assigned: CallResult<(Principal, )> =
+        system.sim.query(owner, token, "getAssignedShardId", (carol, ));
+    assert!(assigned.is_err());
+    set_beneficiaries(&mut system, owner, &[(carol, 1)]).unwrap();
+    let (assigned, ): (Principal, ) =
+        system.sim.query(owner, token, "getAssignedShardId", (carol, )).unwrap();
+    assert_eq!(assigned, system.register(carol));
+}
@@ -0,0 +1,143 @@
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token::freeze::FreezeEvent;
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction, ProposalStatus};
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{Role, TxError};
+
+const FEE: u64 = 10;
+
+fn set_frozen(
+    system: &mut TokenSystem,
+    caller: Principal,
+    account: Principal,
+    frozen: bool,
+) -> CallResult<()> {
+    let token = system.token;
+    let method = if frozen { "freezeAccount" } else { "unfreezeAccount" };
+    system.sim.update(caller, token, method, (account, ))
+}
+
+fn block_recipients(system: &mut TokenSystem, block_recipients: bool) -> CallResult<()> {
+    let (owner, token) = (system.owner, system.token);
+    system
+        .sim
+        .update(owner, token, "setBlockFrozenRecipients", (block_recipients, ))
+}
+
+fn is_frozen(system: &mut TokenSystem, account: Principal) -> bool {
+    let shard = system.register(account);
+    let (frozen, ): (bool, ) = system
+        .sim
+        .query(account, shard, "isFrozen", (account, ))
+        .unwrap();
+    frozen
+}
+
+#[test]
+fn frozen_accounts_cannot_send() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice, bob) = (system.owner, system.token, user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    system.register(bob);
+
+    set_frozen(&mut system, owner, alice, true).unwrap();
+    assert!(is_frozen(&mut system, alice));
+    assert!(matches!(
+        system.shard_transfer(alice, bob, 100).unwrap(),
+        Err(TxError::AccountFrozen { .. })
+    ));
+    assert!(system.unwrap(alice, 100, alice).unwrap().is_err());
+    // receiving is allowed until recipients are blocked
+    system.mint_underlying(bob, 1_000);
+    system.wrap(bob, 1_000).unwrap();
+    system.shard_transfer(bob, alice, 100).unwrap().unwrap();
+    block_recipients(&mut system, true).unwrap();
+    let balance = system.balance(alice);
+    assert!(system.shard_transfer(bob, alice, 100).unwrap().is_err());
+    assert_eq!(system.balance(alice), balance);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+
+    set_frozen(&mut system, owner, alice, false).unwrap();
+    assert!(!is_frozen(&mut system, alice));
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    let (events, ): (Vec<FreezeEvent>, ) = system
+        .sim
+        .query(owner, token, "getFreezeEvents", (0u64, 10u64))
+        .unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events[0].frozen && !events[1].frozen);
+    assert!(events.iter().all(|event| event.account == alice && event.by == owner));
+}
+
+#[test]
+fn only_admins_freeze_accounts() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice, bob) = (system.owner, system.token, user_id(1), user_id(2));
+    system.register(bob);
+    assert!(set_frozen(&mut system, alice, bob, true).is_err());
+    let result: CallResult<()> =
+        system
+            .sim
+            .update(alice, token, "setBlockFrozenRecipients", (true, ));
+    assert!(result.is_err());
+    assert!(!is_frozen(&mut system, bob));
+
+    let () = system
+        .sim
+        .update(owner, token, "grantRole", (alice, Role::Admin))
+        .unwrap();
+    set_frozen(&mut system, alice, bob, true).unwrap();
+    assert!(is_frozen(&mut system, bob));
+}
+
+#[test]
+fn freezing_does_not_register_accounts() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice) = (system.owner, system.token, user_id(1));
+    assert!(set_frozen(&mut system, owner, alice, true).is_err());
+    let result: CallResult<(Principal, )> =
+        system.sim.query(owner, token, "getAssignedShardId", (alice, ));
+    assert!(result.is_err());
+    let (frozen, ): (Vec<Principal>, ) =
+        system.sim.query(owner, token, "getFrozenAccounts", ()).unwrap();
+    assert!(frozen.is_empty());
+}
+
+#[test]
+fn freezing_is_a_proposal_under_governance() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice) = (system.owner, system.token, user_id(1));
+    system.register(alice);
+    let config = GovernanceConfig {
+        signers: vec![owner],
+        threshold: 1,
+        timelock: 0,
+    };
+    let () = system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+        .unwrap();
+    assert!(set_frozen(&mut system, owner, alice, true).is_err());
+    assert!(block_recipients(&mut system, true).is_err());
+
+    let execute = |system: &mut TokenSystem, action: ProposalAction| {
+        let (id, ): (u64, ) = system
+            .sim
+            .update(owner, token, "submitProposal", (action, ))
+            .unwrap();
+        let (status, ): (ProposalStatus, ) = system
+            .sim
+            .update(owner, token, "executeProposal", (id, ))
+            .unwrap();
+        status
+    };
+    let executed = |status| matches!(status, ProposalStatus::Executed { .. });
+    assert!(executed(execute(&mut system, ProposalAction::FreezeAccount(alice))));
+    assert!(executed(execute(&mut system, ProposalAction::SetBlockFrozenRecipients(true))));
+    assert!(is_frozen(&mut system, alice));
+    assert!(executed(execute(&mut system, ProposalAction::UnfreezeAccount(alice))));
+    assert!(!is_frozen(&mut system, alice));
+}
@@ -0,0 +1,288 @@
+use candid::parser::value::{IDLField, IDLValue, VariantValue};
+use candid::types::Label;
+use candid::{Nat, Principal};
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token::cycles::CyclesConfig;
+use enoki_wrapped_token::governance::{
+    GovernanceConfig, Proposal, ProposalAction, ProposalStatus, MAX_FINISHED_PROPOSALS,
+    MAX_TIMELOCK, PROPOSAL_LIFETIME,
+};
+use enoki_wrapped_token::upgrade::UpgradePayload;
+use enoki_wrapped_token_harness::upgrade::get_mut;
+use enoki_wrapped_token_harness::{user_id, SavedState, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::backup::BackupManifest;
+use enoki_wrapped_token_shared::types::{PauseScope, Result, TxError};
+
+const FEE: u64 = 10;
+const TIMELOCK: u64 = 1_000_000_000_000;
+
+fn configure(
+    system: &mut TokenSystem,
+    signers: &[Principal],
+    threshold: u32,
+    timelock: u64,
+) -> CallResult<()> {
+    let config = GovernanceConfig {
+        signers: signers.to_vec(),
+        threshold,
+        timelock,
+    };
+    let (owner, token) = (system.owner, system.token);
+    system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+}
+
+fn submit(system: &mut TokenSystem, signer: Principal, action: ProposalAction) -> CallResult<u64> {
+    let token = system.token;
+    let (id, ): (u64, ) = system.sim.update(signer, token, "submitProposal", (action, ))?;
+    Ok(id)
+}
+
+fn approve(system: &mut TokenSystem, signer: Principal, id: u64) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(signer, token, "approveProposal", (id, ))
+}
+
+fn cancel(system: &mut TokenSystem, signer: Principal, id: u64) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(signer, token, "cancelProposal", (id, ))
+}
+
+fn execute(system: &mut TokenSystem, signer: Principal, id: u64) -> CallResult<ProposalStatus> {
+    let token = system.token;
+    let (status, ): (ProposalStatus, ) =
+        system.sim.update(signer, token, "executeProposal", (id, ))?;
+    Ok(status)
+}
+
+fn status(system: &mut TokenSystem, id: u64) -> ProposalStatus {
+    let (owner, token) = (system.owner, system.token);
+    let (proposal, ): (Option<Proposal>, ) =
+        system.sim.query(owner, token, "getProposal", (id, )).unwrap();
+    proposal.unwrap().status
+}
+
+fn fee(system: &mut TokenSystem) -> Nat {
+    let (owner, token) = (system.owner, system.token);
+    let (fee, ): (Nat, ) = system.sim.query(owner, token, "getFee", ()).unwrap();
+    fee
+}
+
+#[test]
+fn proposals_execute_once_the_threshold_is_met() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token) = (system.owner, system.token);
+    let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+    configure(&mut system, &[alice, bob, carol], 2, 0).unwrap();
+    // admin actions are gated, and so is the configuration
+    let result: CallResult<()> = system.sim.update(owner, token, "setFee", (Nat::from(20), ));
+    assert!(result.is_err());
+    assert!(configure(&mut system, &[owner], 1, 0).is_err());
+
+    assert!(submit(&mut system, owner, ProposalAction::SetFee(Nat::from(20))).is_err());
+    let id = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(20))).unwrap();
+    assert!(matches!(status(&mut system, id), ProposalStatus::Open));
+    assert!(execute(&mut system, alice, id).is_err());
+    assert!(approve(&mut system, owner, id).is_err());
+    // approving twice counts once
+    approve(&mut system, alice, id).unwrap();
+    assert!(matches!(status(&mut system, id), ProposalStatus::Open));
+
+    approve(&mut system, bob, id).unwrap();
+    assert!(matches!(status(&mut system, id), ProposalStatus::Approved { .. }));
+    assert!(execute(&mut system, owner, id).is_err());
+    let status = execute(&mut system, carol, id).unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert_eq!(fee(&mut system), 20u64);
+    assert!(execute(&mut system, carol, id).is_err());
+}
+
+#[test]
+fn proposals_wait_for_the_timelock() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let alice = user_id(1);
+    assert!(configure(&mut system, &[alice], 1, MAX_TIMELOCK + 1).is_err());
+    assert!(configure(&mut system, &[alice], 1, u64::MAX).is_err());
+    configure(&mut system, &[alice], 1, TIMELOCK).unwrap();
+
+    let id = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(20))).unwrap();
+    assert!(execute(&mut system, alice, id).is_err());
+    system.sim.advance_time(TIMELOCK);
+    let status = execute(&mut system, alice, id).unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert_eq!(fee(&mut system), 20u64);
+}
+
+#[test]
+fn any_signer_cancels_a_proposal() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, alice, bob) = (system.owner, user_id(1), user_id(2));
+    configure(&mut system, &[alice, bob], 1, 0).unwrap();
+
+    let id = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(20))).unwrap();
+    assert!(cancel(&mut system, owner, id).is_err());
+    cancel(&mut system, bob, id).unwrap();
+    assert!(matches!(status(&mut system, id), ProposalStatus::Cancelled { by, .. } if by == bob));
+    assert!(execute(&mut system, alice, id).is_err());
+    assert!(approve(&mut system, alice, id).is_err());
+    assert!(cancel(&mut system, alice, id).is_err());
+    assert_eq!(fee(&mut system), FEE);
+}
+
+#[test]
+fn approvals_are_counted_against_the_current_signers() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+    configure(&mut system, &[alice, bob], 2, 0).unwrap();
+    let fee_proposal = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(20))).unwrap();
+    approve(&mut system, bob, fee_proposal).unwrap();
+
+    // bob is replaced by carol
+    let config = GovernanceConfig {
+        signers: vec![alice, carol],
+        threshold: 2,
+        timelock: 0,
+    };
+    let id = submit(&mut system, alice, ProposalAction::SetGovernance(config)).unwrap();
+    approve(&mut system, bob, id).unwrap();
+    execute(&mut system, alice, id).unwrap();
+
+    assert!(execute(&mut system, alice, fee_proposal).is_err());
+    approve(&mut system, carol, fee_proposal).unwrap();
+    let status = execute(&mut system, carol, fee_proposal).unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert_eq!(fee(&mut system), 20u64);
+}
+
+/// Leaves proposal `id` executing, as an execution that trapped after its first call does.
+fn trap_execution(system: &mut TokenSystem, id: u64) {
+    let token = system.token;
+    system.sim.upgrade(token, |bytes| {
+        let mut state = SavedState::decode::<UpgradePayload>(&bytes);
+        let mut index = 0;
+        state.rewrite("governance", |value| {
+            if get_mut(value, "proposer").is_none() {
+                return;
+            }
+            if index == id {
+                let executing = IDLField {
+                    id: Label::Named("Executing".to_string()),
+                    val: IDLValue::Null,
+                };
+                *get_mut(value, "status").unwrap() =
+                    IDLValue::Variant(VariantValue(Box::new(executing), 0));
+            }
+            index += 1;
+        });
+        state.encode()
+    });
+}
+
+#[test]
+fn a_trapped_execution_is_cancelled() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let alice = user_id(1);
+    configure(&mut system, &[alice], 1, 0).unwrap();
+    let action = ProposalAction::SetFee(Nat::from(20));
+    let id = submit(&mut system, alice, action.clone()).unwrap();
+    trap_execution(&mut system, id);
+    assert!(matches!(status(&mut system, id), ProposalStatus::Executing));
+    assert!(execute(&mut system, alice, id).is_err());
+
+    cancel(&mut system, alice, id).unwrap();
+    assert!(matches!(status(&mut system, id), ProposalStatus::Cancelled { .. }));
+    let id = submit(&mut system, alice, action).unwrap();
+    let status = execute(&mut system, alice, id).unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert_eq!(fee(&mut system), 20u64);
+}
+
+#[test]
+fn only_pausing_bypasses_governance() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, shard, alice) = (system.owner, system.token, system.shards[0], user_id(1));
+    configure(&mut system, &[alice], 1, 0).unwrap();
+
+    let () = system
+        .sim
+        .update(owner, token, "pause", (PauseScope::Wrap, ))
+        .unwrap();
+    let result: CallResult<()> = system.sim.update(owner, token, "unpause", (PauseScope::Wrap, ));
+    assert!(result.is_err());
+    let result: CallResult<()> =
+        system
+            .sim
+            .update(owner, token, "setCyclesConfig", (CyclesConfig::default(), ));
+    assert!(result.is_err());
+    let result: CallResult<()> = system.sim.update(owner, token, "fixSiblings", ());
+    assert!(result.is_err());
+    let (result, ): (Result<u64>, ) = system
+        .sim
+        .update(owner, token, "rebalanceShard", (shard, ))
+        .unwrap();
+    assert!(matches!(result, Err(TxError::Other(message)) if message.contains("proposal")));
+    let (manifest, ): (Result<BackupManifest>, ) = system
+        .sim
+        .update(owner, shard, "beginBackup", ())
+        .unwrap();
+    for canister in [token, shard] {
+        let (result, ): (Result<()>, ) = system
+            .sim
+            .update(owner, canister, "beginRestore", (manifest.clone().unwrap(), ))
+            .unwrap();
+        assert!(matches!(result, Err(TxError::Other(message)) if message.contains("proposal")));
+    }
+
+    let id = submit(&mut system, alice, ProposalAction::Unpause(PauseScope::Wrap)).unwrap();
+    assert!(matches!(execute(&mut system, alice, id).unwrap(), ProposalStatus::Executed { .. }));
+    let (paused, ): (Vec<PauseScope>, ) =
+        system.sim.query(owner, shard, "getPausedScopes", ()).unwrap();
+    assert!(paused.is_empty());
+    let id = submit(&mut system, alice, ProposalAction::FixSiblings).unwrap();
+    assert!(matches!(execute(&mut system, alice, id).unwrap(), ProposalStatus::Executed { .. }));
+}
+
+fn proposals(system: &mut TokenSystem) -> Vec<Proposal> {
+    let (owner, token) = (system.owner, system.token);
+    let (proposals, ): (Vec<Proposal>, ) = system
+        .sim
+        .query(owner, token, "getProposals", (0u64, 1_000u64))
+        .unwrap();
+    proposals
+}
+
+#[test]
+fn finished_and_expired_proposals_are_pruned() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    configure(&mut system, &[alice, bob], 2, 0).unwrap();
+    let stale = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(30))).unwrap();
+    system.sim.advance_time(PROPOSAL_LIFETIME + 1);
+    assert!(approve(&mut system, bob, stale).is_err());
+
+    for fee in 0..MAX_FINISHED_PROPOSALS as u64 + 5 {
+        let id = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(fee))).unwrap();
+        approve(&mut system, bob, id).unwrap();
+        execute(&mut system, alice, id).unwrap();
+    }
+    let id = submit(&mut system, alice, ProposalAction::SetFee(Nat::from(20))).unwrap();
+    assert_eq!(id, MAX_FINISHED_PROPOSALS as u64 + 6);
+    let kept = proposals(&mut system);
+    assert_eq!(kept.len(), MAX_FINISHED_PROPOSALS + 1);
+    assert!(kept.windows(2).all(|pair| pair[0].id < pair[1].id));
+    assert_eq!(kept[0].id, 6);
+    assert!(get_proposal(&mut system, stale).is_none());
+
+    approve(&mut system, bob, id).unwrap();
+    assert!(matches!(execute(&mut system, bob, id).unwrap(), ProposalStatus::Executed { .. }));
+    assert_eq!(fee(&mut system), 20u64);
+}
+
+fn get_proposal(system: &mut TokenSystem, id: u64) -> Option<Proposal> {
+    let (owner, token) = (system.owner, system.token);
+    let (proposal, ): (Option<Proposal>, ) =
+        system.sim.query(owner, token, "getProposal", (id, )).unwrap();
+    proposal
+}
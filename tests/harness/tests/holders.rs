@@ -0,0 +1,127 @@
+use candid::{Nat, Principal};
+
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{
+    Account, FeeBeneficiary, Holder, HolderOrder, HoldersCursor, HoldersPage, Result,
+};
+
+const FEE: u64 = 10;
+
+/// Users 1 to 20 wrapped 100 plus their number, and sent 50 to the next one, with the fees paid
+/// out to a beneficiary. User 21 registered without wrapping.
+fn system() -> (TokenSystem, Principal) {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, FEE, 1);
+    let beneficiary = user_id(99);
+    let (owner, token) = (system.owner, system.token);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(
+            owner,
+            token,
+            "setFeeBeneficiaries",
+            (vec![FeeBeneficiary { id: beneficiary, weight: 1 }], ),
+        )
+        .unwrap();
+    result.unwrap();
+    for n in 1..=20 {
+        system.mint_underlying(user_id(n), 100 + n);
+        system.wrap(user_id(n), 100 + n).unwrap();
+    }
+    for n in 1..=20 {
+        system.shard_transfer(user_id(n), user_id(n % 20 + 1), 50).unwrap().unwrap();
+    }
+    system.register(user_id(21));
+    system.sim.advance_time(60_000_000_000);
+    system.sim.heartbeat();
+    system.sim.run();
+    (system, beneficiary)
+}
+
+fn page(
+    system: &mut TokenSystem,
+    order: HolderOrder,
+    after: Option<HoldersCursor>,
+    limit: u64,
+) -> HoldersPage {
+    let (owner, token) = (system.owner, system.token);
+    let (page, ) = system
+        .sim
+        .update(owner, token, "getHolders", (order, after, limit))
+        .unwrap();
+    page
+}
+
+fn all_holders(system: &mut TokenSystem, order: HolderOrder, limit: u64) -> Vec<Holder> {
+    let mut holders = vec![];
+    let mut after = None;
+    loop {
+        let page = page(system, order, after, limit);
+        assert!(page.holders.len() <= limit as usize);
+        holders.extend(page.holders);
+        match page.next {
+            Some(next) => after = Some(next),
+            None => return holders,
+        }
+    }
+}
+
+#[test]
+fn holders_are_paged_across_shards_by_owner() {
+    let (mut system, beneficiary) = system();
+    let holders = all_holders(&mut system, HolderOrder::Owner, 7);
+
+    assert_eq!(holders.len(), 21);
+    assert!(holders.windows(2).all(|w| w[0].owner < w[1].owner));
+    assert!(holders.iter().all(|h| h.owner != user_id(21)));
+    let fees = holders.iter().find(|h| h.owner == beneficiary).unwrap();
+    assert_eq!(fees.balance, 20 * FEE);
+    let total = holders.iter().fold(Nat::from(0), |sum, h| sum + h.balance.clone());
+    assert_eq!(total + system.accrued_fees(), system.wrapped_supply());
+}
+
+#[test]
+fn holders_are_paged_by_decreasing_balance() {
+    let (mut system, beneficiary) = system();
+    let holders = all_holders(&mut system, HolderOrder::Balance, 4);
+
+    assert!(holders
+        .windows(2)
+        .all(|w| HolderOrder::Balance.compare(&w[0], &w[1]).is_le()));
+    // the shares collected on every shard were paid out on the beneficiary's shard
+    let shares = holders.iter().filter(|h| h.owner == beneficiary).count();
+    assert_eq!(shares, 1);
+    assert_eq!(holders.len(), 21);
+    assert_eq!(holders[0].owner, beneficiary);
+    assert_eq!(holders[1].owner, user_id(20));
+}
+
+#[test]
+fn subaccounts_count_towards_their_owner() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let alice = user_id(1);
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let shard = system.register(alice);
+    let savings = Account::new(alice, Some([1; 32]));
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(
+            alice,
+            shard,
+            "shardAccountTransfer",
+            (None::<[u8; 32]>, shard, savings, Nat::from(300)),
+        )
+        .unwrap();
+    result.unwrap();
+
+    let (holders, ): (Vec<(Principal, Nat)>, ) = system
+        .sim
+        .query(alice, shard, "getHolders", (0u64, 10u64))
+        .unwrap();
+    assert_eq!(holders, vec![(alice, Nat::from(1_000 - 1 - FEE))]);
+    let (holders, ): (Vec<(Principal, Nat)>, ) = system
+        .sim
+        .query(alice, shard, "getHolders", (1u64, 10u64))
+        .unwrap();
+    assert!(holders.is_empty());
+}
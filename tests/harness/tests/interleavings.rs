@@ -0,0 +1,283 @@
+use candid::{decode_args, encode_args, encode_one, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use enoki_wrapped_token_harness::explorer::replay;
+use enoki_wrapped_token_harness::{
+    canister_id, user_id, Explorer, Model, NativeCanister, Schedule, Simulator, TokenSystem,
+};
+use enoki_wrapped_token_shard::escrow::HOLD_TIMEOUT;
+use enoki_wrapped_token_shared::types::{ByteBuf, NotificationResponse, Result, TxError};
+
+const FEE: u64 = 10;
+const WRAPPED: u64 = 100;
+const AMOUNT: u64 = 80;
+
+/// Alice, with `WRAPPED` tokens wrapped, and Bob, a spender of Alice's account, each try
+/// to move `AMOUNT` of it concurrently. At most one of the debits can succeed.
+struct Race {
+    system: TokenSystem,
+    alice: Principal,
+    alice_shard: Principal,
+    initial_balance: Nat,
+    carol: Principal,
+    submitted: Vec<usize>,
+}
+
+impl Race {
+    fn new(accept: bool) -> Self {
+        let mut system = TokenSystem::new(Schedule::Fifo, 3, FEE, 1);
+        let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+        let alice_shard = system.register(alice);
+        let carol_shard = system.register(carol);
+        system.register(bob);
+        system.mint_underlying(alice, 1_000);
+        system.wrap(alice, WRAPPED).unwrap();
+        let () = system
+            .sim
+            .update(alice, alice_shard, "addSpender", (bob, ))
+            .unwrap();
+        let initial_balance = system.balance(alice);
+
+        let callback = canister_id(50);
+        system.sim.install(
+            callback,
+            Box::new(NativeCanister(move |_, _: &str, _| {
+                if accept {
+                    Ok(encode_one(NotificationResponse::Accept).unwrap())
+                } else {
+                    Err((RejectionCode::CanisterReject, "rejected".to_string()))
+                }
+            })),
+            system.owner,
+            (),
+        );
+
+        let value = Nat::from(AMOUNT);
+        // Alice's messages are delivered in the order they were sent, Bob's can overtake them
+        let messages = vec![
+            (
+                alice,
+                "shardTransferAndCall",
+                encode_args((
+                    carol_shard,
+                    carol,
+                    value.clone(),
+                    callback,
+                    "deposit".to_string(),
+                    ByteBuf::new(),
+                )),
+            ),
+            (
+                alice,
+                "shardTransfer",
+                encode_args((carol_shard, carol, value.clone())),
+            ),
+            (
+                bob,
+                "shardSpend",
+                encode_args((alice, carol_shard, carol, value)),
+            ),
+        ];
+        let submitted = messages
+            .into_iter()
+            .map(|(caller, method, args)| {
+                system.sim.submit(caller, alice_shard, method, args.unwrap())
+            })
+            .collect();
+
+        Self {
+            system,
+            alice,
+            alice_shard,
+            initial_balance,
+            carol,
+            submitted,
+        }
+    }
+
+    fn succeeded(&self, index: usize) -> bool {
+        match self.system.sim.ingress_reply(self.submitted[index]) {
+            Some(Ok(bytes)) if index == 0 => {
+                matches!(decode_args::<(Result<NotificationResponse>, )>(bytes), Ok((Ok(_), )))
+            }
+            Some(Ok(bytes)) => matches!(decode_args::<(Result<()>, )>(bytes), Ok((Ok(()), ))),
+            _ => false,
+        }
+    }
+
+    /// The transferAndCall debited Alice, but its notification failed and the value was refunded.
+    fn refunded(&self) -> bool {
+        match self.system.sim.ingress_reply(self.submitted[0]) {
+            Some(Ok(bytes)) => matches!(
+                decode_args::<(Result<NotificationResponse>, )>(bytes),
+                Ok((Err(TxError::TransferCallbackError(_)), ))
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl Model for Race {
+    fn simulator(&mut self) -> &mut Simulator {
+        &mut self.system.sim
+    }
+
+    fn check_step(&mut self) -> std::result::Result<(), String> {
+        match self.system.sim.traps().iter().find(|(_, m)| m.contains("subtract")) {
+            Some((canister, message)) => Err(format!("overdraft on {}: {}", canister, message)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_final(&mut self) -> std::result::Result<(), String> {
+        let supply = self.system.wrapped_supply();
+        let custody = self.system.underlying_custody();
+        if supply != custody {
+            return Err(format!("wrapped supply {} != custody {}", supply, custody));
+        }
+
+        let debits = (0..self.submitted.len())
+            .filter(|&index| self.succeeded(index))
+            .count() as u64;
+        if debits > 1 {
+            return Err(format!("{} debits of {} succeeded", debits, AMOUNT));
+        }
+
+        let (alice, carol) = (self.alice, self.carol);
+        let carol_shard = self.system.register(carol);
+        let (alice_balance, ): (Nat, ) = self
+            .system
+            .sim
+            .query(alice, self.alice_shard, "shardBalanceOf", (alice, ))
+            .unwrap();
+        let (carol_balance, ): (Nat, ) = self
+            .system
+            .sim
+            .query(carol, carol_shard, "shardBalanceOf", (carol, ))
+            .unwrap();
+        // refunds keep the fee
+        let refunded_fee = if self.refunded() { FEE } else { 0 };
+        if alice_balance != self.initial_balance.clone() - debits * AMOUNT - refunded_fee
+            || carol_balance != debits * (AMOUNT - FEE)
+        {
+            return Err(format!(
+                "{} debits, but alice has {} and carol has {}",
+                debits, alice_balance, carol_balance
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn concurrent_debits_never_overdraw() {
+    let report = Explorer::new(|| Race::new(true)).explore();
+    assert!(report.complete);
+    assert!(report.schedules > 1);
+    assert!(
+        report.violations.is_empty(),
+        "{} of {} schedules failed, first: {:#?}",
+        report.violations.len(),
+        report.schedules,
+        report.violations[0]
+    );
+}
+
+#[test]
+fn rejected_notifications_are_refunded() {
+    let report = Explorer::new(|| Race::new(false)).explore();
+    assert!(report.complete);
+    assert!(
+        report.violations.is_empty(),
+        "{} of {} schedules failed, first: {:#?}",
+        report.violations.len(),
+        report.schedules,
+        report.violations[0]
+    );
+}
+
+/// Expects Bob's spend to always fail, which it only does in the schedules where Alice's
+/// messages run first.
+struct SpendFails(Race);
+
+impl Model for SpendFails {
+    fn simulator(&mut self) -> &mut Simulator {
+        self.0.simulator()
+    }
+
+    fn check_final(&mut self) -> std::result::Result<(), String> {
+        match self.0.succeeded(2) {
+            true => Err("spend succeeded".to_string()),
+            false => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn violations_can_be_replayed() {
+    let report = Explorer::new(|| SpendFails(Race::new(true))).explore();
+    assert!(report.complete);
+    assert!(report.violations.len() < report.schedules);
+    let violation = report.violations.last().expect("no violation found");
+    assert_eq!(violation.trace.len(), violation.schedule.len());
+    let mut model = replay(SpendFails(Race::new(true)), &violation.schedule);
+    assert_eq!(model.check_final(), Err(violation.error.clone()));
+}
+
+#[test]
+fn exploration_stops_at_max_schedules() {
+    let report = Explorer::new(|| Race::new(true)).max_schedules(2).explore();
+    assert_eq!(report.schedules, 2);
+    assert!(!report.complete);
+}
+
+/// Has `caller` refund held transfer `id`, ahead of the messages still queued.
+fn refund(system: &mut TokenSystem, caller: Principal, shard: Principal, id: u64) -> Result<()> {
+    let args = encode_args((id, )).unwrap();
+    let ingress = system.sim.submit(caller, shard, "refundHeldTransfer", args);
+    let index = *system.sim.deliverable().last().unwrap();
+    system.sim.step(index);
+    let bytes = system.sim.ingress_reply(ingress).unwrap().as_ref().unwrap();
+    decode_args::<(Result<()>, )>(bytes).unwrap().0
+}
+
+#[test]
+fn stale_held_transfers_are_refunded() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 1, FEE, 1);
+    let (alice, carol, owner) = (user_id(1), user_id(3), system.owner);
+    let shard = system.register(alice);
+    system.register(carol);
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, WRAPPED).unwrap();
+    let initial_balance = system.balance(alice);
+    let callback = canister_id(50);
+    system.sim.install(
+        callback,
+        Box::new(NativeCanister(|_, _: &str, _| {
+            Ok(encode_one(NotificationResponse::Accept).unwrap())
+        })),
+        owner,
+        (),
+    );
+
+    // the notification is sent, but has not returned
+    let args = (shard, carol, Nat::from(AMOUNT), callback, "deposit".to_string(), ByteBuf::new());
+    let transfer = system
+        .sim
+        .submit(alice, shard, "shardTransferAndCall", encode_args(args).unwrap());
+    system.sim.step(0);
+    assert!(refund(&mut system, owner, shard, 0).is_err());
+    system.sim.advance_time(HOLD_TIMEOUT);
+    assert!(matches!(refund(&mut system, alice, shard, 0), Err(TxError::Unauthorized)));
+    refund(&mut system, owner, shard, 0).unwrap();
+    assert!(refund(&mut system, owner, shard, 0).is_err());
+
+    // the notification returning late credits nothing
+    system.sim.run();
+    let bytes = system.sim.ingress_reply(transfer).unwrap().as_ref().unwrap();
+    let (result, ): (Result<NotificationResponse>, ) = decode_args(bytes).unwrap();
+    assert!(result.is_err());
+    assert_eq!(system.balance(alice), initial_balance - FEE);
+    assert_eq!(system.balance(carol), 0);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
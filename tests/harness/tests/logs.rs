@@ -0,0 +1,108 @@
+use candid::{Nat, Principal};
+
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::log::{LogEntry, LogLevel, LogOutcome};
+
+const FEE: u64 = 10;
+
+fn logs(system: &mut TokenSystem, canister: Principal, since: u64, level: LogLevel) -> Vec<LogEntry> {
+    let owner = system.owner;
+    let (entries, ) = system
+        .sim
+        .query(owner, canister, "getLogs", (since, level))
+        .unwrap();
+    entries
+}
+
+/// Alice wrapped 1_000 tokens, bob is on another shard.
+fn system() -> TokenSystem {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    assert_ne!(system.register(alice), system.register(bob));
+    system
+}
+
+#[test]
+fn failed_transfers_from_the_main_contract_can_be_traced_to_the_shard() {
+    let mut system = system();
+    let (alice, bob, token) = (user_id(1), user_id(2), system.token);
+    let alice_shard = system.register(alice);
+
+    // the main contract traps after the shard refused the transfer
+    let result: Result<(), _> = system
+        .sim
+        .update(alice, token, "transfer", (bob, Nat::from(5_000)));
+    assert!(result.is_err());
+
+    let main = logs(&mut system, token, 0, LogLevel::Debug);
+    let started = main.iter().find(|e| e.operation == "transfer").unwrap();
+    assert_eq!(started.outcome, LogOutcome::Started);
+    assert_eq!(started.caller, alice);
+    assert!(main
+        .iter()
+        .all(|e| e.operation != "transfer" || e.outcome == LogOutcome::Started));
+
+    let shard = logs(&mut system, alice_shard, 0, LogLevel::Error);
+    let failed = shard
+        .iter()
+        .find(|e| e.args_digest == started.args_digest)
+        .unwrap();
+    assert_eq!(failed.operation, "transferFromManager");
+    assert_eq!(failed.caller, token);
+    assert_eq!(
+        failed.outcome,
+        LogOutcome::Failed("InsufficientBalance".to_string())
+    );
+}
+
+#[test]
+fn logs_are_filtered_by_level_and_id() {
+    let mut system = system();
+    let (alice, bob) = (user_id(1), user_id(2));
+    let alice_shard = system.register(alice);
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    system.shard_transfer(alice, bob, 5_000).unwrap().unwrap_err();
+    system.shard_transfer(alice, bob, 200).unwrap().unwrap();
+
+    let all = logs(&mut system, alice_shard, 0, LogLevel::Debug);
+    let transfers: Vec<&LogEntry> = all.iter().filter(|e| e.operation == "shardTransfer").collect();
+    assert_eq!(transfers.len(), 3);
+    assert!(transfers.iter().all(|e| e.caller == alice));
+    assert_ne!(transfers[0].args_digest, transfers[2].args_digest);
+
+    let errors = logs(&mut system, alice_shard, 0, LogLevel::Error);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].id, transfers[1].id);
+    let since = logs(&mut system, alice_shard, transfers[1].id + 1, LogLevel::Info);
+    assert_eq!(since.len(), 1);
+    assert_eq!(since[0].id, transfers[2].id);
+
+    // admins only
+    let result: Result<(Vec<LogEntry>, ), _> =
+        system
+            .sim
+            .query(alice, alice_shard, "getLogs", (0u64, LogLevel::Debug));
+    assert!(result.is_err());
+    let token = system.token;
+    let result: Result<(Vec<LogEntry>, ), _> =
+        system.sim.query(alice, token, "getLogs", (0u64, LogLevel::Debug));
+    assert!(result.is_err());
+}
+
+#[test]
+fn only_the_latest_entries_are_kept() {
+    let mut system = system();
+    let (alice, bob) = (user_id(1), user_id(2));
+    let alice_shard = system.register(alice);
+    let before = logs(&mut system, alice_shard, 0, LogLevel::Debug).len() as u64;
+    for _ in 0..1_000 {
+        system.shard_transfer(alice, bob, 5).unwrap().unwrap_err();
+    }
+
+    let entries = logs(&mut system, alice_shard, 0, LogLevel::Debug);
+    assert_eq!(entries.len(), 1_000);
+    assert_eq!(entries[0].id, before);
+    assert_eq!(entries.last().unwrap().id, before + 999);
+}
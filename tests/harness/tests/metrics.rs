@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use candid::{Nat, Principal};
+
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::metrics::{HttpRequest, HttpResponse};
+use enoki_wrapped_token_shared::types::{ByteBuf, TxError};
+
+const FEE: u64 = 10;
+
+fn get(system: &mut TokenSystem, canister: Principal, url: &str) -> HttpResponse {
+    let request = HttpRequest {
+        method: "GET".to_string(),
+        url: url.to_string(),
+        headers: vec![],
+        body: ByteBuf::new(),
+    };
+    let owner = system.owner;
+    let (response, ) = system
+        .sim
+        .query(owner, canister, "http_request", (request, ))
+        .unwrap();
+    response
+}
+
+/// Scrapes `canister`, returning each sample by name and labels.
+fn scrape(system: &mut TokenSystem, canister: Principal) -> HashMap<String, u64> {
+    let response = get(system, canister, "/metrics?format=prometheus");
+    assert_eq!(response.status_code, 200);
+    String::from_utf8(response.body.into_vec())
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 3, "{}", line);
+            (fields[0].to_string(), fields[1].parse().unwrap())
+        })
+        .collect()
+}
+
+/// Sum of a metric over all the shards.
+fn sum_over_shards(system: &mut TokenSystem, name: &str) -> u64 {
+    let shards = system.shards.clone();
+    shards
+        .into_iter()
+        .map(|shard| scrape(system, shard)[name])
+        .sum()
+}
+
+#[test]
+fn shards_count_their_operations() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    for user in [alice, bob] {
+        system.mint_underlying(user, 1_000);
+        system.wrap(user, 1_000).unwrap();
+    }
+    assert_ne!(system.register(alice), system.register(bob));
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    system.unwrap(bob, 500, bob).unwrap().unwrap();
+
+    assert_eq!(sum_over_shards(&mut system, "enoki_shard_accounts"), 2);
+    assert_eq!(sum_over_shards(&mut system, "enoki_shard_wraps_total"), 2);
+    assert_eq!(sum_over_shards(&mut system, "enoki_shard_transfers_total"), 1);
+    assert_eq!(sum_over_shards(&mut system, "enoki_shard_unwraps_total"), 1);
+    let balances = sum_over_shards(&mut system, "enoki_shard_balances");
+    let fees = sum_over_shards(&mut system, "enoki_shard_accrued_fees");
+    assert_eq!(Nat::from(balances + fees), system.wrapped_supply());
+    assert_eq!(fees, 2 * FEE);
+
+    let alice_shard = system.register(alice);
+    let metrics = scrape(&mut system, alice_shard);
+    assert_eq!(metrics["enoki_shard_held_transfers"], 0);
+    assert_eq!(
+        metrics["enoki_shard_pending_notifications{status=\"DeadLetter\"}"],
+        0
+    );
+    assert!(metrics.contains_key("enoki_cycle_balance"));
+}
+
+#[test]
+fn failed_sibling_calls_are_counted() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let alice = user_id(1);
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let alice_shard = system.register(alice);
+    let other_shard = *system.shards.iter().find(|s| **s != alice_shard).unwrap();
+
+    // the recipient has no account on the other shard, which rejects the transfer
+    let (result, ): (Result<(), TxError>, ) = system
+        .sim
+        .update(
+            alice,
+            alice_shard,
+            "shardTransfer",
+            (other_shard, user_id(66), Nat::from(100)),
+        )
+        .unwrap();
+    assert!(result.is_err());
+
+    let metrics = scrape(&mut system, alice_shard);
+    assert_eq!(metrics["enoki_shard_failed_sibling_calls_total"], 1);
+    assert_eq!(metrics["enoki_shard_transfers_total"], 0);
+}
+
+#[test]
+fn main_contract_reports_accounts_per_shard() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    system.register(bob);
+    system.register(carol);
+    let token = system.token;
+    let _: () = system
+        .sim
+        .update(alice, token, "transfer", (bob, Nat::from(100)))
+        .unwrap();
+
+    let metrics = scrape(&mut system, token);
+    assert_eq!(metrics["enoki_shards"], 2);
+    assert_eq!(metrics["enoki_transfers_total"], 1);
+    let shards = system.shards.clone();
+    let accounts: u64 = shards
+        .iter()
+        .map(|shard| metrics[&format!("enoki_accounts{{shard=\"{}\"}}", shard)])
+        .sum();
+    assert_eq!(accounts, 3);
+
+    assert_eq!(get(&mut system, token, "/").status_code, 404);
+    assert_eq!(get(&mut system, shards[0], "/stats").status_code, 404);
+}
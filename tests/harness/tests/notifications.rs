@@ -0,0 +1,233 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use candid::{decode_args, encode_one, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use enoki_wrapped_token_harness::{canister_id, user_id, NativeCanister, Schedule, TokenSystem};
+use enoki_wrapped_token_shard::notifications::{NotificationStatus, PendingNotification};
+use enoki_wrapped_token_shared::types::{
+    ByteBuf, NotificationResponse, Result, ShardedTransferNotification, TxError,
+};
+
+const FEE: u64 = 10;
+const SECOND: u64 = 1_000_000_000;
+
+struct Exchange {
+    system: TokenSystem,
+    alice: Principal,
+    alice_shard: Principal,
+    exchange: Principal,
+    exchange_shard: Principal,
+    online: Rc<Cell<bool>>,
+    deposits: Rc<RefCell<Vec<ShardedTransferNotification>>>,
+}
+
+impl Exchange {
+    fn new() -> Self {
+        let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+        let (alice, exchange) = (user_id(1), canister_id(50));
+        let online = Rc::new(Cell::new(false));
+        let deposits = Rc::new(RefCell::new(vec![]));
+        let (is_online, received) = (online.clone(), deposits.clone());
+        system.sim.install(
+            exchange,
+            Box::new(NativeCanister(move |_, _: &str, args: Vec<u8>| {
+                if !is_online.get() {
+                    return Err((RejectionCode::CanisterError, "offline".to_string()));
+                }
+                let (notification, ): (ShardedTransferNotification, ) =
+                    decode_args(&args).unwrap();
+                received.borrow_mut().push(notification);
+                Ok(encode_one(NotificationResponse::Accept).unwrap())
+            })),
+            system.owner,
+            (),
+        );
+        system.mint_underlying(alice, 1_000);
+        system.wrap(alice, 1_000).unwrap();
+        let alice_shard = system.register(alice);
+        let exchange_shard = system.register(exchange);
+        Self {
+            system,
+            alice,
+            alice_shard,
+            exchange,
+            exchange_shard,
+            online,
+            deposits,
+        }
+    }
+
+    fn deposit(&mut self, value: u64) -> Result<u64> {
+        let (result, ) = self
+            .system
+            .sim
+            .update(
+                self.alice,
+                self.alice_shard,
+                "shardTransferAndNotify",
+                (
+                    self.exchange_shard,
+                    self.exchange,
+                    Nat::from(value),
+                    self.exchange,
+                    "deposit".to_string(),
+                    ByteBuf::from(&b"order-1"[..]),
+                ),
+            )
+            .unwrap();
+        result
+    }
+
+    fn pending(&mut self) -> Vec<PendingNotification> {
+        let (pending, ) = self
+            .system
+            .sim
+            .query(self.alice, self.alice_shard, "getPendingNotifications", ())
+            .unwrap();
+        pending
+    }
+
+    fn heartbeat_after(&mut self, nanoseconds: u64) {
+        self.system.sim.advance_time(nanoseconds);
+        self.system.sim.heartbeat();
+        self.system.sim.run();
+    }
+
+    fn retry(&mut self, caller: Principal, id: u64) -> Result<()> {
+        let (result, ) = self
+            .system
+            .sim
+            .update(caller, self.alice_shard, "retryNotification", (id, ))
+            .unwrap();
+        result
+    }
+}
+
+#[test]
+fn recipient_is_credited_before_the_notification() {
+    let mut exchange = Exchange::new();
+    let id = exchange.deposit(100).unwrap();
+    assert_eq!(exchange.system.balance(exchange.exchange), 100u64 - FEE);
+
+    let pending = exchange.pending();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].id, id);
+    assert_eq!(pending[0].status, NotificationStatus::Scheduled);
+
+    exchange.online.set(true);
+    exchange.heartbeat_after(0);
+    assert!(exchange.pending().is_empty());
+    let deposits = exchange.deposits.borrow();
+    assert_eq!(deposits.len(), 1);
+    assert_eq!(deposits[0].from, exchange.alice);
+    assert_eq!(deposits[0].value, 100u64 - FEE);
+    assert_eq!(deposits[0].data.as_ref(), b"order-1");
+}
+
+#[test]
+fn failed_notifications_are_retried_with_backoff() {
+    let mut exchange = Exchange::new();
+    exchange.deposit(100).unwrap();
+
+    exchange.heartbeat_after(0);
+    let pending = exchange.pending();
+    assert_eq!(pending[0].attempts, 1);
+    assert_eq!(pending[0].status, NotificationStatus::Scheduled);
+    assert!(pending[0].last_error.as_ref().unwrap().contains("offline"));
+
+    // the second attempt is due one second after the first, the third two seconds later
+    exchange.heartbeat_after(SECOND / 2);
+    assert_eq!(exchange.pending()[0].attempts, 1);
+    exchange.heartbeat_after(SECOND);
+    assert_eq!(exchange.pending()[0].attempts, 2);
+    exchange.heartbeat_after(SECOND);
+    assert_eq!(exchange.pending()[0].attempts, 2);
+
+    exchange.online.set(true);
+    exchange.heartbeat_after(SECOND);
+    assert!(exchange.pending().is_empty());
+    assert_eq!(exchange.deposits.borrow().len(), 1);
+}
+
+#[test]
+fn undeliverable_notifications_are_dead_lettered() {
+    let mut exchange = Exchange::new();
+    let id = exchange.deposit(100).unwrap();
+    for attempt in 0..10 {
+        exchange.heartbeat_after(SECOND << attempt);
+    }
+    let pending = exchange.pending();
+    assert_eq!(pending[0].attempts, 10);
+    assert_eq!(pending[0].status, NotificationStatus::DeadLetter);
+
+    // dead letters are only delivered on request
+    exchange.online.set(true);
+    exchange.heartbeat_after(SECOND << 10);
+    assert!(exchange.deposits.borrow().is_empty());
+
+    let mallory = user_id(66);
+    assert!(matches!(
+        exchange.retry(mallory, id),
+        Err(TxError::Unauthorized)
+    ));
+    assert!(matches!(
+        exchange.retry(exchange.exchange, id + 1),
+        Err(TxError::NotificationNotFound)
+    ));
+    let exchange_id = exchange.exchange;
+    exchange.retry(exchange_id, id).unwrap();
+    assert!(exchange.pending().is_empty());
+    assert_eq!(exchange.deposits.borrow().len(), 1);
+}
+
+#[test]
+fn failed_transfers_are_not_notified() {
+    let mut exchange = Exchange::new();
+    assert!(matches!(
+        exchange.deposit(10_000),
+        Err(TxError::InsufficientBalance)
+    ));
+    assert!(exchange.pending().is_empty());
+}
+
+
+#[test]
+fn dead_letters_are_dropped_once_acknowledged() {
+    let mut exchange = Exchange::new();
+    let id = exchange.deposit(100).unwrap();
+    let acknowledge = |exchange: &mut Exchange, caller: Principal| -> Result<()> {
+        let shard = exchange.alice_shard;
+        let (result, ) = exchange
+            .system
+            .sim
+            .update(caller, shard, "acknowledgeNotification", (id, ))
+            .unwrap();
+        result
+    };
+    // only given-up deliveries are dropped
+    let alice = exchange.alice;
+    assert!(matches!(acknowledge(&mut exchange, alice), Err(TxError::Other(_))));
+    for attempt in 0..10 {
+        exchange.heartbeat_after(SECOND << attempt);
+    }
+    assert!(matches!(acknowledge(&mut exchange, user_id(66)), Err(TxError::Unauthorized)));
+    acknowledge(&mut exchange, alice).unwrap();
+    assert!(exchange.pending().is_empty());
+    assert!(matches!(acknowledge(&mut exchange, alice), Err(TxError::NotificationNotFound)));
+}
+
+#[test]
+fn retries_are_still_scheduled_after_an_upgrade() {
+    let mut exchange = Exchange::new();
+    exchange.deposit(100).unwrap();
+    exchange.heartbeat_after(0);
+    let shard = exchange.alice_shard;
+    exchange.system.sim.upgrade(shard, |bytes| bytes);
+
+    exchange.online.set(true);
+    exchange.heartbeat_after(SECOND);
+    assert!(exchange.pending().is_empty());
+    assert_eq!(exchange.deposits.borrow().len(), 1);
+}
@@ -0,0 +1,108 @@
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{PauseScope, Role, TxError};
+
+const FEE: u64 = 10;
+
+fn pause(system: &mut TokenSystem, caller: Principal, scope: PauseScope) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(caller, token, "pause", (scope, ))
+}
+
+fn unpause(system: &mut TokenSystem, caller: Principal, scope: PauseScope) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(caller, token, "unpause", (scope, ))
+}
+
+/// The scopes paused on the main contract and on every shard, which must agree.
+fn paused_scopes(system: &mut TokenSystem) -> Vec<PauseScope> {
+    let (owner, token) = (system.owner, system.token);
+    let (mut scopes, ): (Vec<PauseScope>, ) =
+        system.sim.query(owner, token, "getPausedScopes", ()).unwrap();
+    scopes.sort_by_key(|scope| *scope as u8);
+    for shard in system.shards.clone() {
+        let (mut on_shard, ): (Vec<PauseScope>, ) =
+            system.sim.query(owner, shard, "getPausedScopes", ()).unwrap();
+        on_shard.sort_by_key(|scope| *scope as u8);
+        assert_eq!(on_shard, scopes, "the scopes are not synced to shard {}", shard);
+    }
+    scopes
+}
+
+fn funded(system: &mut TokenSystem, n: u64) -> Principal {
+    let user = user_id(n);
+    system.mint_underlying(user, 1_000);
+    system.wrap(user, 500).unwrap();
+    user
+}
+
+#[test]
+fn scopes_pause_their_operations() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let owner = system.owner;
+    let (alice, bob) = (funded(&mut system, 1), funded(&mut system, 2));
+
+    pause(&mut system, owner, PauseScope::Wrap).unwrap();
+    assert_eq!(paused_scopes(&mut system), vec![PauseScope::Wrap]);
+    assert!(system.wrap(alice, 100).is_err());
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    system.unwrap(alice, 100, alice).unwrap().unwrap();
+    unpause(&mut system, owner, PauseScope::Wrap).unwrap();
+    system.wrap(alice, 100).unwrap();
+
+    pause(&mut system, owner, PauseScope::Unwrap).unwrap();
+    assert!(matches!(system.unwrap(alice, 100, alice).unwrap(), Err(TxError::Paused)));
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    unpause(&mut system, owner, PauseScope::Unwrap).unwrap();
+
+    pause(&mut system, owner, PauseScope::Transfers).unwrap();
+    assert!(matches!(system.shard_transfer(alice, bob, 100).unwrap(), Err(TxError::Paused)));
+    system.wrap(alice, 100).unwrap();
+    unpause(&mut system, owner, PauseScope::Transfers).unwrap();
+
+    pause(&mut system, owner, PauseScope::Callbacks).unwrap();
+    let result = system.shard_transfer_and_call(alice, bob, 100, user_id(9), b"");
+    assert!(matches!(result.unwrap(), Err(TxError::Paused)));
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    unpause(&mut system, owner, PauseScope::Callbacks).unwrap();
+    assert!(paused_scopes(&mut system).is_empty());
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn pausing_everything_stops_every_operation() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let owner = system.owner;
+    let (alice, bob) = (funded(&mut system, 1), funded(&mut system, 2));
+
+    pause(&mut system, owner, PauseScope::All).unwrap();
+    assert!(system.wrap(alice, 100).is_err());
+    assert!(matches!(system.unwrap(alice, 100, alice).unwrap(), Err(TxError::Paused)));
+    assert!(matches!(system.shard_transfer(alice, bob, 100).unwrap(), Err(TxError::Paused)));
+
+    // unpausing another scope leaves them paused
+    unpause(&mut system, owner, PauseScope::Transfers).unwrap();
+    assert!(matches!(system.shard_transfer(alice, bob, 100).unwrap(), Err(TxError::Paused)));
+    unpause(&mut system, owner, PauseScope::All).unwrap();
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+}
+
+#[test]
+fn only_pausers_pause() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice, bob) = (system.owner, system.token, user_id(1), user_id(2));
+    assert!(pause(&mut system, alice, PauseScope::All).is_err());
+    assert!(paused_scopes(&mut system).is_empty());
+
+    let () = system
+        .sim
+        .update(owner, token, "grantRole", (alice, Role::Pauser))
+        .unwrap();
+    pause(&mut system, alice, PauseScope::Transfers).unwrap();
+    assert!(unpause(&mut system, bob, PauseScope::Transfers).is_err());
+    assert_eq!(paused_scopes(&mut system), vec![PauseScope::Transfers]);
+    unpause(&mut system, alice, PauseScope::Transfers).unwrap();
+    assert!(paused_scopes(&mut system).is_empty());
+}
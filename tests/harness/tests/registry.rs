@@ -0,0 +1,208 @@
+use candid::{encode_one, Nat, Principal};
+
+use enoki_wrapped_token_harness::{user_id, NativeCanister, Schedule, TokenSystem};
+use enoki_wrapped_token_shard::management::ManagerContractData;
+use enoki_wrapped_token_shared::types::{Result, ShardRegistry, TxError};
+
+fn registry(system: &mut TokenSystem) -> ShardRegistry {
+    let (owner, token) = (system.owner, system.token);
+    let (registry, ) = system.sim.query(owner, token, "getShardRegistry", ()).unwrap();
+    registry
+}
+
+fn details(system: &mut TokenSystem, shard: Principal) -> ManagerContractData {
+    let owner = system.owner;
+    let (details, ) = system
+        .sim
+        .query(owner, shard, "getManagementDetails", ())
+        .unwrap();
+    details
+}
+
+#[test]
+fn every_shard_holds_the_other_shards_of_the_latest_registry() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, 10, 1);
+    let registry = registry(&mut system);
+    assert_eq!(registry.version, 3);
+    assert_eq!(registry.shards.len(), 3);
+
+    for &shard in &registry.shards {
+        let details = details(&mut system, shard);
+        assert_eq!(details.registry_version, 3);
+        assert_eq!(details.sibling_shards.len(), 2);
+        assert!(!details.sibling_shards.contains(&shard));
+    }
+}
+
+#[test]
+fn stale_registries_are_rejected() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, 10, 1);
+    let (token, shard) = (system.token, system.shards[0]);
+    let stale = ShardRegistry {
+        version: 1,
+        shards: vec![shard],
+        ring: None,
+        latest_snapshot: 0,
+        governed: false,
+    };
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(token, shard, "setShardRegistry", (stale, ))
+        .unwrap();
+    assert!(matches!(result, Err(TxError::StaleRegistry { version: 2 })));
+    assert_eq!(details(&mut system, shard).sibling_shards.len(), 1);
+
+    let current = registry(&mut system);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(user_id(1), shard, "setShardRegistry", (current, ))
+        .unwrap();
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+}
+
+#[test]
+fn shards_that_missed_a_push_pull_the_registry() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, 10, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let stale = system.register(alice);
+    system.register(user_id(3));
+
+    system.sim.set_fault_injector(move |_, to, method| {
+        to == stale && method == "setShardRegistry"
+    });
+    let new = system.add_shard();
+    system.sim.clear_fault_injector();
+    assert_eq!(details(&mut system, stale).registry_version, 2);
+    assert_eq!(system.register(bob), new);
+
+    // the stale shard checks the destination before sending
+    system.shard_transfer(alice, bob, 500).unwrap().unwrap();
+    assert_eq!(system.balance(bob), 490u64);
+    let details = details(&mut system, stale);
+    assert_eq!(details.registry_version, 3);
+    assert!(details.sibling_shards.contains(&new));
+
+    system.shard_transfer(bob, alice, 100).unwrap().unwrap();
+    assert_eq!(system.balance(alice), 589u64);
+}
+
+#[test]
+fn fix_siblings_pushes_the_registry_again() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, 10, 1);
+    let (owner, token, stale) = (system.owner, system.token, system.shards[0]);
+    let removed = system.shards[2];
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "startDecommission", (removed, ))
+        .unwrap();
+    result.unwrap();
+
+    system.sim.set_fault_injector(move |_, to, method| {
+        to == stale && method == "setShardRegistry"
+    });
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "finishDecommission", (removed, ))
+        .unwrap();
+    assert!(result.is_err());
+    assert!(details(&mut system, stale).sibling_shards.contains(&removed));
+
+    system.sim.clear_fault_injector();
+    let () = system.sim.update(owner, token, "fixSiblings", ()).unwrap();
+    let details = details(&mut system, stale);
+    assert_eq!(details.registry_version, 5);
+    assert!(!details.sibling_shards.contains(&removed));
+}
+
+/// A shard that missed the push adding a third one, with alice on it and bob on the new shard.
+fn missed_push(system: &mut TokenSystem) -> (Principal, Principal) {
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let stale = system.register(alice);
+    system.register(user_id(3));
+
+    system.sim.set_fault_injector(move |_, to, method| {
+        to == stale && method == "setShardRegistry"
+    });
+    let new = system.add_shard();
+    system.sim.clear_fault_injector();
+    assert_eq!(system.register(bob), new);
+    (stale, new)
+}
+
+fn batch_transfer(
+    system: &mut TokenSystem,
+    from: Principal,
+    transfers: Vec<(Principal, Principal, Nat)>,
+) -> Vec<Result<()>> {
+    let shard = system.register(from);
+    let (results, ): (Result<Vec<Result<()>>>, ) = system
+        .sim
+        .update(from, shard, "shardBatchTransfer", (transfers, ))
+        .unwrap();
+    results.unwrap()
+}
+
+#[test]
+fn batch_transfers_pull_the_registry_before_sending() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, 10, 1);
+    let (stale, new) = missed_push(&mut system);
+    let (alice, bob) = (user_id(1), user_id(2));
+
+    let results = batch_transfer(&mut system, alice, vec![(new, bob, Nat::from(500))]);
+    assert!(results[0].is_ok());
+    assert_eq!(system.balance(bob), 490u64);
+    assert!(details(&mut system, stale).sibling_shards.contains(&new));
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn batch_transfers_pull_the_registry_before_crediting() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, 10, 1);
+    let (stale, new) = missed_push(&mut system);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(bob, 1_000);
+    system.wrap(bob, 1_000).unwrap();
+
+    let results = batch_transfer(&mut system, bob, vec![(stale, alice, Nat::from(100))]);
+    assert!(results[0].is_ok());
+    assert_eq!(system.balance(alice), 999u64 + 90);
+    assert!(details(&mut system, stale).sibling_shards.contains(&new));
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn batched_transfers_without_a_result_are_refunded() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, 10, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let (from, to) = (system.register(alice), system.register(bob));
+    assert_ne!(from, to);
+    let balance = system.balance(alice);
+
+    // a recipient shard that credits nothing and replies with no result
+    let owner = system.owner;
+    system.sim.install(
+        to,
+        Box::new(NativeCanister(|_, _: &str, _| Ok(encode_one(Vec::<Result<()>>::new()).unwrap()))),
+        owner,
+        (),
+    );
+    let results = batch_transfer(
+        &mut system,
+        alice,
+        vec![(to, bob, Nat::from(100)), (to, bob, Nat::from(200))],
+    );
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|result| result.is_err()));
+    // only the fees are kept
+    let (remaining, ): (Nat, ) = system
+        .sim
+        .query(alice, from, "shardBalanceOf", (alice, ))
+        .unwrap();
+    assert_eq!(remaining, balance - 20u64);
+}
@@ -0,0 +1,182 @@
+use candid::{Nat, Principal};
+use ic_cdk::api::call::CallResult;
+
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction, ProposalStatus};
+use enoki_wrapped_token_harness::{user_id, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{PauseScope, Result, Role, TxError};
+
+const FEE: u64 = 10;
+
+fn grant(
+    system: &mut TokenSystem,
+    caller: Principal,
+    user: Principal,
+    role: Role,
+) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(caller, token, "grantRole", (user, role))
+}
+
+fn revoke(
+    system: &mut TokenSystem,
+    caller: Principal,
+    user: Principal,
+    role: Role,
+) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(caller, token, "revokeRole", (user, role))
+}
+
+/// The roles of `user` on the main contract and on every shard, which must agree.
+fn roles(system: &mut TokenSystem, user: Principal) -> Vec<Role> {
+    let token = system.token;
+    let (roles, ): (Vec<Role>, ) = system.sim.query(user, token, "getRoles", (user, )).unwrap();
+    for shard in system.shards.clone() {
+        let (mut on_shard, ): (Vec<Role>, ) =
+            system.sim.query(user, shard, "getRoles", (user, )).unwrap();
+        let mut expected = roles.clone();
+        on_shard.sort_by_key(|role| *role as u8);
+        expected.sort_by_key(|role| *role as u8);
+        assert_eq!(on_shard, expected, "the roles are not synced to shard {}", shard);
+    }
+    roles
+}
+
+fn set_fee(system: &mut TokenSystem, caller: Principal, fee: u64) -> CallResult<()> {
+    let token = system.token;
+    system.sim.update(caller, token, "setFee", (Nat::from(fee), ))
+}
+
+#[test]
+fn roles_are_granted_and_synced_to_the_shards() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, alice) = (system.owner, user_id(1));
+    assert_eq!(roles(&mut system, owner).len(), 4);
+    assert!(roles(&mut system, alice).is_empty());
+    assert!(set_fee(&mut system, alice, 20).is_err());
+
+    grant(&mut system, owner, alice, Role::FeeManager).unwrap();
+    assert_eq!(roles(&mut system, alice), vec![Role::FeeManager]);
+    set_fee(&mut system, alice, 20).unwrap();
+    // other roles are still denied
+    let token = system.token;
+    let result: CallResult<()> = system.sim.update(alice, token, "pause", (PauseScope::All, ));
+    assert!(result.is_err());
+
+    revoke(&mut system, owner, alice, Role::FeeManager).unwrap();
+    assert!(roles(&mut system, alice).is_empty());
+    assert!(set_fee(&mut system, alice, 30).is_err());
+}
+
+#[test]
+fn only_admins_manage_roles_and_only_the_owner_grants_admin() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, alice, bob) = (system.owner, user_id(1), user_id(2));
+    assert!(grant(&mut system, alice, bob, Role::Pauser).is_err());
+    assert!(grant(&mut system, alice, alice, Role::Admin).is_err());
+
+    grant(&mut system, owner, alice, Role::Admin).unwrap();
+    assert_eq!(roles(&mut system, alice).len(), 4);
+    grant(&mut system, alice, bob, Role::Pauser).unwrap();
+    assert!(grant(&mut system, alice, bob, Role::Admin).is_err());
+    assert!(revoke(&mut system, alice, alice, Role::Admin).is_err());
+    revoke(&mut system, alice, bob, Role::Pauser).unwrap();
+    assert!(roles(&mut system, bob).is_empty());
+
+    revoke(&mut system, owner, alice, Role::Admin).unwrap();
+    assert!(roles(&mut system, alice).is_empty());
+    assert!(grant(&mut system, alice, bob, Role::Pauser).is_err());
+}
+
+#[test]
+fn ownership_is_accepted_by_the_proposed_owner() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice, bob) = (system.owner, system.token, user_id(1), user_id(2));
+    let result: CallResult<()> = system.sim.update(alice, token, "proposeOwner", (alice, ));
+    assert!(result.is_err());
+    let () = system
+        .sim
+        .update(owner, token, "proposeOwner", (alice, ))
+        .unwrap();
+    let (pending, ): (Option<Principal>, ) =
+        system.sim.query(owner, token, "getPendingOwner", ()).unwrap();
+    assert_eq!(pending, Some(alice));
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(bob, token, "acceptOwnership", ())
+        .unwrap();
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(alice, token, "acceptOwnership", ())
+        .unwrap();
+    result.unwrap();
+    let (new_owner, ): (Principal, ) = system.sim.query(owner, token, "owner", ()).unwrap();
+    assert_eq!(new_owner, alice);
+    assert_eq!(roles(&mut system, alice).len(), 4);
+    assert!(roles(&mut system, owner).is_empty());
+    assert!(set_fee(&mut system, owner, 20).is_err());
+    set_fee(&mut system, alice, 20).unwrap();
+}
+
+#[test]
+fn roles_are_granted_by_proposal_under_governance() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice) = (system.owner, system.token, user_id(1));
+    let config = GovernanceConfig {
+        signers: vec![owner],
+        threshold: 1,
+        timelock: 0,
+    };
+    let () = system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+        .unwrap();
+    assert!(grant(&mut system, owner, alice, Role::Pauser).is_err());
+
+    let execute = |system: &mut TokenSystem, action: ProposalAction| {
+        let (id, ): (u64, ) = system
+            .sim
+            .update(owner, token, "submitProposal", (action, ))
+            .unwrap();
+        let (status, ): (ProposalStatus, ) = system
+            .sim
+            .update(owner, token, "executeProposal", (id, ))
+            .unwrap();
+        status
+    };
+    let status = execute(&mut system, ProposalAction::GrantRole(alice, Role::Pauser));
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert_eq!(roles(&mut system, alice), vec![Role::Pauser]);
+    assert!(revoke(&mut system, owner, alice, Role::Pauser).is_err());
+    let status = execute(&mut system, ProposalAction::RevokeRole(alice, Role::Pauser));
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    assert!(roles(&mut system, alice).is_empty());
+}
+
+#[test]
+fn roles_missed_by_a_shard_on_an_ownership_change_are_sent_again() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token, alice) = (system.owner, system.token, user_id(1));
+    let shard = system.shards[1];
+    let () = system
+        .sim
+        .update(owner, token, "proposeOwner", (alice, ))
+        .unwrap();
+    system
+        .sim
+        .set_fault_injector(move |_, to, method| to == shard && method == "setRoles");
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(alice, token, "acceptOwnership", ())
+        .unwrap();
+    assert!(result.is_err());
+    system.sim.clear_fault_injector();
+    let (new_owner, ): (Principal, ) = system.sim.query(owner, token, "owner", ()).unwrap();
+    assert_eq!(new_owner, alice);
+
+    let () = system.sim.update(alice, token, "fixSiblings", ()).unwrap();
+    assert_eq!(roles(&mut system, alice).len(), 4);
+    assert!(roles(&mut system, owner).is_empty());
+}
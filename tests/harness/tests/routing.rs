@@ -0,0 +1,281 @@
+use candid::{Nat, Principal};
+use futures::executor::block_on;
+
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction, ProposalStatus};
+use enoki_wrapped_token::routing::RoutingMode;
+use enoki_wrapped_token_client::TokenClient;
+use enoki_wrapped_token_harness::{user_id, Schedule, SimulatorTransport, TokenSystem};
+use enoki_wrapped_token_shard::interfaces::dip20::TxReceipt;
+use enoki_wrapped_token_shared::routing::ShardRing;
+use enoki_wrapped_token_shared::types::{Result, ShardRegistry, TxError};
+
+const FEE: u64 = 10;
+
+fn set_mode(system: &mut TokenSystem, mode: RoutingMode) {
+    let (owner, token) = (system.owner, system.token);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "setRoutingMode", (mode, ))
+        .unwrap();
+    result.unwrap();
+}
+
+fn ring(system: &mut TokenSystem) -> ShardRing {
+    let (owner, token) = (system.owner, system.token);
+    let (registry, ): (ShardRegistry, ) =
+        system.sim.query(owner, token, "getShardRegistry", ()).unwrap();
+    ShardRing::new(&registry.ring.unwrap())
+}
+
+fn assigned_shard(system: &mut TokenSystem, user: Principal) -> Principal {
+    let (owner, token) = (system.owner, system.token);
+    let (shard, ) = system
+        .sim
+        .query(owner, token, "getAssignedShardId", (user, ))
+        .unwrap();
+    shard
+}
+
+fn shard_balance(system: &mut TokenSystem, shard: Principal, user: Principal) -> Nat {
+    let (balance, ) = system
+        .sim
+        .query(user, shard, "shardBalanceOf", (user, ))
+        .unwrap();
+    balance
+}
+
+fn rebalance(system: &mut TokenSystem, shard: Principal) -> Result<u64> {
+    let (owner, token) = (system.owner, system.token);
+    let (result, ) = system
+        .sim
+        .update(owner, token, "rebalanceShard", (shard, ))
+        .unwrap();
+    result
+}
+
+/// Wraps on the shard the ring places `user` on, without registering.
+fn wrap_on(system: &mut TokenSystem, shard: Principal, user: Principal, amount: u64) {
+    system.mint_underlying(user, amount);
+    let underlying = system.underlying;
+    let (receipt, ): (TxReceipt, ) = system
+        .sim
+        .update(user, underlying, "approve", (shard, Nat::from(amount)))
+        .unwrap();
+    receipt.unwrap();
+    let () = system
+        .sim
+        .update(user, shard, "wrap", (Nat::from(amount), ))
+        .unwrap();
+}
+
+#[test]
+fn hashed_accounts_need_no_registration() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, FEE, 1);
+    set_mode(&mut system, RoutingMode::Hashed);
+    let ring = ring(&mut system);
+    let (alice, bob, carol) = (user_id(1), user_id(2), user_id(3));
+    let alice_shard = ring.shard_for(&alice).unwrap();
+    let bob_shard = ring.shard_for(&bob).unwrap();
+
+    wrap_on(&mut system, alice_shard, alice, 1_000);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(alice, alice_shard, "shardTransfer", (bob_shard, bob, Nat::from(500)))
+        .unwrap();
+    result.unwrap();
+    assert_eq!(shard_balance(&mut system, bob_shard, bob), 490u64);
+    assert_eq!(assigned_shard(&mut system, bob), bob_shard);
+
+    // the main contract routes without registering either
+    let token = system.token;
+    let () = system
+        .sim
+        .update(bob, token, "transfer", (carol, Nat::from(100)))
+        .unwrap();
+    let carol_shard = ring.shard_for(&carol).unwrap();
+    assert_eq!(shard_balance(&mut system, carol_shard, carol), 90u64);
+    assert_eq!(system.register(carol), carol_shard);
+}
+
+#[test]
+fn shards_refuse_accounts_the_ring_places_elsewhere() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    set_mode(&mut system, RoutingMode::Hashed);
+    let ring = ring(&mut system);
+    let alice = user_id(1);
+    let alice_shard = ring.shard_for(&alice).unwrap();
+    let stranger = (2..)
+        .map(user_id)
+        .find(|user| ring.shard_for(user) != Some(alice_shard))
+        .unwrap();
+
+    wrap_on(&mut system, alice_shard, alice, 1_000);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(
+            alice,
+            alice_shard,
+            "shardTransfer",
+            (alice_shard, stranger, Nat::from(500)),
+        )
+        .unwrap();
+    assert!(matches!(result, Err(TxError::AccountDoesNotExist { .. })));
+}
+
+#[test]
+fn rebalancing_moves_accounts_onto_the_ring() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let users: Vec<Principal> = (1..=40).map(user_id).collect();
+    for &user in &users {
+        system.mint_underlying(user, 100);
+        system.wrap(user, 100).unwrap();
+    }
+    let first = system.shards[0];
+    assert!(matches!(rebalance(&mut system, first), Err(TxError::Other(_))));
+
+    set_mode(&mut system, RoutingMode::Hashed);
+    system.add_shard();
+    for shard in system.shards.clone() {
+        assert_eq!(rebalance(&mut system, shard).unwrap(), 0);
+    }
+
+    let ring = ring(&mut system);
+    let mut used = vec![];
+    for &user in &users {
+        let shard = ring.shard_for(&user).unwrap();
+        assert_eq!(assigned_shard(&mut system, user), shard);
+        assert_eq!(shard_balance(&mut system, shard, user), 99u64);
+        used.push(shard);
+    }
+    assert!(used.contains(&system.shards[2]));
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn registered_accounts_move_onto_the_ring_when_it_is_enabled() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, FEE, 1);
+    let users: Vec<Principal> = (1..=30).map(user_id).collect();
+    for &user in &users {
+        system.mint_underlying(user, 100);
+        system.wrap(user, 100).unwrap();
+    }
+    set_mode(&mut system, RoutingMode::Hashed);
+
+    let ring = ring(&mut system);
+    for &user in &users {
+        let shard = ring.shard_for(&user).unwrap();
+        assert_eq!(assigned_shard(&mut system, user), shard);
+        assert_eq!(shard_balance(&mut system, shard, user), 99u64);
+    }
+    let token = system.token;
+    let client = TokenClient::new(SimulatorTransport::new(&mut system.sim, users[0]), token);
+    block_on(async {
+        assert_eq!(client.balance_of(users[1]).await.unwrap(), 99u64);
+        client.transfer(users[1], Nat::from(50)).await.unwrap();
+        assert_eq!(client.balance_of(users[1]).await.unwrap(), 139u64);
+    });
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn ring_accounts_are_registered_when_it_is_disabled() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, FEE, 1);
+    set_mode(&mut system, RoutingMode::Hashed);
+    let ring = ring(&mut system);
+    let alice = user_id(1);
+    let alice_shard = ring.shard_for(&alice).unwrap();
+    wrap_on(&mut system, alice_shard, alice, 1_000);
+
+    set_mode(&mut system, RoutingMode::Directory);
+    assert_eq!(assigned_shard(&mut system, alice), alice_shard);
+    assert_eq!(system.balance(alice), 999u64);
+}
+
+#[test]
+fn clients_compute_shards_from_the_ring() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 3, FEE, 1);
+    set_mode(&mut system, RoutingMode::Hashed);
+    let ring = ring(&mut system);
+    let (alice, bob) = (user_id(1), user_id(2));
+    wrap_on(&mut system, ring.shard_for(&alice).unwrap(), alice, 1_000);
+
+    let token = system.token;
+    let client = TokenClient::new(SimulatorTransport::new(&mut system.sim, alice), token);
+    block_on(async {
+        client.transfer(bob, Nat::from(300)).await.unwrap();
+        assert_eq!(client.balance_of(bob).await.unwrap(), 290u64);
+    });
+
+    let calls = client.transport().calls();
+    assert_eq!(calls.iter().filter(|m| *m == "getShardRegistry").count(), 1);
+    assert!(!calls.iter().any(|m| m == "getAssignedShardId" || m == "register"));
+}
+
+#[test]
+fn the_routing_mode_is_set_by_proposal_under_governance() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (owner, token) = (system.owner, system.token);
+    let config = GovernanceConfig {
+        signers: vec![owner],
+        threshold: 1,
+        timelock: 0,
+    };
+    let () = system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+        .unwrap();
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "setRoutingMode", (RoutingMode::Hashed, ))
+        .unwrap();
+    assert!(result.is_err());
+
+    let action = ProposalAction::SetRoutingMode(RoutingMode::Hashed);
+    let (id, ): (u64, ) = system
+        .sim
+        .update(owner, token, "submitProposal", (action, ))
+        .unwrap();
+    let (status, ): (ProposalStatus, ) = system
+        .sim
+        .update(owner, token, "executeProposal", (id, ))
+        .unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+    let (mode, ): (RoutingMode, ) = system.sim.query(owner, token, "getRoutingMode", ()).unwrap();
+    assert_eq!(mode, RoutingMode::Hashed);
+    assert!(ring(&mut system).shard_for(&user_id(1)).is_some());
+}
+
+#[test]
+fn frozen_accounts_stay_frozen_when_rebalanced() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 1, FEE, 1);
+    let (owner, token) = (system.owner, system.token);
+    let users: Vec<Principal> = (1..=20).map(user_id).collect();
+    for &user in &users {
+        system.mint_underlying(user, 100);
+        system.wrap(user, 100).unwrap();
+        let () = system
+            .sim
+            .update(owner, token, "freezeAccount", (user, ))
+            .unwrap();
+    }
+    let first = system.shards[0];
+    set_mode(&mut system, RoutingMode::Hashed);
+    let added = system.add_shard();
+    assert_eq!(rebalance(&mut system, first).unwrap(), 0);
+
+    let moved: Vec<Principal> = users
+        .iter()
+        .copied()
+        .filter(|&user| assigned_shard(&mut system, user) == added)
+        .collect();
+    assert!(!moved.is_empty());
+    for user in moved {
+        let (frozen, ): (bool, ) = system.sim.query(user, added, "isFrozen", (user, )).unwrap();
+        assert!(frozen);
+        let (result, ): (Result<()>, ) = system
+            .sim
+            .update(user, added, "shardTransfer", (first, users[0], Nat::from(10)))
+            .unwrap();
+        assert!(matches!(result, Err(TxError::AccountFrozen { .. })));
+    }
+}
@@ -0,0 +1,206 @@
+use candid::{decode_args, encode_args, encode_one, Nat, Principal};
+
+use enoki_wrapped_token_harness::{
+    canister_id, user_id, Explorer, Model, NativeCanister, Schedule, Simulator, TokenSystem,
+};
+use enoki_wrapped_token_shared::types::{ByteBuf, NotificationResponse, Result, TxError};
+
+const FEE: u64 = 10;
+const WRAPPED: u64 = 1_000;
+const AMOUNT: u64 = 500;
+
+fn take_snapshot(system: &mut TokenSystem) -> Result<u64> {
+    let (owner, token) = (system.owner, system.token);
+    let (result, ) = system.sim.update(owner, token, "takeSnapshot", ()).unwrap();
+    result
+}
+
+fn balance_at(system: &mut TokenSystem, owner: Principal, id: u64) -> Result<Nat> {
+    let token = system.token;
+    let (result, ) = system
+        .sim
+        .update(owner, token, "balanceAtSnapshot", (owner, id))
+        .unwrap();
+    result
+}
+
+#[derive(Clone, Copy)]
+enum Send {
+    Transfer,
+    /// To an account the recipient's shard does not hold, which is refunded.
+    Refunded,
+    TransferAndCall,
+}
+
+/// Alice sends `AMOUNT` to Carol on another shard while a snapshot is taken.
+struct SnapshotRace {
+    system: TokenSystem,
+    alice: Principal,
+    carol: Principal,
+    snapshot: usize,
+}
+
+impl SnapshotRace {
+    fn new(send: Send) -> Self {
+        let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+        let (alice, carol) = (user_id(1), user_id(2));
+        let alice_shard = system.register(alice);
+        let carol_shard = system.register(carol);
+        assert_ne!(alice_shard, carol_shard);
+        system.mint_underlying(alice, WRAPPED);
+        system.wrap(alice, WRAPPED).unwrap();
+
+        let callback = canister_id(50);
+        system.sim.install(
+            callback,
+            Box::new(NativeCanister(|_, _: &str, _| {
+                Ok(encode_one(NotificationResponse::Accept).unwrap())
+            })),
+            system.owner,
+            (),
+        );
+
+        let value = Nat::from(AMOUNT);
+        let (method, args) = match send {
+            Send::Transfer => ("shardTransfer", encode_args((carol_shard, carol, value))),
+            Send::Refunded => ("shardTransfer", encode_args((carol_shard, user_id(9), value))),
+            Send::TransferAndCall => (
+                "shardTransferAndCall",
+                encode_args((
+                    carol_shard,
+                    carol,
+                    value,
+                    callback,
+                    "deposit".to_string(),
+                    ByteBuf::new(),
+                )),
+            ),
+        };
+        system.sim.submit(alice, alice_shard, method, args.unwrap());
+        let (owner, token) = (system.owner, system.token);
+        let snapshot = system
+            .sim
+            .submit(owner, token, "takeSnapshot", encode_args(()).unwrap());
+        Self {
+            system,
+            alice,
+            carol,
+            snapshot,
+        }
+    }
+}
+
+impl Model for SnapshotRace {
+    fn simulator(&mut self) -> &mut Simulator {
+        &mut self.system.sim
+    }
+
+    /// The snapshot sees the transfer either not started, or done.
+    fn check_final(&mut self) -> std::result::Result<(), String> {
+        let id = match self.system.sim.ingress_reply(self.snapshot) {
+            Some(Ok(bytes)) => match decode_args::<(Result<u64>, )>(bytes) {
+                Ok((Ok(id), )) => id,
+                other => return Err(format!("snapshot failed: {:?}", other)),
+            },
+            other => return Err(format!("snapshot failed: {:?}", other)),
+        };
+        let (alice, carol) = (self.alice, self.carol);
+        let alice_balance = balance_at(&mut self.system, alice, id).unwrap();
+        let carol_balance = balance_at(&mut self.system, carol, id).unwrap();
+        let before = (Nat::from(WRAPPED - 1), Nat::from(0));
+        let after = (Nat::from(WRAPPED - 1 - AMOUNT), Nat::from(AMOUNT - FEE));
+        let refunded = (Nat::from(WRAPPED - 1 - FEE), Nat::from(0));
+        let state = (alice_balance, carol_balance);
+        if state != before && state != after && state != refunded {
+            return Err(format!("inconsistent snapshot: {:?}", state));
+        }
+        Ok(())
+    }
+}
+
+fn assert_consistent(send: Send) {
+    let report = Explorer::new(|| SnapshotRace::new(send)).explore();
+    assert!(report.complete);
+    assert!(report.schedules > 1);
+    assert!(
+        report.violations.is_empty(),
+        "{} of {} schedules failed, first: {:#?}",
+        report.violations.len(),
+        report.schedules,
+        report.violations[0]
+    );
+}
+
+#[test]
+fn snapshots_count_transfers_in_flight_once() {
+    assert_consistent(Send::Transfer);
+}
+
+#[test]
+fn snapshots_count_refunded_transfers_for_the_sender() {
+    assert_consistent(Send::Refunded);
+}
+
+#[test]
+fn snapshots_count_held_transfers_once() {
+    assert_consistent(Send::TransferAndCall);
+}
+
+#[test]
+fn balances_at_snapshots_stay_after_transfers() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, WRAPPED);
+    system.wrap(alice, WRAPPED).unwrap();
+    system.register(bob);
+
+    let first = take_snapshot(&mut system).unwrap();
+    system.shard_transfer(alice, bob, AMOUNT).unwrap().unwrap();
+    let second = take_snapshot(&mut system).unwrap();
+    assert_eq!(second, first + 1);
+
+    assert_eq!(balance_at(&mut system, alice, first).unwrap(), WRAPPED - 1);
+    assert_eq!(balance_at(&mut system, bob, first).unwrap(), 0u64);
+    assert_eq!(balance_at(&mut system, alice, second).unwrap(), WRAPPED - 1 - AMOUNT);
+    assert_eq!(balance_at(&mut system, bob, second).unwrap(), AMOUNT - FEE);
+    assert!(matches!(
+        balance_at(&mut system, alice, second + 1),
+        Err(TxError::SnapshotDoesNotExist)
+    ));
+
+    let token = system.token;
+    let (result, ): (Result<u64>, ) = system.sim.update(bob, token, "takeSnapshot", ()).unwrap();
+    assert!(matches!(result, Err(TxError::Unauthorized)));
+}
+
+#[test]
+fn snapshots_stay_with_the_shard_accounts_moved_off() {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let alice = user_id(1);
+    system.mint_underlying(alice, WRAPPED);
+    system.wrap(alice, WRAPPED).unwrap();
+    let drained = system.register(alice);
+    let id = take_snapshot(&mut system).unwrap();
+
+    let (owner, token) = (system.owner, system.token);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "startDecommission", (drained, ))
+        .unwrap();
+    result.unwrap();
+    let (result, ): (Result<u64>, ) = system
+        .sim
+        .update(owner, token, "drainShard", (drained, ))
+        .unwrap();
+    assert_eq!(result.unwrap(), 0);
+    assert_eq!(balance_at(&mut system, alice, id).unwrap(), WRAPPED - 1);
+    let later = take_snapshot(&mut system).unwrap();
+    assert_eq!(balance_at(&mut system, alice, later).unwrap(), WRAPPED - 1);
+
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "finishDecommission", (drained, ))
+        .unwrap();
+    result.unwrap();
+    assert!(matches!(balance_at(&mut system, alice, id), Err(TxError::Other(_))));
+}
@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use candid::{decode_args, encode_one, Nat, Principal};
+
+use enoki_wrapped_token_harness::{canister_id, user_id, NativeCanister, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{
+    Account, ByteBuf, NotificationResponse, Result, ShardedTransferNotification, Subaccount,
+    TxError,
+};
+
+const FEE: u64 = 10;
+
+/// An exchange keeping a deposit subaccount per user. Alice and bob each wrapped 1_000 tokens
+/// and are on different shards.
+struct Exchange {
+    system: TokenSystem,
+    id: Principal,
+    deposits: Rc<RefCell<Vec<ShardedTransferNotification>>>,
+}
+
+fn subaccount(n: u8) -> Subaccount {
+    let mut subaccount = [0; 32];
+    subaccount[31] = n;
+    subaccount
+}
+
+impl Exchange {
+    fn new() -> Self {
+        let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+        let id = canister_id(50);
+        let deposits = Rc::new(RefCell::new(vec![]));
+        let received = deposits.clone();
+        system.sim.install(
+            id,
+            Box::new(NativeCanister(move |_, _: &str, args: Vec<u8>| {
+                let (notification, ): (ShardedTransferNotification, ) =
+                    decode_args(&args).unwrap();
+                received.borrow_mut().push(notification);
+                Ok(encode_one(NotificationResponse::Accept).unwrap())
+            })),
+            system.owner,
+            (),
+        );
+        system.register(id);
+        for user in [user_id(1), user_id(2)] {
+            system.mint_underlying(user, 1_000);
+            system.wrap(user, 1_000).unwrap();
+        }
+        assert_ne!(system.register(user_id(1)), system.register(user_id(2)));
+        Self {
+            system,
+            id,
+            deposits,
+        }
+    }
+
+    fn account(&self, n: u8) -> Account {
+        Account::new(self.id, Some(subaccount(n)))
+    }
+
+    fn transfer(
+        &mut self,
+        from: Principal,
+        from_subaccount: Option<Subaccount>,
+        to: Account,
+        value: u64,
+    ) -> Result<()> {
+        let from_shard = self.system.register(from);
+        let to_shard = self.system.register(to.owner);
+        let (result, ) = self
+            .system
+            .sim
+            .update(
+                from,
+                from_shard,
+                "shardAccountTransfer",
+                (from_subaccount, to_shard, to, Nat::from(value)),
+            )
+            .unwrap();
+        result
+    }
+
+    fn balance(&mut self, account: Account) -> Nat {
+        let shard = self.system.register(account.owner);
+        let (balance, ) = self
+            .system
+            .sim
+            .query(account.owner, shard, "shardAccountBalanceOf", (account, ))
+            .unwrap();
+        balance
+    }
+}
+
+#[test]
+fn deposits_to_subaccounts_are_isolated() {
+    let mut exchange = Exchange::new();
+    let (alice, bob) = (user_id(1), user_id(2));
+    let (alice_deposit, bob_deposit) = (exchange.account(1), exchange.account(2));
+
+    // one of them is a transfer to a sibling shard
+    exchange.transfer(alice, None, alice_deposit, 100).unwrap();
+    exchange.transfer(bob, None, bob_deposit, 200).unwrap();
+    exchange.transfer(alice, None, alice_deposit, 100).unwrap();
+
+    assert_eq!(exchange.balance(alice_deposit), 200u64 - 2 * FEE);
+    assert_eq!(exchange.balance(bob_deposit), 200u64 - FEE);
+    assert_eq!(exchange.balance(exchange.account(3)), 0u64);
+    let id = exchange.id;
+    assert_eq!(exchange.system.balance(id), 0u64);
+    assert_eq!(
+        exchange.system.wrapped_supply(),
+        exchange.system.underlying_custody()
+    );
+}
+
+#[test]
+fn subaccounts_spend_only_their_own_balance() {
+    let mut exchange = Exchange::new();
+    let (alice, bob, id) = (user_id(1), user_id(2), exchange.id);
+    exchange.transfer(alice, None, exchange.account(1), 100).unwrap();
+    exchange.transfer(bob, None, exchange.account(2), 100).unwrap();
+
+    // the exchange pays out of alice's deposit
+    exchange
+        .transfer(id, Some(subaccount(1)), Account::from(bob), 50)
+        .unwrap();
+    assert_eq!(exchange.balance(exchange.account(1)), 90u64 - 50);
+    assert_eq!(exchange.system.balance(bob), 999u64 - 100 + 50 - FEE);
+    assert!(matches!(
+        exchange.transfer(id, Some(subaccount(1)), Account::from(bob), 50),
+        Err(TxError::InsufficientBalance)
+    ));
+    assert!(matches!(
+        exchange.transfer(id, None, Account::from(bob), 50),
+        Err(TxError::InsufficientBalance)
+    ));
+    // only the owner spends from its subaccounts
+    assert!(matches!(
+        exchange.transfer(alice, Some(subaccount(1)), Account::from(bob), 20),
+        Err(TxError::InsufficientBalance)
+    ));
+
+    // between subaccounts of the same owner
+    exchange
+        .transfer(id, Some(subaccount(2)), exchange.account(1), 50)
+        .unwrap();
+    assert_eq!(exchange.balance(exchange.account(1)), 40u64 + 50 - FEE);
+    assert_eq!(exchange.balance(exchange.account(2)), 90u64 - 50);
+}
+
+#[test]
+fn zero_subaccount_is_the_default_one() {
+    let mut exchange = Exchange::new();
+    let (alice, bob) = (user_id(1), user_id(2));
+    let zero = Account {
+        owner: bob,
+        subaccount: Some([0; 32]),
+    };
+
+    exchange.transfer(alice, None, zero, 100).unwrap();
+    assert_eq!(exchange.system.balance(bob), 999u64 + 100 - FEE);
+    assert_eq!(exchange.balance(zero), exchange.balance(Account::from(bob)));
+    exchange
+        .transfer(bob, Some([0; 32]), Account::from(alice), 100)
+        .unwrap();
+    assert_eq!(exchange.system.balance(alice), 999u64 - 100 + 100 - FEE);
+
+    // subaccounts of unregistered principals cannot be credited
+    let (stranger, alice_shard) = (user_id(66), exchange.system.register(alice));
+    let (result, ): (Result<()>, ) = exchange
+        .system
+        .sim
+        .update(
+            alice,
+            alice_shard,
+            "shardAccountTransfer",
+            (
+                None::<Subaccount>,
+                alice_shard,
+                Account::new(stranger, Some(subaccount(1))),
+                Nat::from(100),
+            ),
+        )
+        .unwrap();
+    assert!(matches!(result, Err(TxError::AccountDoesNotExist { .. })));
+}
+
+#[test]
+fn notifications_carry_the_subaccounts() {
+    let mut exchange = Exchange::new();
+    let (alice, id) = (user_id(1), exchange.id);
+    let (alice_shard, exchange_shard) = (
+        exchange.system.register(alice),
+        exchange.system.register(id),
+    );
+
+    let (result, ): (Result<NotificationResponse>, ) = exchange
+        .system
+        .sim
+        .update(
+            alice,
+            alice_shard,
+            "shardAccountTransferAndCall",
+            (
+                None::<Subaccount>,
+                exchange_shard,
+                exchange.account(1),
+                Nat::from(100),
+                id,
+                "deposit".to_string(),
+                ByteBuf::from(b"order-1".to_vec()),
+            ),
+        )
+        .unwrap();
+    assert!(matches!(result, Ok(NotificationResponse::Accept)));
+    assert_eq!(exchange.balance(exchange.account(1)), 100u64 - FEE);
+
+    let deposits = exchange.deposits.borrow();
+    assert_eq!(deposits.len(), 1);
+    assert_eq!(deposits[0].from_account(), Account::from(alice));
+    assert_eq!(deposits[0].to_account(), Account::new(id, Some(subaccount(1))));
+}
@@ -0,0 +1,267 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use candid::{decode_args, encode_args, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use enoki_wrapped_token_harness::{canister_id, user_id, NativeCanister, Schedule, TokenSystem};
+use enoki_wrapped_token_shard::subscriptions::{
+    Subscription, MAX_DELIVERIES_PER_HEARTBEAT, MAX_SUBSCRIPTIONS_PER_SUBSCRIBER,
+};
+use enoki_wrapped_token_shared::types::{BalanceEvent, CreditKind, Result, TxError};
+
+const FEE: u64 = 10;
+
+struct Subscriber {
+    system: TokenSystem,
+    id: Principal,
+    shard: Principal,
+    online: Rc<Cell<bool>>,
+    received: Rc<RefCell<Vec<BalanceEvent>>>,
+}
+
+impl Subscriber {
+    fn new() -> Self {
+        let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+        let id = canister_id(50);
+        let online = Rc::new(Cell::new(true));
+        let received = Rc::new(RefCell::new(vec![]));
+        let (is_online, events) = (online.clone(), received.clone());
+        system.sim.install(
+            id,
+            Box::new(NativeCanister(move |_, method: &str, args: Vec<u8>| {
+                assert_eq!(method, "onCredit");
+                if !is_online.get() {
+                    return Err((RejectionCode::CanisterError, "offline".to_string()));
+                }
+                let (batch, ): (Vec<BalanceEvent>, ) = decode_args(&args).unwrap();
+                events.borrow_mut().extend(batch);
+                Ok(encode_args(()).unwrap())
+            })),
+            system.owner,
+            (),
+        );
+        let shard = system.register(id);
+        for user in [user_id(1), user_id(2)] {
+            system.mint_underlying(user, 1_000);
+            system.wrap(user, 1_000).unwrap();
+        }
+        Self {
+            system,
+            id,
+            shard,
+            online,
+            received,
+        }
+    }
+
+    fn subscribe(&mut self, caller: Principal, account: Option<Principal>) -> Result<u64> {
+        let (result, ) = self
+            .system
+            .sim
+            .update(caller, self.shard, "subscribe", (account, "onCredit".to_string()))
+            .unwrap();
+        result
+    }
+
+    fn subscriptions(&mut self) -> Vec<Subscription> {
+        let (subscriptions, ) = self
+            .system
+            .sim
+            .query(self.id, self.shard, "getSubscriptions", ())
+            .unwrap();
+        subscriptions
+    }
+
+    fn heartbeat_after(&mut self, nanoseconds: u64) {
+        self.system.sim.advance_time(nanoseconds);
+        self.system.sim.heartbeat();
+        self.system.sim.run();
+    }
+}
+
+#[test]
+fn credits_are_delivered_to_subscribers() {
+    let mut subscriber = Subscriber::new();
+    let (alice, bob, id) = (user_id(1), user_id(2), subscriber.id);
+    subscriber.subscribe(id, Some(id)).unwrap();
+
+    subscriber.system.shard_transfer(alice, id, 100).unwrap().unwrap();
+    subscriber.system.shard_transfer(bob, id, 200).unwrap().unwrap();
+    // not subscribed to
+    subscriber.system.shard_transfer(alice, bob, 300).unwrap().unwrap();
+    assert!(subscriber.received.borrow().is_empty());
+
+    subscriber.heartbeat_after(0);
+    let received = subscriber.received.borrow();
+    assert_eq!(received.len(), 2);
+    let shards = (subscriber.system.register(alice), subscriber.system.register(bob));
+    assert_eq!(
+        received[0].kind,
+        CreditKind::Transfer {
+            from: alice,
+            from_shard: shards.0
+        }
+    );
+    assert_eq!(received[0].amount, 100u64 - FEE);
+    assert_eq!(
+        received[1].kind,
+        CreditKind::Transfer {
+            from: bob,
+            from_shard: shards.1
+        }
+    );
+    assert!(received[0].id < received[1].id);
+    drop(received);
+
+    // delivered events are not sent again
+    subscriber.heartbeat_after(0);
+    assert_eq!(subscriber.received.borrow().len(), 2);
+}
+
+#[test]
+fn failed_deliveries_resume_from_the_cursor() {
+    let mut subscriber = Subscriber::new();
+    let (alice, id) = (user_id(1), subscriber.id);
+    subscriber.subscribe(id, Some(id)).unwrap();
+    subscriber.online.set(false);
+
+    subscriber.system.shard_transfer(alice, id, 100).unwrap().unwrap();
+    subscriber.heartbeat_after(0);
+    let subscription = &subscriber.subscriptions()[0];
+    assert_eq!(subscription.failures, 1);
+    assert!(subscription.last_error.as_ref().unwrap().contains("offline"));
+
+    subscriber.online.set(true);
+    subscriber.system.shard_transfer(alice, id, 200).unwrap().unwrap();
+    // still backing off
+    subscriber.heartbeat_after(0);
+    assert!(subscriber.received.borrow().is_empty());
+
+    subscriber.heartbeat_after(1_000_000_000);
+    let amounts: Vec<Nat> = subscriber
+        .received
+        .borrow()
+        .iter()
+        .map(|e| e.amount.clone())
+        .collect();
+    assert_eq!(amounts, vec![Nat::from(90), Nat::from(190)]);
+    assert_eq!(subscriber.subscriptions()[0].failures, 0);
+
+    // events are only kept until every subscriber received them
+    let (events, ): (Vec<BalanceEvent>, ) = subscriber
+        .system
+        .sim
+        .query(id, subscriber.shard, "getBalanceEvents", (Some(id), 0u64, 100u64))
+        .unwrap();
+    assert!(events.is_empty());
+}
+
+#[test]
+fn unsubscribed_canisters_stop_receiving_events() {
+    let mut subscriber = Subscriber::new();
+    let (alice, id) = (user_id(1), subscriber.id);
+    let subscription = subscriber.subscribe(id, Some(id)).unwrap();
+
+    let unsubscribe = |subscriber: &mut Subscriber, caller: Principal| -> Result<()> {
+        let shard = subscriber.shard;
+        let (result, ) = subscriber
+            .system
+            .sim
+            .update(caller, shard, "unsubscribe", (subscription, ))
+            .unwrap();
+        result
+    };
+    assert!(matches!(
+        unsubscribe(&mut subscriber, alice),
+        Err(TxError::Unauthorized)
+    ));
+    unsubscribe(&mut subscriber, id).unwrap();
+
+    subscriber.system.shard_transfer(alice, id, 100).unwrap().unwrap();
+    subscriber.heartbeat_after(0);
+    assert!(subscriber.received.borrow().is_empty());
+}
+
+#[test]
+fn subscribing_to_every_account_requires_the_operator_role() {
+    let mut subscriber = Subscriber::new();
+    let id = subscriber.id;
+    assert!(matches!(
+        subscriber.subscribe(id, None),
+        Err(TxError::Unauthorized)
+    ));
+
+    let owner = subscriber.system.owner;
+    let token = subscriber.system.token;
+    let () = subscriber
+        .system
+        .sim
+        .update(owner, token, "grantRole", (id, enoki_wrapped_token_shared::types::Role::ShardOperator))
+        .unwrap();
+    subscriber.subscribe(id, None).unwrap();
+
+    // wrapping credits the account on the subscriber's shard
+    let shard = subscriber.shard;
+    let user = (3..)
+        .map(user_id)
+        .find(|&user| subscriber.system.register(user) == shard)
+        .unwrap();
+    subscriber.system.mint_underlying(user, 1_000);
+    subscriber.system.wrap(user, 500).unwrap();
+    subscriber.heartbeat_after(0);
+    let received = subscriber.received.borrow();
+    assert!(received
+        .iter()
+        .any(|e| e.account == user && e.kind == CreditKind::Wrap));
+}
+
+#[test]
+fn subscriptions_are_capped_per_subscriber() {
+    let mut subscriber = Subscriber::new();
+    let (alice, id) = (user_id(1), subscriber.id);
+    for _ in 0..MAX_SUBSCRIPTIONS_PER_SUBSCRIBER {
+        subscriber.subscribe(id, Some(id)).unwrap();
+    }
+    assert!(matches!(
+        subscriber.subscribe(id, Some(id)),
+        Err(TxError::TooManySubscriptions)
+    ));
+    // other subscribers are not affected
+    subscriber.subscribe(alice, Some(id)).unwrap();
+}
+
+#[test]
+fn heartbeats_take_turns_delivering_to_subscriptions() {
+    let mut subscriber = Subscriber::new();
+    let (alice, id) = (user_id(1), subscriber.id);
+    // canisters that are not installed, so that every delivery fails and is counted
+    let subscribers: Vec<Principal> = (10..13).map(user_id).collect();
+    for &caller in &subscribers {
+        for _ in 0..MAX_SUBSCRIPTIONS_PER_SUBSCRIBER {
+            subscriber.subscribe(caller, Some(id)).unwrap();
+        }
+    }
+    let total = subscribers.len() * MAX_SUBSCRIPTIONS_PER_SUBSCRIBER;
+    assert!(total > MAX_DELIVERIES_PER_HEARTBEAT);
+
+    let attempted = |subscriber: &mut Subscriber| -> usize {
+        let shard = subscriber.shard;
+        subscribers
+            .iter()
+            .map(|&caller| {
+                let (subscriptions, ): (Vec<Subscription>, ) = subscriber
+                    .system
+                    .sim
+                    .query(caller, shard, "getSubscriptions", ())
+                    .unwrap();
+                subscriptions.iter().filter(|sub| sub.failures > 0).count()
+            })
+            .sum()
+    };
+    subscriber.system.shard_transfer(alice, id, 100).unwrap().unwrap();
+    subscriber.heartbeat_after(0);
+    assert_eq!(attempted(&mut subscriber), MAX_DELIVERIES_PER_HEARTBEAT);
+    subscriber.heartbeat_after(0);
+    assert_eq!(attempted(&mut subscriber), total);
+}
@@ -0,0 +1,224 @@
+use candid::{decode_args, encode_one, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use enoki_wrapped_token_harness::{user_id, NativeCanister, Reply, Schedule, TokenSystem};
+use enoki_wrapped_token_shared::types::{
+    NotificationResponse, Result, ShardedTransferNotification, TxError,
+};
+
+const FEE: u64 = 10;
+const UNDERLYING_FEE: u64 = 1;
+
+fn system(num_shards: u64) -> TokenSystem {
+    TokenSystem::new(Schedule::Fifo, num_shards, FEE, UNDERLYING_FEE)
+}
+
+#[test]
+fn accounts_are_spread_across_shards() {
+    let mut system = system(2);
+    let first = system.register(user_id(1));
+    let second = system.register(user_id(2));
+    assert_ne!(first, second);
+    assert_eq!(system.register(user_id(1)), first);
+}
+
+#[test]
+fn wrap_and_unwrap() {
+    let mut system = system(2);
+    let user = user_id(1);
+    system.mint_underlying(user, 1_000);
+
+    system.wrap(user, 500).unwrap();
+    // the underlying fee is paid out of the wrapped amount
+    assert_eq!(system.balance(user), 499u64);
+    assert_eq!(system.underlying_balance(user), 500u64);
+    assert_eq!(system.total_supply(), 499u64);
+
+    system.unwrap(user, 200, user).unwrap().unwrap();
+    assert_eq!(system.balance(user), 299u64);
+    assert_eq!(system.underlying_balance(user), 500u64 + 200 - FEE - UNDERLYING_FEE);
+    assert_eq!(system.accrued_fees(), FEE);
+}
+
+#[test]
+fn transfer_across_shards() {
+    let mut system = system(2);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    assert_ne!(system.register(alice), system.register(bob));
+
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    assert_eq!(system.balance(alice), 999u64 - 100);
+    assert_eq!(system.balance(bob), 100u64 - FEE);
+    assert_eq!(system.total_supply(), 999u64);
+}
+
+#[test]
+fn failed_transfer_leaves_balances_unchanged() {
+    let mut system = system(2);
+    let (alice, bob) = (user_id(1), user_id(2));
+    system.mint_underlying(alice, 100);
+    system.wrap(alice, 100).unwrap();
+
+    let result = system.shard_transfer(alice, bob, 1_000).unwrap();
+    assert!(matches!(result, Err(TxError::InsufficientBalance)), "{:?}", result);
+    assert_eq!(system.balance(alice), 99u64);
+    assert_eq!(system.balance(bob), 0u64);
+}
+
+#[test]
+fn batch_transfer_reports_each_transfer() {
+    let mut system = system(2);
+    let alice = user_id(1);
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    let alice_shard = system.register(alice);
+    let mut on_shard = |local: bool| {
+        (2..)
+            .map(user_id)
+            .find(|&user| (system.register(user) == alice_shard) == local)
+            .unwrap()
+    };
+    let (bob, carol) = (on_shard(false), on_shard(true));
+    let bob_shard = system.register(bob);
+    let stranger = user_id(99);
+
+    let transfers = vec![
+        (bob_shard, bob, Nat::from(100)),
+        (alice_shard, carol, Nat::from(200)),
+        // not registered
+        (bob_shard, stranger, Nat::from(300)),
+        (bob_shard, bob, Nat::from(FEE)),
+        (alice_shard, carol, Nat::from(10_000)),
+    ];
+    let (results, ): (Result<Vec<Result<()>>>, ) = system
+        .sim
+        .update(alice, alice_shard, "shardBatchTransfer", (transfers, ))
+        .unwrap();
+    let results = results.unwrap();
+    assert!(results[0].is_ok());
+    assert!(results[1].is_ok());
+    assert!(matches!(results[2], Err(TxError::AccountDoesNotExist { .. })));
+    assert!(matches!(results[3], Err(TxError::TransferValueTooSmall)));
+    assert!(matches!(results[4], Err(TxError::InsufficientBalance)));
+
+    assert_eq!(system.balance(bob), 100u64 - FEE);
+    assert_eq!(system.balance(carol), 200u64 - FEE);
+    // the fee of the refunded transfer is kept
+    assert_eq!(system.balance(alice), 999u64 - 100 - 200 - FEE);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+type Callback = fn(ShardedTransferNotification) -> Reply;
+
+/// Wraps 1_000 tokens for alice, and installs a `deposit` callback that replies with `reply`.
+fn callback_system(num_shards: u64, reply: Callback) -> (TokenSystem, Principal) {
+    let mut system = system(num_shards);
+    let alice = user_id(1);
+    let callback = Principal::from_slice(&[0xca, 0x11]);
+    system.sim.install(
+        callback,
+        Box::new(NativeCanister(move |_, method: &str, args: Vec<u8>| {
+            assert_eq!(method, "deposit");
+            let (notification, ) = decode_args(&args).unwrap();
+            reply(notification)
+        })),
+        alice,
+        (),
+    );
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    (system, callback)
+}
+
+fn respond(response: NotificationResponse) -> Reply {
+    Ok(encode_one(response).unwrap())
+}
+
+#[test]
+fn transfer_and_call_notifies_the_recipient() {
+    let (mut system, callback) = callback_system(2, |notification| {
+        assert_eq!(notification.data.as_ref(), b"order-1");
+        respond(NotificationResponse::Accept)
+    });
+    let (alice, receiver) = (user_id(1), user_id(2));
+
+    let response = system
+        .shard_transfer_and_call(alice, receiver, 100, callback, b"order-1")
+        .unwrap();
+    assert_eq!(response.unwrap(), NotificationResponse::Accept);
+    assert_eq!(system.balance(receiver), 100u64 - FEE);
+}
+
+#[test]
+fn failed_transfer_and_call_is_refunded() {
+    for num_shards in [1, 2] {
+        let (mut system, callback) = callback_system(num_shards, |_| {
+            Err((RejectionCode::CanisterError, "trapped".to_string()))
+        });
+        let (alice, receiver) = (user_id(1), user_id(2));
+
+        let response = system
+            .shard_transfer_and_call(alice, receiver, 100, callback, b"")
+            .unwrap();
+        assert!(
+            matches!(response, Err(TxError::TransferCallbackError(_))),
+            "{:?}",
+            response
+        );
+        // only the fee is kept
+        assert_eq!(system.balance(alice), 999u64 - FEE);
+        assert_eq!(system.balance(receiver), 0u64);
+        assert_eq!(system.wrapped_supply(), system.underlying_custody());
+    }
+}
+
+#[test]
+fn rejected_part_of_transfer_and_call_is_refunded() {
+    let responses: [(Callback, u64); 3] = [
+        (|_| respond(NotificationResponse::Reject("closed".to_string())), 0),
+        (|_| respond(NotificationResponse::PartialAccept(Nat::from(30))), 30),
+        // cannot accept more than was sent
+        (|_| respond(NotificationResponse::PartialAccept(Nat::from(500))), 90),
+    ];
+    for num_shards in [1, 2] {
+        for (reply, accepted) in responses {
+            let (mut system, callback) = callback_system(num_shards, reply);
+            let (alice, receiver) = (user_id(1), user_id(2));
+
+            let response = system
+                .shard_transfer_and_call(alice, receiver, 100, callback, b"")
+                .unwrap();
+            assert!(response.is_ok(), "{:?}", response);
+            assert_eq!(system.balance(alice), 999u64 - FEE - accepted);
+            assert_eq!(system.balance(receiver), accepted);
+            assert_eq!(system.wrapped_supply(), system.underlying_custody());
+        }
+    }
+}
+
+#[test]
+fn random_schedules_are_reproducible() {
+    let run = |seed| {
+        let mut system = TokenSystem::new(Schedule::Random(seed), 3, FEE, UNDERLYING_FEE);
+        let users: Vec<Principal> = (1..=4).map(user_id).collect();
+        for &user in users.iter() {
+            system.mint_underlying(user, 1_000);
+            system.wrap(user, 1_000).unwrap();
+        }
+        let shards: Vec<Principal> = users.iter().map(|&u| system.register(u)).collect();
+        // concurrent transfers in a ring, interleaved by the schedule
+        for i in 0..users.len() {
+            let j = (i + 1) % users.len();
+            let args = candid::encode_args((shards[j], users[j], Nat::from(300))).unwrap();
+            system.sim.submit(users[i], shards[i], "shardTransfer", args);
+        }
+        system.sim.run();
+        let balances: Vec<Nat> = users.iter().map(|&u| system.balance(u)).collect();
+        assert_eq!(system.total_supply(), 4u64 * 999);
+        (balances, system.sim.time())
+    };
+
+    assert_eq!(run(7), run(7));
+}
@@ -0,0 +1,750 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use candid::parser::value::IDLValue;
+use candid::{decode_args, encode_args, encode_one, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use enoki_wrapped_token as token;
+use enoki_wrapped_token::cycles::CyclesConfig;
+use enoki_wrapped_token::governance::{GovernanceConfig, ProposalAction, ProposalStatus};
+use enoki_wrapped_token::routing::RoutingMode;
+use enoki_wrapped_token::shards::{Shard, ShardStatus};
+use enoki_wrapped_token::snapshots::Snapshot;
+use enoki_wrapped_token_shard as shard;
+use enoki_wrapped_token_shard::escrow::HeldTransfer;
+use enoki_wrapped_token_shard::management::ManagerContractData;
+use enoki_wrapped_token_shard::notifications::PendingNotification;
+use enoki_wrapped_token_shard::stable::{
+    LegacyEscrowState, LegacyNotificationsState, LegacyShardBalances,
+};
+use enoki_wrapped_token_shard::subscriptions::Subscription;
+use enoki_wrapped_token_harness::upgrade::get_mut;
+use enoki_wrapped_token_harness::{
+    canister_id, user_id, NativeCanister, SavedState, Schedule, TokenSystem,
+};
+use enoki_wrapped_token_shared::log::{LogEntry, LogLevel};
+use enoki_wrapped_token_shared::metrics::{HttpRequest, HttpResponse};
+use enoki_wrapped_token_shared::types::{
+    Account, ByteBuf, FeeBeneficiary, NotificationResponse, PauseScope, Result, Role,
+    ShardRegistry, ShardedTransferNotification, TxError,
+};
+
+const FEE: u64 = 10;
+
+type Undo = fn(&mut SavedState);
+type Decode = fn(&[u8]) -> SavedState;
+
+/// How the state saved by the main contract changed, newest first: each entry turns the state
+/// saved by a payload version into the state saved by the version before it.
+const TOKEN_HISTORY: &[(u32, Undo)] = &[
+    (13, |state| {
+        state.remove(&["version"]);
+    }),
+    (12, |state| {
+        state.remove(&["snapshots"]);
+    }),
+    (11, |state| {
+        state.remove(&["routing_mode"]);
+    }),
+    (10, |state| {
+        state.remove(&["registry_version"]);
+    }),
+    (9, |state| {
+        state.remove_nested("shards", &["status"]);
+    }),
+    (8, |state| {
+        state
+            .remove(&["cycles"])
+            .remove_nested("shards", &["cycles_history", "cycles_warning"]);
+    }),
+    (7, |state| {
+        state.remove(&["logs"]);
+    }),
+    (6, |state| {
+        state.remove(&["counters"]);
+    }),
+    (5, |state| {
+        state.remove(&["freeze_state"]);
+    }),
+    (4, |state| {
+        state.remove(&["paused"]);
+    }),
+    (3, |state| {
+        state.remove(&["governance"]);
+    }),
+    (2, |state| {
+        state
+            .remove(&["roles"])
+            .remove_nested("management_stats", &["pending_owner"]);
+    }),
+    (1, |state| {
+        state.remove_nested("management_stats", &["fee_beneficiaries"]);
+    }),
+];
+
+/// The same for the shards.
+const SHARD_HISTORY: &[(u32, Undo)] = &[
+    (16, |state| {
+        state.remove(&["version"]).remove_nested("escrow", &["held_at"]);
+    }),
+    (15, |state| {
+        state.remove(&["snapshots"]).remove_nested("escrow", &["epoch"]);
+    }),
+    (14, |state| {
+        state.remove_nested("manager_data", &["ring"]);
+    }),
+    (13, |state| {
+        state.remove_nested("manager_data", &["registry_version"]);
+    }),
+    (12, |state| {
+        state.remove(&["draining"]);
+    }),
+    (11, |state| {
+        state.remove(&["logs"]);
+    }),
+    (10, |state| {
+        state.remove(&["counters"]);
+    }),
+    (9, |state| {
+        state
+            .rewrite("shard_balances", to_owner)
+            .set_type::<LegacyShardBalances>("shard_balances")
+            .rewrite("escrow", to_owner)
+            .set_type::<Option<LegacyEscrowState>>("escrow")
+            .remove_nested("notifications", &["from_subaccount", "to_subaccount"])
+            .remove_nested("subscriptions", &["subaccount"]);
+    }),
+    (8, |state| {
+        state.remove(&["subscriptions"]);
+    }),
+    (7, |state| {
+        state
+            .rewrite("notifications", to_text)
+            .set_type::<Option<LegacyNotificationsState>>("notifications");
+    }),
+    (6, |state| {
+        state.remove(&["notifications"]);
+    }),
+    (5, |state| {
+        state.remove(&["escrow"]);
+    }),
+    (4, |state| {
+        state.remove(&["freeze_state"]);
+    }),
+    (3, |state| {
+        state.remove(&["paused"]);
+    }),
+    (2, |state| {
+        state.remove_nested("manager_data", &["roles"]);
+    }),
+    (1, |state| {
+        state.remove(&["fee_distribution"]);
+    }),
+];
+
+/// Replaces an account by its owner, as saved before subaccounts.
+fn to_owner(value: &mut IDLValue) {
+    if get_mut(value, "subaccount").is_some() {
+        if let Some(owner) = get_mut(value, "owner").cloned() {
+            *value = owner;
+        }
+    }
+}
+
+/// Replaces the data of a notification by text, as saved before it was a blob.
+fn to_text(value: &mut IDLValue) {
+    if let Some(data) = get_mut(value, "data") {
+        if let IDLValue::Vec(bytes) = data {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .map(|byte| match byte {
+                    IDLValue::Nat8(byte) => *byte,
+                    _ => panic!("data is not a blob"),
+                })
+                .collect();
+            *data = IDLValue::Text(String::from_utf8(bytes).unwrap());
+        }
+    }
+}
+
+/// Upgrades `canister` from the state payload version `version` would have saved.
+fn upgrade_from(
+    system: &mut TokenSystem,
+    canister: Principal,
+    decode: Decode,
+    history: &[(u32, Undo)],
+    version: u32,
+) {
+    system.sim.upgrade(canister, |bytes| {
+        let mut state = decode(&bytes);
+        for (_, undo) in history.iter().filter(|(introduced, _)| *introduced > version) {
+            undo(&mut state);
+        }
+        state.encode()
+    });
+}
+
+fn upgrade_token_from(system: &mut TokenSystem, version: u32) {
+    let token = system.token;
+    let decode = SavedState::decode::<token::upgrade::UpgradePayload>;
+    upgrade_from(system, token, decode, TOKEN_HISTORY, version);
+}
+
+fn upgrade_shards_from(system: &mut TokenSystem, version: u32) {
+    for shard in system.shards.clone() {
+        let decode = SavedState::decode::<shard::upgrade::UpgradePayload>;
+        upgrade_from(system, shard, decode, SHARD_HISTORY, version);
+    }
+}
+
+/// Alice and Bob on different shards, after Alice sent Bob 300.
+fn system() -> (TokenSystem, Principal, Principal) {
+    let mut system = TokenSystem::new(Schedule::Fifo, 2, FEE, 1);
+    let (alice, bob) = (user_id(1), user_id(2));
+    assert_ne!(system.register(alice), system.register(bob));
+    system.mint_underlying(alice, 1_000);
+    system.wrap(alice, 1_000).unwrap();
+    system.shard_transfer(alice, bob, 300).unwrap().unwrap();
+    (system, alice, bob)
+}
+
+/// The balances and supply survive, and the token still moves between shards.
+fn assert_state_survived(system: &mut TokenSystem, alice: Principal, bob: Principal) {
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300);
+    assert_eq!(system.balance(bob), 300 - FEE);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+    system.shard_transfer(bob, alice, 100).unwrap().unwrap();
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 + 100 - FEE);
+}
+
+#[test]
+fn the_current_state_survives_an_upgrade() {
+    let (mut system, alice, bob) = system();
+    for canister in system.shards.clone().into_iter().chain([system.token]) {
+        system.sim.upgrade(canister, |bytes| bytes);
+    }
+    assert_state_survived(&mut system, alice, bob);
+}
+
+/// The bytes `canister` saves to stable memory, through an upgrade that keeps them.
+fn saved_bytes(system: &mut TokenSystem, canister: Principal) -> Vec<u8> {
+    let mut saved = None;
+    system.sim.upgrade(canister, |bytes| {
+        saved = Some(bytes.clone());
+        bytes
+    });
+    saved.unwrap()
+}
+
+fn next_version(value: &mut IDLValue) {
+    if let IDLValue::Nat32(version) = value {
+        *version += 1;
+    }
+}
+
+#[test]
+fn payloads_of_newer_versions_are_refused() {
+    let (mut system, _, _) = system();
+    let (shard_id, token_id) = (system.shards[0], system.token);
+    let saved = saved_bytes(&mut system, shard_id);
+    let payload = shard::upgrade::decode_payload(&saved).unwrap();
+    assert_eq!(payload.version(), Some(shard::upgrade::PAYLOAD_VERSION));
+    let mut state = SavedState::decode::<shard::upgrade::UpgradePayload>(&saved);
+    state.rewrite("version", next_version);
+    assert!(shard::upgrade::decode_payload(&state.encode()).is_err());
+
+    let saved = saved_bytes(&mut system, token_id);
+    let payload = token::upgrade::decode_payload(&saved).unwrap();
+    assert_eq!(payload.version(), Some(token::upgrade::PAYLOAD_VERSION));
+    let mut state = SavedState::decode::<token::upgrade::UpgradePayload>(&saved);
+    state.rewrite("version", next_version);
+    assert!(token::upgrade::decode_payload(&state.encode()).is_err());
+}
+
+#[test]
+fn upgrades_from_before_version_tags() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 12);
+    upgrade_shards_from(&mut system, 15);
+    assert_state_survived(&mut system, alice, bob);
+}
+
+#[test]
+fn upgrades_from_before_snapshots() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 11);
+    upgrade_shards_from(&mut system, 14);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (snapshots, ): (Vec<Snapshot>, ) =
+        system.sim.query(owner, token, "getSnapshots", ()).unwrap();
+    assert!(snapshots.is_empty());
+    let (id, ): (Result<u64>, ) = system.sim.update(owner, token, "takeSnapshot", ()).unwrap();
+    let (balance, ): (Result<Nat>, ) = system
+        .sim
+        .update(alice, token, "balanceAtSnapshot", (alice, id.unwrap()))
+        .unwrap();
+    assert_eq!(balance.unwrap(), system.balance(alice));
+}
+
+#[test]
+fn upgrades_from_before_hashed_routing() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 10);
+    upgrade_shards_from(&mut system, 13);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (mode, ): (RoutingMode, ) = system.sim.query(owner, token, "getRoutingMode", ()).unwrap();
+    assert_eq!(mode, RoutingMode::Directory);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "setRoutingMode", (RoutingMode::Hashed, ))
+        .unwrap();
+    result.unwrap();
+    let (registry, ): (ShardRegistry, ) =
+        system.sim.query(owner, token, "getShardRegistry", ()).unwrap();
+    assert_eq!(registry.ring.unwrap().len(), 2);
+}
+
+#[test]
+fn upgrades_from_before_registry_versions() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 9);
+    upgrade_shards_from(&mut system, 12);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (registry, ): (ShardRegistry, ) =
+        system.sim.query(owner, token, "getShardRegistry", ()).unwrap();
+    assert_eq!(registry.version, 0);
+    let added = system.add_shard();
+    for shard in system.shards.clone() {
+        let (details, ): (ManagerContractData, ) = system
+            .sim
+            .query(owner, shard, "getManagementDetails", ())
+            .unwrap();
+        assert_eq!(details.registry_version, 1);
+        assert_eq!(details.sibling_shards.len(), 2);
+    }
+    system.shard_transfer(alice, user_id(3), 100).unwrap().unwrap();
+    assert_eq!(system.register(user_id(3)), added);
+}
+
+#[test]
+fn upgrades_from_before_decommissioning() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 8);
+    upgrade_shards_from(&mut system, 11);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (shards, ): (Vec<Shard>, ) = system.sim.query(owner, token, "getShardsInfo", ()).unwrap();
+    assert!(shards.iter().all(|shard| shard.status == ShardStatus::Active));
+    let old = system.register(alice);
+    let (draining, ): (bool, ) = system.sim.query(owner, old, "isDraining", ()).unwrap();
+    assert!(!draining);
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "startDecommission", (old, ))
+        .unwrap();
+    result.unwrap();
+    let (left, ): (Result<u64>, ) = system.sim.update(owner, token, "drainShard", (old, )).unwrap();
+    assert_eq!(left.unwrap(), 0);
+    assert_ne!(system.register(alice), old);
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 + 100 - FEE);
+}
+
+#[test]
+fn upgrades_from_before_cycles_polling() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 7);
+    upgrade_shards_from(&mut system, 11);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (config, ): (CyclesConfig, ) = system.sim.query(owner, token, "getCyclesConfig", ()).unwrap();
+    assert_eq!(config.threshold, CyclesConfig::default().threshold);
+    let (shards, ): (Vec<Shard>, ) = system.sim.query(owner, token, "getShardsInfo", ()).unwrap();
+    assert!(shards.iter().all(|shard| shard.cycles_history.is_empty()));
+    let (pool, ): (u64, ) = system.sim.query(owner, token, "getCyclesPool", ()).unwrap();
+    assert_eq!(pool, 0);
+}
+
+#[test]
+fn upgrades_from_before_event_logs() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 6);
+    upgrade_shards_from(&mut system, 10);
+    let (owner, token) = (system.owner, system.token);
+    let (entries, ): (Vec<LogEntry>, ) = system
+        .sim
+        .query(owner, token, "getLogs", (0u64, LogLevel::Debug))
+        .unwrap();
+    assert!(entries.is_empty());
+    assert_state_survived(&mut system, alice, bob);
+
+    let shard = system.register(bob);
+    let (entries, ): (Vec<LogEntry>, ) = system
+        .sim
+        .query(owner, shard, "getLogs", (0u64, LogLevel::Debug))
+        .unwrap();
+    assert!(entries.iter().any(|entry| entry.operation == "shardTransfer"));
+}
+
+#[test]
+fn upgrades_from_before_metrics() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 5);
+    upgrade_shards_from(&mut system, 9);
+    assert_state_survived(&mut system, alice, bob);
+
+    // only the transfer made since the upgrade is counted
+    let shard = system.register(bob);
+    let request = HttpRequest {
+        method: "GET".to_string(),
+        url: "/metrics?format=prometheus".to_string(),
+        headers: vec![],
+        body: ByteBuf::new(),
+    };
+    let (response, ): (HttpResponse, ) = system
+        .sim
+        .query(bob, shard, "http_request", (request, ))
+        .unwrap();
+    let body = String::from_utf8(response.body.into_vec()).unwrap();
+    assert!(body.lines().any(|line| line.starts_with("enoki_shard_transfers_total 1 ")));
+}
+
+#[test]
+fn upgrades_from_before_subaccounts() {
+    let (mut system, alice, bob) = system();
+    let callback = canister_id(50);
+    system.sim.install(
+        callback,
+        Box::new(NativeCanister(|_, _: &str, _| {
+            Ok(encode_one(NotificationResponse::Accept).unwrap())
+        })),
+        system.owner,
+        (),
+    );
+    let (alice_shard, bob_shard) = (system.register(alice), system.register(bob));
+    let args = (bob_shard, bob, Nat::from(100), callback, "deposit".to_string(), ByteBuf::new());
+    system.sim.submit(
+        alice,
+        alice_shard,
+        "shardTransferAndCall",
+        encode_args(args).unwrap(),
+    );
+    // upgraded while the transfer is held
+    loop {
+        let (held, ): (Vec<(u64, HeldTransfer)>, ) = system
+            .sim
+            .query(alice, alice_shard, "getHeldTransfers", ())
+            .unwrap();
+        if !held.is_empty() {
+            break;
+        }
+        let next = system.sim.deliverable()[0];
+        system.sim.step(next);
+    }
+    upgrade_token_from(&mut system, 5);
+    upgrade_shards_from(&mut system, 8);
+    let (held, ): (Vec<(u64, HeldTransfer)>, ) = system
+        .sim
+        .query(alice, alice_shard, "getHeldTransfers", ())
+        .unwrap();
+    assert_eq!(held[0].1.from, Account::from(alice));
+    assert_eq!(held[0].1.to, Account::from(bob));
+    system.sim.run();
+
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 - 100);
+    assert_eq!(system.balance(bob), 300 - FEE + 100 - FEE);
+    let savings = Account::new(alice, Some([1; 32]));
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(
+            alice,
+            alice_shard,
+            "shardAccountTransfer",
+            (None::<[u8; 32]>, alice_shard, savings, Nat::from(50)),
+        )
+        .unwrap();
+    result.unwrap();
+    let (saved, ): (Nat, ) = system
+        .sim
+        .query(alice, alice_shard, "shardAccountBalanceOf", (savings, ))
+        .unwrap();
+    assert_eq!(saved, 50 - FEE);
+}
+
+#[test]
+fn upgrades_from_before_subscriptions() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 5);
+    upgrade_shards_from(&mut system, 7);
+    assert_state_survived(&mut system, alice, bob);
+
+    let shard = system.register(alice);
+    let (subscriptions, ): (Vec<Subscription>, ) = system
+        .sim
+        .query(system.owner, shard, "getSubscriptions", ())
+        .unwrap();
+    assert!(subscriptions.is_empty());
+    let (id, ): (Result<u64>, ) = system
+        .sim
+        .update(alice, shard, "subscribe", (Some(alice), "onCredit".to_string()))
+        .unwrap();
+    assert_eq!(id.unwrap(), 0);
+}
+
+#[test]
+fn upgrades_from_before_blob_notification_data() {
+    let (mut system, alice, bob) = system();
+    let callback = canister_id(50);
+    let online = Rc::new(Cell::new(false));
+    let received = Rc::new(RefCell::new(vec![]));
+    let (is_online, notifications) = (online.clone(), received.clone());
+    system.sim.install(
+        callback,
+        Box::new(NativeCanister(move |_, _: &str, args: Vec<u8>| {
+            if !is_online.get() {
+                return Err((RejectionCode::CanisterError, "offline".to_string()));
+            }
+            let (notification, ): (ShardedTransferNotification, ) = decode_args(&args).unwrap();
+            notifications.borrow_mut().push(notification);
+            Ok(encode_one(NotificationResponse::Accept).unwrap())
+        })),
+        system.owner,
+        (),
+    );
+    let (alice_shard, bob_shard) = (system.register(alice), system.register(bob));
+    let args = (
+        bob_shard,
+        bob,
+        Nat::from(100),
+        callback,
+        "deposit".to_string(),
+        ByteBuf::from(&b"order-1"[..]),
+    );
+    let (result, ): (Result<u64>, ) = system
+        .sim
+        .update(alice, alice_shard, "shardTransferAndNotify", args)
+        .unwrap();
+    result.unwrap();
+    system.sim.heartbeat();
+    system.sim.run();
+
+    upgrade_token_from(&mut system, 5);
+    upgrade_shards_from(&mut system, 6);
+    let (pending, ): (Vec<PendingNotification>, ) = system
+        .sim
+        .query(alice, alice_shard, "getPendingNotifications", ())
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].attempts, 1);
+    assert_eq!(pending[0].notification.data, ByteBuf::from(&b"order-1"[..]));
+
+    online.set(true);
+    system.sim.advance_time(1_000_000_000);
+    system.sim.heartbeat();
+    system.sim.run();
+    assert_eq!(received.borrow().len(), 1);
+    assert_eq!(received.borrow()[0].data, ByteBuf::from(&b"order-1"[..]));
+    assert_eq!(system.balance(bob), 300 - FEE + 100 - FEE);
+}
+
+#[test]
+fn upgrades_from_before_notifications() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 5);
+    upgrade_shards_from(&mut system, 5);
+    assert_state_survived(&mut system, alice, bob);
+
+    let callback = canister_id(50);
+    system.sim.install(
+        callback,
+        Box::new(NativeCanister(|_, _: &str, _| {
+            Ok(encode_one(NotificationResponse::Accept).unwrap())
+        })),
+        system.owner,
+        (),
+    );
+    let (alice_shard, bob_shard) = (system.register(alice), system.register(bob));
+    let args = (bob_shard, bob, Nat::from(100), callback, "deposit".to_string(), ByteBuf::new());
+    let (result, ): (Result<u64>, ) = system
+        .sim
+        .update(alice, alice_shard, "shardTransferAndNotify", args)
+        .unwrap();
+    assert_eq!(result.unwrap(), 0);
+    system.sim.heartbeat();
+    system.sim.run();
+    let (pending, ): (Vec<PendingNotification>, ) = system
+        .sim
+        .query(alice, alice_shard, "getPendingNotifications", ())
+        .unwrap();
+    assert!(pending.is_empty());
+    assert_eq!(system.balance(bob), 300 - FEE - 100 + 100 - FEE);
+}
+
+#[test]
+fn upgrades_from_before_escrow() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 5);
+    upgrade_shards_from(&mut system, 4);
+    assert_state_survived(&mut system, alice, bob);
+
+    let callback = canister_id(50);
+    system.sim.install(
+        callback,
+        Box::new(NativeCanister(|_, _: &str, _| {
+            Ok(encode_one(NotificationResponse::Reject("closed".to_string())).unwrap())
+        })),
+        system.owner,
+        (),
+    );
+    let (alice_shard, bob_shard) = (system.register(alice), system.register(bob));
+    let args = (bob_shard, bob, Nat::from(100), callback, "deposit".to_string(), ByteBuf::new());
+    system.sim.submit(
+        alice,
+        alice_shard,
+        "shardTransferAndCall",
+        encode_args(args).unwrap(),
+    );
+    system.sim.run();
+    let (held, ): (Vec<(u64, HeldTransfer)>, ) = system
+        .sim
+        .query(alice, alice_shard, "getHeldTransfers", ())
+        .unwrap();
+    assert!(held.is_empty());
+    // the rejected value is refunded, less the fee
+    assert_eq!(system.balance(alice), 1_000 - 1 - 300 + 100 - FEE - FEE);
+    assert_eq!(system.wrapped_supply(), system.underlying_custody());
+}
+
+#[test]
+fn upgrades_from_before_freezing() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 4);
+    upgrade_shards_from(&mut system, 3);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let () = system
+        .sim
+        .update(owner, token, "freezeAccount", (alice, ))
+        .unwrap();
+    assert!(matches!(
+        system.shard_transfer(alice, bob, 100).unwrap(),
+        Err(TxError::AccountFrozen { .. })
+    ));
+}
+
+#[test]
+fn upgrades_from_before_pausing() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 3);
+    upgrade_shards_from(&mut system, 2);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (paused, ): (Vec<PauseScope>, ) =
+        system.sim.query(owner, token, "getPausedScopes", ()).unwrap();
+    assert!(paused.is_empty());
+    let () = system
+        .sim
+        .update(owner, token, "pause", (PauseScope::Transfers, ))
+        .unwrap();
+    assert!(matches!(
+        system.shard_transfer(alice, bob, 100).unwrap(),
+        Err(TxError::Paused)
+    ));
+}
+
+#[test]
+fn upgrades_from_before_governance() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 2);
+    upgrade_shards_from(&mut system, 2);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (config, ): (GovernanceConfig, ) =
+        system.sim.query(owner, token, "getGovernanceConfig", ()).unwrap();
+    assert!(config.signers.is_empty());
+    let config = GovernanceConfig {
+        signers: vec![owner],
+        threshold: 1,
+        timelock: 0,
+    };
+    let () = system
+        .sim
+        .update(owner, token, "configureGovernance", (config, ))
+        .unwrap();
+    let (id, ): (u64, ) = system
+        .sim
+        .update(owner, token, "submitProposal", (ProposalAction::SetFee(Nat::from(20)), ))
+        .unwrap();
+    assert_eq!(id, 0);
+    let (status, ): (ProposalStatus, ) = system
+        .sim
+        .update(owner, token, "executeProposal", (id, ))
+        .unwrap();
+    assert!(matches!(status, ProposalStatus::Executed { .. }));
+}
+
+#[test]
+fn upgrades_from_before_roles() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 1);
+    upgrade_shards_from(&mut system, 1);
+    assert_state_survived(&mut system, alice, bob);
+
+    // the owner keeps administering the shards
+    let (owner, token) = (system.owner, system.token);
+    for shard in system.shards.clone() {
+        let (roles, ): (Vec<Role>, ) = system.sim.query(owner, shard, "getRoles", (owner, )).unwrap();
+        assert!(roles.contains(&Role::Admin));
+    }
+    let (pending, ): (Option<Principal>, ) =
+        system.sim.query(owner, token, "getPendingOwner", ()).unwrap();
+    assert_eq!(pending, None);
+
+    let () = system
+        .sim
+        .update(owner, token, "grantRole", (alice, Role::Pauser))
+        .unwrap();
+    let shard = system.register(alice);
+    let (roles, ): (Vec<Role>, ) = system.sim.query(alice, shard, "getRoles", (alice, )).unwrap();
+    assert_eq!(roles, vec![Role::Pauser]);
+}
+
+#[test]
+fn upgrades_from_before_fee_distribution() {
+    let (mut system, alice, bob) = system();
+    upgrade_token_from(&mut system, 0);
+    upgrade_shards_from(&mut system, 0);
+    assert_state_survived(&mut system, alice, bob);
+
+    let (owner, token) = (system.owner, system.token);
+    let (beneficiaries, ): (Vec<FeeBeneficiary>, ) =
+        system.sim.query(owner, token, "getFeeBeneficiaries", ()).unwrap();
+    assert!(beneficiaries.is_empty());
+    let shard = system.register(alice);
+    let carol = (3..)
+        .map(user_id)
+        .find(|&user| system.register(user) == shard)
+        .unwrap();
+    let beneficiaries = vec![FeeBeneficiary {
+        id: carol,
+        weight: 1,
+    }];
+    let (result, ): (Result<()>, ) = system
+        .sim
+        .update(owner, token, "setFeeBeneficiaries", (beneficiaries, ))
+        .unwrap();
+    result.unwrap();
+    system.shard_transfer(alice, bob, 100).unwrap().unwrap();
+    assert_eq!(system.balance(carol), FEE);
+}
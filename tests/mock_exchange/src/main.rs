@@ -6,7 +6,10 @@ use std::ops::AddAssign;
 use candid::{candid_method, CandidType, Nat, Principal};
 use ic_cdk_macros::*;
 
-use enoki_wrapped_token_shared::types::ShardedTransferNotification;
+use enoki_wrapped_token_client::{CanisterTransport, TokenClient};
+use enoki_wrapped_token_shared::types::{
+    NotificationResponse, ShardedTransferNotification, TxError,
+};
 
 #[init]
 #[candid_method(init)]
@@ -15,8 +18,8 @@ async fn init(token_accepted: Principal) {
 }
 
 async fn register(token: Principal) {
-    let response: Result<(Principal,), _> = ic_cdk::call(token, "register", (ic_cdk::id(),)).await;
-    let assigned_shard = response.unwrap().0;
+    let client = TokenClient::new(CanisterTransport, token);
+    let assigned_shard = client.register(ic_cdk::id()).await.unwrap();
     STATE.with(|s| s.borrow_mut().assigned_shard = assigned_shard);
 }
 
@@ -68,7 +71,7 @@ fn get_deposit_shard_id() -> Principal {
 
 #[update(name = "deposit")]
 #[candid_method(update)]
-async fn deposit(notification: ShardedTransferNotification) -> String {
+async fn deposit(notification: ShardedTransferNotification) -> NotificationResponse {
     assert_from_token_shard(ic_cdk::caller()).await;
     assert_eq!(notification.to, ic_cdk::id());
     STATE.with(|s| {
@@ -78,7 +81,7 @@ async fn deposit(notification: ShardedTransferNotification) -> String {
             .or_default()
             .add_assign(notification.value);
     });
-    "OK".to_string()
+    NotificationResponse::Accept
 }
 
 #[update(name = "withdrawAll")]
@@ -87,13 +90,13 @@ async fn withdraw_all(shard_id: Principal, to: Principal) {
     let amount = STATE
         .with(|s| s.borrow_mut().deposits.remove(&ic_cdk::caller()))
         .expect("no deposits found");
-    let response: Result<(), _> = ic_cdk::call(
+    let response: Result<(Result<(), TxError>,), _> = ic_cdk::call(
         STATE.with(|s| s.borrow().assigned_shard),
         "shardTransfer",
         (shard_id, to, amount),
     )
     .await;
-    response.unwrap();
+    response.unwrap().0.unwrap();
 }
 
 #[query(name = "balance")]